@@ -9,7 +9,7 @@ use gtk::{
 use tracing::level_filters::LevelFilter;
 use tray_icon::menu::MenuEvent;
 
-use snxcore::{controller::ServiceCommand, model::params::TunnelParams, platform::SingleInstance};
+use snxcore::{controller::ServiceCommand, model::params::TunnelParams, platform::SingleInstance, redact::Redactor};
 
 use crate::theme::init_theme_monitoring;
 
@@ -34,6 +34,7 @@ fn main() -> anyhow::Result<()> {
         return Ok(());
     }
 
+    let redactor = Arc::new(Redactor::new(&tunnel_params.log_redact));
     let subscriber = tracing_subscriber::fmt()
         .with_max_level(
             tunnel_params
@@ -41,6 +42,7 @@ fn main() -> anyhow::Result<()> {
                 .parse::<LevelFilter>()
                 .unwrap_or(LevelFilter::OFF),
         )
+        .with_writer(move || redactor.writer(std::io::stdout()))
         .finish();
 
     tracing::subscriber::set_global_default(subscriber).unwrap();
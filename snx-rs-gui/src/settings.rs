@@ -128,7 +128,10 @@ impl SettingsDialog {
         let auth_type = gtk::ComboBoxText::builder().build();
         let tunnel_type = gtk::ComboBoxText::builder().build();
         let user_name = gtk::Entry::builder().text(&params.user_name).build();
-        let password = gtk::Entry::builder().text(&params.password).visibility(false).build();
+        let password = gtk::Entry::builder()
+            .text(params.password.as_str())
+            .visibility(false)
+            .build();
 
         let no_dns = gtk::CheckButton::builder().active(params.no_dns).build();
 
@@ -184,7 +187,7 @@ impl SettingsDialog {
             )
             .build();
         let cert_password = gtk::Entry::builder()
-            .text(params.cert_password.as_deref().unwrap_or_default())
+            .text(params.cert_password.as_deref().map(String::as_str).unwrap_or_default())
             .visibility(false)
             .build();
         let cert_id = gtk::Entry::builder()
@@ -369,10 +372,11 @@ impl SettingsDialog {
         params.login_type = self.widgets.auth_type.active_id().unwrap_or_default().into();
         params.tunnel_type = match self.widgets.tunnel_type.active().unwrap_or_default() {
             0 => TunnelType::Ipsec,
-            _ => TunnelType::Ssl,
+            1 => TunnelType::Ssl,
+            _ => TunnelType::Auto,
         };
         params.user_name = self.widgets.user_name.text().into();
-        params.password = self.widgets.password.text().into();
+        params.password = String::from(self.widgets.password.text()).into();
         params.no_dns = self.widgets.no_dns.is_active();
         params.search_domains = self
             .widgets
@@ -422,7 +426,7 @@ impl SettingsDialog {
             if text.is_empty() {
                 None
             } else {
-                Some(text.into())
+                Some(String::from(text).into())
             }
         };
         params.cert_id = {
@@ -489,13 +493,12 @@ impl SettingsDialog {
         let tunnel_box = self.form_box("Tunnel type");
         self.widgets.tunnel_type.insert_text(0, "IPSec");
         self.widgets.tunnel_type.insert_text(1, "SSL");
-        self.widgets
-            .tunnel_type
-            .set_active(if self.params.tunnel_type == TunnelType::Ipsec {
-                Some(0)
-            } else {
-                Some(1)
-            });
+        self.widgets.tunnel_type.insert_text(2, "Auto");
+        self.widgets.tunnel_type.set_active(Some(match self.params.tunnel_type {
+            TunnelType::Ipsec => 0,
+            TunnelType::Ssl => 1,
+            TunnelType::Auto => 2,
+        }));
         tunnel_box.pack_start(&self.widgets.tunnel_type, false, true, 0);
         tunnel_box
     }
@@ -175,6 +175,7 @@ impl AppTray {
                     Err(ref e) if command == ServiceCommand::Connect => {
                         let _ = prompt::GtkPrompt.show_notification("Connection failed", &e.to_string());
                     }
+                    Ok(ref status) => notify_changes(self.status.as_ref().ok(), status),
                     _ => {}
                 }
 
@@ -191,3 +192,18 @@ impl AppTray {
         Ok(())
     }
 }
+
+// new warnings and a disconnect by the service, e.g. after the maximum session duration, are shown once
+fn notify_changes(prev: Option<&ConnectionStatus>, status: &ConnectionStatus) {
+    for warning in &status.warnings {
+        if !prev.is_some_and(|prev| prev.warnings.contains(warning)) {
+            let _ = prompt::GtkPrompt.show_notification("Warning", warning);
+        }
+    }
+
+    if let Some(ref reason) = status.disconnect_reason {
+        if !prev.is_some_and(|prev| prev.disconnect_reason.as_ref() == Some(reason)) {
+            let _ = prompt::GtkPrompt.show_notification("Disconnected", reason);
+        }
+    }
+}
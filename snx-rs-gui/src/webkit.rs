@@ -9,7 +9,10 @@ use webkit2gtk::{
     WebContext, WebView, WebViewExt, WebViewExtManual, WebsiteDataManager, WebsiteDataManagerExt,
 };
 
-use snxcore::{browser::BrowserController, model::params::TunnelParams};
+use snxcore::{
+    browser::{self, BrowserController},
+    model::params::TunnelParams,
+};
 
 pub struct WebkitBrowser(pub Arc<TunnelParams>);
 
@@ -59,23 +62,26 @@ fn open_browser(url: String, params: Arc<TunnelParams>) -> anyhow::Result<()> {
             .height_request(500)
             .build();
 
-        let data_manager = WebsiteDataManager::default();
-        data_manager.set_persistent_credential_storage_enabled(true);
+        let cookie_store = browser::saml_cookie_store(&params);
+
+        let data_manager = if cookie_store.is_some() {
+            WebsiteDataManager::default()
+        } else {
+            WebsiteDataManager::new_ephemeral()
+        };
+        data_manager.set_persistent_credential_storage_enabled(cookie_store.is_some());
         data_manager.set_tls_errors_policy(if params.ignore_server_cert {
             TLSErrorsPolicy::Ignore
         } else {
             TLSErrorsPolicy::Fail
         });
 
-        let dir = TunnelParams::default_config_path().parent().unwrap().to_owned();
-
-        let _ = std::fs::create_dir_all(&dir);
-        let cookies_file = dir.join("cookies.db");
-
-        data_manager
-            .cookie_manager()
-            .unwrap()
-            .set_persistent_storage(&format!("{}", cookies_file.display()), CookiePersistentStorage::Sqlite);
+        if let Some(cookies_file) = cookie_store {
+            data_manager
+                .cookie_manager()
+                .unwrap()
+                .set_persistent_storage(&format!("{}", cookies_file.display()), CookiePersistentStorage::Sqlite);
+        }
 
         let context = WebContext::builder().website_data_manager(&data_manager).build();
         let webview = WebView::new_with_context_and_user_content_manager(&context, &UserContentManager::new());
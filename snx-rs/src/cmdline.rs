@@ -1,10 +1,18 @@
-use std::{path::PathBuf, time::Duration};
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
 
+use anyhow::anyhow;
 use clap::Parser;
 use ipnet::Ipv4Net;
 use tracing::level_filters::LevelFilter;
 
-use snxcore::model::params::{CertType, OperationMode, TunnelParams, TunnelType};
+use snxcore::model::params::{
+    AppRouting, CertType, DnsManager, ExistingInterface, IpMode, KeepaliveFailure, OperationMode, Quirk, TunnelParams,
+    TunnelType,
+};
 
 #[derive(Parser)]
 #[clap(about = "VPN client for Checkpoint security gateway", name = "snx-rs")]
@@ -29,6 +37,15 @@ pub struct CmdlineParams {
     #[clap(long = "config-file", short = 'c', help = "Read parameters from config file")]
     pub config_file: Option<PathBuf>,
 
+    #[clap(
+        long = "ready-file",
+        help = "Command mode: create the given file once the service accepts commands, for health checks"
+    )]
+    pub ready_file: Option<PathBuf>,
+
+    #[clap(long = "json", help = "Info mode: print the login types as JSON")]
+    pub json: bool,
+
     #[clap(
         long = "log-level",
         short = 'l',
@@ -36,6 +53,22 @@ pub struct CmdlineParams {
     )]
     pub log_level: Option<LevelFilter>,
 
+    #[clap(
+        long = "quiet",
+        short = 'q',
+        conflicts_with = "verbose",
+        help = "Only log errors, overrides the log level"
+    )]
+    pub quiet: bool,
+
+    #[clap(
+        long = "verbose",
+        short = 'v',
+        action = clap::ArgAction::Count,
+        help = "Enable debug logging, repeat for trace level, overrides the log level"
+    )]
+    pub verbose: u8,
+
     #[clap(long = "search-domains", short = 'd', help = "Additional search domains")]
     pub search_domains: Vec<String>,
 
@@ -83,7 +116,7 @@ pub struct CmdlineParams {
     )]
     pub ignore_server_cert: Option<bool>,
 
-    #[clap(long = "tunnel-type", short = 'e', help = "Tunnel type, one of: ssl, ipsec")]
+    #[clap(long = "tunnel-type", short = 'e', help = "Tunnel type, one of: ssl, ipsec, auto")]
     pub tunnel_type: Option<TunnelType>,
 
     #[clap(long = "ca-cert", short = 'k', help = "Custom CA cert file in PEM or DER format")]
@@ -106,7 +139,7 @@ pub struct CmdlineParams {
     #[clap(
         long = "cert-path",
         short = 'z',
-        help = "Path to PEM file for PKCS8, path to PFX file for PKCS12, path to driver file for PKCS11 token"
+        help = "Path to PEM file for PKCS8, path to PFX file for PKCS12, path to driver file for PKCS11 token. For PKCS8 and PKCS12 a directory or comma-separated list selects the certificate by issuer"
     )]
     pub cert_path: Option<PathBuf>,
 
@@ -120,9 +153,21 @@ pub struct CmdlineParams {
     #[clap(long = "cert-id", short = 'w', help = "Certificate ID in hexadecimal form")]
     pub cert_id: Option<String>,
 
+    #[clap(
+        long = "cert-expiry-warning",
+        help = "Warn when the client certificate expires within the given number of days [default: 14]"
+    )]
+    pub cert_expiry_warning: Option<u64>,
+
     #[clap(long = "if-name", short = 'f', help = "Interface name for tun or xfrm device")]
     pub if_name: Option<String>,
 
+    #[clap(
+        long = "tun-device",
+        help = "SSL only: TUN device for the tunnel traffic, an existing persistent device is attached to"
+    )]
+    pub tun_device: Option<String>,
+
     #[clap(
         long = "no-keychain",
         short = 'K',
@@ -130,6 +175,12 @@ pub struct CmdlineParams {
     )]
     pub no_keychain: Option<bool>,
 
+    #[clap(
+        long = "keychain-timeout",
+        help = "Seconds to wait for the OS keychain before falling back to the password prompt [default: 5]"
+    )]
+    pub keychain_timeout: Option<u64>,
+
     #[clap(
         long = "server-prompt",
         short = 'P',
@@ -140,15 +191,377 @@ pub struct CmdlineParams {
     #[clap(long = "esp-lifetime", short = 'E', help = "IPSec ESP lifetime in seconds")]
     pub esp_lifetime: Option<u64>,
 
+    #[clap(
+        long = "esp-max-bytes",
+        help = "Rekey the IPSec ESP SA before the given number of bytes is sent"
+    )]
+    pub esp_max_bytes: Option<u64>,
+
+    #[clap(
+        long = "socket-rcvbuf",
+        help = "Receive buffer size of the IPSec UDP socket in bytes"
+    )]
+    pub socket_rcvbuf: Option<usize>,
+
+    #[clap(long = "socket-sndbuf", help = "Send buffer size of the IPSec UDP socket in bytes")]
+    pub socket_sndbuf: Option<usize>,
+
     #[clap(long = "ike-lifetime", short = 'L', help = "IPSec IKE lifetime in seconds")]
     pub ike_lifetime: Option<u64>,
 
     #[clap(long = "ike-port", short = 'R', help = "IPSec IKE communication port [default: 500]")]
     pub ike_port: Option<u16>,
+
+    #[clap(
+        long = "anti-replay-window",
+        help = "IPSec ESP anti-replay window size in packets, 0 to disable, maximum is 32"
+    )]
+    pub anti_replay_window: Option<u32>,
+
+    #[clap(
+        long = "tunnel-mtu",
+        help = "MTU of the tunnel interface, overrides the MTU pushed by the gateway"
+    )]
+    pub tunnel_mtu: Option<u16>,
+
+    #[clap(
+        long = "pmtud",
+        help = "Set the DF bit on the outer IPSec packets for path MTU discovery [default: true]"
+    )]
+    pub pmtud: Option<bool>,
+
+    #[clap(
+        long = "rate-limit",
+        help = "Limit the outbound tunnel traffic to the given number of bytes per second"
+    )]
+    pub rate_limit: Option<u64>,
+
+    #[clap(
+        long = "rate-limit-inbound",
+        help = "Apply the rate limit to the inbound tunnel traffic as well [default: false]"
+    )]
+    pub rate_limit_inbound: Option<bool>,
+
+    #[clap(
+        long = "keepalive",
+        help = "Send periodic keepalive packets to detect a dead tunnel [default: true]"
+    )]
+    pub keepalive: Option<bool>,
+
+    #[clap(
+        long = "keepalive-target",
+        help = "IPSec only: inner address to send the keepalive packets to instead of the gateway"
+    )]
+    pub keepalive_target: Option<Ipv4Addr>,
+
+    #[clap(
+        long = "mfa-cache-ttl",
+        help = "Reuse the last MFA answers for the given number of seconds on reconnect, 0 to disable [default: 0]"
+    )]
+    pub mfa_cache_ttl: Option<u64>,
+
+    #[clap(
+        long = "mfa-attempts",
+        help = "Number of answers allowed for a rejected MFA challenge if the gateway accepts retries [default: 1]"
+    )]
+    pub mfa_attempts: Option<u32>,
+
+    #[clap(
+        long = "mfa-push-wait",
+        help = "Wait the given number of seconds for the push approval before asking for the code, 0 to disable [default: 0]"
+    )]
+    pub mfa_push_wait: Option<u64>,
+
+    #[clap(
+        long = "mfa-preference",
+        help = "Code delivery methods to request automatically in the order of preference, e.g. push,sms,otp"
+    )]
+    pub mfa_preference: Vec<String>,
+
+    #[clap(
+        long = "otp-pipe",
+        help = "Named pipe to read the OTP codes from, written by an external device"
+    )]
+    pub otp_pipe: Option<PathBuf>,
+
+    #[clap(
+        long = "auth-failure-limit",
+        help = "Refuse to connect after this many consecutive authentication failures, 0 to disable [default: 5]"
+    )]
+    pub auth_failure_limit: Option<u32>,
+
+    #[clap(
+        long = "auth-cooldown",
+        help = "Seconds to refuse connecting once the authentication failure limit is reached [default: 300]"
+    )]
+    pub auth_cooldown: Option<u64>,
+
+    #[clap(
+        long = "retry-on-busy",
+        help = "Retry the connection this many times when the gateway is busy [default: 0]"
+    )]
+    pub retry_on_busy: Option<u32>,
+
+    #[clap(
+        long = "busy-error-codes",
+        help = "Gateway error code meaning that the gateway is busy, may be repeated"
+    )]
+    pub busy_error_codes: Vec<u32>,
+
+    #[clap(
+        long = "outer-vrf",
+        help = "Name of the VRF to bind the outer IPSec traffic to, for advanced Linux routing setups"
+    )]
+    pub outer_vrf: Option<String>,
+
+    #[clap(
+        long = "disconnect-grace",
+        help = "Delay tunnel teardown on disconnect by the given number of seconds, 0 to disable [default: 0]"
+    )]
+    pub disconnect_grace: Option<u64>,
+
+    #[clap(
+        long = "max-session-duration",
+        help = "Disconnect the tunnel after the given number of seconds, 0 to disable [default: 0]"
+    )]
+    pub max_session_duration: Option<u64>,
+
+    #[clap(
+        long = "prewarm-refresh",
+        help = "Refresh interval in seconds for the pre-authenticated standby session [default: 1800]"
+    )]
+    pub prewarm_refresh: Option<u64>,
+
+    #[clap(
+        long = "realm",
+        help = "Authentication realm id for gateways with several realms per login type"
+    )]
+    pub realm: Option<String>,
+
+    #[clap(
+        long = "idp",
+        help = "SAML identity provider id or name for gateways with several providers"
+    )]
+    pub idp: Option<String>,
+
+    #[clap(
+        long = "saml-manual",
+        help = "Paste the SAML token from the browser instead of receiving it on the local port [default: false]"
+    )]
+    pub saml_manual: Option<bool>,
+
+    #[clap(
+        long = "saml-session-reuse",
+        help = "Keep the identity provider session of the embedded browser between the connections [default: true]"
+    )]
+    pub saml_session_reuse: Option<bool>,
+
+    #[clap(
+        long = "saml-session-ttl",
+        help = "Drop the stored identity provider session when unused for the given number of seconds, 0 for no limit [default: 0]"
+    )]
+    pub saml_session_ttl: Option<u64>,
+
+    #[clap(
+        long = "embedded-dns",
+        help = "Run a local DNS forwarder for split DNS instead of configuring the system resolver per domain"
+    )]
+    pub embedded_dns: Option<bool>,
+
+    #[clap(
+        long = "dns-split-domains",
+        help = "Domains resolved via the tunnel DNS by the embedded forwarder [default: acquired search domains]"
+    )]
+    pub dns_split_domains: Vec<String>,
+
+    #[clap(
+        long = "posture-script",
+        help = "Script which prints custom endpoint compliance data as key=value lines"
+    )]
+    pub posture_script: Option<PathBuf>,
+
+    #[clap(
+        long = "reconnect-on-resume",
+        help = "Probe the tunnel after system resume and reconnect if it is dead [default: false]"
+    )]
+    pub reconnect_on_resume: Option<bool>,
+
+    #[clap(
+        long = "route-table",
+        help = "IPSec routing table id for the tunnel routes, traffic from the tunnel address is directed to it"
+    )]
+    pub route_table: Option<u32>,
+
+    #[clap(
+        long = "force-tunnel-dns",
+        help = "Send all DNS queries to the tunnel resolvers while connected [default: false]"
+    )]
+    pub force_tunnel_dns: Option<bool>,
+
+    #[clap(
+        long = "persist-stats",
+        help = "Keep the traffic usage windows across reconnects [default: false]"
+    )]
+    pub persist_stats: Option<bool>,
+
+    #[clap(
+        long = "instance-name",
+        help = "Name of the service instance, isolates the command port and keychain entries of parallel instances"
+    )]
+    pub instance_name: Option<String>,
+
+    #[clap(
+        long = "listen-port",
+        help = "Local UDP port of the command server, overrides the port derived from the instance name [default: 7779]"
+    )]
+    pub listen_port: Option<u16>,
+
+    #[clap(
+        long = "honor-encryption-domain",
+        help = "Route only the encryption domain pushed by the gateway, false for a full tunnel [default: true]"
+    )]
+    pub honor_encryption_domain: Option<bool>,
+
+    #[clap(
+        long = "strict-routes",
+        help = "Refuse to connect when existing routes take precedence over the IPSec tunnel routes [default: false]"
+    )]
+    pub strict_routes: Option<bool>,
+
+    #[clap(
+        long = "isolate-overlaps",
+        help = "Use a separate routing table when the routes overlap another tunnel [default: false]"
+    )]
+    pub isolate_overlaps: Option<bool>,
+
+    #[clap(
+        long = "ip-mode",
+        help = "Address family for the gateway connections, one of: auto, v4, v6 [default: auto]"
+    )]
+    pub ip_mode: Option<IpMode>,
+
+    #[clap(
+        long = "dns-manager",
+        help = "DNS settings mechanism, one of: auto, resolved, networkmanager, resolvconf, file [default: auto]"
+    )]
+    pub dns_manager: Option<DnsManager>,
+
+    #[clap(
+        long = "auto-exclude-local",
+        help = "Keep the directly connected local subnets reachable outside of the tunnel [default: true]"
+    )]
+    pub auto_exclude_local: Option<bool>,
+
+    #[clap(
+        long = "reconnect-on",
+        help = "Keepalive failures which trigger a reconnect in command mode, any of: timeout, unreachable, resume, auth-revoked"
+    )]
+    pub reconnect_on: Vec<KeepaliveFailure>,
+
+    #[clap(
+        long = "quirks",
+        help = "Gateway workarounds, any of: no-compression, no-client-logging-data, no-redirect-check"
+    )]
+    pub quirks: Vec<Quirk>,
+
+    #[clap(
+        long = "redirect-hosts",
+        help = "Additional host the gateway may redirect the control requests to, may be repeated"
+    )]
+    pub redirect_hosts: Vec<String>,
+
+    #[clap(
+        long = "app-routing",
+        help = "Route only the given applications through the IPSec tunnel: cgroup:<path> or fwmark:<mark>"
+    )]
+    pub app_routing: Option<AppRouting>,
+
+    #[clap(
+        long = "tunnel-ports",
+        help = "Route only the traffic to the given destination ports through the IPSec tunnel"
+    )]
+    pub tunnel_ports: Vec<u16>,
+
+    #[clap(
+        long = "detect-captive-portal",
+        help = "Check for a captive portal before connecting [default: false]"
+    )]
+    pub detect_captive_portal: Option<bool>,
+
+    #[clap(
+        long = "verify-cleanup",
+        help = "Verify that the tunnel device and routing rules are removed on disconnect [default: true]"
+    )]
+    pub verify_cleanup: Option<bool>,
+
+    #[clap(
+        long = "on-existing-interface",
+        help = "What to do when the tunnel interface already exists, one of: fail, reuse, recreate [default: recreate]"
+    )]
+    pub on_existing_interface: Option<ExistingInterface>,
+
+    #[clap(
+        long = "flush-dns-on-disconnect",
+        help = "Flush the local DNS cache after disconnecting [default: false]"
+    )]
+    pub flush_dns_on_disconnect: Option<bool>,
+
+    #[clap(
+        long = "ddns-hostname",
+        help = "Register the tunnel address under this hostname with a dynamic DNS update"
+    )]
+    pub ddns_hostname: Option<String>,
+
+    #[clap(long = "ddns-server", help = "DNS server which receives the dynamic DNS updates")]
+    pub ddns_server: Option<String>,
+
+    #[clap(long = "ddns-key", help = "TSIG key file for signing the dynamic DNS updates")]
+    pub ddns_key: Option<PathBuf>,
+
+    #[clap(
+        long = "log-redact",
+        help = "Additional field names or patterns whose values are removed from the log output"
+    )]
+    pub log_redact: Vec<String>,
+
+    #[clap(
+        long = "auto-start-service",
+        help = "Restart the systemd service when it is not reachable and retry the command [default: false]"
+    )]
+    pub auto_start_service: Option<bool>,
+
+    #[clap(
+        long = "http-api-bind",
+        help = "Address and port of the HTTP control API in command mode, e.g. 127.0.0.1:7780"
+    )]
+    pub http_api_bind: Option<SocketAddr>,
+
+    #[clap(long = "http-api-token", help = "Bearer token required by the HTTP control API")]
+    pub http_api_token: Option<String>,
+
+    #[clap(
+        long = "doh-resolver",
+        help = "DNS-over-HTTPS endpoint inside the tunnel which resolves the tunnel DNS queries, e.g. https://10.0.0.53/dns-query"
+    )]
+    pub doh_resolver: Option<String>,
+
+    #[clap(
+        long = "secrets-dir",
+        help = "Directory with one file per option, such as server-name or password, read over the configuration file"
+    )]
+    pub secrets_dir: Option<PathBuf>,
 }
 
 impl CmdlineParams {
-    pub fn merge_into_tunnel_params(self, other: &mut TunnelParams) {
+    pub fn merge_into_tunnel_params(self, other: &mut TunnelParams) -> anyhow::Result<()> {
+        // the secrets are read first so that the command line options take precedence
+        if let Some(secrets_dir) = self.secrets_dir {
+            other
+                .load_secrets(&secrets_dir)
+                .map_err(|e| anyhow!("Unable to read the secrets from {}: {}", secrets_dir.display(), e))?;
+            other.secrets_dir = Some(secrets_dir);
+        }
+
         if let Some(server_name) = self.server_name {
             other.server_name = server_name;
         }
@@ -158,11 +571,18 @@ impl CmdlineParams {
         }
 
         if let Some(password) = self.password {
-            other.password = password;
+            other.password = password.into();
             let _ = other.decode_password();
         }
 
-        if let Some(log_level) = self.log_level {
+        let verbosity = match (self.quiet, self.verbose) {
+            (true, _) => Some(LevelFilter::ERROR),
+            (false, 0) => None,
+            (false, 1) => Some(LevelFilter::DEBUG),
+            (false, _) => Some(LevelFilter::TRACE),
+        };
+
+        if let Some(log_level) = verbosity.or(self.log_level) {
             other.log_level = log_level.to_string();
         }
 
@@ -223,21 +643,33 @@ impl CmdlineParams {
         }
 
         if let Some(cert_password) = self.cert_password {
-            other.cert_password = Some(cert_password);
+            other.cert_password = Some(cert_password.into());
         }
 
         if let Some(cert_id) = self.cert_id {
             other.cert_id = Some(cert_id);
         }
 
+        if let Some(cert_expiry_warning) = self.cert_expiry_warning {
+            other.cert_expiry_warning = Duration::from_secs(cert_expiry_warning.saturating_mul(86400));
+        }
+
         if let Some(if_name) = self.if_name {
             other.if_name = Some(if_name);
         }
 
+        if let Some(tun_device) = self.tun_device {
+            other.tun_device = Some(tun_device);
+        }
+
         if let Some(no_keychain) = self.no_keychain {
             other.no_keychain = no_keychain;
         }
 
+        if let Some(keychain_timeout) = self.keychain_timeout {
+            other.keychain_timeout = Duration::from_secs(keychain_timeout);
+        }
+
         if let Some(server_prompt) = self.server_prompt {
             other.server_prompt = server_prompt;
         }
@@ -246,6 +678,18 @@ impl CmdlineParams {
             other.esp_lifetime = Duration::from_secs(esp_lifetime);
         }
 
+        if let Some(esp_max_bytes) = self.esp_max_bytes {
+            other.esp_max_bytes = esp_max_bytes;
+        }
+
+        if let Some(socket_rcvbuf) = self.socket_rcvbuf {
+            other.socket_rcvbuf = Some(socket_rcvbuf);
+        }
+
+        if let Some(socket_sndbuf) = self.socket_sndbuf {
+            other.socket_sndbuf = Some(socket_sndbuf);
+        }
+
         if let Some(ike_lifetime) = self.ike_lifetime {
             other.ike_lifetime = Duration::from_secs(ike_lifetime);
         }
@@ -253,5 +697,241 @@ impl CmdlineParams {
         if let Some(ike_port) = self.ike_port {
             other.ike_port = ike_port;
         }
+
+        if let Some(anti_replay_window) = self.anti_replay_window {
+            other.anti_replay_window = Some(anti_replay_window);
+        }
+
+        if self.tunnel_mtu.is_some() {
+            other.tunnel_mtu = self.tunnel_mtu;
+        }
+
+        if let Some(pmtud) = self.pmtud {
+            other.pmtud = pmtud;
+        }
+
+        if self.rate_limit.is_some() {
+            other.rate_limit = self.rate_limit.filter(|&rate| rate > 0);
+        }
+
+        if let Some(rate_limit_inbound) = self.rate_limit_inbound {
+            other.rate_limit_inbound = rate_limit_inbound;
+        }
+
+        if let Some(keepalive) = self.keepalive {
+            other.keepalive = keepalive;
+        }
+
+        if self.keepalive_target.is_some() {
+            other.keepalive_target = self.keepalive_target;
+        }
+
+        if let Some(mfa_cache_ttl) = self.mfa_cache_ttl {
+            other.mfa_cache_ttl = Duration::from_secs(mfa_cache_ttl);
+        }
+
+        if let Some(mfa_attempts) = self.mfa_attempts {
+            other.mfa_attempts = mfa_attempts;
+        }
+
+        if let Some(mfa_push_wait) = self.mfa_push_wait {
+            other.mfa_push_wait = Duration::from_secs(mfa_push_wait);
+        }
+
+        if !self.mfa_preference.is_empty() {
+            other.mfa_preference = self
+                .mfa_preference
+                .iter()
+                .flat_map(|methods| methods.split(','))
+                .map(|method| method.trim().to_lowercase())
+                .filter(|method| !method.is_empty())
+                .collect();
+        }
+
+        if let Some(otp_pipe) = self.otp_pipe {
+            other.otp_pipe = Some(otp_pipe);
+        }
+
+        if let Some(auth_failure_limit) = self.auth_failure_limit {
+            other.auth_failure_limit = auth_failure_limit;
+        }
+
+        if let Some(auth_cooldown) = self.auth_cooldown {
+            other.auth_cooldown = Duration::from_secs(auth_cooldown);
+        }
+
+        if let Some(retry_on_busy) = self.retry_on_busy {
+            other.retry_on_busy = retry_on_busy;
+        }
+
+        if !self.busy_error_codes.is_empty() {
+            other.busy_error_codes = self.busy_error_codes;
+        }
+
+        if self.outer_vrf.is_some() {
+            other.outer_vrf = self.outer_vrf;
+        }
+
+        if let Some(disconnect_grace) = self.disconnect_grace {
+            other.disconnect_grace = Duration::from_secs(disconnect_grace);
+        }
+
+        if let Some(max_session_duration) = self.max_session_duration {
+            other.max_session_duration = Duration::from_secs(max_session_duration);
+        }
+
+        if let Some(prewarm_refresh) = self.prewarm_refresh {
+            other.prewarm_refresh = Duration::from_secs(prewarm_refresh);
+        }
+
+        if self.realm.is_some() {
+            other.realm = self.realm;
+        }
+
+        if self.idp.is_some() {
+            other.idp = self.idp;
+        }
+
+        if let Some(saml_manual) = self.saml_manual {
+            other.saml_manual = saml_manual;
+        }
+
+        if let Some(saml_session_reuse) = self.saml_session_reuse {
+            other.saml_session_reuse = saml_session_reuse;
+        }
+
+        if let Some(saml_session_ttl) = self.saml_session_ttl {
+            other.saml_session_ttl = Duration::from_secs(saml_session_ttl);
+        }
+
+        if let Some(embedded_dns) = self.embedded_dns {
+            other.embedded_dns = embedded_dns;
+        }
+
+        if !self.dns_split_domains.is_empty() {
+            other.dns_split_domains = self.dns_split_domains;
+        }
+
+        if self.posture_script.is_some() {
+            other.posture_script = self.posture_script;
+        }
+
+        if let Some(reconnect_on_resume) = self.reconnect_on_resume {
+            other.reconnect_on_resume = reconnect_on_resume;
+        }
+
+        if self.route_table.is_some() {
+            other.route_table = self.route_table;
+        }
+
+        if let Some(force_tunnel_dns) = self.force_tunnel_dns {
+            other.force_tunnel_dns = force_tunnel_dns;
+        }
+
+        if let Some(persist_stats) = self.persist_stats {
+            other.persist_stats = persist_stats;
+        }
+
+        if self.instance_name.is_some() {
+            other.instance_name = self.instance_name;
+        }
+
+        if self.listen_port.is_some() {
+            other.listen_port = self.listen_port;
+        }
+
+        if let Some(honor_encryption_domain) = self.honor_encryption_domain {
+            other.honor_encryption_domain = honor_encryption_domain;
+        }
+
+        if let Some(strict_routes) = self.strict_routes {
+            other.strict_routes = strict_routes;
+        }
+
+        if let Some(isolate_overlaps) = self.isolate_overlaps {
+            other.isolate_overlaps = isolate_overlaps;
+        }
+
+        if let Some(ip_mode) = self.ip_mode {
+            other.ip_mode = ip_mode;
+        }
+
+        if let Some(dns_manager) = self.dns_manager {
+            other.dns_manager = dns_manager;
+        }
+
+        if let Some(auto_exclude_local) = self.auto_exclude_local {
+            other.auto_exclude_local = auto_exclude_local;
+        }
+
+        if !self.reconnect_on.is_empty() {
+            other.reconnect_on = self.reconnect_on;
+        }
+
+        if !self.quirks.is_empty() {
+            other.quirks = self.quirks;
+        }
+
+        if !self.redirect_hosts.is_empty() {
+            other.redirect_hosts = self.redirect_hosts;
+        }
+
+        if self.app_routing.is_some() {
+            other.app_routing = self.app_routing;
+        }
+
+        if !self.tunnel_ports.is_empty() {
+            other.tunnel_ports = self.tunnel_ports;
+        }
+
+        if let Some(detect_captive_portal) = self.detect_captive_portal {
+            other.detect_captive_portal = detect_captive_portal;
+        }
+
+        if let Some(verify_cleanup) = self.verify_cleanup {
+            other.verify_cleanup = verify_cleanup;
+        }
+
+        if let Some(on_existing_interface) = self.on_existing_interface {
+            other.on_existing_interface = on_existing_interface;
+        }
+
+        if let Some(flush_dns_on_disconnect) = self.flush_dns_on_disconnect {
+            other.flush_dns_on_disconnect = flush_dns_on_disconnect;
+        }
+
+        if let Some(ddns_hostname) = self.ddns_hostname {
+            other.ddns_hostname = Some(ddns_hostname);
+        }
+
+        if let Some(ddns_server) = self.ddns_server {
+            other.ddns_server = Some(ddns_server);
+        }
+
+        if let Some(ddns_key) = self.ddns_key {
+            other.ddns_key = Some(ddns_key);
+        }
+
+        if !self.log_redact.is_empty() {
+            other.log_redact = self.log_redact;
+        }
+
+        if let Some(auto_start_service) = self.auto_start_service {
+            other.auto_start_service = auto_start_service;
+        }
+
+        if let Some(http_api_bind) = self.http_api_bind {
+            other.http_api_bind = Some(http_api_bind);
+        }
+
+        if let Some(http_api_token) = self.http_api_token {
+            other.http_api_token = Some(http_api_token.into());
+        }
+
+        if let Some(doh_resolver) = self.doh_resolver {
+            other.doh_resolver = Some(doh_resolver);
+        }
+
+        Ok(())
     }
 }
@@ -1,4 +1,4 @@
-use std::{future::Future, sync::Arc};
+use std::{future::Future, path::PathBuf, sync::Arc};
 
 use anyhow::anyhow;
 use clap::Parser;
@@ -8,18 +8,23 @@ use tokio::{
     sync::{mpsc, oneshot},
 };
 use tracing::{debug, metadata::LevelFilter, warn};
+use zeroize::Zeroizing;
 
 use snxcore::{
-    browser::run_otp_listener,
+    browser,
     ccc::CccHttpClient,
+    certs,
+    http_api::HttpApi,
     model::{
         params::{OperationMode, TunnelParams},
-        MfaType, SessionState,
+        ConnectionPhase, MfaType, SessionState,
     },
     platform,
     prompt::{SecurePrompt, TtyPrompt, OTP_TIMEOUT},
-    server::CommandServer,
-    server_info, tunnel,
+    redact::Redactor,
+    server::{CommandServer, PrivilegedOptions},
+    server_info,
+    tunnel::{self, TunnelEvent},
 };
 
 use crate::cmdline::CmdlineParams;
@@ -65,16 +70,20 @@ async fn main() -> anyhow::Result<()> {
     }
 
     let mode = cmdline_params.mode;
+    let ready_file = cmdline_params.ready_file.clone();
+    let json = cmdline_params.json;
 
     let mut params = if let Some(ref config_file) = cmdline_params.config_file {
         TunnelParams::load(config_file)?
     } else {
         TunnelParams::default()
     };
-    cmdline_params.merge_into_tunnel_params(&mut params);
+    cmdline_params.merge_into_tunnel_params(&mut params)?;
 
+    let redactor = Arc::new(Redactor::new(&params.log_redact));
     let subscriber = tracing_subscriber::fmt()
         .with_max_level(params.log_level.parse::<LevelFilter>().unwrap_or(LevelFilter::OFF))
+        .with_writer(move || redactor.writer(std::io::stdout()))
         .finish();
     tracing::subscriber::set_global_default(subscriber)?;
 
@@ -87,16 +96,19 @@ async fn main() -> anyhow::Result<()> {
         }
         OperationMode::Command => {
             debug!("Running in command mode");
-            main_command().await
+            main_command(params, ready_file).await
         }
-        OperationMode::Info => main_info(params).await,
+        OperationMode::Info => main_info(params, json).await,
     }
 }
 
-async fn main_info(params: TunnelParams) -> anyhow::Result<()> {
+async fn main_info(params: TunnelParams, json: bool) -> anyhow::Result<()> {
     if params.server_name.is_empty() {
         return Err(anyhow!("Missing required parameters: server name!"));
     }
+    if json {
+        return snxcore::util::print_login_types_json(&server_info::get_login_types(&params).await?);
+    }
     let client = CccHttpClient::new(Arc::new(params), None);
     let info = client.get_server_info().await?;
     snxcore::util::print_login_options(&info);
@@ -104,30 +116,86 @@ async fn main_info(params: TunnelParams) -> anyhow::Result<()> {
     Ok(())
 }
 
-async fn main_command() -> anyhow::Result<()> {
+async fn main_command(params: TunnelParams, ready_file: Option<PathBuf>) -> anyhow::Result<()> {
     if let Err(e) = platform::start_network_state_monitoring().await {
         warn!("Unable to start network monitoring: {}", e);
     }
-    let server = CommandServer::new(snxcore::server::LISTEN_PORT);
+    let mut server = CommandServer::new(snxcore::server::listen_port(&params));
+    server.set_socket_path(snxcore::ipc::socket_path(params.instance_name.as_deref()));
+    if let Some(api) = HttpApi::from_params(&params) {
+        server.set_http_api(api);
+    }
+    server.set_privileged_options(PrivilegedOptions::from_params(&params));
+
+    // the service always runs in the foreground, supervisors are told when it accepts commands
+    let ready_path = ready_file.clone();
+    server.set_ready_handler(move || {
+        if let Err(e) = platform::notify_ready() {
+            warn!("Unable to send the readiness notification: {}", e);
+        }
+        if let Some(ref ready_file) = ready_path {
+            if let Err(e) = std::fs::write(ready_file, format!("{}\n", std::process::id())) {
+                warn!("Unable to create the ready file {}: {}", ready_file.display(), e);
+            }
+        }
+    });
+
+    let result = await_termination(server.run()).await;
+
+    if let Some(ref ready_file) = ready_file {
+        let _ = std::fs::remove_file(ready_file);
+    }
 
-    await_termination(server.run()).await
+    result
 }
 
-async fn main_standalone(params: TunnelParams) -> anyhow::Result<()> {
+async fn main_standalone(mut params: TunnelParams) -> anyhow::Result<()> {
     // TODO: reuse code from CommandServer and ServiceController
 
     let (command_sender, command_receiver) = mpsc::channel(16);
 
-    if params.server_name.is_empty() || params.login_type.is_empty() {
-        return Err(anyhow!("Missing required parameters: server name and/or login type"));
+    if params.server_name.is_empty() {
+        return Err(anyhow!("Missing required parameter: server name"));
+    }
+
+    // the server info fetched for the realm selection also serves the MFA prompts
+    let mut info = None;
+
+    // the realm selection is only offered when neither the login type nor the realm is configured
+    if params.login_type.is_empty() {
+        params.login_type = match params.realm {
+            Some(ref realm) => realm.clone(),
+            None => {
+                let server_info = server_info::get(&params).await?;
+                let login_type = server_info::select_login_type(server_info.clone(), &TtyPrompt)?;
+                info = Some(server_info);
+                login_type
+            }
+        };
     }
 
-    let mut mfa_prompts = server_info::get_mfa_prompts(&params).await.unwrap_or_default();
+    let mut mfa_prompts = if params.server_prompt {
+        let info = match info {
+            Some(info) => Ok(info),
+            None => server_info::get(&params).await,
+        };
+        info.map(|info| server_info::mfa_prompts(info, &params.login_type))
+            .unwrap_or_default()
+    } else {
+        Default::default()
+    };
+
+    println!("{}...", ConnectionPhase::Authenticating);
+
+    certs::check_client_cert(&params)?;
 
-    let mut connector = tunnel::new_tunnel_connector(Arc::new(params)).await?;
+    let params = Arc::new(params);
+    let mut connector = tunnel::new_tunnel_connector(params.clone()).await?;
+    let params = Arc::new(params.with_tunnel_type(connector.tunnel_type()));
     let mut session = connector.authenticate().await?;
 
     while let SessionState::PendingChallenge(challenge) = session.state.clone() {
+        println!("{}...", ConnectionPhase::Mfa);
         match challenge.mfa_type {
             MfaType::UserInput => {
                 let prompt = mfa_prompts.pop_front().unwrap_or_else(|| challenge.prompt.clone());
@@ -140,18 +208,50 @@ async fn main_standalone(params: TunnelParams) -> anyhow::Result<()> {
                     }
                 }
             }
+            MfaType::PasswordChange => {
+                println!("The gateway requires a password change");
+                let password = Zeroizing::new(TtyPrompt.get_secure_input(&challenge.prompt)?);
+                let confirmation = Zeroizing::new(TtyPrompt.get_secure_input("Repeat the new password: ")?);
+                if password != confirmation {
+                    return Err(anyhow!("The new passwords do not match!"));
+                }
+                session = connector.challenge_code(session, &password).await?;
+            }
             MfaType::SamlSso => {
                 println!("For SAML authentication please open the following URL in your browser:");
                 println!("{}", challenge.prompt);
-                let (tx, rx) = oneshot::channel();
-                tokio::spawn(run_otp_listener(tx));
-                let otp = tokio::time::timeout(OTP_TIMEOUT, rx).await??;
+                let tcp = if params.saml_manual {
+                    None
+                } else {
+                    browser::bind_otp_listener().await.ok()
+                };
+                let otp = match tcp {
+                    Some(tcp) => {
+                        let (tx, rx) = oneshot::channel();
+                        tokio::spawn(browser::run_otp_listener(tcp, tx));
+                        tokio::time::timeout(OTP_TIMEOUT, rx).await??
+                    }
+                    None => browser::read_manual_otp(&TtyPrompt, "Redirected address or SAML token: ")?,
+                };
                 session = connector.challenge_code(session, &otp).await?;
             }
         }
     }
 
-    let tunnel = connector.create_tunnel(session, command_sender).await?;
+    let (phase_sender, mut phase_receiver) = mpsc::channel(16);
+    let phase_printer = tokio::spawn(async move {
+        while let Some(phase) = phase_receiver.recv().await {
+            println!("{}...", phase);
+        }
+    });
+
+    let tunnel = connector
+        .create_tunnel(session, command_sender, Some(phase_sender))
+        .await?;
+
+    // the phase sender is dropped by now, wait for the pending phases to be printed
+    let _ = phase_printer.await;
+    println!("{}", ConnectionPhase::Connected);
 
     if let Err(e) = platform::start_network_state_monitoring().await {
         warn!("Unable to start network monitoring: {}", e);
@@ -167,6 +267,16 @@ async fn main_standalone(params: TunnelParams) -> anyhow::Result<()> {
         tokio::select! {
             event = event_receiver.recv() => {
                 if let Some(event) = event {
+                    if matches!(event, TunnelEvent::Connected) && params.rate_limit.is_some() {
+                        let rate_limit = platform::set_rate_limit(
+                            params.device_name(),
+                            params.rate_limit,
+                            params.rate_limit_inbound,
+                        );
+                        if let Err(e) = rate_limit.await {
+                            warn!("Unable to set the rate limit: {}", e);
+                        }
+                    }
                     let _ = connector.handle_tunnel_event(event).await;
                 }
             }
@@ -0,0 +1,146 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use tokio::net::UdpSocket;
+use tracing::debug;
+
+use crate::{model::BenchmarkResult, platform::UdpSocketExt, tunnel::make_keepalive_packet};
+
+const PROBE_COUNT: u32 = 1000;
+const PROBE_SIZE: usize = 1024;
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+// the gateway answers the keepalive probes, a custom target is expected to echo whatever it receives
+fn make_probe() -> Vec<u8> {
+    let mut probe = make_keepalive_packet().to_vec();
+    probe.resize(PROBE_SIZE, 0);
+    probe
+}
+
+// an ICMP error such as port unreachable is reported by the next socket call, the probe counts as lost
+fn record_reply(result: &mut BenchmarkResult, reply: std::io::Result<usize>) -> bool {
+    match reply {
+        Ok(size) => {
+            result.packets_received += 1;
+            result.bytes_received += size as u64;
+            true
+        }
+        Err(e) => {
+            debug!("Probe lost: {}", e);
+            false
+        }
+    }
+}
+
+// sends a burst of probes to the target and counts the replies, the replies are received while sending
+// so that the socket buffer does not overflow
+pub async fn run(target: SocketAddr, source: Option<Ipv4Addr>) -> anyhow::Result<BenchmarkResult> {
+    let socket = UdpSocket::bind((source.unwrap_or(Ipv4Addr::UNSPECIFIED), 0)).await?;
+    socket.connect(target).await?;
+
+    // same as for the keepalive, the gateway does not set the UDP checksum correctly
+    socket.set_no_check(true)?;
+
+    debug!("Running throughput test against {}", target);
+
+    let probe = make_probe();
+    let mut buf = vec![0u8; 65536];
+    let mut result = BenchmarkResult {
+        target,
+        ..Default::default()
+    };
+
+    let started = Instant::now();
+    let mut last_reply = started;
+
+    while result.packets_sent < PROBE_COUNT {
+        tokio::select! {
+            biased;
+            reply = socket.recv(&mut buf) => {
+                if record_reply(&mut result, reply) {
+                    last_reply = Instant::now();
+                }
+            }
+            sent = socket.send(&probe) => {
+                result.packets_sent += 1;
+                result.bytes_sent += match sent {
+                    Ok(sent) => sent as u64,
+                    Err(e) => {
+                        debug!("Probe lost: {}", e);
+                        probe.len() as u64
+                    }
+                };
+            }
+        }
+    }
+
+    while let Ok(reply) = tokio::time::timeout(REPLY_TIMEOUT, socket.recv(&mut buf)).await {
+        if record_reply(&mut result, reply) {
+            last_reply = Instant::now();
+        }
+    }
+
+    result.elapsed = last_reply.duration_since(started);
+
+    debug!("Throughput test result: {:?}", result);
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_echo_target() {
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = echo.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 65536];
+            while let Ok((size, peer)) = echo.recv_from(&mut buf).await {
+                let _ = echo.send_to(&buf[..size], peer).await;
+            }
+        });
+
+        let result = run(target, Some(Ipv4Addr::LOCALHOST)).await.unwrap();
+
+        assert_eq!(result.packets_sent, PROBE_COUNT);
+        assert_eq!(result.bytes_sent, PROBE_COUNT as u64 * PROBE_SIZE as u64);
+        assert!(result.packets_received > 0);
+        assert!(result.loss_percent() < 100.0);
+    }
+
+    #[tokio::test]
+    async fn test_closed_target() {
+        // the refused probes are counted as lost instead of failing the benchmark
+        let closed = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = closed.local_addr().unwrap();
+        drop(closed);
+
+        let result = run(target, Some(Ipv4Addr::LOCALHOST)).await.unwrap();
+
+        assert_eq!(result.packets_sent, PROBE_COUNT);
+        assert_eq!(result.packets_received, 0);
+        assert_eq!(result.loss_percent(), 100.0);
+    }
+
+    #[test]
+    fn test_throughput() {
+        // the gateway answers the padded probes with the short keepalive replies
+        let result = BenchmarkResult {
+            packets_sent: 1000,
+            packets_received: 500,
+            bytes_sent: 1000 * PROBE_SIZE as u64,
+            bytes_received: 500 * 84,
+            elapsed: Duration::from_secs(1),
+            ..Default::default()
+        };
+        assert_eq!(result.throughput_mbps(), 4.096);
+        assert_eq!(result.loss_percent(), 50.0);
+
+        assert_eq!(BenchmarkResult::default().throughput_mbps(), 0.0);
+    }
+}
@@ -1,3 +1,10 @@
+use std::{
+    fs::OpenOptions,
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
 use anyhow::anyhow;
 use once_cell::sync::Lazy;
 use regex::Regex;
@@ -6,16 +13,26 @@ use tokio::{
     net::TcpListener,
     sync::oneshot,
 };
+use tracing::{debug, warn};
+
+use crate::{model::params::TunnelParams, prompt::SecurePrompt};
+
+const SAML_COOKIES_FILE: &str = "cookies.db";
+const MANUAL_OTP_ATTEMPTS: usize = 3;
 
 pub trait BrowserController {
     fn open(&self, url: &str) -> anyhow::Result<()>;
     fn close(&self);
 }
 
-pub async fn run_otp_listener(sender: oneshot::Sender<String>) -> anyhow::Result<()> {
+// the port is bound before the browser is opened, so that a failure can fall back to the manual input
+pub async fn bind_otp_listener() -> anyhow::Result<TcpListener> {
+    Ok(TcpListener::bind("127.0.0.1:7779").await?)
+}
+
+pub async fn run_otp_listener(tcp: TcpListener, sender: oneshot::Sender<String>) -> anyhow::Result<()> {
     static OTP_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r#"^GET /(?<otp>[0-9a-f]{60}|[0-9A-F]{60}).*"#).unwrap());
 
-    let tcp = TcpListener::bind("127.0.0.1:7779").await?;
     let (mut stream, _) = tcp.accept().await?;
 
     let mut buf = [0u8; 65];
@@ -38,3 +55,130 @@ pub async fn run_otp_listener(sender: oneshot::Sender<String>) -> anyhow::Result
     }
     Err(anyhow!("No OTP acquired!"))
 }
+
+fn is_stale(path: &Path, ttl: Duration) -> bool {
+    !ttl.is_zero()
+        && std::fs::metadata(path)
+            .and_then(|meta| meta.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok())
+            .is_some_and(|age| age > ttl)
+}
+
+// cookie storage of the embedded browser, which keeps the identity provider session between the connections.
+// Returns None when the session must not be reused, the browser should then use an ephemeral session.
+pub fn saml_cookie_store(params: &TunnelParams) -> Option<PathBuf> {
+    let config_path = TunnelParams::instance_config_path(params.instance_name.as_deref());
+    let path = config_path.parent()?.join(SAML_COOKIES_FILE);
+
+    if !params.saml_session_reuse || is_stale(&path, params.saml_session_ttl) {
+        debug!("Removing the stored SAML session");
+        let _ = std::fs::remove_file(&path);
+    }
+
+    if !params.saml_session_reuse {
+        return None;
+    }
+
+    if let Some(dir) = path.parent() {
+        let _ = std::fs::create_dir_all(dir);
+    }
+
+    // the cookies are created in advance, so that the browser engine does not create them readable by others
+    let result = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .open(&path)
+        .and_then(|_| std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)));
+
+    match result {
+        Ok(()) => Some(path),
+        Err(e) => {
+            warn!("Unable to create the SAML cookie store {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+// the pasted input is either the token itself or the localhost address the browser was redirected to
+pub fn parse_otp(input: &str) -> Option<String> {
+    static TOKEN_RE: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r#"(?:^|/)(?<otp>[0-9a-f]{60}|[0-9A-F]{60})(?:[/?#]|$)"#).unwrap());
+
+    TOKEN_RE
+        .captures(input.trim())
+        .and_then(|captures| captures.name("otp"))
+        .map(|otp| otp.as_str().to_owned())
+}
+
+// a mistyped token is asked for again instead of failing the login
+pub fn read_manual_otp<P: SecurePrompt + ?Sized>(prompt: &P, label: &str) -> anyhow::Result<String> {
+    for _ in 0..MANUAL_OTP_ATTEMPTS {
+        let input = prompt.get_secure_input(label)?;
+        match parse_otp(&input) {
+            Some(otp) => return Ok(otp),
+            None => warn!("Invalid SAML token, expected 60 hex digits"),
+        }
+    }
+
+    Err(anyhow!("No valid SAML token entered!"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_otp() {
+        let token = "0123456789abcdef".repeat(4)[..60].to_owned();
+
+        assert_eq!(parse_otp(&token).as_deref(), Some(token.as_str()));
+        assert_eq!(
+            parse_otp(&format!(" http://localhost:7779/{}?x=1\n", token)).as_deref(),
+            Some(token.as_str())
+        );
+        assert_eq!(parse_otp(&token[..59]), None);
+        assert_eq!(parse_otp(&format!("{}0", token)), None);
+        assert_eq!(parse_otp("0123456789ABCDEFabcdef"), None);
+    }
+
+    struct PastedInput(std::cell::RefCell<Vec<String>>);
+
+    impl SecurePrompt for PastedInput {
+        fn get_secure_input(&self, _prompt: &str) -> anyhow::Result<String> {
+            Ok(self.0.borrow_mut().remove(0))
+        }
+
+        fn show_notification(&self, _summary: &str, _message: &str) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_read_manual_otp() {
+        let token = "0123456789abcdef".repeat(4)[..60].to_owned();
+
+        let prompt = PastedInput(vec!["typo".to_owned(), String::new(), token.clone()].into());
+        assert_eq!(read_manual_otp(&prompt, "SAML token: ").unwrap(), token);
+
+        let prompt = PastedInput(vec!["typo".to_owned(); MANUAL_OTP_ATTEMPTS].into());
+        assert!(read_manual_otp(&prompt, "SAML token: ").is_err());
+    }
+
+    #[test]
+    fn test_is_stale() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cookies.db");
+        std::fs::write(&path, b"").unwrap();
+
+        assert!(!is_stale(&path, Duration::ZERO));
+        assert!(!is_stale(&path, Duration::from_secs(3600)));
+
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(is_stale(&path, Duration::from_millis(10)));
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(!is_stale(&path, Duration::from_millis(10)));
+    }
+}
@@ -0,0 +1,139 @@
+use std::path::Path;
+
+use anyhow::anyhow;
+use base64::Engine;
+use openssl::{
+    hash::MessageDigest,
+    pkcs5::pbkdf2_hmac,
+    symm::{decrypt_aead, encrypt_aead, Cipher},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::model::params::TunnelParams;
+
+pub const BUNDLE_VERSION: u32 = 1;
+
+const KDF_ITERATIONS: usize = 200_000;
+const SALT_SIZE: usize = 16;
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+const KEY_SIZE: usize = 32;
+
+/// Portable configuration bundle, encrypted with AES-256-GCM under a passphrase-derived key
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConfigBundle {
+    pub version: u32,
+    pub salt: String,
+    pub nonce: String,
+    pub tag: String,
+    pub data: String,
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> anyhow::Result<[u8; KEY_SIZE]> {
+    let mut key = [0u8; KEY_SIZE];
+    pbkdf2_hmac(
+        passphrase.as_bytes(),
+        salt,
+        KDF_ITERATIONS,
+        MessageDigest::sha256(),
+        &mut key,
+    )?;
+    Ok(key)
+}
+
+impl ConfigBundle {
+    pub fn seal(params: &TunnelParams, passphrase: &str) -> anyhow::Result<Self> {
+        if passphrase.is_empty() {
+            return Err(anyhow!("Empty passphrase!"));
+        }
+
+        let salt: [u8; SALT_SIZE] = rand::random();
+        let nonce: [u8; NONCE_SIZE] = rand::random();
+        let key = derive_key(passphrase, &salt)?;
+
+        let plain = serde_json::to_vec(params)?;
+        let mut tag = [0u8; TAG_SIZE];
+        let data = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &key,
+            Some(&nonce),
+            &BUNDLE_VERSION.to_be_bytes(),
+            &plain,
+            &mut tag,
+        )?;
+
+        Ok(Self {
+            version: BUNDLE_VERSION,
+            salt: hex::encode(salt),
+            nonce: hex::encode(nonce),
+            tag: hex::encode(tag),
+            data: base64::engine::general_purpose::STANDARD.encode(data),
+        })
+    }
+
+    pub fn open(&self, passphrase: &str) -> anyhow::Result<TunnelParams> {
+        if self.version != BUNDLE_VERSION {
+            return Err(anyhow!(
+                "Unsupported bundle version: {}, expected: {}",
+                self.version,
+                BUNDLE_VERSION
+            ));
+        }
+
+        let salt = hex::decode(&self.salt)?;
+        let nonce = hex::decode(&self.nonce)?;
+        let tag = hex::decode(&self.tag)?;
+        let data = base64::engine::general_purpose::STANDARD.decode(&self.data)?;
+        let key = derive_key(passphrase, &salt)?;
+
+        let plain = decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &key,
+            Some(&nonce),
+            &self.version.to_be_bytes(),
+            &data,
+            &tag,
+        )
+        .map_err(|_| anyhow!("Cannot decrypt bundle: wrong passphrase or corrupted data!"))?;
+
+        Ok(serde_json::from_slice(&plain)?)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<()> {
+        Ok(std::fs::write(path, serde_json::to_vec_pretty(self)?)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_open() {
+        let params = TunnelParams {
+            server_name: "remote.acme.com".to_owned(),
+            user_name: "user".to_owned(),
+            password: "secret".to_owned().into(),
+            ..Default::default()
+        };
+
+        let bundle = ConfigBundle::seal(&params, "passphrase").unwrap();
+        assert!(!bundle.data.contains("secret"));
+
+        let opened = bundle.open("passphrase").unwrap();
+        assert_eq!(opened.server_name, params.server_name);
+        assert_eq!(opened.password, params.password);
+
+        assert!(bundle.open("wrong").is_err());
+
+        let future = ConfigBundle {
+            version: BUNDLE_VERSION + 1,
+            ..bundle
+        };
+        assert!(future.open("passphrase").is_err());
+    }
+}
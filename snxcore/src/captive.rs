@@ -0,0 +1,62 @@
+use std::time::Duration;
+
+use reqwest::{header::LOCATION, redirect::Policy, StatusCode};
+use tracing::debug;
+
+use crate::model::params::TunnelParams;
+
+const PROBE_URL: &str = "http://connectivitycheck.gstatic.com/generate_204";
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// the probe endpoint answers with an empty 204 reply, a portal either redirects or serves its own page
+fn portal_url(status: StatusCode, location: Option<&str>) -> Option<String> {
+    if status == StatusCode::NO_CONTENT {
+        None
+    } else if status.is_redirection() {
+        Some(location.unwrap_or(PROBE_URL).to_owned())
+    } else {
+        Some(PROBE_URL.to_owned())
+    }
+}
+
+// returns the portal page URL if the network is behind a captive portal.
+// Probe failures are not treated as a portal, the connection attempt reports the actual error.
+pub async fn detect(params: &TunnelParams) -> Option<String> {
+    let mut builder = reqwest::Client::builder()
+        .timeout(PROBE_TIMEOUT)
+        .redirect(Policy::none());
+
+    if let Some(address) = params.ip_mode.local_address() {
+        builder = builder.local_address(address);
+    }
+
+    let reply = match builder.build().ok()?.get(PROBE_URL).send().await {
+        Ok(reply) => reply,
+        Err(e) => {
+            debug!("Captive portal probe failed: {}", e);
+            return None;
+        }
+    };
+
+    let location = reply.headers().get(LOCATION).and_then(|v| v.to_str().ok());
+    let url = portal_url(reply.status(), location);
+
+    debug!("Captive portal probe status: {}, portal: {:?}", reply.status(), url);
+
+    url
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_portal_url() {
+        assert_eq!(portal_url(StatusCode::NO_CONTENT, None), None);
+        assert_eq!(
+            portal_url(StatusCode::FOUND, Some("http://portal.local/login")).as_deref(),
+            Some("http://portal.local/login")
+        );
+        assert_eq!(portal_url(StatusCode::OK, None).as_deref(), Some(PROBE_URL));
+    }
+}
@@ -1,28 +1,143 @@
 use std::{
+    io::Read,
     net::Ipv4Addr,
     sync::{
-        atomic::{AtomicU32, Ordering},
+        atomic::{AtomicI64, AtomicU32, Ordering},
         Arc,
     },
     time::Duration,
 };
 
 use anyhow::anyhow;
-use reqwest::{Certificate, Identity};
-use tracing::{trace, warn};
+use chrono::{DateTime, Utc};
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use reqwest::{
+    header::{ACCEPT_ENCODING, CONTENT_ENCODING, DATE, RETRY_AFTER},
+    redirect::Policy,
+    Certificate, Identity, StatusCode, Url,
+};
+use tracing::{debug, trace, warn};
 
 use crate::{
     model::{
-        params::{CertType, TunnelParams},
+        params::{CertType, Quirk, TunnelParams},
         proto::*,
         VpnSession,
     },
+    posture::{self, PostureInfo},
     sexpr::SExpression,
+    tunnel::GatewayBusy,
 };
 
 static REQUEST_ID: AtomicU32 = AtomicU32::new(2);
+// seconds the local clock is ahead of the gateway, taken from the date of the last reply
+static CLOCK_SKEW: AtomicI64 = AtomicI64::new(0);
+// time-based one-time codes change every 30 seconds
+const TOTP_STEP: i64 = 30;
 const REQUEST_TIMEOUT: Duration = Duration::from_secs(600);
 const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_REDIRECTS: usize = 5;
+const CORRELATION_ID_HEADER: &str = "X-Correlation-ID";
+
+// the requests carry the credentials, so they only follow the redirects to the configured server or an allowed host
+fn redirect_target(params: &TunnelParams, base: &Url, location: &str) -> anyhow::Result<String> {
+    let url = base.join(location)?;
+
+    if url.scheme() != "https" {
+        return Err(anyhow!("Refusing the redirect to a non-HTTPS location: {}!", url));
+    }
+
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow!("No host in redirect location: {}", location))?;
+
+    if !host.eq_ignore_ascii_case(&params.server_name)
+        && !params
+            .redirect_hosts
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(host))
+    {
+        return Err(anyhow!(
+            "Refusing the redirect to {}, it is neither the configured server nor one of the redirect-hosts!",
+            host
+        ));
+    }
+
+    // the port is only present when it differs from the HTTPS default
+    Ok(match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_owned(),
+    })
+}
+
+fn redirect_host(params: &TunnelParams, response: &reqwest::Response) -> anyhow::Result<String> {
+    let location = response
+        .headers()
+        .get(reqwest::header::LOCATION)
+        .and_then(|v| v.to_str().ok())
+        .ok_or_else(|| anyhow!("Redirect response without location!"))?;
+
+    redirect_target(params, response.url(), location)
+}
+
+// only the delay in seconds is supported, the HTTP date form is ignored
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    value.trim().parse::<u64>().ok().map(Duration::from_secs)
+}
+
+fn retry_after(response: &reqwest::Response) -> Option<Duration> {
+    response
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_retry_after)
+}
+
+// the HTTP date has a resolution of one second
+fn parse_clock_skew(date: &str, now: DateTime<Utc>) -> Option<i64> {
+    let server_time = DateTime::parse_from_rfc2822(date).ok()?;
+    Some(now.signed_duration_since(server_time).num_seconds())
+}
+
+fn update_clock_skew(response: &reqwest::Response) {
+    let skew = response
+        .headers()
+        .get(DATE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| parse_clock_skew(v, Utc::now()));
+
+    if let Some(skew) = skew {
+        CLOCK_SKEW.store(skew, Ordering::SeqCst);
+    }
+}
+
+/// Skew of the local clock against the gateway clock in seconds, if it exceeds the TOTP step
+pub fn totp_clock_skew() -> Option<i64> {
+    let skew = CLOCK_SKEW.load(Ordering::SeqCst);
+    (skew.abs() > TOTP_STEP).then_some(skew)
+}
+
+// the gateway may send compressed replies when the client advertises it
+fn decode_body(encoding: Option<&str>, body: &[u8]) -> anyhow::Result<String> {
+    let mut reply = String::new();
+
+    match encoding.map(|e| e.trim().to_lowercase()).as_deref() {
+        None | Some("identity") => reply = String::from_utf8_lossy(body).into_owned(),
+        Some("gzip" | "x-gzip") => {
+            GzDecoder::new(body).read_to_string(&mut reply)?;
+        }
+        // HTTP deflate is zlib-wrapped, but some servers send a raw deflate stream
+        Some("deflate") => {
+            if ZlibDecoder::new(body).read_to_string(&mut reply).is_err() {
+                reply.clear();
+                DeflateDecoder::new(body).read_to_string(&mut reply)?;
+            }
+        }
+        Some(other) => return Err(anyhow!("Unsupported content encoding: {}", other)),
+    }
+
+    Ok(reply)
+}
 
 pub struct CccHttpClient {
     params: Arc<TunnelParams>,
@@ -42,7 +157,7 @@ impl CccHttpClient {
         REQUEST_ID.fetch_add(1, Ordering::SeqCst)
     }
 
-    fn new_auth_request(&self) -> CccClientRequestData {
+    fn new_auth_request(&self, posture: Option<&PostureInfo>) -> CccClientRequestData {
         let (request_type, username, password) = if self.params.cert_type == CertType::None {
             (
                 "UserPass",
@@ -64,12 +179,10 @@ impl CccHttpClient {
                 client_type: self.params.tunnel_type.as_client_type().to_owned(),
                 username,
                 password,
-                client_logging_data: Some(ClientLoggingData {
-                    os_name: Some("Windows".into()),
-                    device_id: Some(crate::util::get_device_id().into()),
-                    ..Default::default()
-                }),
+                client_logging_data: (!self.params.has_quirk(Quirk::NoClientLoggingData))
+                    .then(|| posture::client_logging_data(posture)),
                 selected_login_option: Some(self.params.login_type.clone()),
+                selected_realm_id: self.params.realm.clone(),
                 endpoint_os: None,
             }),
         }
@@ -91,6 +204,53 @@ impl CccHttpClient {
         }
     }
 
+    fn new_otp_delivery_request(&self, method: &str) -> CccClientRequestData {
+        CccClientRequestData {
+            header: RequestHeader {
+                id: self.new_request_id(),
+                request_type: "OtpDelivery".to_string(),
+                session_id: self.session_id(),
+                protocol_version: None,
+            },
+            data: RequestData::OtpDelivery(OtpDeliveryRequest {
+                client_type: self.params.tunnel_type.as_client_type().to_owned(),
+                auth_session_id: self.session_id().unwrap_or_default(),
+                delivery_method: method.to_owned(),
+            }),
+        }
+    }
+
+    fn new_push_status_request(&self) -> CccClientRequestData {
+        CccClientRequestData {
+            header: RequestHeader {
+                id: self.new_request_id(),
+                request_type: "PushStatus".to_string(),
+                session_id: self.session_id(),
+                protocol_version: None,
+            },
+            data: RequestData::PushStatus(PushStatusRequest {
+                client_type: self.params.tunnel_type.as_client_type().to_owned(),
+                auth_session_id: self.session_id().unwrap_or_default(),
+            }),
+        }
+    }
+
+    fn new_password_change_request(&self, new_password: &str) -> CccClientRequestData {
+        CccClientRequestData {
+            header: RequestHeader {
+                id: self.new_request_id(),
+                request_type: "PasswordChange".to_string(),
+                session_id: self.session_id(),
+                protocol_version: None,
+            },
+            data: RequestData::PasswordChange(PasswordChangeRequest {
+                client_type: self.params.tunnel_type.as_client_type().to_owned(),
+                auth_session_id: self.session_id().unwrap_or_default(),
+                new_password: new_password.into(),
+            }),
+        }
+    }
+
     fn new_key_management_request(&self, spi: u32) -> CccClientRequestData {
         CccClientRequestData {
             header: RequestHeader {
@@ -150,9 +310,21 @@ impl CccHttpClient {
     }
 
     async fn send_raw_request(&self, request: CccClientRequestData) -> anyhow::Result<SExpression> {
+        Ok(self.send_raw_request_with_host(request).await?.0)
+    }
+
+    // returns the reply together with the host which has served it
+    async fn send_raw_request_with_host(&self, request: CccClientRequestData) -> anyhow::Result<(SExpression, String)> {
         let expr = SExpression::from(CccClientRequest { data: request });
 
-        let mut builder = reqwest::Client::builder().connect_timeout(CONNECT_TIMEOUT);
+        // redirects are followed manually to keep the POST method and body
+        let mut builder = reqwest::Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .redirect(Policy::none());
+
+        if let Some(address) = self.params.ip_mode.local_address() {
+            builder = builder.local_address(address);
+        }
 
         if let Some(ref ca_cert) = self.params.ca_cert {
             let data = tokio::fs::read(ca_cert).await?;
@@ -175,7 +347,11 @@ impl CccHttpClient {
                 CertType::Pkcs8 => Some(Identity::from_pkcs8_pem(&data, &data)?),
                 CertType::Pkcs12 => Some(Identity::from_pkcs12_der(
                     &data,
-                    self.params.cert_password.as_deref().unwrap_or_default(),
+                    self.params
+                        .cert_password
+                        .as_deref()
+                        .map(String::as_str)
+                        .unwrap_or_default(),
                 )?),
                 _ => None,
             };
@@ -193,20 +369,60 @@ impl CccHttpClient {
 
         trace!("Request to server: {}", expr);
 
-        let req = client
-            .post(format!("https://{}{}", self.params.server_name, path))
-            .body(expr.to_string())
-            .build()?;
+        let body = expr.to_string();
+        let mut server_name = self.params.server_name.clone();
 
-        let reply = tokio::time::timeout(REQUEST_TIMEOUT, client.execute(req))
-            .await??
-            .error_for_status()?
-            .text()
-            .await?;
+        for _ in 0..=MAX_REDIRECTS {
+            let mut req = client
+                .post(format!("https://{}{}", server_name, path))
+                .body(body.clone());
 
-        trace!("Reply from server: {}", reply);
+            if !self.params.has_quirk(Quirk::NoCompression) {
+                req = req.header(ACCEPT_ENCODING, "gzip, deflate");
+            }
 
-        reply.parse::<SExpression>()
+            if let Some(ref correlation_id) = self.params.correlation_id {
+                req = req.header(CORRELATION_ID_HEADER, correlation_id);
+            }
+
+            let req = req.build()?;
+
+            let response = tokio::time::timeout(REQUEST_TIMEOUT, client.execute(req)).await??;
+
+            if response.status().is_redirection() {
+                server_name = redirect_host(&self.params, &response)?;
+                debug!("Redirected to {}", server_name);
+                continue;
+            }
+
+            if response.status() == StatusCode::SERVICE_UNAVAILABLE {
+                let retry_after = retry_after(&response);
+                let message = match retry_after {
+                    Some(delay) => format!(
+                        "Gateway {} is busy, retry after {} seconds!",
+                        server_name,
+                        delay.as_secs()
+                    ),
+                    None => format!("Gateway {} is busy!", server_name),
+                };
+                return Err(GatewayBusy(message, retry_after).into());
+            }
+
+            let response = response.error_for_status()?;
+            update_clock_skew(&response);
+            let encoding = response
+                .headers()
+                .get(CONTENT_ENCODING)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_owned());
+            let reply = decode_body(encoding.as_deref(), &response.bytes().await?)?;
+
+            trace!("Reply from server: {}", reply);
+
+            return Ok((reply.parse::<SExpression>()?, server_name));
+        }
+
+        Err(anyhow!("Too many redirects from {}", self.params.server_name))
     }
 
     async fn send_request(&self, request: CccClientRequestData) -> anyhow::Result<CccServerResponseData> {
@@ -218,11 +434,20 @@ impl CccHttpClient {
     }
 
     async fn send_ccc_request(&self, req: CccClientRequestData) -> anyhow::Result<ResponseData> {
-        self.send_request(req).await?.into_data()
+        let response = self.send_request(req).await?;
+        let code = response.header.return_code;
+        if self.params.busy_error_codes.contains(&code) {
+            return Err(GatewayBusy(
+                format!("Gateway {} is busy, error code: {}!", self.params.server_name, code),
+                None,
+            )
+            .into());
+        }
+        response.into_data()
     }
 
-    pub async fn authenticate(&self) -> anyhow::Result<AuthResponse> {
-        let req = self.new_auth_request();
+    pub async fn authenticate(&self, posture: Option<&PostureInfo>) -> anyhow::Result<AuthResponse> {
+        let req = self.new_auth_request(posture);
 
         match self.send_ccc_request(req).await? {
             ResponseData::Auth(data) => Ok(data),
@@ -239,6 +464,32 @@ impl CccHttpClient {
         }
     }
 
+    // asks the gateway to send the one-time code of the pending challenge with the given method
+    pub async fn request_otp_delivery(&self, method: &str) -> anyhow::Result<()> {
+        let req = self.new_otp_delivery_request(method);
+        self.send_ccc_request(req).await?;
+        Ok(())
+    }
+
+    // the challenge stays pending until the sign-in is approved on the device of the user
+    pub async fn poll_push_status(&self) -> anyhow::Result<AuthResponse> {
+        let req = self.new_push_status_request();
+
+        match self.send_ccc_request(req).await? {
+            ResponseData::Auth(data) => Ok(data),
+            _ => Err(anyhow!("Invalid push status response!")),
+        }
+    }
+
+    pub async fn change_password(&self, new_password: &str) -> anyhow::Result<AuthResponse> {
+        let req = self.new_password_change_request(new_password);
+
+        match self.send_ccc_request(req).await? {
+            ResponseData::Auth(data) => Ok(data),
+            _ => Err(anyhow!("Invalid password change response!")),
+        }
+    }
+
     pub async fn get_ipsec_tunnel_params(&self, spi: u32) -> anyhow::Result<KeyManagementResponse> {
         let req = self.new_key_management_request(spi);
 
@@ -269,4 +520,101 @@ impl CccHttpClient {
     pub async fn get_server_info(&self) -> anyhow::Result<SExpression> {
         self.send_raw_request(self.new_client_hello_request()).await
     }
+
+    // together with the host which has served it, the gateway may redirect clients to an alternate entry point
+    pub async fn get_server_info_with_host(&self) -> anyhow::Result<(SExpression, String)> {
+        self.send_raw_request_with_host(self.new_client_hello_request()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::*;
+
+    const SERVER_INFO: &str = r#"(CCCserverResponse
+    :ResponseHeader (
+        :id (1)
+        :type (ClientHello)
+        :session_id ()
+        :return_code (600)
+    )
+    :ResponseData (
+        :protocol_version (
+            :protocol_version (100)
+            :features (0x0000000F)
+        )
+    )
+)"#;
+
+    #[test]
+    fn test_redirect_target() {
+        let params = TunnelParams {
+            server_name: "vpn.acme.com".to_owned(),
+            redirect_hosts: vec!["vpn2.acme.com".to_owned()],
+            ..Default::default()
+        };
+        let base = Url::parse("https://vpn.acme.com/clients/").unwrap();
+
+        assert_eq!(
+            redirect_target(&params, &base, "/clients/cert/").unwrap(),
+            "vpn.acme.com"
+        );
+        assert_eq!(
+            redirect_target(&params, &base, "https://VPN2.acme.com/clients/").unwrap(),
+            "vpn2.acme.com"
+        );
+        assert_eq!(
+            redirect_target(&params, &base, "https://vpn2.acme.com:8443/clients/").unwrap(),
+            "vpn2.acme.com:8443"
+        );
+        assert_eq!(
+            redirect_target(&params, &base, "https://vpn2.acme.com:443/clients/").unwrap(),
+            "vpn2.acme.com"
+        );
+
+        // the credentials are neither sent to another host nor over plain HTTP
+        assert!(redirect_target(&params, &base, "https://portal.example.com/clients/").is_err());
+        assert!(redirect_target(&params, &base, "http://vpn2.acme.com/clients/").is_err());
+        assert!(redirect_target(&params, &base, "http://vpn.acme.com/clients/").is_err());
+    }
+
+    #[test]
+    fn test_parse_clock_skew() {
+        let now = DateTime::parse_from_rfc2822("Wed, 21 Oct 2015 07:28:00 GMT")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(parse_clock_skew("Wed, 21 Oct 2015 07:27:15 GMT", now), Some(45));
+        assert_eq!(parse_clock_skew("Wed, 21 Oct 2015 07:28:10 GMT", now), Some(-10));
+        assert_eq!(parse_clock_skew("yesterday", now), None);
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after(" 5 "), Some(Duration::from_secs(5)));
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"), None);
+    }
+
+    #[test]
+    fn test_decode_gzip_server_info() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(SERVER_INFO.as_bytes()).unwrap();
+        let body = encoder.finish().unwrap();
+
+        let reply = decode_body(Some("gzip"), &body).unwrap();
+        assert_eq!(reply, SERVER_INFO);
+
+        let expr = reply.parse::<SExpression>().unwrap();
+        assert_eq!(
+            expr.get_value::<u32>("CCCserverResponse:ResponseData:protocol_version:protocol_version"),
+            Some(100)
+        );
+
+        assert_eq!(decode_body(None, SERVER_INFO.as_bytes()).unwrap(), SERVER_INFO);
+        assert!(decode_body(Some("br"), &body).is_err());
+    }
 }
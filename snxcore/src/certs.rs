@@ -0,0 +1,409 @@
+use std::{
+    net::{TcpStream, ToSocketAddrs},
+    path::{Path, PathBuf},
+    process::Command,
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use chrono::{DateTime, Local};
+use directories_next::ProjectDirs;
+use openssl::{
+    asn1::Asn1Time,
+    hash::MessageDigest,
+    pkcs12::Pkcs12,
+    ssl::{HandshakeError, SslConnector, SslMethod, SslRef, SslVerifyMode},
+    x509::{X509NameRef, X509Ref, X509},
+};
+use tracing::{debug, warn};
+
+use crate::{
+    model::params::{CertType, TunnelParams},
+    util,
+};
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// cert path may point to a directory or contain a comma-separated list of files
+fn candidate_paths(path: &Path) -> Vec<PathBuf> {
+    if path.is_dir() {
+        let mut paths = std::fs::read_dir(path)
+            .map(|entries| {
+                entries
+                    .flatten()
+                    .map(|entry| entry.path())
+                    .filter(|p| p.is_file())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        paths.sort();
+        paths
+    } else {
+        path.to_string_lossy()
+            .split(',')
+            .map(|s| PathBuf::from(s.trim()))
+            .filter(|p| !p.as_os_str().is_empty())
+            .collect()
+    }
+}
+
+fn load_cert(path: &Path, cert_type: CertType, password: Option<&str>) -> Option<X509> {
+    let data = std::fs::read(path).ok()?;
+    match cert_type {
+        CertType::Pkcs12 => {
+            Pkcs12::from_der(&data)
+                .ok()?
+                .parse2(password.unwrap_or_default())
+                .ok()?
+                .cert
+        }
+        CertType::Pkcs8 => X509::from_pem(&data).ok(),
+        _ => None,
+    }
+}
+
+// certificates are public objects of the token, no login is needed to read them
+fn load_pkcs11_cert(driver_path: &Path, cert_id: &str) -> Option<X509> {
+    let output = Command::new("pkcs11-tool")
+        .arg("--module")
+        .arg(driver_path)
+        .args(["--read-object", "--type", "cert", "--id", &cert_id.replace(':', "")])
+        .output();
+
+    match output {
+        Ok(output) if output.status.success() => X509::from_der(&output.stdout).ok(),
+        Ok(output) => {
+            debug!(
+                "Unable to read the PKCS11 certificate: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            None
+        }
+        Err(e) => {
+            debug!("Unable to run pkcs11-tool: {}", e);
+            None
+        }
+    }
+}
+
+fn not_after(cert: &X509) -> Option<DateTime<Local>> {
+    let diff = Asn1Time::from_unix(0).ok()?.diff(cert.not_after()).ok()?;
+    DateTime::from_timestamp(diff.days as i64 * 86400 + diff.secs as i64, 0).map(|time| time.with_timezone(&Local))
+}
+
+/// Expiry of the client certificate with the warning to show when it expires soon.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CertExpiry {
+    pub expires: DateTime<Local>,
+    pub warning: Option<String>,
+}
+
+fn check_expiry(
+    name: &str,
+    expires: DateTime<Local>,
+    now: DateTime<Local>,
+    threshold: Duration,
+) -> anyhow::Result<CertExpiry> {
+    if expires <= now {
+        return Err(anyhow!("Client certificate {} has expired on {}", name, expires));
+    }
+
+    let warning = (expires - now)
+        .to_std()
+        .is_ok_and(|left| left < threshold)
+        .then(|| format!("Client certificate {} expires on {}", name, expires));
+    if let Some(ref warning) = warning {
+        warn!("{}", warning);
+    }
+
+    Ok(CertExpiry { expires, warning })
+}
+
+/// Check the expiry of the configured client certificate file or PKCS11 certificate.
+/// Fails if the certificate has expired and warns if it expires within the `cert-expiry-warning` period.
+pub fn check_client_cert(params: &TunnelParams) -> anyhow::Result<Option<CertExpiry>> {
+    let (name, cert) = match (params.cert_type, &params.cert_path) {
+        (CertType::Pkcs12 | CertType::Pkcs8, Some(path)) => {
+            // expired certificates are skipped when one of several is selected
+            if path.is_dir() || candidate_paths(path).len() != 1 {
+                return Ok(None);
+            }
+            // the tunnel connector reports the certificates which cannot be loaded
+            let cert = load_cert(
+                path,
+                params.cert_type,
+                params.cert_password.as_deref().map(String::as_str),
+            );
+            (path.display().to_string(), cert)
+        }
+        (CertType::Pkcs11, _) => {
+            let Some(ref cert_id) = params.cert_id else {
+                debug!("No PKCS11 certificate id, the expiry is not checked");
+                return Ok(None);
+            };
+            let driver_path = params.cert_path.clone().unwrap_or_else(|| "opensc-pkcs11.so".into());
+            let cert = load_pkcs11_cert(&driver_path, cert_id);
+            (format!("with id {}", cert_id), cert)
+        }
+        _ => return Ok(None),
+    };
+
+    let Some(expires) = cert.as_ref().and_then(not_after) else {
+        return Ok(None);
+    };
+
+    check_expiry(&name, expires, Local::now(), params.cert_expiry_warning).map(Some)
+}
+
+fn ca_names(ssl: &SslRef) -> Vec<Vec<u8>> {
+    ssl.client_ca_list()
+        .map(|list| list.iter().flat_map(|name| name.to_der().ok()).collect())
+        .unwrap_or_default()
+}
+
+// the blocking connect has its own timeout, the socket timeouts only apply to the handshake
+fn connect_gateway(host: &str, port: u16) -> anyhow::Result<TcpStream> {
+    let mut last_error = None;
+
+    for address in (host, port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, HANDSHAKE_TIMEOUT) {
+            Ok(tcp) => return Ok(tcp),
+            Err(e) => last_error = Some(e),
+        }
+    }
+
+    Err(match last_error {
+        Some(e) => anyhow!("Unable to connect to {}:{}: {}", host, port, e),
+        None => anyhow!("No address for {}!", host),
+    })
+}
+
+// unverified TLS handshake with the gateway, the result is taken from the session state
+fn inspect_handshake<F, T>(server_name: &str, f: F) -> anyhow::Result<T>
+where
+    F: FnOnce(&SslRef) -> T,
+{
+    let mut builder = SslConnector::builder(SslMethod::tls_client())?;
+    builder.set_verify(SslVerifyMode::NONE);
+    let connector = builder.build();
+
+    let (host, port) = util::split_server_name(server_name);
+    let tcp = connect_gateway(host, port.unwrap_or(443))?;
+    tcp.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    tcp.set_write_timeout(Some(HANDSHAKE_TIMEOUT))?;
+
+    // the handshake is expected to fail when a client certificate is required
+    match connector.connect(host, tcp) {
+        Ok(stream) => Ok(f(stream.ssl())),
+        Err(HandshakeError::Failure(stream)) => Ok(f(stream.ssl())),
+        Err(e) => Err(anyhow!("TLS handshake with {} failed: {}", server_name, e)),
+    }
+}
+
+// distinguished names of the CAs accepted by the gateway, sent in the TLS CertificateRequest
+fn acceptable_cas(server_name: &str) -> anyhow::Result<Vec<Vec<u8>>> {
+    inspect_handshake(server_name, ca_names)
+}
+
+fn format_name(name: &X509NameRef) -> String {
+    name.entries()
+        .map(|entry| {
+            let key = entry.object().nid().short_name().unwrap_or("?");
+            let value = entry
+                .data()
+                .as_utf8()
+                .map(|v| v.to_string())
+                .unwrap_or_else(|_| "?".to_owned());
+            format!("{}={}", key, value)
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn format_fingerprint(digest: &[u8]) -> String {
+    digest
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Certificate presented by the gateway in the TLS handshake
+#[derive(Debug, Clone)]
+pub struct ServerCert {
+    pub subject: String,
+    pub issuer: String,
+    pub not_after: String,
+    pub sha256: String,
+    pub pem: Vec<u8>,
+}
+
+impl ServerCert {
+    fn from_x509(cert: &X509Ref) -> anyhow::Result<Self> {
+        Ok(Self {
+            subject: format_name(cert.subject_name()),
+            issuer: format_name(cert.issuer_name()),
+            not_after: cert.not_after().to_string(),
+            sha256: format_fingerprint(&cert.digest(MessageDigest::sha256())?),
+            pem: cert.to_pem()?,
+        })
+    }
+}
+
+/// Certificate chain fetched from the gateway together with the file it is cached in
+#[derive(Debug, Clone)]
+pub struct ServerChain {
+    pub certs: Vec<ServerCert>,
+    pub cache_path: PathBuf,
+}
+
+/// Fetch the certificate chain of the gateway without verifying it, the server certificate comes first.
+/// Only the TLS handshake is performed, no authentication request is sent.
+pub async fn fetch_server_chain(server_name: &str) -> anyhow::Result<Vec<ServerCert>> {
+    let server_name = server_name.to_owned();
+    let chain = tokio::task::spawn_blocking(move || {
+        inspect_handshake(&server_name, |ssl| {
+            ssl.peer_cert_chain()
+                .map(|chain| chain.iter().map(ServerCert::from_x509).collect::<Vec<_>>())
+        })
+    })
+    .await??;
+
+    chain
+        .ok_or_else(|| anyhow!("No certificate received from the gateway!"))?
+        .into_iter()
+        .collect()
+}
+
+// the fetched chain is kept per gateway in the cache directory
+fn chain_cache_path(instance_name: Option<&str>, server_name: &str) -> PathBuf {
+    let dir = ProjectDirs::from("", "", "snx-rs").expect("No home directory!");
+    let file_name = format!("{}.pem", server_name);
+    match instance_name {
+        Some(instance_name) => dir.cache_dir().join(instance_name).join("certs").join(file_name),
+        None => dir.cache_dir().join("certs").join(file_name),
+    }
+}
+
+fn write_chain(path: &Path, chain: &[ServerCert]) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(
+        path,
+        chain
+            .iter()
+            .flat_map(|cert| cert.pem.iter().copied())
+            .collect::<Vec<_>>(),
+    )?;
+    Ok(())
+}
+
+/// Save the certificate chain of the gateway as PEM into the cache directory, returns the file path.
+pub fn cache_server_chain(params: &TunnelParams, chain: &[ServerCert]) -> anyhow::Result<PathBuf> {
+    let path = chain_cache_path(params.instance_name.as_deref(), &params.server_name);
+    write_chain(&path, chain)?;
+    Ok(path)
+}
+
+/// Select the client certificate whose issuer is accepted by the gateway.
+/// Returns None if a single certificate file is configured.
+pub async fn select_client_cert(params: &TunnelParams) -> anyhow::Result<Option<PathBuf>> {
+    let path = match (params.cert_type, &params.cert_path) {
+        (CertType::Pkcs12 | CertType::Pkcs8, Some(path)) => path,
+        _ => return Ok(None),
+    };
+
+    let candidates = candidate_paths(path);
+    if !path.is_dir() && candidates.len() < 2 {
+        return Ok(None);
+    }
+
+    let now = Local::now();
+    let certs = candidates
+        .into_iter()
+        .filter_map(|p| {
+            load_cert(
+                &p,
+                params.cert_type,
+                params.cert_password.as_deref().map(String::as_str),
+            )
+            .map(|cert| (p, cert))
+        })
+        .filter(|(p, cert)| match not_after(cert) {
+            Some(expires) if expires <= now => {
+                warn!(
+                    "Skipping the client certificate {} which has expired on {}",
+                    p.display(),
+                    expires
+                );
+                false
+            }
+            _ => true,
+        })
+        .collect::<Vec<_>>();
+
+    let server_name = params.server_name.clone();
+    let cas = tokio::task::spawn_blocking(move || acceptable_cas(&server_name)).await??;
+
+    debug!("Gateway accepts client certificates from {} CAs", cas.len());
+
+    certs
+        .iter()
+        .find(|(_, cert)| cert.issuer_name().to_der().is_ok_and(|der| cas.contains(&der)))
+        .map(|(p, _)| Some(p.clone()))
+        .ok_or_else(|| {
+            let available = certs
+                .iter()
+                .map(|(p, _)| p.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            anyhow!(
+                "No client certificate is issued by a CA accepted by the gateway, available: {}",
+                if available.is_empty() { "none" } else { &available }
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chain_cache_path() {
+        let path = chain_cache_path(None, "vpn.example.com");
+        assert!(path.ends_with("certs/vpn.example.com.pem"));
+
+        let path = chain_cache_path(Some("work"), "vpn.example.com");
+        assert!(path.ends_with("work/certs/vpn.example.com.pem"));
+    }
+
+    #[test]
+    fn test_check_expiry() {
+        let path = "/etc/snx-rs/client.pem";
+        let now = Local::now();
+        let threshold = Duration::from_secs(14 * 86400);
+
+        let expires = now + chrono::Duration::days(30);
+        assert_eq!(
+            check_expiry(path, expires, now, threshold).unwrap(),
+            CertExpiry { expires, warning: None }
+        );
+
+        let expires = now + chrono::Duration::days(3);
+        let expiry = check_expiry(path, expires, now, threshold).unwrap();
+        assert_eq!(expiry.expires, expires);
+        assert!(expiry
+            .warning
+            .is_some_and(|warning| warning.contains("/etc/snx-rs/client.pem")));
+
+        let err = check_expiry(path, now - chrono::Duration::days(1), now, threshold).unwrap_err();
+        assert!(err.to_string().contains("/etc/snx-rs/client.pem"));
+    }
+
+    #[test]
+    fn test_format_fingerprint() {
+        assert_eq!(format_fingerprint(&[0xab, 0x01, 0xff]), "AB:01:FF");
+        assert_eq!(format_fingerprint(&[]), "");
+    }
+}
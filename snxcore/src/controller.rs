@@ -1,22 +1,75 @@
-use std::{collections::VecDeque, str::FromStr, sync::Arc, time::Duration};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    net::SocketAddr,
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use anyhow::anyhow;
+use ipnet::Ipv4Net;
+use once_cell::sync::Lazy;
+use regex::Regex;
 use tokio::sync::oneshot;
-use tracing::warn;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info_span, trace, warn, Instrument};
+use zeroize::Zeroizing;
 
 use crate::{
-    browser::{run_otp_listener, BrowserController},
+    browser::{self, BrowserController},
+    bundle::ConfigBundle,
+    captive,
     ccc::CccHttpClient,
+    certs::{self, ServerChain},
+    ipc::{self, IpcClient},
     model::{
-        params::TunnelParams, ConnectionStatus, MfaChallenge, MfaType, TunnelServiceRequest, TunnelServiceResponse,
+        params::TunnelParams, proto::ServerInfoResponse, BenchmarkResult, ConnectionPhase, ConnectionResult,
+        ConnectionStatus, MfaChallenge, MfaType, ProgressEvent, ServiceVersion, TunnelServiceRequest,
+        TunnelServiceResponse,
     },
     platform::{self, UdpSocketExt},
-    prompt::{SecurePrompt, OTP_TIMEOUT},
+    prompt::{self, MfaProvider, SecurePrompt, OTP_TIMEOUT},
     server_info,
 };
 
 const RECV_TIMEOUT: Duration = Duration::from_secs(2);
 const CONNECT_TIMEOUT: Duration = Duration::from_secs(120);
+const WAIT_INTERVAL: Duration = Duration::from_secs(1);
+const STATUS_RETRY_INTERVAL: Duration = Duration::from_millis(500);
+const STATUS_RETRY_TIMEOUT: Duration = Duration::from_secs(10);
+const MFA_DELIVERY_TIMEOUT: Duration = Duration::from_secs(30);
+const PUSH_POLL_INTERVAL: Duration = Duration::from_secs(2);
+const BENCHMARK_TIMEOUT: Duration = Duration::from_secs(60);
+const SERVICE_UNIT: &str = "snx-rs.service";
+const SERVICE_START_TIMEOUT: Duration = Duration::from_secs(15);
+// entry of the MFA preference for a code from an authenticator app, nothing is delivered
+const MFA_PREFERENCE_OTP: &str = "otp";
+// delivery method which is approved on the phone, the next method is tried when it is not approved in time
+const MFA_PREFERENCE_PUSH: &str = "push";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PreferredDelivery {
+    // the sign-in has been approved on the device, no code is needed
+    Approved,
+    // a code has been requested or comes from an authenticator app
+    CodeRequested,
+    NotRequested,
+}
+
+// "[code id] message" from the authentication reply or "error code: code" from a failed CCC request
+fn gateway_error_code(message: &str) -> Option<u32> {
+    static CODE_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"^\[(\d+)\s|error code: (\d+)").unwrap());
+    let captures = CODE_RE.captures(message)?;
+    captures.get(1).or_else(|| captures.get(2))?.as_str().parse().ok()
+}
+
+// nothing listens on the IPC socket nor on the UDP port, as opposed to a busy service
+fn is_service_unreachable(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<std::io::Error>()
+        .is_some_and(|e| e.kind() == std::io::ErrorKind::ConnectionRefused)
+}
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ServiceCommand {
@@ -25,6 +78,17 @@ pub enum ServiceCommand {
     Disconnect,
     Reconnect,
     Info,
+    Cancel,
+    AuthTest,
+    Suspend,
+    Resume,
+    Prewarm,
+    // bytes per second, 0 removes the limit
+    SetRateLimit(u64),
+    AddRoute(Ipv4Net),
+    RemoveRoute(Ipv4Net),
+    // connects unless a healthy tunnel exists
+    Ensure,
 }
 
 impl FromStr for ServiceCommand {
@@ -37,6 +101,12 @@ impl FromStr for ServiceCommand {
             "disconnect" => Ok(Self::Disconnect),
             "reconnect" => Ok(Self::Reconnect),
             "info" => Ok(Self::Info),
+            "cancel" => Ok(Self::Cancel),
+            "auth-test" => Ok(Self::AuthTest),
+            "suspend" => Ok(Self::Suspend),
+            "resume" => Ok(Self::Resume),
+            "prewarm" => Ok(Self::Prewarm),
+            "ensure" => Ok(Self::Ensure),
             other => Err(anyhow!("Invalid command: {}", other)),
         }
     }
@@ -46,9 +116,20 @@ pub struct ServiceController<B, P> {
     pub params: Arc<TunnelParams>,
     prompt: P,
     mfa_prompts: Option<VecDeque<String>>,
-    password: String,
+    password: Zeroizing<String>,
     first_password: bool,
+    mfa_failures: u32,
     browser_controller: B,
+    phase_handler: Option<Box<dyn Fn(ConnectionPhase) + Send + Sync>>,
+    mfa_provider: Option<Box<dyn MfaProvider + Send + Sync>>,
+    wait: bool,
+    mfa_rounds: u32,
+    error_messages: BTreeMap<u32, String>,
+    cancel_token: CancellationToken,
+    version_checked: bool,
+    correlation_id: Option<String>,
+    // fetched once per connect flow
+    server_info: Option<ServerInfoResponse>,
 }
 
 impl<B, P> ServiceController<B, P>
@@ -58,49 +139,226 @@ where
 {
     pub fn new(prompt: P, browser_controller: B, params: Arc<TunnelParams>) -> anyhow::Result<Self> {
         Ok(Self {
-            params,
             prompt,
             mfa_prompts: None,
-            password: String::new(),
+            password: Zeroizing::default(),
             first_password: true,
+            mfa_failures: 0,
             browser_controller,
+            phase_handler: None,
+            mfa_provider: None,
+            wait: false,
+            mfa_rounds: 0,
+            error_messages: params.error_messages.clone(),
+            cancel_token: CancellationToken::new(),
+            version_checked: false,
+            correlation_id: None,
+            server_info: None,
+            params,
         })
     }
 
+    // block connect until the tunnel is up or has failed, bounded by the connect timeout
+    pub fn set_wait(&mut self, wait: bool) {
+        self.wait = wait;
+    }
+
+    // called on each connection phase transition, tunnel setup phases are logged by the service
+    pub fn set_phase_handler<F>(&mut self, handler: F)
+    where
+        F: Fn(ConnectionPhase) + Send + Sync + 'static,
+    {
+        self.phase_handler = Some(Box::new(handler));
+    }
+
+    // answers all MFA challenges instead of the prompt and the browser
+    pub fn set_mfa_provider<M>(&mut self, provider: M)
+    where
+        M: MfaProvider + Send + Sync + 'static,
+    {
+        self.mfa_provider = Some(Box::new(provider));
+    }
+
+    // aborts the running command when cancelled, for example on the host application shutdown
+    pub fn set_cancellation_token(&mut self, token: CancellationToken) {
+        self.cancel_token = token;
+    }
+
+    // friendly message for a gateway error code, in addition to the error-messages option
+    pub fn add_error_message<S: Into<String>>(&mut self, code: u32, message: S) {
+        self.error_messages.insert(code, message.into());
+    }
+
+    // translates the known gateway error codes, unknown errors are passed through as is
+    fn service_error(&self, message: String) -> anyhow::Error {
+        let known = gateway_error_code(&message).and_then(|code| Some((code, self.error_messages.get(&code)?)));
+        match known {
+            Some((code, friendly)) => anyhow!("{} (error code {})", friendly, code),
+            None => anyhow!(message),
+        }
+    }
+
+    fn report_phase(&self, phase: ConnectionPhase) {
+        if let Some(ref handler) = self.phase_handler {
+            handler(phase);
+        }
+    }
+
+    // cancel safe: when the cancellation token fires a pending connection is cancelled in the service
+    // and the browser opened for the SAML authentication is closed
     pub async fn command(&mut self, command: ServiceCommand) -> anyhow::Result<ConnectionStatus> {
+        let token = self.cancel_token.clone();
+
+        let result = {
+            let command_fut = self.run_command(command);
+            tokio::select! {
+                result = command_fut => Some(result),
+                _ = token.cancelled() => None,
+            }
+        };
+
+        match result {
+            Some(result) => result,
+            None => {
+                warn!("Command {:?} cancelled", command);
+                self.browser_controller.close();
+                if matches!(
+                    command,
+                    ServiceCommand::Connect
+                        | ServiceCommand::Reconnect
+                        | ServiceCommand::AuthTest
+                        | ServiceCommand::Prewarm
+                        | ServiceCommand::Ensure
+                ) {
+                    let _ = self.send_receive(TunnelServiceRequest::Cancel, RECV_TIMEOUT).await;
+                }
+                Err(anyhow!("Command cancelled!"))
+            }
+        }
+    }
+
+    async fn run_command(&mut self, command: ServiceCommand) -> anyhow::Result<ConnectionStatus> {
+        // the local commands do not talk to the service
+        if command != ServiceCommand::Info {
+            self.check_version().await;
+        }
+
         match command {
             ServiceCommand::Status => self.do_status().await,
             ServiceCommand::Connect => {
                 self.do_status().await?;
-                self.do_connect().await
+                self.do_connect(TunnelServiceRequest::Connect).await
             }
             ServiceCommand::Disconnect => {
                 self.do_status().await?;
                 self.do_disconnect().await
             }
             ServiceCommand::Reconnect => {
-                let _ = self.do_disconnect().await;
-                self.do_connect().await
+                self.do_status().await?;
+                self.do_connect(TunnelServiceRequest::Reconnect).await
             }
             ServiceCommand::Info => self.do_info().await,
+            ServiceCommand::Cancel => self.cancel().await,
+            ServiceCommand::AuthTest => {
+                self.do_status().await?;
+                self.do_connect(TunnelServiceRequest::AuthTest).await
+            }
+            ServiceCommand::Suspend => self.send_command(TunnelServiceRequest::Suspend).await,
+            ServiceCommand::Resume => self.send_command(TunnelServiceRequest::Resume).await,
+            ServiceCommand::SetRateLimit(rate) => {
+                let rate = if rate == 0 { None } else { Some(rate) };
+                self.send_command(TunnelServiceRequest::SetRateLimit(rate)).await
+            }
+            ServiceCommand::AddRoute(route) => self.send_command(TunnelServiceRequest::AddRoute(route)).await,
+            ServiceCommand::RemoveRoute(route) => self.send_command(TunnelServiceRequest::RemoveRoute(route)).await,
+            ServiceCommand::Prewarm => {
+                self.do_status().await?;
+                self.do_connect(TunnelServiceRequest::Prewarm).await
+            }
+            ServiceCommand::Ensure => self.do_ensure().await,
+        }
+    }
+
+    // a failing keepalive terminates the tunnel, so a connected tunnel without a pending disconnect is healthy
+    async fn do_ensure(&mut self) -> anyhow::Result<ConnectionStatus> {
+        let status = self.do_status().await?;
+
+        if status.connected_since.is_none() {
+            debug!("Tunnel is not connected, connecting");
+            self.do_connect(TunnelServiceRequest::Connect).await
+        } else if status.disconnect_pending {
+            debug!("Tunnel disconnect is pending, reconnecting");
+            self.do_connect(TunnelServiceRequest::Reconnect).await
+        } else if status.suspended {
+            debug!("Tunnel is suspended, resuming");
+            self.send_command(TunnelServiceRequest::Resume).await
+        } else {
+            debug!("Tunnel is connected, nothing to do");
+            Ok(status)
         }
     }
 
+    // same as the connect command, with a summary of the connection attempt, cancel safe like the command
+    pub async fn connect(&mut self) -> anyhow::Result<ConnectionResult> {
+        let started = Instant::now();
+        self.mfa_rounds = 0;
+
+        let status = self.command(ServiceCommand::Connect).await?;
+
+        Ok(ConnectionResult {
+            server_name: self.params.server_name.clone(),
+            login_type: self.params.login_type.clone(),
+            realm: status.realm.clone(),
+            mfa_rounds: self.mfa_rounds,
+            elapsed: started.elapsed(),
+            crypto: status.crypto.clone(),
+            status,
+        })
+    }
+
+    // not cancel safe: dropping it while answering an MFA challenge leaves the challenge pending in the service,
+    // use the command method with a cancellation token instead
     #[async_recursion::async_recursion]
     pub async fn do_status(&mut self) -> anyhow::Result<ConnectionStatus> {
         let response = self.send_receive(TunnelServiceRequest::GetStatus, RECV_TIMEOUT).await?;
         match response {
             TunnelServiceResponse::ConnectionStatus(status) => {
                 if let (None, Some(mfa)) = (status.connected_since, &status.mfa) {
-                    match self.get_mfa_input(mfa).await {
+                    self.report_phase(ConnectionPhase::Mfa);
+                    self.mfa_rounds += 1;
+                    if self.is_push_candidate(mfa) && self.wait_push_approval(self.params.mfa_push_wait).await? {
+                        self.await_status_response().await?;
+                        return self.do_status().await;
+                    }
+                    let mut offer_delivery = self.is_delivery_candidate(mfa);
+                    if offer_delivery {
+                        match self.request_preferred_delivery(mfa).await? {
+                            PreferredDelivery::Approved => {
+                                self.await_status_response().await?;
+                                return self.do_status().await;
+                            }
+                            PreferredDelivery::CodeRequested => offer_delivery = false,
+                            PreferredDelivery::NotRequested => {}
+                        }
+                    }
+                    match self.get_mfa_input(mfa, offer_delivery).await {
                         Ok(input) => {
                             let result = self.do_challenge_code(input.clone()).await;
                             if result.is_ok()
-                                && mfa.mfa_type == MfaType::UserInput
+                                && matches!(mfa.mfa_type, MfaType::UserInput | MfaType::PasswordChange)
                                 && !self.password.is_empty()
                                 && !self.params.no_keychain
                             {
-                                let _ = platform::store_password(&self.params.user_name, &input).await;
+                                let store = platform::store_password(
+                                    &self.params.server_name,
+                                    &self.params.user_name,
+                                    self.params.instance_name.as_deref(),
+                                    self.params.profile.as_deref(),
+                                    &input,
+                                );
+                                if tokio::time::timeout(self.params.keychain_timeout, store).await.is_err() {
+                                    warn!("Keychain access timed out, the password is not stored");
+                                }
                                 self.password.clear();
                             }
                             result
@@ -114,18 +372,274 @@ where
                     Ok(status)
                 }
             }
+            TunnelServiceResponse::Error(e) => Err(self.service_error(e)),
+            _ => Err(anyhow!("Unexpected response")),
+        }
+    }
+
+    // read-only status request for monitoring: pending MFA challenges are not answered, no password is
+    // stored and the service state is left untouched, cancel safe
+    pub async fn query_status(&self) -> anyhow::Result<ConnectionStatus> {
+        match self
+            .send_receive(TunnelServiceRequest::GetStatusReadOnly, RECV_TIMEOUT)
+            .await?
+        {
+            TunnelServiceResponse::ConnectionStatus(status) => Ok(status),
+            TunnelServiceResponse::Error(e) => Err(self.service_error(e)),
+            _ => Err(anyhow!("Unexpected response")),
+        }
+    }
+
+    // aborts an in-progress connection, including one waiting for the MFA input, cancel safe
+    pub async fn cancel(&self) -> anyhow::Result<ConnectionStatus> {
+        self.send_command(TunnelServiceRequest::Cancel).await
+    }
+
+    // sends a request which is answered with Ok and returns the resulting status
+    async fn send_command(&self, request: TunnelServiceRequest) -> anyhow::Result<ConnectionStatus> {
+        match self.send_receive(request, RECV_TIMEOUT).await? {
+            TunnelServiceResponse::Ok => self.query_status().await,
+            TunnelServiceResponse::Error(e) => Err(self.service_error(e)),
+            _ => Err(anyhow!("Unexpected response")),
+        }
+    }
+
+    pub async fn service_version(&self) -> anyhow::Result<ServiceVersion> {
+        match self.send_receive(TunnelServiceRequest::Version, RECV_TIMEOUT).await? {
+            TunnelServiceResponse::Version(version) => Ok(version),
+            TunnelServiceResponse::Error(e) => Err(self.service_error(e)),
+            _ => Err(anyhow!("Unexpected response")),
+        }
+    }
+
+    // throughput test over the established tunnel, run by the service which refuses it when not connected
+    pub async fn benchmark(&self, target: Option<SocketAddr>) -> anyhow::Result<BenchmarkResult> {
+        match self
+            .send_receive(TunnelServiceRequest::Benchmark(target), BENCHMARK_TIMEOUT)
+            .await?
+        {
+            TunnelServiceResponse::Benchmark(result) => Ok(result),
+            TunnelServiceResponse::Error(e) => Err(self.service_error(e)),
+            _ => Err(anyhow!("Unexpected response")),
+        }
+    }
+
+    // calls the handler with the progress events of the service until the service goes away, cancel safe
+    pub async fn watch<F>(&self, mut handler: F) -> anyhow::Result<()>
+    where
+        F: FnMut(ProgressEvent),
+    {
+        let client = IpcClient::connect(ipc::socket_path(self.params.instance_name.as_deref())).await?;
+        let mut receiver = client.subscribe(TunnelServiceRequest::Watch).await?;
+
+        while let Some(response) = receiver.recv().await {
+            match response {
+                TunnelServiceResponse::Progress(event) => handler(event),
+                TunnelServiceResponse::Error(e) => return Err(self.service_error(e)),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // a service which was not restarted after an upgrade may not understand all requests, checked once
+    async fn check_version(&mut self) {
+        if self.version_checked {
+            return;
+        }
+        self.version_checked = true;
+
+        match self.service_version().await {
+            Ok(service) if service.protocol != ipc::PROTOCOL_VERSION => warn!(
+                "Incompatible service protocol version {} (service {}), expected {} (client {}), restart the service",
+                service.protocol,
+                service.version,
+                ipc::PROTOCOL_VERSION,
+                env!("CARGO_PKG_VERSION")
+            ),
+            Ok(service) if service.version != env!("CARGO_PKG_VERSION") => warn!(
+                "Service version {} differs from the client version {}",
+                service.version,
+                env!("CARGO_PKG_VERSION")
+            ),
+            Ok(service) => trace!("Service version: {}, protocol: {}", service.version, service.protocol),
+            // older services reject the request, an unreachable service is reported by the command itself
+            Err(e) => debug!("Unable to query the service version: {}", e),
+        }
+    }
+
+    // resolves when the service has dropped the pending MFA challenge, e.g. after a cancel request
+    async fn wait_mfa_cancelled(&self) {
+        loop {
+            tokio::time::sleep(WAIT_INTERVAL).await;
+            if let Ok(status) = self.query_status().await {
+                if status.mfa.is_none() {
+                    return;
+                }
+            }
+        }
+    }
+
+    // gateways federating several identity providers need the choice before the SAML redirect
+    async fn saml_url(&mut self, url: &str) -> anyhow::Result<String> {
+        let providers = match self.server_info().await {
+            Ok(info) => server_info::identity_providers(info, self.params.realm_id()),
+            Err(_) => Vec::new(),
+        };
+        match (providers.len(), self.params.idp.as_deref()) {
+            (0, Some(idp)) => server_info::with_identity_provider(url, idp),
+            (0 | 1, None) => Ok(url.to_owned()),
+            (_, idp) => {
+                let id = server_info::select_identity_provider(&self.prompt, idp, &providers)?;
+                debug!("Selected identity provider: {}", id);
+                server_info::with_identity_provider(url, &id)
+            }
+        }
+    }
+
+    // the code is requested only if the user picks one of the offered methods, the prompt follows in any case
+    async fn offer_otp_delivery(&self, mfa: &MfaChallenge) {
+        let text = format!(
+            "Send the code via {}? Leave empty if already received: ",
+            mfa.delivery.join(", ")
+        );
+        let Ok(method) = self.prompt.get_plain_input(&text) else {
+            return;
+        };
+
+        if method.is_empty() {
+            return;
+        }
+
+        if !mfa.delivery.contains(&method) {
+            warn!("Unknown delivery method: {}", method);
+            return;
+        }
+
+        if let Err(e) = self.request_otp_delivery(&method, CONNECT_TIMEOUT).await {
+            warn!("Unable to request the code delivery: {}", e);
+        }
+    }
+
+    async fn request_otp_delivery(&self, method: &str, timeout: Duration) -> anyhow::Result<()> {
+        match self
+            .send_receive(TunnelServiceRequest::RequestOtpDelivery(method.to_owned()), timeout)
+            .await?
+        {
+            TunnelServiceResponse::Ok => Ok(()),
             TunnelServiceResponse::Error(e) => Err(anyhow!(e)),
-            TunnelServiceResponse::Ok => Err(anyhow!("Unexpected response")),
+            _ => Err(anyhow!("Invalid response to the code delivery request")),
         }
     }
 
-    async fn get_mfa_input(&mut self, mfa: &MfaChallenge) -> anyhow::Result<String> {
+    // the challenges with the code delivery which are answered interactively, not the stored password
+    fn is_delivery_candidate(&self, mfa: &MfaChallenge) -> bool {
+        mfa.mfa_type == MfaType::UserInput
+            && !mfa.delivery.is_empty()
+            && self.mfa_provider.is_none()
+            && (self.password.is_empty() || !self.first_password)
+    }
+
+    // the preferred methods are requested in order until one succeeds, a push must also be approved in time
+    async fn request_preferred_delivery(&self, mfa: &MfaChallenge) -> anyhow::Result<PreferredDelivery> {
+        for method in &self.params.mfa_preference {
+            if method == MFA_PREFERENCE_OTP {
+                debug!("Preferring the authenticator code over the code delivery");
+                return Ok(PreferredDelivery::CodeRequested);
+            }
+
+            let Some(offered) = mfa.delivery.iter().find(|m| m.eq_ignore_ascii_case(method)) else {
+                continue;
+            };
+
+            if let Err(e) = self.request_otp_delivery(offered, MFA_DELIVERY_TIMEOUT).await {
+                warn!(
+                    "Unable to request the code via {}, trying the next method: {}",
+                    offered, e
+                );
+                continue;
+            }
+
+            if !offered.eq_ignore_ascii_case(MFA_PREFERENCE_PUSH) {
+                debug!("Requested the code delivery via {}", offered);
+                return Ok(PreferredDelivery::CodeRequested);
+            }
+
+            if self.wait_push_approval(MFA_DELIVERY_TIMEOUT).await? {
+                return Ok(PreferredDelivery::Approved);
+            }
+            warn!("The push via {} has not been approved, trying the next method", offered);
+        }
+        Ok(PreferredDelivery::NotRequested)
+    }
+
+    // the challenges after the password may be approved on the phone, not after a rejected code
+    fn is_push_candidate(&self, mfa: &MfaChallenge) -> bool {
+        !self.params.mfa_push_wait.is_zero()
+            && mfa.mfa_type == MfaType::UserInput
+            && !self.first_password
+            && self.mfa_failures == 0
+            && self.mfa_provider.is_none()
+    }
+
+    // true if the sign-in has been approved within the window
+    async fn wait_push_approval(&self, window: Duration) -> anyhow::Result<bool> {
+        let _ = self.prompt.show_notification(
+            "Waiting for approval",
+            &format!(
+                "Approve the sign-in request on your device within {} seconds",
+                window.as_secs()
+            ),
+        );
+
+        let deadline = tokio::time::Instant::now() + window;
+
+        while tokio::time::Instant::now() < deadline {
+            match self
+                .send_receive(TunnelServiceRequest::PollPushApproval, CONNECT_TIMEOUT)
+                .await?
+            {
+                TunnelServiceResponse::Ok => {
+                    debug!("Sign-in approved on the device");
+                    return Ok(true);
+                }
+                TunnelServiceResponse::ConnectionStatus(status) if status.mfa.is_some() => {
+                    tokio::time::sleep(PUSH_POLL_INTERVAL).await;
+                }
+                TunnelServiceResponse::ConnectionStatus(_) => return Err(anyhow!("Connection cancelled!")),
+                TunnelServiceResponse::Error(e) => {
+                    warn!("Push approval failed: {}", e);
+                    return Ok(false);
+                }
+                _ => return Err(anyhow!("Unexpected response")),
+            }
+        }
+
+        warn!("No approval within {} seconds", window.as_secs());
+
+        Ok(false)
+    }
+
+    async fn get_mfa_input(&mut self, mfa: &MfaChallenge, offer_delivery: bool) -> anyhow::Result<String> {
+        if let Some(ref provider) = self.mfa_provider {
+            return provider.answer(mfa).await;
+        }
+
         match mfa.mfa_type {
             MfaType::UserInput => {
                 if !self.password.is_empty() && self.first_password {
                     self.first_password = false;
-                    Ok(self.password.clone())
+                    Ok(self.password.as_str().to_owned())
                 } else {
+                    if offer_delivery {
+                        self.offer_otp_delivery(mfa).await;
+                    }
+                    // the pipe only supplies the codes, the password comes first
+                    if let (Some(pipe), false) = (self.params.otp_pipe.clone(), self.first_password) {
+                        if let Some(code) = self.read_otp_pipe(&pipe).await? {
+                            return Ok(code);
+                        }
+                    }
                     let prompt = self
                         .mfa_prompts
                         .as_mut()
@@ -134,23 +648,65 @@ where
                     let input = self.prompt.get_secure_input(&prompt)?;
                     if self.first_password {
                         self.first_password = false;
-                        self.password.clone_from(&input);
+                        self.password = Zeroizing::new(input.clone());
                     }
                     Ok(input)
                 }
             }
+            // the new password answers the password prompt of the repeated authentication and goes to the keychain
+            MfaType::PasswordChange => {
+                warn!("The gateway requires a password change");
+                let password = Zeroizing::new(self.prompt.get_secure_input(&mfa.prompt)?);
+                let confirmation = Zeroizing::new(self.prompt.get_secure_input("Repeat the new password: ")?);
+                if password != confirmation {
+                    return Err(anyhow!("The new passwords do not match!"));
+                }
+                self.password.clone_from(&password);
+                self.first_password = true;
+                Ok(password.as_str().to_owned())
+            }
             MfaType::SamlSso => {
                 let (tx, rx) = oneshot::channel();
-                tokio::spawn(run_otp_listener(tx));
+                let url = self.saml_url(&mfa.prompt).await?;
+
+                let tcp = if self.params.saml_manual {
+                    None
+                } else {
+                    match browser::bind_otp_listener().await {
+                        Ok(tcp) => Some(tcp),
+                        Err(e) => {
+                            warn!("Unable to start the OTP listener: {}", e);
+                            None
+                        }
+                    }
+                };
+
+                let Some(tcp) = tcp else {
+                    return self.get_manual_otp(&url);
+                };
+
+                let listener = tokio::spawn(browser::run_otp_listener(tcp, tx));
+
+                self.browser_controller.open(&url)?;
+
+                let result = tokio::select! {
+                    result = tokio::time::timeout(OTP_TIMEOUT, rx) => result.ok().and_then(Result::ok),
+                    _ = self.wait_mfa_cancelled() => {
+                        listener.abort();
+                        self.browser_controller.close();
+                        return Err(anyhow!("Connection cancelled!"));
+                    }
+                };
 
-                self.browser_controller.open(&mfa.prompt)?;
+                // the listener holds the OTP port until it has received a request
+                listener.abort();
 
-                match tokio::time::timeout(OTP_TIMEOUT, rx).await {
-                    Ok(Ok(otp)) => {
+                match result {
+                    Some(otp) => {
                         self.browser_controller.close();
                         Ok(otp)
                     }
-                    _ => {
+                    None => {
                         warn!("Unable to acquire OTP from the browser");
                         Err(anyhow!("Unable to acquire OTP from the browser!"))
                     }
@@ -159,34 +715,177 @@ where
         }
     }
 
-    async fn do_connect(&mut self) -> anyhow::Result<ConnectionStatus> {
-        self.fill_mfa_prompts().await;
+    async fn read_otp_pipe(&self, pipe: &Path) -> anyhow::Result<Option<String>> {
+        debug!("Waiting for the OTP from {}", pipe.display());
+        let result = tokio::select! {
+            result = prompt::read_otp_pipe(pipe, OTP_TIMEOUT) => result?,
+            _ = self.wait_mfa_cancelled() => return Err(anyhow!("Connection cancelled!")),
+        };
+        if result.is_none() {
+            warn!("OTP pipe {} does not exist, asking for the code", pipe.display());
+        }
+        Ok(result)
+    }
+
+    // the token is pasted by the user when the local port cannot receive the browser redirect
+    fn get_manual_otp(&self, url: &str) -> anyhow::Result<String> {
+        if let Err(e) = self.browser_controller.open(url) {
+            warn!("Unable to open the browser: {}", e);
+        }
+
+        let _ = self.prompt.show_notification(
+            "SAML authentication",
+            &format!(
+                "Sign in at {} and paste the address the browser is redirected to (http://localhost:7779/...)",
+                url
+            ),
+        );
+
+        browser::read_manual_otp(&self.prompt, "SAML token: ")
+    }
+
+    // id of the last connect flow, attached to the control requests and the log entries of the client and service
+    pub fn correlation_id(&self) -> Option<&str> {
+        self.correlation_id.as_deref()
+    }
+
+    async fn do_connect<F>(&mut self, new_request: F) -> anyhow::Result<ConnectionStatus>
+    where
+        F: FnOnce(TunnelParams) -> TunnelServiceRequest,
+    {
+        let correlation_id = format!("{:08x}", rand::random::<u32>());
+        self.correlation_id = Some(correlation_id.clone());
+        self.server_info = None;
+        self.params = Arc::new(TunnelParams {
+            correlation_id: Some(correlation_id.clone()),
+            ..(*self.params).clone()
+        });
+
+        let span = info_span!("connect", correlation_id = %correlation_id);
+        self.run_connect(new_request).instrument(span).await
+    }
 
+    // with the auth test and prewarm requests the service runs the authentication and MFA challenges only,
+    // without creating the tunnel
+    async fn run_connect<F>(&mut self, new_request: F) -> anyhow::Result<ConnectionStatus>
+    where
+        F: FnOnce(TunnelParams) -> TunnelServiceRequest,
+    {
         let params = self.params.clone();
 
-        if params.server_name.is_empty() || params.login_type.is_empty() {
-            return Err(anyhow!(
-                "Missing required parameters in the config file: server name and/or login type"
-            ));
+        if params.server_name.is_empty() {
+            return Err(anyhow!("Missing required parameter in the config file: server name"));
+        }
+
+        if params.detect_captive_portal {
+            if let Some(url) = captive::detect(&params).await {
+                warn!("Captive portal detected: {}", url);
+                let _ = self.browser_controller.open(&url);
+                return Err(anyhow!(
+                    "The network requires signing in to a captive portal first, open {} in the browser",
+                    url
+                ));
+            }
         }
 
+        // the realm selection is only offered when neither the login type nor the realm is configured
+        if params.login_type.is_empty() {
+            let login_type = match params.realm {
+                Some(ref realm) => realm.clone(),
+                None => server_info::select_login_type(self.server_info().await?, &self.prompt)?,
+            };
+            self.params = Arc::new(TunnelParams {
+                login_type,
+                ..(*params).clone()
+            });
+        }
+
+        // the prompts belong to the factors of the resolved login type
+        self.fill_mfa_prompts().await;
+
         if !params.user_name.is_empty() && !params.no_keychain && params.password.is_empty() {
-            if let Ok(password) = platform::acquire_password(&self.params.user_name).await {
-                self.password = password;
+            let acquire = platform::acquire_password(
+                &self.params.server_name,
+                &self.params.user_name,
+                self.params.instance_name.as_deref(),
+                self.params.profile.as_deref(),
+            );
+            match tokio::time::timeout(self.params.keychain_timeout, acquire).await {
+                Ok(Ok(password)) => self.password = password.into(),
+                Ok(Err(_)) => {}
+                Err(_) => warn!(
+                    "Keychain access timed out after {} seconds, falling back to the password prompt",
+                    self.params.keychain_timeout.as_secs()
+                ),
             }
         }
 
-        let response = self
-            .send_receive(TunnelServiceRequest::Connect((*self.params).clone()), CONNECT_TIMEOUT)
-            .await;
+        self.report_phase(ConnectionPhase::Authenticating);
+
+        let request = new_request((*self.params).clone());
+        let establish = matches!(
+            request,
+            TunnelServiceRequest::Connect(_) | TunnelServiceRequest::Reconnect(_)
+        );
+
+        let response = self.send_receive(request, CONNECT_TIMEOUT).await;
         match response {
-            Ok(TunnelServiceResponse::Ok) => self.do_status().await,
-            Ok(TunnelServiceResponse::Error(error)) => Err(anyhow!(error)),
+            Ok(TunnelServiceResponse::Ok) => {
+                self.await_status_response().await?;
+                let status = self.do_status().await?;
+                if !establish {
+                    return Ok(status);
+                }
+                let status = if self.wait {
+                    self.wait_connected(status).await?
+                } else {
+                    status
+                };
+                if status.connected_since.is_some() {
+                    self.report_phase(ConnectionPhase::Connected);
+                }
+                Ok(status)
+            }
+            Ok(TunnelServiceResponse::Error(error)) => Err(self.service_error(error)),
             Ok(_) => Err(anyhow!("Invalid response!")),
             Err(e) => Err(e),
         }
     }
 
+    // the service may be busy right after a successful connect, retry the status request for a while
+    async fn await_status_response(&self) -> anyhow::Result<()> {
+        let deadline = tokio::time::Instant::now() + STATUS_RETRY_TIMEOUT;
+
+        loop {
+            match self.send_receive(TunnelServiceRequest::GetStatus, RECV_TIMEOUT).await {
+                Ok(_) => return Ok(()),
+                Err(e) if tokio::time::Instant::now() >= deadline => return Err(e),
+                Err(_) => tokio::time::sleep(STATUS_RETRY_INTERVAL).await,
+            }
+        }
+    }
+
+    async fn wait_connected(&mut self, mut status: ConnectionStatus) -> anyhow::Result<ConnectionStatus> {
+        let deadline = tokio::time::Instant::now() + CONNECT_TIMEOUT;
+
+        loop {
+            if status.connected_since.is_some() {
+                return Ok(status);
+            }
+
+            if status.mfa.is_none() {
+                return Err(anyhow!("Connection failed!"));
+            }
+
+            if tokio::time::Instant::now() >= deadline {
+                return Err(anyhow!("Timeout while waiting for the connection!"));
+            }
+
+            tokio::time::sleep(WAIT_INTERVAL).await;
+            status = self.do_status().await?;
+        }
+    }
+
     async fn do_challenge_code(&mut self, code: String) -> anyhow::Result<ConnectionStatus> {
         let response = self
             .send_receive(
@@ -195,11 +894,24 @@ where
             )
             .await;
         match response {
-            Ok(TunnelServiceResponse::Ok) => self.do_status().await,
+            Ok(TunnelServiceResponse::Ok) => {
+                self.mfa_failures = 0;
+                self.await_status_response().await?;
+                self.do_status().await
+            }
             Ok(TunnelServiceResponse::Error(e)) => {
+                // the service keeps the challenge pending if the gateway accepts another answer
+                self.mfa_failures += 1;
+                if self.mfa_failures < self.params.mfa_attempts
+                    && self.query_status().await.is_ok_and(|status| status.mfa.is_some())
+                {
+                    warn!("{}, {} attempts left", e, self.params.mfa_attempts - self.mfa_failures);
+                    return self.do_status().await;
+                }
+                self.mfa_failures = 0;
                 self.send_receive(TunnelServiceRequest::Disconnect, RECV_TIMEOUT)
                     .await?;
-                Err(anyhow!(e))
+                Err(self.service_error(e))
             }
             Ok(_) => Err(anyhow!("Invalid response!")),
             Err(e) => Err(e),
@@ -212,13 +924,61 @@ where
         self.do_status().await
     }
 
+    // with the auto start option a service which is not running is restarted and the request is sent once more
     async fn send_receive(
         &self,
         request: TunnelServiceRequest,
         timeout: Duration,
     ) -> anyhow::Result<TunnelServiceResponse> {
-        let udp = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
-        udp.connect(format!("127.0.0.1:{}", crate::server::LISTEN_PORT)).await?;
+        match self.send_receive_once(request.clone(), timeout).await {
+            Err(e) if self.params.auto_start_service && is_service_unreachable(&e) => {
+                warn!("The service is not reachable, restarting {}", SERVICE_UNIT);
+                self.restart_service().await?;
+                self.send_receive_once(request, timeout).await
+            }
+            result => result,
+        }
+    }
+
+    async fn restart_service(&self) -> anyhow::Result<()> {
+        crate::util::run_command("systemctl", ["restart", SERVICE_UNIT]).await?;
+
+        let deadline = tokio::time::Instant::now() + SERVICE_START_TIMEOUT;
+
+        loop {
+            match self
+                .send_receive_once(TunnelServiceRequest::Version, RECV_TIMEOUT)
+                .await
+            {
+                Ok(_) => {
+                    debug!("Service is up");
+                    return Ok(());
+                }
+                Err(_) if tokio::time::Instant::now() >= deadline => {
+                    return Err(anyhow!(
+                        "The service did not come up within {} seconds!",
+                        SERVICE_START_TIMEOUT.as_secs()
+                    ));
+                }
+                Err(_) => tokio::time::sleep(STATUS_RETRY_INTERVAL).await,
+            }
+        }
+    }
+
+    async fn send_receive_once(
+        &self,
+        request: TunnelServiceRequest,
+        timeout: Duration,
+    ) -> anyhow::Result<TunnelServiceResponse> {
+        // older services without the IPC socket are reached with single-shot datagrams
+        match IpcClient::connect(ipc::socket_path(self.params.instance_name.as_deref())).await {
+            Ok(client) => return client.request(request, timeout).await,
+            Err(e) => trace!("IPC socket is not available, using UDP: {}", e),
+        }
+
+        let udp = crate::server::bind_loopback(0).await?;
+        let port = crate::server::listen_port(&self.params);
+        udp.connect((udp.local_addr()?.ip(), port)).await?;
 
         let data = serde_json::to_vec(&request)?;
 
@@ -228,8 +988,25 @@ where
     }
 
     async fn fill_mfa_prompts(&mut self) {
-        self.mfa_prompts
-            .replace(server_info::get_mfa_prompts(&self.params).await.unwrap_or_default());
+        let prompts = if self.params.server_prompt {
+            match self.server_info().await {
+                Ok(info) => server_info::mfa_prompts(info, &self.params.login_type),
+                Err(_) => VecDeque::new(),
+            }
+        } else {
+            VecDeque::new()
+        };
+        self.mfa_prompts.replace(prompts);
+    }
+
+    // the realm selection, the MFA prompts and the identity providers share one request to the gateway
+    async fn server_info(&mut self) -> anyhow::Result<ServerInfoResponse> {
+        if let Some(ref info) = self.server_info {
+            return Ok(info.clone());
+        }
+        let info = server_info::get(&self.params).await?;
+        self.server_info = Some(info.clone());
+        Ok(info)
     }
 
     async fn do_info(&self) -> anyhow::Result<ConnectionStatus> {
@@ -240,4 +1017,41 @@ where
 
         Ok(ConnectionStatus::default())
     }
+
+    // fetches and caches the gateway certificate chain for setting up a custom CA or the pinning,
+    // the last certificate is written to the CA file
+    pub async fn fetch_cert(&self, ca_file: Option<&Path>) -> anyhow::Result<ServerChain> {
+        let certs = certs::fetch_server_chain(&self.params.server_name).await?;
+        let cache_path = certs::cache_server_chain(&self.params, &certs)?;
+
+        if let (Some(path), Some(ca)) = (ca_file, certs.last()) {
+            std::fs::write(path, &ca.pem)?;
+        }
+
+        Ok(ServerChain { certs, cache_path })
+    }
+
+    pub fn export_config<T: AsRef<Path>>(&self, path: T) -> anyhow::Result<()> {
+        let passphrase = self.prompt.get_secure_input("Bundle passphrase: ")?;
+        let confirmation = self.prompt.get_secure_input("Confirm passphrase: ")?;
+        if passphrase != confirmation {
+            return Err(anyhow!("Passphrases do not match!"));
+        }
+
+        ConfigBundle::seal(&self.params, &passphrase)?.save(path)
+    }
+
+    pub fn import_config<T: AsRef<Path>>(&self, path: T) -> anyhow::Result<()> {
+        let bundle = ConfigBundle::load(path)?;
+        let passphrase = self.prompt.get_secure_input("Bundle passphrase: ")?;
+
+        let mut params = bundle.open(&passphrase)?;
+        params.config_file.clone_from(&self.params.config_file);
+
+        if let Some(dir) = params.config_file.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        params.save()
+    }
 }
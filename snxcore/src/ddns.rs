@@ -0,0 +1,123 @@
+use std::{
+    ffi::OsStr,
+    net::{IpAddr, Ipv4Addr},
+};
+
+use anyhow::anyhow;
+use tracing::{debug, warn};
+
+use crate::{model::params::TunnelParams, util};
+
+const NSUPDATE: &str = "nsupdate";
+const RECORD_TTL: u32 = 60;
+
+// RFC 2136 update for nsupdate, the previous records of the hostname are replaced
+fn update_script(server: Option<&str>, hostname: &str, address: Option<Ipv4Addr>) -> String {
+    let mut script = String::new();
+
+    if let Some(server) = server {
+        script.push_str(&format!("server {}\n", server));
+    }
+
+    script.push_str(&format!("update delete {} A\n", hostname));
+
+    if let Some(address) = address {
+        script.push_str(&format!("update add {} {} A {}\n", hostname, RECORD_TTL, address));
+    }
+
+    script.push_str("send\n");
+
+    script
+}
+
+// the names end up in the nsupdate script, anything but a plain DNS name or address could inject commands
+fn check_names(server: Option<&str>, hostname: &str) -> anyhow::Result<()> {
+    if !util::is_valid_dns_name(hostname) {
+        return Err(anyhow!("Invalid DDNS hostname: {}!", hostname.escape_debug()));
+    }
+    match server {
+        Some(server) if server.parse::<IpAddr>().is_err() && !util::is_valid_dns_name(server) => {
+            Err(anyhow!("Invalid DDNS server: {}!", server.escape_debug()))
+        }
+        _ => Ok(()),
+    }
+}
+
+async fn send_update(params: &TunnelParams, hostname: &str, address: Option<Ipv4Addr>) -> anyhow::Result<()> {
+    let mut args: Vec<&OsStr> = Vec::new();
+
+    if let Some(ref key) = params.ddns_key {
+        args.push("-k".as_ref());
+        args.push(key.as_os_str());
+    }
+
+    check_names(params.ddns_server.as_deref(), hostname)?;
+
+    let script = update_script(params.ddns_server.as_deref(), hostname, address);
+
+    util::run_command_with_input(NSUPDATE, args, &script).await?;
+
+    Ok(())
+}
+
+// registers the tunnel address under the configured hostname, a failure does not affect the tunnel
+pub async fn register(params: &TunnelParams, address: Ipv4Addr) {
+    let Some(ref hostname) = params.ddns_hostname else {
+        return;
+    };
+
+    debug!("Registering {} as {} in DNS", address, hostname);
+
+    if let Err(e) = send_update(params, hostname, Some(address)).await {
+        warn!("Unable to register {} in DNS: {}", hostname, e);
+    }
+}
+
+pub async fn deregister(params: &TunnelParams) {
+    let Some(ref hostname) = params.ddns_hostname else {
+        return;
+    };
+
+    debug!("Removing {} from DNS", hostname);
+
+    if let Err(e) = send_update(params, hostname, None).await {
+        warn!("Unable to remove {} from DNS: {}", hostname, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_update_script() {
+        assert_eq!(
+            update_script(
+                Some("ns1.example.com"),
+                "laptop.vpn.example.com",
+                Some(Ipv4Addr::new(10, 0, 0, 5))
+            ),
+            "server ns1.example.com\n\
+             update delete laptop.vpn.example.com A\n\
+             update add laptop.vpn.example.com 60 A 10.0.0.5\n\
+             send\n"
+        );
+        assert_eq!(
+            update_script(None, "laptop.vpn.example.com", None),
+            "update delete laptop.vpn.example.com A\nsend\n"
+        );
+    }
+
+    #[test]
+    fn test_check_names() {
+        assert!(check_names(None, "laptop.vpn.example.com").is_ok());
+        assert!(check_names(Some("ns1.example.com"), "laptop.vpn.example.com.").is_ok());
+        assert!(check_names(Some("10.0.0.53"), "laptop").is_ok());
+        assert!(check_names(Some("fd00::53"), "laptop").is_ok());
+
+        assert!(check_names(None, "laptop\nupdate delete example.com A").is_err());
+        assert!(check_names(None, "laptop vpn").is_err());
+        assert!(check_names(None, "").is_err());
+        assert!(check_names(Some("ns1.example.com\nsend"), "laptop").is_err());
+    }
+}
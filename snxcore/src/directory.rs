@@ -0,0 +1,124 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use directories_next::ProjectDirs;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::model::params::TunnelParams;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+// a directory may only point to another gateway, the local settings are kept otherwise
+const PROFILE_OPTIONS: &[&str] = &["server-name", "login-type", "tunnel-type", "realm", "ike-port"];
+
+/// Gateway profile published by the directory server
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Profile {
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    pub options: BTreeMap<String, String>,
+}
+
+impl Profile {
+    pub fn apply(&self, params: &mut TunnelParams) {
+        params.profile = Some(self.name.clone());
+        for (k, v) in &self.options {
+            if PROFILE_OPTIONS.contains(&k.as_str()) {
+                params.set_option(k, v.clone());
+            } else {
+                warn!("Ignoring option {} from the directory profile {}", k, self.name);
+            }
+        }
+    }
+}
+
+fn cache_path(instance_name: Option<&str>) -> PathBuf {
+    let dir = ProjectDirs::from("", "", "snx-rs").expect("No home directory!");
+    match instance_name {
+        Some(instance_name) => dir.cache_dir().join(instance_name).join("directory.json"),
+        None => dir.cache_dir().join("directory.json"),
+    }
+}
+
+fn read_cache(path: &Path, ttl: Option<Duration>) -> Option<Vec<Profile>> {
+    if let Some(ttl) = ttl {
+        let age = std::fs::metadata(path).ok()?.modified().ok()?.elapsed().ok()?;
+        if age > ttl {
+            return None;
+        }
+    }
+    serde_json::from_slice(&std::fs::read(path).ok()?).ok()
+}
+
+async fn fetch(url: &str) -> anyhow::Result<Vec<Profile>> {
+    let client = reqwest::Client::builder().timeout(FETCH_TIMEOUT).build()?;
+    let reply = client.get(url).send().await?.error_for_status()?;
+    Ok(serde_json::from_slice(&reply.bytes().await?)?)
+}
+
+// profiles from the directory server, the cached list is used within the TTL and when the server is unreachable
+pub async fn profiles(params: &TunnelParams) -> anyhow::Result<Vec<Profile>> {
+    let url = params
+        .directory_url
+        .as_deref()
+        .ok_or_else(|| anyhow!("No directory URL configured!"))?;
+
+    let path = cache_path(params.instance_name.as_deref());
+
+    if let Some(profiles) = read_cache(&path, Some(params.directory_ttl)) {
+        return Ok(profiles);
+    }
+
+    match fetch(url).await {
+        Ok(profiles) => {
+            debug!("Fetched {} profiles from {}", profiles.len(), url);
+            if let Some(dir) = path.parent() {
+                let _ = std::fs::create_dir_all(dir);
+            }
+            if let Err(e) = std::fs::write(&path, serde_json::to_vec(&profiles)?) {
+                warn!("Unable to cache the directory profiles: {}", e);
+            }
+            Ok(profiles)
+        }
+        Err(e) => {
+            warn!("Unable to fetch the directory profiles from {}: {}", url, e);
+            read_cache(&path, None).ok_or(e)
+        }
+    }
+}
+
+pub async fn apply_profile(params: &mut TunnelParams, name: &str) -> anyhow::Result<()> {
+    let profiles = profiles(params).await?;
+    let profile = profiles
+        .iter()
+        .find(|profile| profile.name == name)
+        .ok_or_else(|| anyhow!("Unknown profile: {}", name))?;
+    profile.apply(params);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_profile() {
+        let profile: Profile = serde_json::from_str(
+            r#"{"name": "emea", "options": {"server-name": "emea.example.com", "posture-script": "/tmp/x"}}"#,
+        )
+        .unwrap();
+
+        let mut params = TunnelParams::default();
+        profile.apply(&mut params);
+
+        assert_eq!(params.server_name, "emea.example.com");
+        assert_eq!(params.profile.as_deref(), Some("emea"));
+        assert!(params.posture_script.is_none());
+    }
+}
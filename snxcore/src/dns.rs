@@ -0,0 +1,287 @@
+use std::{
+    fmt,
+    net::{Ipv4Addr, SocketAddr},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use reqwest::{
+    header::{ACCEPT, CONTENT_TYPE},
+    Certificate, Url,
+};
+use tokio::{net::UdpSocket, sync::oneshot};
+use tracing::{debug, trace, warn};
+
+use crate::{model::params::TunnelParams, platform::UdpSocketExt};
+
+pub const FORWARDER_ADDRESS: &str = "127.0.0.1";
+
+const DNS_PORT: u16 = 53;
+const DNS_HEADER_SIZE: usize = 12;
+const MAX_DNS_PACKET_SIZE: usize = 4096;
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
+const SYSTEM_RESOLV_CONF: &[&str] = &["/run/systemd/resolve/resolv.conf", "/etc/resolv.conf"];
+const DNS_MESSAGE_TYPE: &str = "application/dns-message";
+
+// extract the first question name from the DNS query packet
+fn parse_query_name(packet: &[u8]) -> Option<String> {
+    let mut labels = Vec::new();
+    let mut offset = DNS_HEADER_SIZE;
+
+    loop {
+        let len = *packet.get(offset)? as usize;
+        if len == 0 {
+            break;
+        }
+        // compression pointers are not expected in queries
+        if len & 0xc0 != 0 {
+            return None;
+        }
+        let label = packet.get(offset + 1..offset + 1 + len)?;
+        labels.push(String::from_utf8_lossy(label).to_lowercase());
+        offset += len + 1;
+    }
+
+    Some(labels.join("."))
+}
+
+fn is_split_domain(name: &str, split_domains: &[String]) -> bool {
+    split_domains.iter().any(|domain| {
+        let domain = domain.trim_matches('.').to_lowercase();
+        name == domain || name.ends_with(&format!(".{}", domain))
+    })
+}
+
+// with all queries sent to the tunnel the system resolver is not used at all
+fn is_tunnel_query(name: Option<&str>, split_domains: &[String], all_queries: bool) -> bool {
+    all_queries || name.is_some_and(|name| is_split_domain(name, split_domains))
+}
+
+fn system_dns_servers() -> Vec<Ipv4Addr> {
+    SYSTEM_RESOLV_CONF
+        .iter()
+        .filter_map(|path| std::fs::read_to_string(path).ok())
+        .map(|data| {
+            data.lines()
+                .filter_map(|line| line.trim().strip_prefix("nameserver"))
+                .filter_map(|s| s.trim().parse::<Ipv4Addr>().ok())
+                .filter(|addr| !addr.is_loopback())
+                .collect::<Vec<_>>()
+        })
+        .find(|servers| !servers.is_empty())
+        .unwrap_or_default()
+}
+
+async fn forward_query(query: &[u8], upstream: Ipv4Addr) -> anyhow::Result<Vec<u8>> {
+    let udp = UdpSocket::bind("0.0.0.0:0").await?;
+    udp.connect((upstream, DNS_PORT)).await?;
+    udp.send_receive(query, UPSTREAM_TIMEOUT).await
+}
+
+/// DNS-over-HTTPS resolver (RFC 8484) which answers the queries sent to the tunnel instead of the tunnel DNS servers
+pub struct DohResolver {
+    client: reqwest::Client,
+    url: Url,
+}
+
+impl DohResolver {
+    // the host name of the endpoint is resolved once, before the system resolver is pointed to the forwarder
+    pub async fn new(url: &str, ca_cert: Option<&Path>) -> anyhow::Result<Self> {
+        let url = Url::parse(url)?;
+        if url.scheme() != "https" {
+            return Err(anyhow!("The DoH resolver must be an https URL: {}", url));
+        }
+
+        let mut builder = reqwest::Client::builder().timeout(UPSTREAM_TIMEOUT);
+
+        if let Some(host) = url.domain() {
+            let port = url.port_or_known_default().unwrap_or(443);
+            let address = tokio::net::lookup_host((host, port))
+                .await?
+                .next()
+                .ok_or_else(|| anyhow!("Unable to resolve the DoH resolver {}!", host))?;
+            debug!("Resolved the DoH resolver {} to {}", host, address);
+            builder = builder.resolve(host, address);
+        }
+
+        if let Some(ca_cert) = ca_cert {
+            let data = tokio::fs::read(ca_cert).await?;
+            let cert = Certificate::from_pem(&data).or_else(|_| Certificate::from_der(&data))?;
+            builder = builder.add_root_certificate(cert);
+        }
+
+        Ok(Self {
+            client: builder.build()?,
+            url,
+        })
+    }
+
+    async fn query(&self, query: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let reply = self
+            .client
+            .post(self.url.clone())
+            .header(CONTENT_TYPE, DNS_MESSAGE_TYPE)
+            .header(ACCEPT, DNS_MESSAGE_TYPE)
+            .body(query.to_vec())
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(reply.bytes().await?.to_vec())
+    }
+}
+
+enum Upstream {
+    Udp(Ipv4Addr),
+    Doh(DohResolver),
+}
+
+impl Upstream {
+    async fn resolve(&self, query: &[u8]) -> anyhow::Result<Vec<u8>> {
+        match self {
+            Self::Udp(server) => forward_query(query, *server).await,
+            Self::Doh(resolver) => resolver.query(query).await,
+        }
+    }
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Udp(server) => write!(f, "{}", server),
+            Self::Doh(resolver) => write!(f, "{}", resolver.url),
+        }
+    }
+}
+
+// the DoH resolver takes over the tunnel DNS, also when the forwarder is not enabled on its own
+pub fn use_forwarder(params: &TunnelParams) -> bool {
+    params.doh_resolver.is_some() || (params.embedded_dns && !params.force_tunnel_dns)
+}
+
+pub async fn start_forwarder(
+    params: &TunnelParams,
+    tunnel_servers: Vec<Ipv4Addr>,
+    split_domains: Vec<String>,
+) -> anyhow::Result<DnsForwarder> {
+    let doh = match params.doh_resolver {
+        Some(ref url) => Some(DohResolver::new(url, params.ca_cert.as_deref()).await?),
+        None => None,
+    };
+    DnsForwarder::start(tunnel_servers, split_domains, doh, params.force_tunnel_dns).await
+}
+
+/// Local DNS forwarder which sends queries for split domains to the tunnel DNS servers or the DoH resolver
+/// and everything else to the system resolver
+pub struct DnsForwarder {
+    stop_sender: Option<oneshot::Sender<()>>,
+}
+
+impl DnsForwarder {
+    pub async fn start(
+        tunnel_servers: Vec<Ipv4Addr>,
+        split_domains: Vec<String>,
+        doh: Option<DohResolver>,
+        all_queries: bool,
+    ) -> anyhow::Result<Self> {
+        let tunnel_upstream = match doh {
+            Some(resolver) => Upstream::Doh(resolver),
+            None => Upstream::Udp(
+                *tunnel_servers
+                    .first()
+                    .ok_or_else(|| anyhow!("No tunnel DNS servers for the embedded forwarder!"))?,
+            ),
+        };
+
+        let system_server = system_dns_servers().first().copied();
+
+        debug!(
+            "Starting DNS forwarder, tunnel DNS: {}, system DNS: {:?}, split domains: {:?}, all queries: {}",
+            tunnel_upstream, system_server, split_domains, all_queries
+        );
+
+        let tunnel_upstream = Arc::new(tunnel_upstream);
+        let system_upstream = system_server.map(|server| Arc::new(Upstream::Udp(server)));
+
+        let socket = Arc::new(UdpSocket::bind((FORWARDER_ADDRESS, DNS_PORT)).await?);
+        let (stop_sender, mut stop_receiver) = oneshot::channel();
+        let split_domains = Arc::new(split_domains);
+
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_DNS_PACKET_SIZE];
+
+            loop {
+                tokio::select! {
+                    result = socket.recv_from(&mut buf) => {
+                        if let Ok((size, from)) = result {
+                            let query = buf[0..size].to_vec();
+                            let name = parse_query_name(&query);
+                            let upstream = if is_tunnel_query(name.as_deref(), &split_domains, all_queries) {
+                                Some(tunnel_upstream.clone())
+                            } else {
+                                system_upstream.clone()
+                            };
+                            if let Some(upstream) = upstream {
+                                tokio::spawn(reply_query(socket.clone(), query, from, upstream));
+                            }
+                        }
+                    }
+                    _ = &mut stop_receiver => {
+                        debug!("Stopping DNS forwarder");
+                        break;
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            stop_sender: Some(stop_sender),
+        })
+    }
+}
+
+async fn reply_query(socket: Arc<UdpSocket>, query: Vec<u8>, from: SocketAddr, upstream: Arc<Upstream>) {
+    trace!("Forwarding DNS query from {} to {}", from, upstream);
+    match upstream.resolve(&query).await {
+        Ok(reply) => {
+            let _ = socket.send_to(&reply, from).await;
+        }
+        Err(e) => warn!("DNS query to {} failed: {}", upstream, e),
+    }
+}
+
+impl Drop for DnsForwarder {
+    fn drop(&mut self) {
+        if let Some(sender) = self.stop_sender.take() {
+            let _ = sender.send(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_domain_query() {
+        let mut packet = vec![0u8; DNS_HEADER_SIZE];
+        packet.extend([
+            4, b'h', b'o', b's', b't', 4, b'A', b'C', b'M', b'E', 3, b'c', b'o', b'm', 0, 0, 1, 0, 1,
+        ]);
+
+        let name = parse_query_name(&packet).unwrap();
+        assert_eq!(name, "host.acme.com");
+
+        assert!(is_split_domain(&name, &["acme.com".to_owned()]));
+        assert!(is_split_domain(&name, &[".ACME.com.".to_owned()]));
+        assert!(!is_split_domain(&name, &["me.com".to_owned()]));
+        assert!(parse_query_name(&packet[0..DNS_HEADER_SIZE + 3]).is_none());
+
+        assert!(is_tunnel_query(Some(&name), &["acme.com".to_owned()], false));
+        assert!(!is_tunnel_query(Some("example.org"), &["acme.com".to_owned()], false));
+        assert!(is_tunnel_query(Some("example.org"), &["acme.com".to_owned()], true));
+        assert!(!is_tunnel_query(None, &["acme.com".to_owned()], false));
+    }
+}
@@ -0,0 +1,333 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use anyhow::anyhow;
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+use tracing::{debug, trace, warn};
+use zeroize::Zeroizing;
+
+use crate::{
+    ipc::{RequestFrame, StreamRequest},
+    model::{params::TunnelParams, TunnelServiceRequest, TunnelServiceResponse},
+};
+
+const MAX_REQUEST_SIZE: u64 = 8192;
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+// the connect request waits for the authentication with the gateway
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Endpoint {
+    Status,
+    Stats,
+    Connect,
+    Disconnect,
+}
+
+impl Endpoint {
+    fn route(method: &str, path: &str) -> Option<Self> {
+        let path = path.split_once('?').map_or(path, |(path, _)| path);
+        match (method, path.trim_end_matches('/')) {
+            ("GET", "/status") => Some(Self::Status),
+            ("GET", "/stats") => Some(Self::Stats),
+            ("POST", "/connect") => Some(Self::Connect),
+            ("POST", "/disconnect") => Some(Self::Disconnect),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct HttpRequest {
+    method: String,
+    path: String,
+    token: Option<String>,
+}
+
+/// Local HTTP control API with the status, stats, connect and disconnect commands.
+/// The requests are authorized with a bearer token and handled by the command server like the IPC requests.
+pub struct HttpApi {
+    bind: SocketAddr,
+    token: Zeroizing<String>,
+    // the connect request uses the configuration the service was started with
+    params: Arc<TunnelParams>,
+}
+
+impl HttpApi {
+    // the API is only enabled when both the bind address and the token are configured
+    pub fn from_params(params: &TunnelParams) -> Option<Self> {
+        let bind = params.http_api_bind?;
+
+        match params.http_api_token {
+            Some(ref token) if !token.is_empty() => Some(Self {
+                bind,
+                token: token.clone(),
+                params: Arc::new(params.clone()),
+            }),
+            _ => {
+                warn!("No http-api-token configured, the HTTP API is disabled");
+                None
+            }
+        }
+    }
+
+    // the token is sent in plain text, remote access goes through a TLS proxy
+    pub async fn bind(&self) -> anyhow::Result<TcpListener> {
+        if !self.bind.ip().is_loopback() {
+            return Err(anyhow!(
+                "The HTTP API must listen on a loopback address, {} is not allowed!",
+                self.bind
+            ));
+        }
+
+        let listener = TcpListener::bind(self.bind).await?;
+
+        debug!("Listening for HTTP API requests on {}", self.bind);
+
+        Ok(listener)
+    }
+
+    pub async fn serve(self, listener: TcpListener, request_sender: mpsc::Sender<StreamRequest>) -> anyhow::Result<()> {
+        let api = Arc::new(self);
+        loop {
+            let (stream, addr) = listener.accept().await?;
+            trace!("Accepted HTTP API connection from {}", addr);
+            let api = api.clone();
+            let request_sender = request_sender.clone();
+            tokio::spawn(async move {
+                if let Err(e) = api.serve_connection(stream, request_sender).await {
+                    debug!("HTTP API connection from {} failed: {}", addr, e);
+                }
+            });
+        }
+    }
+
+    async fn serve_connection(
+        &self,
+        mut stream: TcpStream,
+        request_sender: mpsc::Sender<StreamRequest>,
+    ) -> anyhow::Result<()> {
+        let (reader, mut writer) = stream.split();
+        let mut reader = BufReader::new(reader.take(MAX_REQUEST_SIZE));
+
+        let request = tokio::time::timeout(READ_TIMEOUT, read_request(&mut reader)).await??;
+
+        trace!("HTTP API request: {} {}", request.method, request.path);
+
+        let (status, body) = self.dispatch(&request, &request_sender).await;
+
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        );
+        writer.write_all(response.as_bytes()).await?;
+        writer.shutdown().await?;
+
+        Ok(())
+    }
+
+    async fn dispatch(
+        &self,
+        request: &HttpRequest,
+        request_sender: &mpsc::Sender<StreamRequest>,
+    ) -> (&'static str, String) {
+        if !token_matches(&self.token, request.token.as_deref()) {
+            return ("401 Unauthorized", error_body("Invalid or missing token!"));
+        }
+
+        let Some(endpoint) = Endpoint::route(&request.method, &request.path) else {
+            return ("404 Not Found", error_body("Unknown endpoint!"));
+        };
+
+        let command = match endpoint {
+            Endpoint::Connect => Some(TunnelServiceRequest::Connect((*self.params).clone())),
+            Endpoint::Disconnect => Some(TunnelServiceRequest::Disconnect),
+            Endpoint::Status | Endpoint::Stats => None,
+        };
+
+        // the connect and disconnect commands are answered with the resulting status
+        if let Some(command) = command {
+            match send_request(request_sender, command).await {
+                Ok(TunnelServiceResponse::Error(e)) => return ("500 Internal Server Error", error_body(&e)),
+                Ok(_) => {}
+                Err(e) => return ("503 Service Unavailable", error_body(&e.to_string())),
+            }
+        }
+
+        match send_request(request_sender, TunnelServiceRequest::GetStatusReadOnly).await {
+            Ok(TunnelServiceResponse::ConnectionStatus(status)) => {
+                let body = if endpoint == Endpoint::Stats {
+                    serde_json::to_string(&status.stats)
+                } else {
+                    serde_json::to_string(&status)
+                };
+                match body {
+                    Ok(body) => ("200 OK", body),
+                    Err(e) => ("500 Internal Server Error", error_body(&e.to_string())),
+                }
+            }
+            Ok(TunnelServiceResponse::Error(e)) => ("500 Internal Server Error", error_body(&e)),
+            Ok(_) => ("500 Internal Server Error", error_body("Unexpected response")),
+            Err(e) => ("503 Service Unavailable", error_body(&e.to_string())),
+        }
+    }
+}
+
+async fn read_request<R: AsyncBufRead + Unpin>(reader: &mut R) -> anyhow::Result<HttpRequest> {
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return Err(anyhow!("Invalid HTTP request line!"));
+    };
+
+    let mut request = HttpRequest {
+        method: method.to_owned(),
+        path: path.to_owned(),
+        token: None,
+    };
+
+    // the request body is not used by any endpoint and is not read
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).await? == 0 {
+            return Err(anyhow!("Incomplete HTTP request!"));
+        }
+
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("authorization") {
+                request.token = value
+                    .trim()
+                    .strip_prefix("Bearer ")
+                    .map(|token| token.trim().to_owned());
+            }
+        }
+    }
+
+    Ok(request)
+}
+
+// compares all bytes regardless of the first mismatch
+fn token_matches(expected: &str, actual: Option<&str>) -> bool {
+    actual.is_some_and(|actual| {
+        actual.len() == expected.len()
+            && actual
+                .bytes()
+                .zip(expected.bytes())
+                .fold(0, |acc, (a, b)| acc | (a ^ b))
+                == 0
+    })
+}
+
+fn error_body(message: &str) -> String {
+    serde_json::json!({ "error": message }).to_string()
+}
+
+async fn send_request(
+    request_sender: &mpsc::Sender<StreamRequest>,
+    request: TunnelServiceRequest,
+) -> anyhow::Result<TunnelServiceResponse> {
+    let (sender, mut receiver) = mpsc::channel(1);
+
+    request_sender
+        .send(StreamRequest {
+            frame: RequestFrame { id: 0, request },
+            sender,
+        })
+        .await
+        .map_err(|_| anyhow!("Command server is not running!"))?;
+
+    let frame = tokio::time::timeout(RESPONSE_TIMEOUT, receiver.recv())
+        .await
+        .map_err(|_| anyhow!("Timeout while waiting for the command response!"))?
+        .ok_or_else(|| anyhow!("No response from the command server!"))?;
+
+    Ok(frame.response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_request() {
+        let mut data: &[u8] = b"POST /connect HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer secret\r\n\r\n";
+
+        let request = read_request(&mut data).await.unwrap();
+
+        assert_eq!(
+            request,
+            HttpRequest {
+                method: "POST".to_owned(),
+                path: "/connect".to_owned(),
+                token: Some("secret".to_owned()),
+            }
+        );
+        assert_eq!(Endpoint::route(&request.method, &request.path), Some(Endpoint::Connect));
+        assert_eq!(Endpoint::route("GET", "/status?verbose=1"), Some(Endpoint::Status));
+        assert_eq!(Endpoint::route("GET", "/connect"), None);
+
+        let mut data: &[u8] = b"GET /status HTTP/1.1\r\n";
+        assert!(read_request(&mut data).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bind_loopback_only() {
+        let params = TunnelParams {
+            http_api_bind: Some("0.0.0.0:0".parse().unwrap()),
+            http_api_token: Some("secret".to_owned().into()),
+            ..Default::default()
+        };
+        assert!(HttpApi::from_params(&params).unwrap().bind().await.is_err());
+
+        let params = TunnelParams {
+            http_api_bind: Some("127.0.0.1:0".parse().unwrap()),
+            ..params
+        };
+        assert!(HttpApi::from_params(&params).unwrap().bind().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_wrong_token() {
+        let params = TunnelParams {
+            http_api_bind: Some("127.0.0.1:0".parse().unwrap()),
+            http_api_token: Some("secret".to_owned().into()),
+            ..Default::default()
+        };
+        let api = HttpApi::from_params(&params).unwrap();
+        let (sender, mut receiver) = mpsc::channel(1);
+
+        for token in [Some("wrong"), None] {
+            let request = HttpRequest {
+                method: "POST".to_owned(),
+                path: "/connect".to_owned(),
+                token: token.map(ToOwned::to_owned),
+            };
+            let (status, body) = api.dispatch(&request, &sender).await;
+            assert_eq!(status, "401 Unauthorized");
+            assert!(body.contains("error"));
+        }
+
+        // nothing is passed to the command server
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_token_matches() {
+        assert!(token_matches("secret", Some("secret")));
+        assert!(!token_matches("secret", Some("secreT")));
+        assert!(!token_matches("secret", Some("secret2")));
+        assert!(!token_matches("secret", None));
+    }
+}
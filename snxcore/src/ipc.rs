@@ -0,0 +1,270 @@
+use std::{
+    collections::HashMap,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use bytes::Bytes;
+use futures::{stream::SplitSink, SinkExt, StreamExt};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{UnixListener, UnixStream},
+    sync::{
+        mpsc::{self, error::TrySendError},
+        oneshot,
+    },
+    task::JoinHandle,
+};
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use tracing::{debug, trace, warn};
+
+use crate::model::{TunnelServiceRequest, TunnelServiceResponse};
+
+const SOCKET_DIR: &str = "/run";
+
+// bumped on incompatible changes of the request and response messages
+pub const PROTOCOL_VERSION: u32 = 1;
+
+// responses to a subscription which are not consumed in time are dropped
+const SUBSCRIPTION_CAPACITY: usize = 64;
+
+type PendingResponses = Arc<Mutex<HashMap<u64, oneshot::Sender<TunnelServiceResponse>>>>;
+type Subscriptions = Arc<Mutex<HashMap<u64, mpsc::Sender<TunnelServiceResponse>>>>;
+
+// named instances listen on their own socket, like with the UDP port
+pub fn socket_path(instance_name: Option<&str>) -> PathBuf {
+    match instance_name {
+        Some(name) if !name.is_empty() => Path::new(SOCKET_DIR).join(format!("snx-rs-{}.sock", name)),
+        _ => Path::new(SOCKET_DIR).join("snx-rs.sock"),
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RequestFrame {
+    pub id: u64,
+    pub request: TunnelServiceRequest,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResponseFrame {
+    pub id: u64,
+    pub response: TunnelServiceResponse,
+}
+
+// request received from a stream connection, the response is sent back through the connection sender
+pub struct StreamRequest {
+    pub frame: RequestFrame,
+    pub sender: mpsc::Sender<ResponseFrame>,
+}
+
+pub fn bind<P: AsRef<Path>>(path: P) -> anyhow::Result<UnixListener> {
+    let path = path.as_ref();
+    let _ = std::fs::remove_file(path);
+
+    let listener = UnixListener::bind(path)?;
+
+    // the UDP port is reachable by all local users as well
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o666))?;
+
+    debug!("Listening for IPC connections on {}", path.display());
+
+    Ok(listener)
+}
+
+// accepts the stream connections and forwards their requests to the command server
+pub async fn serve(listener: UnixListener, request_sender: mpsc::Sender<StreamRequest>) -> anyhow::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        trace!("Accepted IPC connection");
+        tokio::spawn(serve_connection(stream, request_sender.clone()));
+    }
+}
+
+async fn serve_connection<S>(stream: S, request_sender: mpsc::Sender<StreamRequest>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sink, mut stream) = Framed::new(stream, LengthDelimitedCodec::new()).split();
+    let (response_sender, mut response_receiver) = mpsc::channel::<ResponseFrame>(16);
+
+    let writer = tokio::spawn(async move {
+        while let Some(frame) = response_receiver.recv().await {
+            let data = serde_json::to_vec(&frame)?;
+            sink.send(Bytes::from(data)).await?;
+        }
+        Ok::<_, anyhow::Error>(())
+    });
+
+    while let Some(Ok(data)) = stream.next().await {
+        match serde_json::from_slice::<RequestFrame>(&data) {
+            Ok(frame) => {
+                let request = StreamRequest {
+                    frame,
+                    sender: response_sender.clone(),
+                };
+                if request_sender.send(request).await.is_err() {
+                    break;
+                }
+            }
+            Err(e) => {
+                warn!("Invalid IPC frame: {}", e);
+                break;
+            }
+        }
+    }
+
+    // the writer completes once the outstanding responses have been sent
+    drop(response_sender);
+    let _ = writer.await;
+}
+
+/// IPC client which correlates the responses by request id, several requests may be outstanding
+pub struct IpcClient {
+    sink: tokio::sync::Mutex<SplitSink<Framed<UnixStream, LengthDelimitedCodec>, Bytes>>,
+    pending: PendingResponses,
+    subscriptions: Subscriptions,
+    next_id: AtomicU64,
+    reader: JoinHandle<()>,
+}
+
+impl IpcClient {
+    pub async fn connect<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        Ok(Self::new(UnixStream::connect(path).await?))
+    }
+
+    fn new(stream: UnixStream) -> Self {
+        let (sink, mut stream) = Framed::new(stream, LengthDelimitedCodec::new()).split();
+        let pending = PendingResponses::default();
+        let subscriptions = Subscriptions::default();
+
+        let pending_copy = pending.clone();
+        let subscriptions_copy = subscriptions.clone();
+        let reader = tokio::spawn(async move {
+            while let Some(Ok(data)) = stream.next().await {
+                match serde_json::from_slice::<ResponseFrame>(&data) {
+                    Ok(frame) => {
+                        if let Some(sender) = pending_copy.lock().remove(&frame.id) {
+                            let _ = sender.send(frame.response);
+                        } else {
+                            let mut subscriptions = subscriptions_copy.lock();
+                            if let Some(sender) = subscriptions.get(&frame.id) {
+                                if let Err(TrySendError::Closed(_)) = sender.try_send(frame.response) {
+                                    subscriptions.remove(&frame.id);
+                                }
+                            }
+                        }
+                    }
+                    Err(e) => warn!("Invalid IPC frame: {}", e),
+                }
+            }
+            // waiting requests fail when their senders are dropped
+            pending_copy.lock().clear();
+            subscriptions_copy.lock().clear();
+        });
+
+        Self {
+            sink: tokio::sync::Mutex::new(sink),
+            pending,
+            subscriptions,
+            next_id: AtomicU64::new(1),
+            reader,
+        }
+    }
+
+    pub async fn request(
+        &self,
+        request: TunnelServiceRequest,
+        timeout: Duration,
+    ) -> anyhow::Result<TunnelServiceResponse> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().insert(id, tx);
+
+        let data = serde_json::to_vec(&RequestFrame { id, request })?;
+        if let Err(e) = self.sink.lock().await.send(Bytes::from(data)).await {
+            self.pending.lock().remove(&id);
+            return Err(e.into());
+        }
+
+        let result = tokio::time::timeout(timeout, rx).await;
+        self.pending.lock().remove(&id);
+
+        match result {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(_)) => Err(anyhow!("IPC connection closed!")),
+            Err(_) => Err(anyhow!("Timeout while waiting for IPC response!")),
+        }
+    }
+
+    // for requests answered with a stream of responses, the receiver ends when the connection is closed
+    pub async fn subscribe(
+        &self,
+        request: TunnelServiceRequest,
+    ) -> anyhow::Result<mpsc::Receiver<TunnelServiceResponse>> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let (tx, rx) = mpsc::channel(SUBSCRIPTION_CAPACITY);
+        self.subscriptions.lock().insert(id, tx);
+
+        let data = serde_json::to_vec(&RequestFrame { id, request })?;
+        if let Err(e) = self.sink.lock().await.send(Bytes::from(data)).await {
+            self.subscriptions.lock().remove(&id);
+            return Err(e.into());
+        }
+
+        Ok(rx)
+    }
+}
+
+impl Drop for IpcClient {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_out_of_order_responses() {
+        let (client_stream, server_stream) = UnixStream::pair().unwrap();
+        let client = IpcClient::new(client_stream);
+
+        // answers the second request first
+        tokio::spawn(async move {
+            let mut framed = Framed::new(server_stream, LengthDelimitedCodec::new());
+            let mut ids = Vec::new();
+            while ids.len() < 2 {
+                let data = framed.next().await.unwrap().unwrap();
+                ids.push(serde_json::from_slice::<RequestFrame>(&data).unwrap().id);
+            }
+            for id in ids.into_iter().rev() {
+                let frame = ResponseFrame {
+                    id,
+                    response: TunnelServiceResponse::Error(id.to_string()),
+                };
+                framed
+                    .send(Bytes::from(serde_json::to_vec(&frame).unwrap()))
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let timeout = Duration::from_secs(5);
+        let (first, second) = tokio::join!(
+            client.request(TunnelServiceRequest::GetStatus, timeout),
+            client.request(TunnelServiceRequest::Disconnect, timeout)
+        );
+
+        assert!(matches!(first.unwrap(), TunnelServiceResponse::Error(id) if id == "1"));
+        assert!(matches!(second.unwrap(), TunnelServiceResponse::Error(id) if id == "2"));
+    }
+}
@@ -1,11 +1,24 @@
+pub mod bench;
 pub mod browser;
+pub mod bundle;
+pub mod captive;
 pub mod ccc;
+pub mod certs;
 pub mod controller;
+pub mod ddns;
+pub mod directory;
+pub mod dns;
+pub mod http_api;
+pub mod ipc;
 pub mod model;
 pub mod platform;
+pub mod posture;
+pub mod progress;
 pub mod prompt;
+pub mod redact;
 pub mod server;
 pub mod server_info;
 pub mod sexpr;
+pub mod timing;
 pub mod tunnel;
 pub mod util;
@@ -1,11 +1,16 @@
 use std::sync::Arc;
-use std::{net::Ipv4Addr, time::Duration};
+use std::{
+    fmt,
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
 
 use chrono::{DateTime, Local};
-use isakmp::model::EspCryptMaterial;
+use ipnet::Ipv4Net;
+use isakmp::model::{EspAuthAlgorithm, EspCryptMaterial, TransformId};
 use serde::{Deserialize, Serialize};
 
-use crate::model::params::TunnelParams;
+use crate::model::params::{TunnelParams, TunnelType};
 
 pub mod params;
 pub mod proto;
@@ -28,6 +33,33 @@ pub struct IpsecSession {
     pub domains: Vec<String>,
     pub esp_in: Arc<EspCryptMaterial>,
     pub esp_out: Arc<EspCryptMaterial>,
+    pub pfs_group: Option<u16>,
+}
+
+impl IpsecSession {
+    pub fn crypto_suite(&self) -> CryptoSuite {
+        let key_bits = self.esp_out.sk_e.len() * 8;
+        let encryption = match self.esp_out.transform_id {
+            TransformId::EspAesCbc => format!("AES-CBC-{}", key_bits),
+            TransformId::Esp3Des => "3DES-CBC".to_owned(),
+            ref other => format!("{:?}", other),
+        };
+
+        let hash_bits = self.esp_out.auth_algorithm.hash_len() * 8;
+        let integrity = match self.esp_out.auth_algorithm {
+            EspAuthAlgorithm::HmacSha96 | EspAuthAlgorithm::HmacSha160 => format!("HMAC-SHA1-{}", hash_bits),
+            EspAuthAlgorithm::HmacSha256 | EspAuthAlgorithm::HmacSha256v2 => format!("HMAC-SHA256-{}", hash_bits),
+            EspAuthAlgorithm::Other(id) => format!("Unknown({})", id),
+        };
+
+        CryptoSuite {
+            encryption,
+            integrity,
+            pfs_group: self.pfs_group,
+            // ESP is always encapsulated into UDP
+            natt: true,
+        }
+    }
 }
 
 impl Default for IpsecSession {
@@ -40,6 +72,7 @@ impl Default for IpsecSession {
             domains: Vec::new(),
             esp_in: Default::default(),
             esp_out: Default::default(),
+            pfs_group: None,
         }
     }
 }
@@ -73,6 +106,8 @@ pub enum MfaType {
     #[default]
     UserInput,
     SamlSso,
+    // forced by the gateway, distinct from the MFA challenges the answer is the new password
+    PasswordChange,
 }
 
 impl MfaType {
@@ -89,12 +124,131 @@ impl MfaType {
 pub struct MfaChallenge {
     pub mfa_type: MfaType,
     pub prompt: String,
+    // methods by which the gateway sends the code on request, e.g. sms or email
+    pub delivery: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, PartialOrd)]
+pub struct CryptoSuite {
+    pub encryption: String,
+    pub integrity: String,
+    pub pfs_group: Option<u16>,
+    pub natt: bool,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default, PartialEq, PartialOrd)]
+pub struct TrafficUsage {
+    pub received: u64,
+    pub sent: u64,
+}
+
+/// Counters of the tunnel device reported by the kernel, including the traffic not seen by the tunnel itself
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default, PartialEq, PartialOrd)]
+pub struct InterfaceStats {
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub rx_packets: u64,
+    pub tx_packets: u64,
+    pub rx_errors: u64,
+    pub tx_errors: u64,
+    pub rx_dropped: u64,
+    pub tx_dropped: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, PartialOrd)]
+pub struct TunnelStats {
+    pub recv_errors: u64,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+    pub keepalive_rtt: Option<u64>,
+    pub esp_sequence: Option<u64>,
+    pub esp_bytes: Option<u64>,
+    pub socket_drops: u64,
+    pub last_minute: TrafficUsage,
+    pub last_hour: TrafficUsage,
+    pub last_day: TrafficUsage,
+    // read on the status request only, not while sampling the usage
+    pub interface: Option<InterfaceStats>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, PartialOrd)]
+pub enum ConnectionPhase {
+    Authenticating,
+    Mfa,
+    FetchingSettings,
+    ConfiguringTunnel,
+    Connected,
+}
+
+impl fmt::Display for ConnectionPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::Authenticating => "Authenticating",
+            Self::Mfa => "Waiting for multi-factor authentication",
+            Self::FetchingSettings => "Fetching client settings",
+            Self::ConfiguringTunnel => "Configuring tunnel",
+            Self::Connected => "Connected",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Fine-grained connection progress pushed to the watch subscribers
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ProgressEvent {
+    pub time: DateTime<Local>,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, PartialOrd)]
+pub struct PhaseTiming {
+    pub name: String,
+    pub millis: u64,
+}
+
+impl PhaseTiming {
+    pub fn new(name: &str, duration: Duration) -> Self {
+        Self {
+            name: name.to_owned(),
+            millis: duration.as_millis() as u64,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, PartialOrd)]
 pub struct ConnectionStatus {
     pub connected_since: Option<DateTime<Local>>,
     pub mfa: Option<MfaChallenge>,
+    pub crypto: Option<CryptoSuite>,
+    pub disconnect_pending: bool,
+    pub realm: Option<String>,
+    // the tunnel type in use, which the auto mode selects when connecting
+    pub tunnel_type: Option<TunnelType>,
+    pub stats: Option<TunnelStats>,
+    pub encryption_domain: Vec<Ipv4Net>,
+    pub suspended: bool,
+    pub standby: bool,
+    pub warnings: Vec<String>,
+    // filled in when the connection timing report is requested
+    pub timings: Vec<PhaseTiming>,
+    // bytes per second
+    pub rate_limit: Option<u64>,
+    // what could not be removed after the last disconnect
+    pub cleanup_warning: Option<String>,
+    // cutoff time when the maximum session duration is set
+    pub session_ends: Option<DateTime<Local>>,
+    // why the service has disconnected the tunnel on its own
+    pub disconnect_reason: Option<String>,
+    // expiry of the client certificate when it could be read at connect
+    pub cert_expires: Option<DateTime<Local>>,
+    // routes added with the add route request while connected
+    pub added_routes: Vec<Ipv4Net>,
+    // connection attempts refused by the busy gateway before it succeeded
+    pub busy_retries: u32,
+    // seconds to wait before reconnecting as suggested by the busy gateway
+    pub suggested_retry_interval: Option<u64>,
+    // when the service reconnects on its own after the busy gateway has refused the automatic reconnect
+    pub reconnect_at: Option<DateTime<Local>>,
 }
 
 impl ConnectionStatus {
@@ -105,10 +259,25 @@ impl ConnectionStatus {
         }
     }
 
+    pub fn connected_with_session(session: &VpnSession) -> Self {
+        Self {
+            crypto: session.ipsec_session.as_ref().map(IpsecSession::crypto_suite),
+            ..Self::connected()
+        }
+    }
+
     pub fn disconnected() -> Self {
         Self::default()
     }
 
+    // authenticated session kept ready for the next connect
+    pub fn standby() -> Self {
+        Self {
+            standby: true,
+            ..Default::default()
+        }
+    }
+
     pub fn mfa(challenge: MfaChallenge) -> Self {
         Self {
             mfa: Some(challenge),
@@ -117,12 +286,89 @@ impl ConnectionStatus {
     }
 }
 
+/// Outcome of a connection attempt made by the service controller
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
+pub struct ConnectionResult {
+    pub server_name: String,
+    pub login_type: String,
+    pub realm: Option<String>,
+    pub mfa_rounds: u32,
+    pub elapsed: Duration,
+    pub crypto: Option<CryptoSuite>,
+    pub status: ConnectionStatus,
+}
+
+/// Outcome of a throughput test over the established tunnel
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct BenchmarkResult {
+    pub target: SocketAddr,
+    pub packets_sent: u32,
+    pub packets_received: u32,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    // until the last reply
+    pub elapsed: Duration,
+}
+
+impl Default for BenchmarkResult {
+    fn default() -> Self {
+        Self {
+            target: SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            packets_sent: 0,
+            packets_received: 0,
+            bytes_sent: 0,
+            bytes_received: 0,
+            elapsed: Duration::ZERO,
+        }
+    }
+}
+
+impl BenchmarkResult {
+    pub fn loss_percent(&self) -> f64 {
+        if self.packets_sent == 0 {
+            0.0
+        } else {
+            self.packets_sent.saturating_sub(self.packets_received) as f64 * 100.0 / self.packets_sent as f64
+        }
+    }
+
+    // of the answered probes, the replies of the gateway are shorter than the probes and are not counted
+    pub fn throughput_mbps(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs == 0.0 || self.packets_sent == 0 {
+            0.0
+        } else {
+            let delivered =
+                self.bytes_sent as f64 * self.packets_received.min(self.packets_sent) as f64 / self.packets_sent as f64;
+            delivered * 8.0 / secs / 1_000_000.0
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum TunnelServiceRequest {
     Connect(TunnelParams),
+    Reconnect(TunnelParams),
     ChallengeCode(String, TunnelParams),
+    RequestOtpDelivery(String),
+    // answered with Ok once the push challenge is approved, with the connection status while it is pending
+    PollPushApproval,
     Disconnect,
     GetStatus,
+    GetStatusReadOnly,
+    Cancel,
+    AuthTest(TunnelParams),
+    Suspend,
+    Resume,
+    Prewarm(TunnelParams),
+    SetRateLimit(Option<u64>),
+    AddRoute(Ipv4Net),
+    RemoveRoute(Ipv4Net),
+    // to the given echo target or to the internal gateway address
+    Benchmark(Option<SocketAddr>),
+    Version,
+    // IPC socket only, the progress events are sent as responses until the connection is closed
+    Watch,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -130,4 +376,14 @@ pub enum TunnelServiceResponse {
     Ok,
     Error(String),
     ConnectionStatus(ConnectionStatus),
+    Version(ServiceVersion),
+    Progress(ProgressEvent),
+    Benchmark(BenchmarkResult),
+}
+
+/// Crate and IPC protocol version of the running service
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServiceVersion {
+    pub version: String,
+    pub protocol: u32,
 }
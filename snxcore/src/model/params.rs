@@ -1,6 +1,8 @@
 use std::{
+    collections::BTreeMap,
     fmt,
     io::{Cursor, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     path::{Path, PathBuf},
     str::FromStr,
     time::Duration,
@@ -11,11 +13,118 @@ use base64::Engine;
 use directories_next::ProjectDirs;
 use ipnet::Ipv4Net;
 use serde::{Deserialize, Serialize};
-use tracing::warn;
+use tracing::{debug, warn};
+use zeroize::Zeroizing;
 
 const DEFAULT_ESP_LIFETIME: Duration = Duration::from_secs(3600);
 const DEFAULT_IKE_LIFETIME: Duration = Duration::from_secs(28800);
 const DEFAULT_IKE_PORT: u16 = 500;
+const DEFAULT_PREWARM_REFRESH: Duration = Duration::from_secs(1800);
+const DEFAULT_KEYCHAIN_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_DIRECTORY_TTL: Duration = Duration::from_secs(86400);
+const DEFAULT_AUTH_FAILURE_LIMIT: u32 = 5;
+const DEFAULT_AUTH_COOLDOWN: Duration = Duration::from_secs(300);
+const DEFAULT_CERT_EXPIRY_WARNING: Duration = Duration::from_secs(14 * 86400);
+
+fn parse_switch(s: &str) -> Option<bool> {
+    match s.to_lowercase().as_str() {
+        "on" | "true" | "yes" | "1" => Some(true),
+        "off" | "false" | "no" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+// options which are written as arrays into TOML files
+const LIST_OPTIONS: &[&str] = &[
+    "search-domains",
+    "ignore-search-domains",
+    "add-routes",
+    "ignore-routes",
+    "dns-split-domains",
+    "reconnect-on",
+    "tunnel-ports",
+    "quirks",
+    "redirect-hosts",
+    "mfa-preference",
+    "log-redact",
+];
+
+fn is_toml(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("toml"))
+}
+
+fn parse_conf_line(line: &str) -> Option<(&str, &str)> {
+    if line.trim().starts_with('#') {
+        return None;
+    }
+    line.split_once('=')
+        .map(|(k, v)| (k.trim(), v.trim_matches(|c: char| c == '"' || c.is_whitespace())))
+        .and_then(|(k, v)| if v.is_empty() { None } else { Some((k, v)) })
+}
+
+// the hidden entries are skipped, such as the ..data links of the Kubernetes secret volumes, and the empty files
+fn secret_names(dir: &Path) -> anyhow::Result<Vec<String>> {
+    let mut names = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if let Some(name) = path.file_name().and_then(|name| name.to_str()) {
+            if !name.starts_with('.') && std::fs::metadata(&path).is_ok_and(|m| m.is_file() && m.len() > 0) {
+                names.push(name.to_owned());
+            }
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+// TOML files use the same option names, values are converted into the plain config representation
+fn toml_options(data: &str) -> anyhow::Result<Vec<(String, String)>> {
+    let table = toml::from_str::<toml::Table>(data)?;
+    let mut options = Vec::new();
+
+    for (k, v) in table {
+        let value = match v {
+            toml::Value::String(s) => s,
+            toml::Value::Array(items) => items
+                .into_iter()
+                .map(|item| match item {
+                    toml::Value::String(s) => s,
+                    other => other.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join(","),
+            toml::Value::Table(_) => {
+                warn!("Ignoring unsupported table option: {}", k);
+                continue;
+            }
+            other => other.to_string(),
+        };
+        if !value.is_empty() {
+            options.push((k, value));
+        }
+    }
+
+    Ok(options)
+}
+
+fn conf_to_toml(conf: &str) -> anyhow::Result<String> {
+    let mut table = toml::Table::new();
+
+    for (k, v) in conf.lines().filter_map(parse_conf_line) {
+        let value = if LIST_OPTIONS.contains(&k) {
+            toml::Value::Array(v.split(',').map(|s| toml::Value::String(s.trim().to_owned())).collect())
+        } else if let Ok(b) = v.parse::<bool>() {
+            toml::Value::Boolean(b)
+        } else if let Ok(n) = v.parse::<i64>() {
+            toml::Value::Integer(n)
+        } else {
+            toml::Value::String(v.to_owned())
+        };
+        table.insert(k.to_owned(), value);
+    }
+
+    Ok(toml::to_string(&table)?)
+}
 
 #[derive(Debug, Clone, Copy, PartialEq, Default)]
 pub enum OperationMode {
@@ -38,11 +147,13 @@ impl FromStr for OperationMode {
     }
 }
 
-#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, PartialOrd, Serialize, Deserialize)]
 pub enum TunnelType {
     #[default]
     Ipsec,
     Ssl,
+    // IPSec when the gateway offers it and the NAT-T probe succeeds, SSL otherwise
+    Auto,
 }
 
 impl TunnelType {
@@ -58,6 +169,7 @@ impl TunnelType {
         match self {
             TunnelType::Ipsec => "ipsec",
             TunnelType::Ssl => "ssl",
+            TunnelType::Auto => "auto",
         }
     }
 }
@@ -69,6 +181,7 @@ impl FromStr for TunnelType {
         match s.to_lowercase().as_str() {
             "ipsec" => Ok(TunnelType::Ipsec),
             "ssl" => Ok(TunnelType::Ssl),
+            "auto" => Ok(TunnelType::Auto),
             _ => Err(anyhow!("Invalid tunnel type!")),
         }
     }
@@ -131,11 +244,242 @@ impl FromStr for CertType {
     }
 }
 
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum IpMode {
+    #[default]
+    Auto,
+    V4,
+    V6,
+}
+
+impl IpMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IpMode::Auto => "auto",
+            IpMode::V4 => "v4",
+            IpMode::V6 => "v6",
+        }
+    }
+
+    // binding the outgoing connections to the unspecified address forces the address family
+    pub fn local_address(&self) -> Option<IpAddr> {
+        match self {
+            IpMode::Auto => None,
+            IpMode::V4 => Some(Ipv4Addr::UNSPECIFIED.into()),
+            IpMode::V6 => Some(Ipv6Addr::UNSPECIFIED.into()),
+        }
+    }
+}
+
+// how the tunnel DNS settings are applied to the system
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum DnsManager {
+    #[default]
+    Auto,
+    Resolved,
+    NetworkManager,
+    Resolvconf,
+    File,
+}
+
+impl DnsManager {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DnsManager::Auto => "auto",
+            DnsManager::Resolved => "resolved",
+            DnsManager::NetworkManager => "networkmanager",
+            DnsManager::Resolvconf => "resolvconf",
+            DnsManager::File => "file",
+        }
+    }
+}
+
+// kind of the keepalive failure which has terminated the tunnel
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum KeepaliveFailure {
+    Timeout,
+    Unreachable,
+    Resume,
+    // the gateway has terminated the session, e.g. when it is revoked by the administrator
+    AuthRevoked,
+}
+
+impl KeepaliveFailure {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KeepaliveFailure::Timeout => "timeout",
+            KeepaliveFailure::Unreachable => "unreachable",
+            KeepaliveFailure::Resume => "resume",
+            KeepaliveFailure::AuthRevoked => "auth-revoked",
+        }
+    }
+}
+
+impl FromStr for KeepaliveFailure {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "timeout" => Ok(KeepaliveFailure::Timeout),
+            "unreachable" => Ok(KeepaliveFailure::Unreachable),
+            "resume" => Ok(KeepaliveFailure::Resume),
+            "auth-revoked" => Ok(KeepaliveFailure::AuthRevoked),
+            _ => Err(anyhow!("Invalid keepalive failure type!")),
+        }
+    }
+}
+
+// workarounds for the behavior of specific gateway versions, enabled by the user
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Quirk {
+    // the gateway sends broken compressed replies, the plain ones are requested
+    NoCompression,
+    // the gateway rejects the authentication requests which carry the client logging data
+    NoClientLoggingData,
+    // the gateway does not answer the hello request which detects redirects to another entry point
+    NoRedirectCheck,
+}
+
+impl Quirk {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Quirk::NoCompression => "no-compression",
+            Quirk::NoClientLoggingData => "no-client-logging-data",
+            Quirk::NoRedirectCheck => "no-redirect-check",
+        }
+    }
+}
+
+impl FromStr for Quirk {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "no-compression" => Ok(Quirk::NoCompression),
+            "no-client-logging-data" => Ok(Quirk::NoClientLoggingData),
+            "no-redirect-check" => Ok(Quirk::NoRedirectCheck),
+            _ => Err(anyhow!("Invalid quirk!")),
+        }
+    }
+}
+
+// processes whose traffic is routed through the tunnel, all other traffic bypasses it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum AppRouting {
+    Cgroup(PathBuf),
+    Fwmark(u32),
+}
+
+impl AppRouting {
+    // processes in the cgroup get the default mark
+    pub const DEFAULT_MARK: u32 = 0x736e78;
+
+    pub fn mark(&self) -> u32 {
+        match self {
+            AppRouting::Cgroup(_) => Self::DEFAULT_MARK,
+            AppRouting::Fwmark(mark) => *mark,
+        }
+    }
+}
+
+impl fmt::Display for AppRouting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppRouting::Cgroup(path) => write!(f, "cgroup:{}", path.display()),
+            AppRouting::Fwmark(mark) => write!(f, "fwmark:{:#x}", mark),
+        }
+    }
+}
+
+impl FromStr for AppRouting {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("cgroup", path)) if !path.trim().is_empty() => Ok(AppRouting::Cgroup(path.trim().into())),
+            Some(("fwmark", mark)) => {
+                let mark = mark.trim();
+                let mark = match mark.strip_prefix("0x") {
+                    Some(hex) => u32::from_str_radix(hex, 16),
+                    None => mark.parse(),
+                };
+                match mark {
+                    Ok(mark) if mark != 0 => Ok(AppRouting::Fwmark(mark)),
+                    _ => Err(anyhow!("Invalid firewall mark!")),
+                }
+            }
+            _ => Err(anyhow!(
+                "Invalid application routing, expected cgroup:<path> or fwmark:<mark>!"
+            )),
+        }
+    }
+}
+
+impl FromStr for IpMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "auto" => Ok(IpMode::Auto),
+            "v4" | "ipv4" => Ok(IpMode::V4),
+            "v6" | "ipv6" => Ok(IpMode::V6),
+            _ => Err(anyhow!("Invalid IP mode!")),
+        }
+    }
+}
+
+impl FromStr for DnsManager {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "auto" => Ok(DnsManager::Auto),
+            "resolved" | "systemd-resolved" => Ok(DnsManager::Resolved),
+            "networkmanager" | "nm" => Ok(DnsManager::NetworkManager),
+            "resolvconf" => Ok(DnsManager::Resolvconf),
+            "file" => Ok(DnsManager::File),
+            _ => Err(anyhow!("Invalid DNS manager!")),
+        }
+    }
+}
+
+// what to do with a stale tunnel interface of the same name, e.g. left over after a crash
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum ExistingInterface {
+    Fail,
+    Reuse,
+    #[default]
+    Recreate,
+}
+
+impl ExistingInterface {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ExistingInterface::Fail => "fail",
+            ExistingInterface::Reuse => "reuse",
+            ExistingInterface::Recreate => "recreate",
+        }
+    }
+}
+
+impl FromStr for ExistingInterface {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "fail" => Ok(ExistingInterface::Fail),
+            "reuse" => Ok(ExistingInterface::Reuse),
+            "recreate" => Ok(ExistingInterface::Recreate),
+            _ => Err(anyhow!("Invalid existing interface policy!")),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TunnelParams {
     pub server_name: String,
     pub user_name: String,
-    pub password: String,
+    pub password: Zeroizing<String>,
     pub log_level: String,
     pub search_domains: Vec<String>,
     pub ignore_search_domains: Vec<String>,
@@ -151,14 +495,90 @@ pub struct TunnelParams {
     pub login_type: String,
     pub cert_type: CertType,
     pub cert_path: Option<PathBuf>,
-    pub cert_password: Option<String>,
+    pub cert_password: Option<Zeroizing<String>>,
     pub cert_id: Option<String>,
+    pub cert_expiry_warning: Duration,
     pub if_name: Option<String>,
+    pub tun_device: Option<String>,
     pub no_keychain: bool,
+    pub strict_password_decode: bool,
+    pub keychain_timeout: Duration,
     pub server_prompt: bool,
     pub esp_lifetime: Duration,
+    pub esp_max_bytes: u64,
+    pub socket_rcvbuf: Option<usize>,
+    pub socket_sndbuf: Option<usize>,
     pub ike_lifetime: Duration,
     pub ike_port: u16,
+    pub anti_replay_window: Option<u32>,
+    pub tunnel_mtu: Option<u16>,
+    pub pmtud: bool,
+    pub rate_limit: Option<u64>,
+    pub rate_limit_inbound: bool,
+    pub keepalive: bool,
+    pub keepalive_target: Option<Ipv4Addr>,
+    pub mfa_cache_ttl: Duration,
+    pub mfa_attempts: u32,
+    pub mfa_push_wait: Duration,
+    pub mfa_preference: Vec<String>,
+    pub otp_pipe: Option<PathBuf>,
+    pub auth_failure_limit: u32,
+    pub auth_cooldown: Duration,
+    pub retry_on_busy: u32,
+    pub busy_error_codes: Vec<u32>,
+    // set by snxctl connect --force for a single request, not part of the configuration
+    pub ignore_cooldown: bool,
+    // set by snxctl connect --timings, the connection phases are timed
+    pub timing_report: bool,
+    // generated by the controller for each connect, sent with the control requests and logged by the service
+    pub correlation_id: Option<String>,
+    // directory profile applied by snxctl --profile, keeps the keychain entries of the profiles apart
+    pub profile: Option<String>,
+    pub outer_vrf: Option<String>,
+    pub disconnect_grace: Duration,
+    pub max_session_duration: Duration,
+    pub realm: Option<String>,
+    pub idp: Option<String>,
+    pub saml_manual: bool,
+    pub saml_session_reuse: bool,
+    pub saml_session_ttl: Duration,
+    pub embedded_dns: bool,
+    pub dns_split_domains: Vec<String>,
+    pub posture_script: Option<PathBuf>,
+    pub reconnect_on_resume: bool,
+    pub route_table: Option<u32>,
+    pub force_tunnel_dns: bool,
+    pub persist_stats: bool,
+    pub instance_name: Option<String>,
+    pub listen_port: Option<u16>,
+    pub honor_encryption_domain: bool,
+    pub strict_routes: bool,
+    pub isolate_overlaps: bool,
+    pub ip_mode: IpMode,
+    pub dns_manager: DnsManager,
+    pub auto_exclude_local: bool,
+    pub reconnect_on: Vec<KeepaliveFailure>,
+    pub quirks: Vec<Quirk>,
+    pub redirect_hosts: Vec<String>,
+    pub error_messages: BTreeMap<u32, String>,
+    pub prewarm_refresh: Duration,
+    pub app_routing: Option<AppRouting>,
+    pub tunnel_ports: Vec<u16>,
+    pub detect_captive_portal: bool,
+    pub verify_cleanup: bool,
+    pub on_existing_interface: ExistingInterface,
+    pub flush_dns_on_disconnect: bool,
+    pub directory_url: Option<String>,
+    pub directory_ttl: Duration,
+    pub ddns_hostname: Option<String>,
+    pub ddns_server: Option<String>,
+    pub ddns_key: Option<PathBuf>,
+    pub log_redact: Vec<String>,
+    pub auto_start_service: bool,
+    pub http_api_bind: Option<SocketAddr>,
+    pub http_api_token: Option<Zeroizing<String>>,
+    pub doh_resolver: Option<String>,
+    pub secrets_dir: Option<PathBuf>,
     pub config_file: PathBuf,
 }
 
@@ -167,7 +587,7 @@ impl Default for TunnelParams {
         Self {
             server_name: String::new(),
             user_name: String::new(),
-            password: String::new(),
+            password: Zeroizing::default(),
             log_level: "off".to_owned(),
             search_domains: Vec::new(),
             ignore_search_domains: Vec::new(),
@@ -185,12 +605,84 @@ impl Default for TunnelParams {
             cert_path: None,
             cert_password: None,
             cert_id: None,
+            cert_expiry_warning: DEFAULT_CERT_EXPIRY_WARNING,
             if_name: None,
+            tun_device: None,
             no_keychain: false,
+            strict_password_decode: false,
+            keychain_timeout: DEFAULT_KEYCHAIN_TIMEOUT,
             server_prompt: true,
             esp_lifetime: DEFAULT_ESP_LIFETIME,
+            esp_max_bytes: 0,
+            socket_rcvbuf: None,
+            socket_sndbuf: None,
             ike_lifetime: DEFAULT_IKE_LIFETIME,
             ike_port: DEFAULT_IKE_PORT,
+            anti_replay_window: None,
+            tunnel_mtu: None,
+            pmtud: true,
+            rate_limit: None,
+            rate_limit_inbound: false,
+            keepalive: true,
+            keepalive_target: None,
+            mfa_cache_ttl: Duration::ZERO,
+            mfa_attempts: 1,
+            mfa_push_wait: Duration::ZERO,
+            mfa_preference: Vec::new(),
+            otp_pipe: None,
+            auth_failure_limit: DEFAULT_AUTH_FAILURE_LIMIT,
+            auth_cooldown: DEFAULT_AUTH_COOLDOWN,
+            retry_on_busy: 0,
+            busy_error_codes: Vec::new(),
+            ignore_cooldown: false,
+            timing_report: false,
+            correlation_id: None,
+            profile: None,
+            outer_vrf: None,
+            disconnect_grace: Duration::ZERO,
+            max_session_duration: Duration::ZERO,
+            realm: None,
+            idp: None,
+            saml_manual: false,
+            saml_session_reuse: true,
+            saml_session_ttl: Duration::ZERO,
+            embedded_dns: false,
+            dns_split_domains: Vec::new(),
+            posture_script: None,
+            reconnect_on_resume: false,
+            route_table: None,
+            force_tunnel_dns: false,
+            persist_stats: false,
+            instance_name: None,
+            listen_port: None,
+            honor_encryption_domain: true,
+            strict_routes: false,
+            isolate_overlaps: false,
+            ip_mode: IpMode::default(),
+            dns_manager: DnsManager::default(),
+            auto_exclude_local: true,
+            reconnect_on: Vec::new(),
+            quirks: Vec::new(),
+            redirect_hosts: Vec::new(),
+            error_messages: BTreeMap::new(),
+            prewarm_refresh: DEFAULT_PREWARM_REFRESH,
+            app_routing: None,
+            tunnel_ports: Vec::new(),
+            detect_captive_portal: false,
+            verify_cleanup: true,
+            on_existing_interface: ExistingInterface::default(),
+            flush_dns_on_disconnect: false,
+            directory_url: None,
+            directory_ttl: DEFAULT_DIRECTORY_TTL,
+            ddns_hostname: None,
+            ddns_server: None,
+            ddns_key: None,
+            log_redact: Vec::new(),
+            auto_start_service: false,
+            http_api_bind: None,
+            http_api_token: None,
+            doh_resolver: None,
+            secrets_dir: None,
             config_file: Self::default_config_path(),
         }
     }
@@ -200,83 +692,275 @@ impl TunnelParams {
     pub const IPSEC_KEEPALIVE_PORT: u16 = 18234;
     pub const DEFAULT_IPSEC_IF_NAME: &'static str = "snx-xfrm";
     pub const DEFAULT_SSL_IF_NAME: &'static str = "snx-tun";
+    pub const APP_ROUTING_TABLE: u32 = 7780;
+    pub const OVERLAP_ROUTING_TABLE: u32 = 7800;
+    pub const OVERLAP_ROUTING_TABLES: u32 = 100;
 
     pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
         let mut params = Self::default();
         let data = std::fs::read_to_string(&path)?;
-        for line in data.lines() {
-            if !line.trim().starts_with('#') {
-                let parts = line
-                    .split_once('=')
-                    .map(|(k, v)| (k.trim(), v.trim_matches(|c: char| c == '"' || c.is_whitespace())))
-                    .and_then(|(k, v)| if v.is_empty() { None } else { Some((k, v)) });
-
-                if let Some((k, v)) = parts {
-                    let v = v.to_owned();
-                    match k {
-                        "server-name" => params.server_name = v,
-                        "user-name" => params.user_name = v,
-                        "password" => params.password = v,
-                        "log-level" => params.log_level = v,
-                        "search-domains" => params.search_domains = v.split(',').map(|s| s.trim().to_owned()).collect(),
-                        "ignore-search-domains" => {
-                            params.ignore_search_domains = v.split(',').map(|s| s.trim().to_owned()).collect()
-                        }
-                        "default-route" => params.default_route = v.parse().unwrap_or_default(),
-                        "no-routing" => params.no_routing = v.parse().unwrap_or_default(),
-                        "add-routes" => params.add_routes = v.split(',').flat_map(|s| s.trim().parse().ok()).collect(),
-                        "ignore-routes" => {
-                            params.ignore_routes = v.split(',').flat_map(|s| s.trim().parse().ok()).collect()
-                        }
-                        "no-dns" => params.no_dns = v.parse().unwrap_or_default(),
-                        "no-cert-check" => params.no_cert_check = v.parse().unwrap_or_default(),
-                        "ignore-server-cert" => params.ignore_server_cert = v.parse().unwrap_or_default(),
-                        "tunnel-type" => params.tunnel_type = v.parse().unwrap_or_default(),
-                        "ca-cert" => params.ca_cert = Some(v.into()),
-                        "login-type" => params.login_type = v,
-                        "cert-type" => params.cert_type = v.parse().unwrap_or_default(),
-                        "cert-path" => params.cert_path = Some(v.into()),
-                        "cert-password" => params.cert_password = Some(v),
-                        "cert-id" => params.cert_id = Some(v),
-                        "if-name" => params.if_name = Some(v),
-                        "no-keychain" => params.no_keychain = v.parse().unwrap_or_default(),
-                        "server-prompt" => params.server_prompt = v.parse().unwrap_or_default(),
-                        "esp-lifetime" => {
-                            params.esp_lifetime = v
-                                .parse::<u64>()
-                                .ok()
-                                .map(Duration::from_secs)
-                                .unwrap_or(DEFAULT_ESP_LIFETIME)
-                        }
-                        "ike-lifetime" => {
-                            params.ike_lifetime = v
-                                .parse::<u64>()
-                                .ok()
-                                .map(Duration::from_secs)
-                                .unwrap_or(DEFAULT_IKE_LIFETIME)
-                        }
-                        "ike-port" => params.ike_port = v.parse().ok().unwrap_or(DEFAULT_IKE_PORT),
-                        other => {
-                            warn!("Ignoring unknown option: {}", other);
-                        }
-                    }
-                }
+
+        if is_toml(path.as_ref()) {
+            for (k, v) in toml_options(&data)? {
+                params.set_option(&k, v);
+            }
+        } else {
+            for (k, v) in data.lines().filter_map(parse_conf_line) {
+                params.set_option(k, v.to_owned());
             }
         }
+
         path.as_ref().clone_into(&mut params.config_file);
-        params.decode_password()?;
+
+        // the keychain or the password prompt take over unless the failure is requested to be fatal
+        if let Err(e) = params.decode_password() {
+            if params.strict_password_decode {
+                return Err(anyhow!(
+                    "Unable to decode the password from {}: {}",
+                    path.as_ref().display(),
+                    e
+                ));
+            }
+            warn!(
+                "The password in {} is corrupt or was encoded by an incompatible version, ignoring it: {}",
+                path.as_ref().display(),
+                e
+            );
+            params.password.clear();
+        }
+
+        // the secrets are stored as they are, without the base64 encoding of the password
+        if let Some(dir) = params.secrets_dir.clone() {
+            params
+                .load_secrets(&dir)
+                .map_err(|e| anyhow!("Unable to read the secrets from {}: {}", dir.display(), e))?;
+        }
 
         Ok(params)
     }
 
+    // one file per option named after it, such as server-name or password, as mounted by the container runtimes
+    pub fn load_secrets(&mut self, dir: &Path) -> anyhow::Result<()> {
+        for name in secret_names(dir)? {
+            let mut value = Zeroizing::new(std::fs::read_to_string(dir.join(&name))?);
+            let len = value.trim_end_matches(['\r', '\n']).len();
+            value.truncate(len);
+            if !value.is_empty() {
+                debug!("Reading {} from the secrets directory {}", name, dir.display());
+                // the buffer is moved into the option without a copy
+                self.set_option(&name.replace('_', "-"), std::mem::take(&mut *value));
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn set_option(&mut self, k: &str, v: String) {
+        match k {
+            "server-name" => self.server_name = v,
+            "user-name" => self.user_name = v,
+            "password" => self.password = v.into(),
+            "log-level" => self.log_level = v,
+            "search-domains" => self.search_domains = v.split(',').map(|s| s.trim().to_owned()).collect(),
+            "ignore-search-domains" => self.ignore_search_domains = v.split(',').map(|s| s.trim().to_owned()).collect(),
+            "default-route" => self.default_route = v.parse().unwrap_or_default(),
+            "no-routing" => self.no_routing = v.parse().unwrap_or_default(),
+            "add-routes" => self.add_routes = v.split(',').flat_map(|s| s.trim().parse().ok()).collect(),
+            "ignore-routes" => self.ignore_routes = v.split(',').flat_map(|s| s.trim().parse().ok()).collect(),
+            "no-dns" => self.no_dns = v.parse().unwrap_or_default(),
+            "no-cert-check" => self.no_cert_check = v.parse().unwrap_or_default(),
+            "ignore-server-cert" => self.ignore_server_cert = v.parse().unwrap_or_default(),
+            "tunnel-type" => self.tunnel_type = v.parse().unwrap_or_default(),
+            "ca-cert" => self.ca_cert = Some(v.into()),
+            "login-type" => self.login_type = v,
+            "cert-type" => self.cert_type = v.parse().unwrap_or_default(),
+            "cert-path" => self.cert_path = Some(v.into()),
+            "cert-password" => self.cert_password = Some(v.into()),
+            "cert-id" => self.cert_id = Some(v),
+            "cert-expiry-warning" => {
+                self.cert_expiry_warning = v
+                    .parse::<u64>()
+                    .ok()
+                    .map(|days| Duration::from_secs(days.saturating_mul(86400)))
+                    .unwrap_or(DEFAULT_CERT_EXPIRY_WARNING)
+            }
+            "if-name" => self.if_name = Some(v),
+            "tun-device" => self.tun_device = Some(v),
+            "no-keychain" => self.no_keychain = v.parse().unwrap_or_default(),
+            "strict-password-decode" => self.strict_password_decode = v.parse().unwrap_or_default(),
+            "keychain-timeout" => {
+                self.keychain_timeout = v
+                    .parse::<u64>()
+                    .ok()
+                    .map(Duration::from_secs)
+                    .unwrap_or(DEFAULT_KEYCHAIN_TIMEOUT)
+            }
+            "server-prompt" => self.server_prompt = v.parse().unwrap_or_default(),
+            "esp-lifetime" => {
+                self.esp_lifetime = v
+                    .parse::<u64>()
+                    .ok()
+                    .map(Duration::from_secs)
+                    .unwrap_or(DEFAULT_ESP_LIFETIME)
+            }
+            "esp-max-bytes" => self.esp_max_bytes = v.parse().unwrap_or_default(),
+            "socket-rcvbuf" => self.socket_rcvbuf = v.parse().ok(),
+            "socket-sndbuf" => self.socket_sndbuf = v.parse().ok(),
+            "ike-lifetime" => {
+                self.ike_lifetime = v
+                    .parse::<u64>()
+                    .ok()
+                    .map(Duration::from_secs)
+                    .unwrap_or(DEFAULT_IKE_LIFETIME)
+            }
+            "ike-port" => self.ike_port = v.parse().ok().unwrap_or(DEFAULT_IKE_PORT),
+            "anti-replay-window" => self.anti_replay_window = v.parse().ok(),
+            "tunnel-mtu" => self.tunnel_mtu = v.parse().ok(),
+            "rate-limit" => self.rate_limit = v.parse().ok().filter(|&rate| rate > 0),
+            "rate-limit-inbound" => self.rate_limit_inbound = v.parse().unwrap_or_default(),
+            "pmtud" => self.pmtud = parse_switch(&v).unwrap_or(true),
+            "keepalive" => self.keepalive = parse_switch(&v).unwrap_or(true),
+            "keepalive-target" => self.keepalive_target = v.parse().ok(),
+            "mfa-cache-ttl" => self.mfa_cache_ttl = v.parse::<u64>().ok().map(Duration::from_secs).unwrap_or_default(),
+            "mfa-attempts" => self.mfa_attempts = v.parse().unwrap_or(1),
+            "mfa-push-wait" => self.mfa_push_wait = v.parse::<u64>().ok().map(Duration::from_secs).unwrap_or_default(),
+            "mfa-preference" => {
+                self.mfa_preference = v
+                    .split(',')
+                    .map(|s| s.trim().to_lowercase())
+                    .filter(|s| !s.is_empty())
+                    .collect()
+            }
+            "otp-pipe" => self.otp_pipe = Some(v.into()),
+            "auth-failure-limit" => self.auth_failure_limit = v.parse().unwrap_or(DEFAULT_AUTH_FAILURE_LIMIT),
+            "retry-on-busy" => self.retry_on_busy = v.parse().unwrap_or_default(),
+            "busy-error-codes" => self.busy_error_codes = v.split(',').filter_map(|s| s.trim().parse().ok()).collect(),
+            "auth-cooldown" => {
+                self.auth_cooldown = v
+                    .parse::<u64>()
+                    .ok()
+                    .map(Duration::from_secs)
+                    .unwrap_or(DEFAULT_AUTH_COOLDOWN)
+            }
+            "outer-vrf" => self.outer_vrf = Some(v),
+            "disconnect-grace" => {
+                self.disconnect_grace = v.parse::<u64>().ok().map(Duration::from_secs).unwrap_or_default()
+            }
+            "max-session-duration" => {
+                self.max_session_duration = v.parse::<u64>().ok().map(Duration::from_secs).unwrap_or_default()
+            }
+            "realm" => self.realm = Some(v),
+            "idp" => self.idp = Some(v),
+            "saml-manual" => self.saml_manual = v.parse().unwrap_or_default(),
+            "saml-session-reuse" => self.saml_session_reuse = v.parse().unwrap_or(true),
+            "saml-session-ttl" => {
+                self.saml_session_ttl = v.parse::<u64>().ok().map(Duration::from_secs).unwrap_or_default()
+            }
+            "posture-script" => self.posture_script = Some(v.into()),
+            "embedded-dns" => self.embedded_dns = v.parse().unwrap_or_default(),
+            "dns-split-domains" => self.dns_split_domains = v.split(',').map(|s| s.trim().to_owned()).collect(),
+            "reconnect-on-resume" => self.reconnect_on_resume = v.parse().unwrap_or_default(),
+            "route-table" => self.route_table = v.parse().ok(),
+            "force-tunnel-dns" => self.force_tunnel_dns = v.parse().unwrap_or_default(),
+            "persist-stats" => self.persist_stats = v.parse().unwrap_or_default(),
+            "instance-name" => self.instance_name = Some(v),
+            "listen-port" => self.listen_port = v.parse().ok(),
+            "honor-encryption-domain" => self.honor_encryption_domain = v.parse().unwrap_or(true),
+            "strict-routes" => self.strict_routes = v.parse().unwrap_or_default(),
+            "isolate-overlaps" => self.isolate_overlaps = v.parse().unwrap_or_default(),
+            "ip-mode" => self.ip_mode = v.parse().unwrap_or_default(),
+            "dns-manager" => self.dns_manager = v.parse().unwrap_or_default(),
+            "auto-exclude-local" => self.auto_exclude_local = v.parse().unwrap_or(true),
+            "reconnect-on" => self.reconnect_on = v.split(',').flat_map(|s| s.trim().parse().ok()).collect(),
+            "quirks" => self.quirks = v.split(',').flat_map(|s| s.trim().parse().ok()).collect(),
+            "redirect-hosts" => {
+                self.redirect_hosts = v
+                    .split(',')
+                    .map(|s| s.trim().to_owned())
+                    .filter(|s| !s.is_empty())
+                    .collect()
+            }
+            "tunnel-ports" => self.tunnel_ports = v.split(',').flat_map(|s| s.trim().parse().ok()).collect(),
+            "error-messages" => self.error_messages = Self::parse_error_messages(&v),
+            "prewarm-refresh" => {
+                self.prewarm_refresh = v
+                    .parse::<u64>()
+                    .ok()
+                    .map(Duration::from_secs)
+                    .unwrap_or(DEFAULT_PREWARM_REFRESH)
+            }
+            "app-routing" => match v.parse() {
+                Ok(app_routing) => self.app_routing = Some(app_routing),
+                Err(e) => warn!("{}", e),
+            },
+            "detect-captive-portal" => self.detect_captive_portal = v.parse().unwrap_or_default(),
+            "verify-cleanup" => self.verify_cleanup = v.parse().unwrap_or(true),
+            "on-existing-interface" => self.on_existing_interface = v.parse().unwrap_or_default(),
+            "flush-dns-on-disconnect" => self.flush_dns_on_disconnect = v.parse().unwrap_or_default(),
+            "directory-url" => self.directory_url = Some(v),
+            "ddns-hostname" => self.ddns_hostname = Some(v),
+            "ddns-server" => self.ddns_server = Some(v),
+            "ddns-key" => self.ddns_key = Some(v.into()),
+            "log-redact" => {
+                self.log_redact = v
+                    .split(',')
+                    .map(|s| s.trim().to_owned())
+                    .filter(|s| !s.is_empty())
+                    .collect()
+            }
+            "auto-start-service" => self.auto_start_service = v.parse().unwrap_or_default(),
+            "http-api-bind" => self.http_api_bind = v.parse().ok(),
+            "http-api-token" => self.http_api_token = Some(v.into()),
+            "doh-resolver" => self.doh_resolver = Some(v),
+            "secrets-dir" => self.secrets_dir = Some(v.into()),
+            "directory-ttl" => {
+                self.directory_ttl = v
+                    .parse::<u64>()
+                    .ok()
+                    .map(Duration::from_secs)
+                    .unwrap_or(DEFAULT_DIRECTORY_TTL)
+            }
+            other => {
+                warn!("Ignoring unknown option: {}", other);
+            }
+        }
+    }
+
     pub fn save(&self) -> anyhow::Result<()> {
+        let mut conf = self.to_conf()?;
+
+        // the options from the secrets directory are not copied into the configuration file
+        if let Some(ref dir) = self.secrets_dir {
+            let names = secret_names(dir)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|name| name.replace('_', "-"))
+                .collect::<Vec<_>>();
+            conf = conf
+                .lines()
+                .filter(|line| !matches!(parse_conf_line(line), Some((k, _)) if names.iter().any(|name| name == k)))
+                .map(|line| format!("{}\n", line))
+                .collect();
+        }
+
+        let data = if is_toml(&self.config_file) {
+            conf_to_toml(&conf)?
+        } else {
+            conf
+        };
+
+        std::fs::write(&self.config_file, data)?;
+
+        Ok(())
+    }
+
+    fn to_conf(&self) -> anyhow::Result<String> {
         let mut buf = Cursor::new(Vec::new());
         writeln!(buf, "server-name={}", self.server_name)?;
         writeln!(buf, "user-name={}", self.user_name)?;
         writeln!(
             buf,
             "password={}",
-            base64::engine::general_purpose::STANDARD.encode(&self.password)
+            base64::engine::general_purpose::STANDARD.encode(self.password.as_bytes())
         )?;
         writeln!(buf, "search-domains={}", self.search_domains.join(","))?;
         writeln!(buf, "ignore-search-domains={}", self.ignore_search_domains.join(","))?;
@@ -313,35 +997,396 @@ impl TunnelParams {
             writeln!(buf, "cert-path={}", cert_path.display())?;
         }
         if let Some(ref cert_password) = self.cert_password {
-            writeln!(buf, "cert-password={}", cert_password)?;
+            writeln!(buf, "cert-password={}", cert_password.as_str())?;
         }
         if let Some(ref cert_id) = self.cert_id {
             writeln!(buf, "cert-id={}", cert_id)?;
         }
+        writeln!(
+            buf,
+            "cert-expiry-warning={}",
+            self.cert_expiry_warning.as_secs() / 86400
+        )?;
         if let Some(ref if_name) = self.if_name {
             writeln!(buf, "if-name={}", if_name)?;
         }
+        if let Some(ref tun_device) = self.tun_device {
+            writeln!(buf, "tun-device={}", tun_device)?;
+        }
         writeln!(buf, "no-keychain={}", self.no_keychain)?;
+        writeln!(buf, "strict-password-decode={}", self.strict_password_decode)?;
+        writeln!(buf, "keychain-timeout={}", self.keychain_timeout.as_secs())?;
         writeln!(buf, "server-prompt={}", self.server_prompt)?;
         writeln!(buf, "esp-lifetime={}", self.esp_lifetime.as_secs())?;
+        writeln!(buf, "esp-max-bytes={}", self.esp_max_bytes)?;
+        if let Some(socket_rcvbuf) = self.socket_rcvbuf {
+            writeln!(buf, "socket-rcvbuf={}", socket_rcvbuf)?;
+        }
+        if let Some(socket_sndbuf) = self.socket_sndbuf {
+            writeln!(buf, "socket-sndbuf={}", socket_sndbuf)?;
+        }
         writeln!(buf, "ike-lifetime={}", self.ike_lifetime.as_secs())?;
         writeln!(buf, "ike-port={}", self.ike_port)?;
+        if let Some(anti_replay_window) = self.anti_replay_window {
+            writeln!(buf, "anti-replay-window={}", anti_replay_window)?;
+        }
+        if let Some(tunnel_mtu) = self.tunnel_mtu {
+            writeln!(buf, "tunnel-mtu={}", tunnel_mtu)?;
+        }
+        writeln!(buf, "pmtud={}", if self.pmtud { "on" } else { "off" })?;
+        if let Some(rate_limit) = self.rate_limit {
+            writeln!(buf, "rate-limit={}", rate_limit)?;
+        }
+        writeln!(buf, "rate-limit-inbound={}", self.rate_limit_inbound)?;
+        writeln!(buf, "keepalive={}", if self.keepalive { "on" } else { "off" })?;
+        if let Some(keepalive_target) = self.keepalive_target {
+            writeln!(buf, "keepalive-target={}", keepalive_target)?;
+        }
+        writeln!(buf, "mfa-cache-ttl={}", self.mfa_cache_ttl.as_secs())?;
+        writeln!(buf, "mfa-attempts={}", self.mfa_attempts)?;
+        writeln!(buf, "mfa-push-wait={}", self.mfa_push_wait.as_secs())?;
+        writeln!(buf, "mfa-preference={}", self.mfa_preference.join(","))?;
+        if let Some(ref otp_pipe) = self.otp_pipe {
+            writeln!(buf, "otp-pipe={}", otp_pipe.display())?;
+        }
+        writeln!(buf, "auth-failure-limit={}", self.auth_failure_limit)?;
+        writeln!(buf, "auth-cooldown={}", self.auth_cooldown.as_secs())?;
+        writeln!(buf, "retry-on-busy={}", self.retry_on_busy)?;
+        writeln!(
+            buf,
+            "busy-error-codes={}",
+            self.busy_error_codes
+                .iter()
+                .map(|code| code.to_string())
+                .collect::<Vec<_>>()
+                .join(",")
+        )?;
+        if let Some(ref outer_vrf) = self.outer_vrf {
+            writeln!(buf, "outer-vrf={}", outer_vrf)?;
+        }
+        writeln!(buf, "disconnect-grace={}", self.disconnect_grace.as_secs())?;
+        writeln!(buf, "max-session-duration={}", self.max_session_duration.as_secs())?;
+        if let Some(ref realm) = self.realm {
+            writeln!(buf, "realm={}", realm)?;
+        }
+        if let Some(ref idp) = self.idp {
+            writeln!(buf, "idp={}", idp)?;
+        }
+        writeln!(buf, "saml-manual={}", self.saml_manual)?;
+        writeln!(buf, "saml-session-reuse={}", self.saml_session_reuse)?;
+        writeln!(buf, "saml-session-ttl={}", self.saml_session_ttl.as_secs())?;
+        writeln!(buf, "embedded-dns={}", self.embedded_dns)?;
+        writeln!(buf, "dns-split-domains={}", self.dns_split_domains.join(","))?;
+        if let Some(ref posture_script) = self.posture_script {
+            writeln!(buf, "posture-script={}", posture_script.display())?;
+        }
+        writeln!(buf, "reconnect-on-resume={}", self.reconnect_on_resume)?;
+        if let Some(route_table) = self.route_table {
+            writeln!(buf, "route-table={}", route_table)?;
+        }
+        writeln!(buf, "force-tunnel-dns={}", self.force_tunnel_dns)?;
+        writeln!(buf, "persist-stats={}", self.persist_stats)?;
+        if let Some(ref instance_name) = self.instance_name {
+            writeln!(buf, "instance-name={}", instance_name)?;
+        }
+        if let Some(listen_port) = self.listen_port {
+            writeln!(buf, "listen-port={}", listen_port)?;
+        }
+        writeln!(buf, "honor-encryption-domain={}", self.honor_encryption_domain)?;
+        writeln!(buf, "strict-routes={}", self.strict_routes)?;
+        writeln!(buf, "isolate-overlaps={}", self.isolate_overlaps)?;
+        writeln!(buf, "ip-mode={}", self.ip_mode.as_str())?;
+        writeln!(buf, "dns-manager={}", self.dns_manager.as_str())?;
+        writeln!(buf, "auto-exclude-local={}", self.auto_exclude_local)?;
+        writeln!(
+            buf,
+            "reconnect-on={}",
+            self.reconnect_on
+                .iter()
+                .map(|f| f.as_str())
+                .collect::<Vec<_>>()
+                .join(",")
+        )?;
+        writeln!(
+            buf,
+            "quirks={}",
+            self.quirks.iter().map(|q| q.as_str()).collect::<Vec<_>>().join(",")
+        )?;
+        writeln!(buf, "redirect-hosts={}", self.redirect_hosts.join(","))?;
+        writeln!(
+            buf,
+            "error-messages={}",
+            self.error_messages
+                .iter()
+                .map(|(code, message)| format!("{}:{}", code, message))
+                .collect::<Vec<_>>()
+                .join(";")
+        )?;
+        writeln!(buf, "prewarm-refresh={}", self.prewarm_refresh.as_secs())?;
+        if let Some(ref app_routing) = self.app_routing {
+            writeln!(buf, "app-routing={}", app_routing)?;
+        }
+        writeln!(
+            buf,
+            "tunnel-ports={}",
+            self.tunnel_ports
+                .iter()
+                .map(|p| p.to_string())
+                .collect::<Vec<_>>()
+                .join(",")
+        )?;
+        writeln!(buf, "detect-captive-portal={}", self.detect_captive_portal)?;
+        writeln!(buf, "verify-cleanup={}", self.verify_cleanup)?;
+        writeln!(buf, "on-existing-interface={}", self.on_existing_interface.as_str())?;
+        writeln!(buf, "flush-dns-on-disconnect={}", self.flush_dns_on_disconnect)?;
+        if let Some(ref directory_url) = self.directory_url {
+            writeln!(buf, "directory-url={}", directory_url)?;
+        }
+        writeln!(buf, "directory-ttl={}", self.directory_ttl.as_secs())?;
+        if let Some(ref ddns_hostname) = self.ddns_hostname {
+            writeln!(buf, "ddns-hostname={}", ddns_hostname)?;
+        }
+        if let Some(ref ddns_server) = self.ddns_server {
+            writeln!(buf, "ddns-server={}", ddns_server)?;
+        }
+        if let Some(ref ddns_key) = self.ddns_key {
+            writeln!(buf, "ddns-key={}", ddns_key.display())?;
+        }
+        writeln!(buf, "log-redact={}", self.log_redact.join(","))?;
+        writeln!(buf, "auto-start-service={}", self.auto_start_service)?;
+        if let Some(http_api_bind) = self.http_api_bind {
+            writeln!(buf, "http-api-bind={}", http_api_bind)?;
+        }
+        if let Some(ref http_api_token) = self.http_api_token {
+            writeln!(buf, "http-api-token={}", http_api_token.as_str())?;
+        }
+        if let Some(ref doh_resolver) = self.doh_resolver {
+            writeln!(buf, "doh-resolver={}", doh_resolver)?;
+        }
+        if let Some(ref secrets_dir) = self.secrets_dir {
+            writeln!(buf, "secrets-dir={}", secrets_dir.display())?;
+        }
 
-        std::fs::write(&self.config_file, buf.into_inner())?;
+        Ok(String::from_utf8_lossy(&buf.into_inner()).into_owned())
+    }
 
-        Ok(())
+    // realm id to authenticate against, the login type is used unless set explicitly
+    pub fn realm_id(&self) -> &str {
+        self.realm.as_deref().unwrap_or(&self.login_type)
     }
 
     pub fn decode_password(&mut self) -> anyhow::Result<()> {
         if !self.password.is_empty() {
-            self.password = String::from_utf8_lossy(&base64::engine::general_purpose::STANDARD.decode(&self.password)?)
-                .into_owned();
+            let decoded = Zeroizing::new(base64::engine::general_purpose::STANDARD.decode(self.password.as_bytes())?);
+            self.password = String::from_utf8_lossy(&decoded).into_owned().into();
         }
         Ok(())
     }
 
     pub fn default_config_path() -> PathBuf {
+        Self::instance_config_path(None)
+    }
+
+    pub fn device_name(&self) -> &str {
+        // the auto mode is resolved by the time the tunnel device exists
+        match self.tunnel_type {
+            TunnelType::Ipsec | TunnelType::Auto => self.if_name.as_deref().unwrap_or(Self::DEFAULT_IPSEC_IF_NAME),
+            TunnelType::Ssl => self
+                .tun_device
+                .as_deref()
+                .or(self.if_name.as_deref())
+                .unwrap_or(Self::DEFAULT_SSL_IF_NAME),
+        }
+    }
+
+    // copy with the tunnel type selected in the auto mode, an explicit tunnel type is kept
+    pub fn with_tunnel_type(&self, tunnel_type: TunnelType) -> Self {
+        Self {
+            tunnel_type: if self.tunnel_type == TunnelType::Auto {
+                tunnel_type
+            } else {
+                self.tunnel_type
+            },
+            ..self.clone()
+        }
+    }
+
+    // a TUN device given for the SSL data path belongs to whoever created it and outlives the tunnel
+    pub fn external_device(&self) -> bool {
+        self.tunnel_type == TunnelType::Ssl && self.tun_device.is_some()
+    }
+
+    // application routing needs a separate table for the tunnel routes
+    pub fn routing_table(&self) -> Option<u32> {
+        let policy_routing = self.app_routing.is_some() || !self.tunnel_ports.is_empty();
+        self.route_table
+            .or_else(|| policy_routing.then_some(Self::APP_ROUTING_TABLE))
+    }
+
+    pub fn has_quirk(&self, quirk: Quirk) -> bool {
+        self.quirks.contains(&quirk)
+    }
+
+    // whether the tunnel terminated by the given keepalive failure should be reconnected
+    pub fn reconnects_on(&self, failure: KeepaliveFailure) -> bool {
+        self.reconnect_on.contains(&failure) || (failure == KeepaliveFailure::Resume && self.reconnect_on_resume)
+    }
+
+    // "code:message" pairs separated by semicolons, so that the messages may contain commas
+    pub fn parse_error_messages(value: &str) -> BTreeMap<u32, String> {
+        value
+            .split(';')
+            .filter_map(|s| s.split_once(':'))
+            .filter_map(|(code, message)| Some((code.trim().parse().ok()?, message.trim().to_owned())))
+            .collect()
+    }
+
+    // named instances keep their configuration in a subdirectory
+    pub fn instance_config_path(instance_name: Option<&str>) -> PathBuf {
         let dir = ProjectDirs::from("", "", "snx-rs").expect("No home directory!");
-        dir.config_dir().join("snx-rs.conf")
+        match instance_name {
+            Some(instance_name) => dir.config_dir().join(instance_name).join("snx-rs.conf"),
+            None => dir.config_dir().join("snx-rs.conf"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reconnect_on() {
+        let mut params = TunnelParams::default();
+        params.set_option("reconnect-on", "timeout, Auth-Revoked,bogus".to_owned());
+        assert_eq!(
+            params.reconnect_on,
+            vec![KeepaliveFailure::Timeout, KeepaliveFailure::AuthRevoked]
+        );
+        assert!(params.reconnects_on(KeepaliveFailure::AuthRevoked));
+        assert!(!params.reconnects_on(KeepaliveFailure::Unreachable));
+    }
+
+    #[test]
+    fn test_toml_options() {
+        let data = r#"
+server-name = "remote.acme.com"
+search-domains = ["acme.com", "corp.acme.com"]
+add-routes = ["10.0.0.0/8", "192.168.1.0/24"]
+no-dns = true
+esp-lifetime = 7200
+"#;
+        let mut params = TunnelParams::default();
+        for (k, v) in toml_options(data).unwrap() {
+            params.set_option(&k, v);
+        }
+
+        assert_eq!(params.server_name, "remote.acme.com");
+        assert_eq!(params.search_domains, ["acme.com", "corp.acme.com"]);
+        assert_eq!(
+            params.add_routes,
+            ["10.0.0.0/8".parse().unwrap(), "192.168.1.0/24".parse().unwrap()]
+        );
+        assert!(params.no_dns);
+        assert_eq!(params.esp_lifetime, Duration::from_secs(7200));
+    }
+
+    #[test]
+    fn test_conf_to_toml() {
+        let params = TunnelParams {
+            server_name: "remote.acme.com".to_owned(),
+            add_routes: vec!["10.0.0.0/8".parse().unwrap()],
+            keepalive: false,
+            ..Default::default()
+        };
+
+        let data = conf_to_toml(&params.to_conf().unwrap()).unwrap();
+
+        let mut parsed = TunnelParams::default();
+        for (k, v) in toml_options(&data).unwrap() {
+            parsed.set_option(&k, v);
+        }
+
+        assert_eq!(parsed.server_name, params.server_name);
+        assert_eq!(parsed.add_routes, params.add_routes);
+        assert_eq!(parsed.ike_port, params.ike_port);
+        assert!(!parsed.keepalive);
+    }
+
+    #[test]
+    fn test_parse_app_routing() {
+        assert_eq!(
+            "cgroup:user.slice/browser.scope".parse::<AppRouting>().unwrap(),
+            AppRouting::Cgroup("user.slice/browser.scope".into())
+        );
+        assert_eq!("fwmark:0x10".parse::<AppRouting>().unwrap(), AppRouting::Fwmark(16));
+        assert_eq!("fwmark:100".parse::<AppRouting>().unwrap(), AppRouting::Fwmark(100));
+        assert!("fwmark:0".parse::<AppRouting>().is_err());
+        assert!("cgroup:".parse::<AppRouting>().is_err());
+        assert!("browser".parse::<AppRouting>().is_err());
+
+        let app_routing = AppRouting::Fwmark(0x20);
+        assert_eq!(app_routing.to_string().parse::<AppRouting>().unwrap(), app_routing);
+    }
+
+    #[test]
+    fn test_tunnel_ports() {
+        let mut params = TunnelParams::default();
+        assert_eq!(params.routing_table(), None);
+
+        params.set_option("tunnel-ports", "443, 3389,x".to_owned());
+        assert_eq!(params.tunnel_ports, vec![443, 3389]);
+        assert_eq!(params.routing_table(), Some(TunnelParams::APP_ROUTING_TABLE));
+    }
+
+    #[test]
+    fn test_mfa_preference() {
+        let mut params = TunnelParams::default();
+        params.set_option("mfa-preference", "Push, sms,,otp".to_owned());
+        assert_eq!(params.mfa_preference, vec!["push", "sms", "otp"]);
+    }
+
+    #[test]
+    fn test_quirks() {
+        let mut params = TunnelParams::default();
+        params.set_option("quirks", "no-compression, bogus,no-redirect-check".to_owned());
+        assert_eq!(params.quirks, vec![Quirk::NoCompression, Quirk::NoRedirectCheck]);
+        assert!(params.has_quirk(Quirk::NoRedirectCheck));
+        assert!(!params.has_quirk(Quirk::NoClientLoggingData));
+    }
+
+    #[test]
+    fn test_parse_error_messages() {
+        let messages =
+            TunnelParams::parse_error_messages("101: Account is locked, contact support; bad;205:Realm not permitted");
+
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[&101], "Account is locked, contact support");
+        assert_eq!(messages[&205], "Realm not permitted");
+    }
+
+    #[test]
+    fn test_load_secrets() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        std::fs::create_dir_all(dir.join("..data")).unwrap();
+        std::fs::write(dir.join("server_name"), "remote.acme.com\n").unwrap();
+        std::fs::write(dir.join("password"), "my secret\r\n").unwrap();
+        std::fs::write(dir.join("user-name"), "").unwrap();
+        std::fs::write(dir.join(".hidden"), "x").unwrap();
+
+        let conf = dir.join("snx-rs.conf");
+        std::fs::write(&conf, format!("user-name=user\nsecrets-dir={}\n", dir.display())).unwrap();
+
+        let params = TunnelParams::load(&conf).unwrap();
+        params.save().unwrap();
+        let saved = std::fs::read_to_string(&conf).unwrap();
+
+        assert_eq!(params.server_name, "remote.acme.com");
+        assert_eq!(params.password.as_str(), "my secret");
+        assert_eq!(params.user_name, "user");
+        assert!(!saved.contains("password="));
+        assert!(!saved.contains("server-name="));
+        assert!(saved.contains("user-name=user\n"));
     }
 }
@@ -209,6 +209,8 @@ pub struct AuthRequest {
     pub client_logging_data: Option<ClientLoggingData>,
     #[serde(rename = "selectedLoginOption")]
     pub selected_login_option: Option<String>,
+    #[serde(rename = "selectedRealmId")]
+    pub selected_realm_id: Option<String>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -218,6 +220,26 @@ pub struct MultiChallengeRequest {
     pub user_input: EncryptedString,
 }
 
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OtpDeliveryRequest {
+    pub client_type: String,
+    pub auth_session_id: String,
+    pub delivery_method: String,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PushStatusRequest {
+    pub client_type: String,
+    pub auth_session_id: String,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PasswordChangeRequest {
+    pub client_type: String,
+    pub auth_session_id: String,
+    pub new_password: EncryptedString,
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ClientLoggingData {
     pub client_name: Option<QuotedString>,
@@ -270,6 +292,9 @@ pub struct LocationAwarenessRequest {
 pub enum RequestData {
     Auth(AuthRequest),
     MultiChallenge(MultiChallengeRequest),
+    OtpDelivery(OtpDeliveryRequest),
+    PasswordChange(PasswordChangeRequest),
+    PushStatus(PushStatusRequest),
     KeyManagement(KeyManagementRequest),
     LocationAwareness(LocationAwarenessRequest),
     ClientHello { client_info: ClientInfo },
@@ -310,6 +335,9 @@ pub struct AuthResponse {
     pub error_id: Option<EncryptedString>,
     pub error_code: Option<u32>,
     pub prompt: Option<EncryptedString>,
+    pub otp_delivery: Option<String>,
+    // set on the first login when the account password has to be changed before connecting
+    pub must_change_password: Option<bool>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -343,6 +371,7 @@ pub struct IpsecSA {
 pub struct ClientSettingsResponse {
     pub gw_internal_ip: Ipv4Addr,
     pub updated_policies: UpdatedPolicies,
+    pub mtu: Option<u16>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -457,6 +486,13 @@ pub struct LoginOption {
     pub display_name: QuotedString,
     pub show_realm: u32,
     pub factors: BTreeMap<String, LoginFactor>,
+    pub identity_providers: Option<BTreeMap<String, IdentityProvider>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IdentityProvider {
+    pub id: String,
+    pub display_name: QuotedString,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -1,4 +1,8 @@
-use std::{net::Ipv4Addr, sync::Arc, time::Duration};
+use std::{
+    net::Ipv4Addr,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
 
 use anyhow::anyhow;
 use ipnet::Ipv4Net;
@@ -9,10 +13,16 @@ use linux as platform_impl;
 pub use platform_impl::{
     acquire_password, get_machine_uuid,
     net::{
-        add_default_route, add_dns_servers, add_dns_suffixes, add_route, add_routes, get_default_ip, is_online,
-        poll_online, start_network_state_monitoring,
+        add_app_routing, add_default_route, add_dns_servers, add_dns_suffixes, add_exclude_routes, add_lookup_rule,
+        add_port_routing, add_route, add_routes, add_table_rule, delete_app_routing, delete_device,
+        delete_exclude_routes, delete_lookup_rule, delete_port_routing, delete_route, delete_table_rule, device_exists,
+        find_free_table, find_route_conflicts, find_tunnel_overlaps, flush_device_routes, flush_dns_cache,
+        get_default_ip, get_interface_counters, get_interface_stats, get_local_subnets, get_udp_drops,
+        get_vrf_default_ip, get_vrf_table, is_online, poll_online, revert_dns, set_default_dns_route,
+        set_dns_forwarder, set_rate_limit, start_network_state_monitoring, tun_device_exists, verify_cleanup,
+        LocalSubnet,
     },
-    new_tun_config, store_password, unmanage_device, IpsecImpl, SingleInstance,
+    new_tun_config, notify_ready, store_password, unmanage_device, IpsecImpl, SingleInstance,
 };
 
 use crate::model::{params::TunnelParams, IpsecSession};
@@ -20,11 +30,27 @@ use crate::model::{params::TunnelParams, IpsecSession};
 #[cfg(target_os = "linux")]
 mod linux;
 
+/// Usage of the outbound ESP security association
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct EspUsage {
+    pub sequence: u64,
+    pub bytes: u64,
+}
+
 #[async_trait::async_trait]
 pub trait IpsecConfigurator {
     async fn configure(&mut self) -> anyhow::Result<()>;
     async fn rekey(&mut self, session: &IpsecSession) -> anyhow::Result<()>;
     async fn cleanup(&mut self);
+    // withdraw the routes and DNS settings while keeping the SA and the interface alive
+    async fn suspend(&mut self) -> anyhow::Result<()>;
+    async fn resume(&mut self) -> anyhow::Result<()>;
+    async fn esp_usage(&self) -> anyhow::Result<EspUsage>;
+    // routes added while connected, kept across suspend and removed on cleanup
+    async fn add_route(&mut self, route: Ipv4Net) -> anyhow::Result<()>;
+    async fn remove_route(&mut self, route: Ipv4Net) -> anyhow::Result<()>;
+    // existing routes which take precedence over the tunnel routes, found while configuring
+    fn route_conflicts(&self) -> Vec<String>;
 }
 
 pub async fn new_ipsec_configurator(
@@ -33,8 +59,42 @@ pub async fn new_ipsec_configurator(
     src_port: u16,
     dest_ip: Ipv4Addr,
     subnets: Vec<Ipv4Net>,
+    mtu: Option<u16>,
 ) -> anyhow::Result<impl IpsecConfigurator> {
-    IpsecImpl::new(tunnel_params, ipsec_session, src_port, dest_ip, subnets).await
+    IpsecImpl::new(tunnel_params, ipsec_session, src_port, dest_ip, subnets, mtu).await
+}
+
+// source address for the outer tunnel traffic, taken from the VRF routing table if configured
+pub async fn get_source_ip(params: &TunnelParams) -> anyhow::Result<Ipv4Addr> {
+    let ip = match params.outer_vrf {
+        Some(ref vrf) => get_vrf_default_ip(vrf).await?,
+        None => get_default_ip().await?,
+    };
+    Ok(ip.parse()?)
+}
+
+const RESUME_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+const RESUME_CLOCK_JUMP: Duration = Duration::from_secs(15);
+
+// Resolves when the system resumes from suspend. The monotonic clock is stopped during suspend
+// while the wall clock is not, so a large difference between them indicates a resume.
+pub async fn wait_for_resume() {
+    let mut last_mono = Instant::now();
+    let mut last_wall = SystemTime::now();
+
+    loop {
+        tokio::time::sleep(RESUME_CHECK_INTERVAL).await;
+
+        let mono = last_mono.elapsed();
+        let wall = last_wall.elapsed().unwrap_or_default();
+
+        if wall > mono + RESUME_CLOCK_JUMP {
+            break;
+        }
+
+        last_mono = Instant::now();
+        last_wall = SystemTime::now();
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]
@@ -46,6 +106,10 @@ pub enum UdpEncap {
 pub trait UdpSocketExt {
     fn set_encap(&self, encap: UdpEncap) -> anyhow::Result<()>;
     fn set_no_check(&self, flag: bool) -> anyhow::Result<()>;
+    fn bind_to_device(&self, device: &str) -> anyhow::Result<()>;
+    // the returned size is the one granted by the kernel, which doubles and clamps the requested one
+    fn set_recv_buffer_size(&self, size: usize) -> anyhow::Result<usize>;
+    fn set_send_buffer_size(&self, size: usize) -> anyhow::Result<usize>;
     async fn send_receive(&self, data: &[u8], timeout: Duration) -> anyhow::Result<Vec<u8>>;
 }
 
@@ -57,9 +121,10 @@ async fn udp_send_receive(socket: &UdpSocket, data: &[u8], timeout: Duration) ->
 
     let result = futures::future::join(send_fut, recv_fut).await;
 
-    if let (Ok(_), Ok(Ok((size, _)))) = result {
-        Ok(buf[0..size].to_vec())
-    } else {
-        Err(anyhow!("Error sending UDP request!"))
+    // the I/O errors are kept so that the callers can tell an unreachable peer from a timeout
+    match result {
+        (Ok(_), Ok(Ok((size, _)))) => Ok(buf[0..size].to_vec()),
+        (Err(e), _) | (_, Ok(Err(e))) => Err(e.into()),
+        (Ok(_), Err(_)) => Err(anyhow!("Timeout while waiting for UDP response!")),
     }
 }
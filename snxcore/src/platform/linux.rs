@@ -1,6 +1,18 @@
 #![allow(clippy::too_many_arguments)]
 
-use std::{collections::HashMap, fs, os::fd::AsRawFd, time::Duration};
+use std::{
+    collections::HashMap,
+    fs,
+    os::{
+        fd::AsRawFd,
+        linux::net::SocketAddrExt,
+        unix::{
+            ffi::OsStrExt,
+            net::{SocketAddr, UnixDatagram},
+        },
+    },
+    time::Duration,
+};
 
 use anyhow::anyhow;
 use nix::{
@@ -8,7 +20,7 @@ use nix::{
     sys::stat::Mode,
     unistd,
 };
-use secret_service::{EncryptionType, SecretService};
+use secret_service::{Collection, EncryptionType, Item, SecretService};
 use tokio::net::UdpSocket;
 use tracing::debug;
 use uuid::Uuid;
@@ -18,10 +30,49 @@ pub use xfrm::XfrmConfigurator as IpsecImpl;
 use crate::platform::{UdpEncap, UdpSocketExt};
 
 pub mod net;
+mod resolver;
 pub mod xfrm;
 
 const UDP_ENCAP_ESPINUDP: libc::c_int = 2; // from /usr/include/linux/udp.h
 
+fn set_buffer_size(socket: &UdpSocket, option: libc::c_int, size: usize) -> anyhow::Result<usize> {
+    let value = size.min(libc::c_int::MAX as usize) as libc::c_int;
+    let mut granted: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+
+    unsafe {
+        let rc = libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            option,
+            &value as *const libc::c_int as _,
+            std::mem::size_of::<libc::c_int>() as _,
+        );
+        if rc != 0 {
+            return Err(anyhow!(
+                "Cannot set socket buffer size: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+
+        let rc = libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            option,
+            &mut granted as *mut libc::c_int as _,
+            &mut len,
+        );
+        if rc != 0 {
+            return Err(anyhow!(
+                "Cannot get socket buffer size: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+    }
+
+    Ok(granted as usize)
+}
+
 #[async_trait::async_trait]
 impl UdpSocketExt for UdpSocket {
     fn set_encap(&self, encap: UdpEncap) -> anyhow::Result<()> {
@@ -63,6 +114,35 @@ impl UdpSocketExt for UdpSocket {
         }
     }
 
+    fn bind_to_device(&self, device: &str) -> anyhow::Result<()> {
+        unsafe {
+            let rc = libc::setsockopt(
+                self.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_BINDTODEVICE,
+                device.as_ptr() as _,
+                device.len() as _,
+            );
+            if rc != 0 {
+                Err(anyhow!(
+                    "Cannot bind socket to device {}: {}",
+                    device,
+                    std::io::Error::last_os_error()
+                ))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    fn set_recv_buffer_size(&self, size: usize) -> anyhow::Result<usize> {
+        set_buffer_size(self, libc::SO_RCVBUF, size)
+    }
+
+    fn set_send_buffer_size(&self, size: usize) -> anyhow::Result<usize> {
+        set_buffer_size(self, libc::SO_SNDBUF, size)
+    }
+
     async fn send_receive(&self, data: &[u8], timeout: Duration) -> anyhow::Result<Vec<u8>> {
         super::udp_send_receive(self, data, timeout).await
     }
@@ -78,49 +158,176 @@ pub fn new_tun_config() -> tun::Configuration {
     config
 }
 
-pub async fn acquire_password(user_name: &str) -> anyhow::Result<String> {
-    let props = HashMap::from([("snx-rs.username", user_name)]);
+const USER_NAME_ATTR: &str = "snx-rs.username";
+const SERVER_NAME_ATTR: &str = "snx-rs.server";
+const INSTANCE_ATTR: &str = "snx-rs.instance";
+const PROFILE_ATTR: &str = "snx-rs.profile";
+
+// keychain entries are kept per gateway, the entries of the named instances and the directory profiles
+// are kept apart by extra attributes
+fn keychain_props<'a>(
+    server_name: Option<&'a str>,
+    user_name: &'a str,
+    instance_name: Option<&'a str>,
+    profile_name: Option<&'a str>,
+) -> HashMap<&'static str, &'a str> {
+    let mut props = HashMap::from([(USER_NAME_ATTR, user_name)]);
+    if let Some(server_name) = server_name {
+        props.insert(SERVER_NAME_ATTR, server_name);
+    }
+    if let Some(instance_name) = instance_name {
+        props.insert(INSTANCE_ATTR, instance_name);
+    }
+    if let Some(profile_name) = profile_name {
+        props.insert(PROFILE_ATTR, profile_name);
+    }
+    props
+}
 
-    debug!("Attempting to acquire password from the keychain");
+fn keychain_label(
+    server_name: &str,
+    user_name: &str,
+    instance_name: Option<&str>,
+    profile_name: Option<&str>,
+) -> String {
+    let namespace = instance_name.into_iter().chain(profile_name).collect::<Vec<_>>();
+    if namespace.is_empty() {
+        format!("snx-rs - {}@{}", user_name, server_name)
+    } else {
+        format!("snx-rs ({}) - {}@{}", namespace.join("/"), user_name, server_name)
+    }
+}
 
-    let ss = SecretService::connect(EncryptionType::Dh).await?;
+// the search matches a subset of the attributes, the entries of other instances and profiles are skipped
+async fn in_namespace(item: &Item<'_>, instance_name: Option<&str>, profile_name: Option<&str>) -> bool {
+    item.get_attributes().await.is_ok_and(|attributes| {
+        attributes.get(INSTANCE_ATTR).map(String::as_str) == instance_name
+            && attributes.get(PROFILE_ATTR).map(String::as_str) == profile_name
+    })
+}
+
+async fn unlocked_collection<'a>(ss: &'a SecretService<'_>) -> anyhow::Result<Collection<'a>> {
     let collection = ss.get_default_collection().await?;
     if let Ok(true) = collection.is_locked().await {
         debug!("Unlocking secret collection");
         let _ = collection.unlock().await;
     }
+    Ok(collection)
+}
 
-    let search_items = ss.search_items(props.clone()).await?;
+pub async fn acquire_password(
+    server_name: &str,
+    user_name: &str,
+    instance_name: Option<&str>,
+    profile_name: Option<&str>,
+) -> anyhow::Result<String> {
+    debug!("Attempting to acquire password from the keychain");
 
-    let item = search_items
-        .unlocked
-        .first()
-        .ok_or_else(|| anyhow!("No item in collection"))?;
+    let ss = SecretService::connect(EncryptionType::Dh).await?;
+    let collection = unlocked_collection(&ss).await?;
+
+    let search_items = ss
+        .search_items(keychain_props(
+            Some(server_name),
+            user_name,
+            instance_name,
+            profile_name,
+        ))
+        .await?;
 
-    let secret = item.get_secret().await?;
+    for item in &search_items.unlocked {
+        if in_namespace(item, instance_name, profile_name).await {
+            let secret = item.get_secret().await?;
+            debug!("Password acquired successfully");
+            return Ok(String::from_utf8_lossy(&secret).into_owned());
+        }
+    }
 
-    debug!("Password acquired successfully");
+    // entries stored before the profiles were kept apart are copied into the namespace of the profile,
+    // the original entry stays in place for the connections without a profile
+    if let Some(profile_name) = profile_name {
+        let shared_items = ss
+            .search_items(keychain_props(Some(server_name), user_name, instance_name, None))
+            .await?;
 
-    Ok(String::from_utf8_lossy(&secret).into_owned())
-}
+        for item in &shared_items.unlocked {
+            if !in_namespace(item, instance_name, None).await {
+                continue;
+            }
 
-pub async fn store_password(user_name: &str, password: &str) -> anyhow::Result<()> {
-    let props = HashMap::from([("snx-rs.username", user_name)]);
+            debug!(
+                "Copying the keychain entry of {} into the profile {}",
+                user_name, profile_name
+            );
 
-    let ss = SecretService::connect(EncryptionType::Dh).await?;
-    let collection = ss.get_default_collection().await?;
+            let secret = item.get_secret().await?;
+            collection
+                .create_item(
+                    &keychain_label(server_name, user_name, instance_name, Some(profile_name)),
+                    keychain_props(Some(server_name), user_name, instance_name, Some(profile_name)),
+                    &secret,
+                    true,
+                    "text/plain",
+                )
+                .await?;
+
+            debug!("Password acquired successfully");
+            return Ok(String::from_utf8_lossy(&secret).into_owned());
+        }
+    }
 
-    if let Ok(true) = collection.is_locked().await {
-        debug!("Unlocking secret collection");
-        let _ = collection.unlock().await;
+    // entries stored by the older versions are keyed by the user name only, the first one found
+    // is moved to the current server
+    let legacy_items = ss
+        .search_items(keychain_props(None, user_name, instance_name, None))
+        .await?;
+
+    for item in legacy_items.unlocked {
+        let attributes = item.get_attributes().await?;
+        if attributes.contains_key(SERVER_NAME_ATTR)
+            || attributes.contains_key(PROFILE_ATTR)
+            || (instance_name.is_none() && attributes.contains_key(INSTANCE_ATTR))
+        {
+            continue;
+        }
+
+        debug!("Migrating the keychain entry of {} to {}", user_name, server_name);
+
+        let secret = item.get_secret().await?;
+        collection
+            .create_item(
+                &keychain_label(server_name, user_name, instance_name, profile_name),
+                keychain_props(Some(server_name), user_name, instance_name, profile_name),
+                &secret,
+                true,
+                "text/plain",
+            )
+            .await?;
+        item.delete().await?;
+
+        debug!("Password acquired successfully");
+        return Ok(String::from_utf8_lossy(&secret).into_owned());
     }
 
+    Err(anyhow!("No item in collection"))
+}
+
+pub async fn store_password(
+    server_name: &str,
+    user_name: &str,
+    instance_name: Option<&str>,
+    profile_name: Option<&str>,
+    password: &str,
+) -> anyhow::Result<()> {
+    let ss = SecretService::connect(EncryptionType::Dh).await?;
+    let collection = unlocked_collection(&ss).await?;
+
     debug!("Attempting to store user password in the keychain");
 
     collection
         .create_item(
-            &format!("snx-rs - {}", user_name),
-            props,
+            &keychain_label(server_name, user_name, instance_name, profile_name),
+            keychain_props(Some(server_name), user_name, instance_name, profile_name),
             password.as_bytes(),
             true,
             "text/plain",
@@ -130,6 +337,24 @@ pub async fn store_password(user_name: &str, password: &str) -> anyhow::Result<(
     Ok(())
 }
 
+// readiness notification for the systemd services of Type=notify, nothing is sent when not started by systemd
+pub fn notify_ready() -> anyhow::Result<()> {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+
+    let socket = UnixDatagram::unbound()?;
+
+    match path.as_bytes().strip_prefix(b"@") {
+        Some(name) => socket.send_to_addr(b"READY=1", &SocketAddr::from_abstract_name(name)?)?,
+        None => socket.send_to(b"READY=1", &path)?,
+    };
+
+    debug!("Sent readiness notification to {}", path.to_string_lossy());
+
+    Ok(())
+}
+
 pub struct SingleInstance {
     name: String,
     handle: Option<nix::libc::c_int>,
@@ -1,14 +1,28 @@
 use std::{
     net::Ipv4Addr,
+    path::{Path, PathBuf},
     sync::{atomic::AtomicBool, atomic::Ordering},
+    time::Duration,
 };
 
 use anyhow::anyhow;
 use futures::StreamExt;
 use ipnet::Ipv4Net;
-use tracing::debug;
+use tracing::{debug, warn};
 use zbus::Connection;
 
+use super::resolver;
+use crate::model::{
+    params::{AppRouting, DnsManager},
+    InterfaceStats,
+};
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+// differs from the application routing mark, both may be used together
+const PORT_ROUTING_MARK: u32 = 0x736e79;
+const CLEANUP_VERIFY_ATTEMPTS: usize = 3;
+const CLEANUP_VERIFY_INTERVAL: Duration = Duration::from_millis(500);
+
 static ONLINE_STATE: AtomicBool = AtomicBool::new(true);
 
 #[derive(Debug, Copy, Clone, PartialEq)]
@@ -88,8 +102,111 @@ pub fn poll_online() {
     });
 }
 
+// received and sent byte counters of the network interface
+pub fn get_interface_counters(device: &str) -> Option<(u64, u64)> {
+    let read = |name: &str| {
+        std::fs::read_to_string(format!("/sys/class/net/{}/statistics/{}", device, name))
+            .ok()
+            .and_then(|s| s.trim().parse::<u64>().ok())
+    };
+    Some((read("rx_bytes")?, read("tx_bytes")?))
+}
+
+fn read_interface_stats(dir: &Path) -> Option<InterfaceStats> {
+    let read = |name: &str| {
+        std::fs::read_to_string(dir.join(name))
+            .ok()
+            .and_then(|s| s.trim().parse::<u64>().ok())
+    };
+    Some(InterfaceStats {
+        rx_bytes: read("rx_bytes")?,
+        tx_bytes: read("tx_bytes")?,
+        rx_packets: read("rx_packets")?,
+        tx_packets: read("tx_packets")?,
+        rx_errors: read("rx_errors")?,
+        tx_errors: read("tx_errors")?,
+        rx_dropped: read("rx_dropped")?,
+        tx_dropped: read("tx_dropped")?,
+    })
+}
+
+pub fn get_interface_stats(device: &str) -> Option<InterfaceStats> {
+    read_interface_stats(&Path::new("/sys/class/net").join(device).join("statistics"))
+}
+
+// the drops column of /proc/net/udp counts the packets discarded due to a full receive buffer
+fn parse_udp_drops(content: &str, port: u16) -> Option<u64> {
+    let port = format!(":{:04X}", port);
+    content.lines().skip(1).find_map(|line| {
+        let parts = line.split_whitespace().collect::<Vec<_>>();
+        if parts.get(1)?.ends_with(&port) {
+            parts.get(12)?.parse().ok()
+        } else {
+            None
+        }
+    })
+}
+
+pub fn get_udp_drops(port: u16) -> Option<u64> {
+    parse_udp_drops(&std::fs::read_to_string("/proc/net/udp").ok()?, port)
+}
+
+// the tun_flags attribute exists for TUN and TAP devices only, IFF_TUN is 0x0001 and IFF_TAP is 0x0002
+fn is_tun_flags(flags: &str) -> bool {
+    u32::from_str_radix(flags.trim().trim_start_matches("0x"), 16).is_ok_and(|flags| flags & 0x000f == 0x0001)
+}
+
+pub fn device_exists(device: &str) -> bool {
+    Path::new("/sys/class/net").join(device).exists()
+}
+
+pub async fn delete_device(device: &str) -> anyhow::Result<()> {
+    crate::util::run_command("ip", ["link", "del", "dev", device]).await?;
+    Ok(())
+}
+
+// whether the device was created beforehand, an existing device which is not a TUN can't be attached to
+pub fn tun_device_exists(device: &str) -> anyhow::Result<bool> {
+    let path = Path::new("/sys/class/net").join(device);
+    if !path.exists() {
+        return Ok(false);
+    }
+    match std::fs::read_to_string(path.join("tun_flags")) {
+        Ok(flags) if is_tun_flags(&flags) => Ok(true),
+        _ => Err(anyhow!("Device {} exists but is not a TUN device!", device)),
+    }
+}
+
 pub async fn get_default_ip() -> anyhow::Result<String> {
     let default_route = crate::util::run_command("ip", ["-4", "route", "show", "default"]).await?;
+    default_route_ip(&default_route).await
+}
+
+pub async fn get_vrf_default_ip(vrf: &str) -> anyhow::Result<String> {
+    get_vrf_table(vrf).await?;
+    let default_route = crate::util::run_command("ip", ["-4", "route", "show", "vrf", vrf, "default"]).await?;
+    default_route_ip(&default_route)
+        .await
+        .map_err(|_| anyhow!("Cannot determine default IP in VRF {}!", vrf))
+}
+
+pub async fn get_vrf_table(vrf: &str) -> anyhow::Result<u32> {
+    let link = crate::util::run_command("ip", ["-d", "link", "show", "dev", vrf, "type", "vrf"])
+        .await
+        .map_err(|e| anyhow!("Cannot query VRF {}: {}", vrf, e))?;
+
+    let mut parts = link.split_whitespace();
+    while let Some(part) = parts.next() {
+        if part == "table" {
+            if let Some(table) = parts.next().and_then(|t| t.parse().ok()) {
+                return Ok(table);
+            }
+        }
+    }
+    Err(anyhow!("VRF {} does not exist!", vrf))
+}
+
+async fn default_route_ip(default_route: &str) -> anyhow::Result<String> {
     let mut parts = default_route.split_whitespace();
     while let Some(part) = parts.next() {
         if part == "dev" {
@@ -111,9 +228,28 @@ pub async fn get_default_ip() -> anyhow::Result<String> {
     Err(anyhow!("Cannot determine default IP!"))
 }
 
-pub async fn add_route(route: Ipv4Net, device: &str, _ipaddr: Ipv4Addr) -> anyhow::Result<()> {
+// routes go to the main table unless a custom table id is given
+fn route_args<'a>(action: &'a str, dest: &'a str, device: &'a str, table: &'a Option<String>) -> Vec<&'a str> {
+    let mut args = vec!["route", action, dest, "dev", device];
+    if let Some(table) = table {
+        args.extend(["table", table.as_str()]);
+    }
+    args
+}
+
+pub async fn add_route(route: Ipv4Net, device: &str, _ipaddr: Ipv4Addr, table: Option<u32>) -> anyhow::Result<()> {
     debug!("Adding route: {} via {}", route, device);
-    crate::util::run_command("ip", ["route", "add", &route.to_string(), "dev", device]).await?;
+    let dest = route.to_string();
+    let table = table.map(|t| t.to_string());
+    crate::util::run_command("ip", route_args("add", &dest, device, &table)).await?;
+    Ok(())
+}
+
+pub async fn delete_route(route: Ipv4Net, device: &str, table: Option<u32>) -> anyhow::Result<()> {
+    debug!("Deleting route: {} via {}", route, device);
+    let dest = route.to_string();
+    let table = table.map(|t| t.to_string());
+    crate::util::run_command("ip", route_args("del", &dest, device, &table)).await?;
     Ok(())
 }
 
@@ -124,38 +260,554 @@ fn subnet_overlaps(index: usize, subnet: Ipv4Net, other: &[Ipv4Net]) -> bool {
         .any(|(i, s)| i != index && (*s == subnet || s.contains(&subnet) || subnet.contains(s)))
 }
 
-pub async fn add_routes(routes: &[Ipv4Net], device: &str, ipaddr: Ipv4Addr) -> anyhow::Result<()> {
+pub async fn add_routes(routes: &[Ipv4Net], device: &str, ipaddr: Ipv4Addr, table: Option<u32>) -> anyhow::Result<()> {
     debug!("Routes to add: {:?}", routes);
     for (_, subnet) in routes
         .iter()
         .enumerate()
         .filter(|(i, s)| !subnet_overlaps(*i, **s, routes))
     {
-        let _ = add_route(*subnet, device, ipaddr).await;
+        let _ = add_route(*subnet, device, ipaddr, table).await;
     }
 
     Ok(())
 }
 
-pub async fn add_default_route(device: &str, _ipaddr: Ipv4Addr) -> anyhow::Result<()> {
+pub async fn add_default_route(device: &str, _ipaddr: Ipv4Addr, table: Option<u32>) -> anyhow::Result<()> {
     debug!("Adding default route for {}", device);
-    let _ = crate::util::run_command("ip", ["route", "add", "default", "dev", device]).await?;
+    let table = table.map(|t| t.to_string());
+    let _ = crate::util::run_command("ip", route_args("add", "default", device, &table)).await?;
+
+    Ok(())
+}
+
+// direct the traffic sourced from the tunnel address to the custom routing table
+pub async fn add_table_rule(source: Ipv4Addr, table: u32) -> anyhow::Result<()> {
+    debug!("Adding routing rule from {} to table {}", source, table);
+    let source = source.to_string();
+    let table = table.to_string();
+    crate::util::run_command("ip", ["rule", "add", "from", &source, "table", &table]).await?;
+    Ok(())
+}
+
+pub async fn delete_table_rule(source: Ipv4Addr, table: u32) -> anyhow::Result<()> {
+    let source = source.to_string();
+    let table = table.to_string();
+    crate::util::run_command("ip", ["rule", "del", "from", &source, "table", &table]).await?;
+    crate::util::run_command("ip", ["route", "flush", "table", &table]).await?;
+    Ok(())
+}
+
+// looked up before the main table for all traffic, a table without a matching route falls through to the next rule
+pub async fn add_lookup_rule(table: u32) -> anyhow::Result<()> {
+    debug!("Adding routing rule for table {}", table);
+    let table = table.to_string();
+    crate::util::run_command("ip", ["rule", "add", "table", &table]).await?;
+    Ok(())
+}
+
+pub async fn delete_lookup_rule(table: u32) -> anyhow::Result<()> {
+    let table = table.to_string();
+    crate::util::run_command("ip", ["rule", "del", "table", &table]).await?;
+    crate::util::run_command("ip", ["route", "flush", "table", &table]).await?;
+    Ok(())
+}
+
+// numeric tables referenced by "ip rule show" or "ip route show table all"
+fn parse_used_tables(output: &str) -> Vec<u32> {
+    let mut tables = Vec::new();
+    for line in output.lines() {
+        let mut parts = line.split_whitespace();
+        while let Some(part) = parts.next() {
+            if matches!(part, "table" | "lookup") {
+                if let Some(table) = parts.next().and_then(|t| t.parse().ok()) {
+                    tables.push(table);
+                }
+            }
+        }
+    }
+    tables
+}
+
+// starting at the given offset the range is searched for the first table which is not in use
+fn select_free_table(used: &[u32], first: u32, count: u32, offset: u32) -> Option<u32> {
+    (0..count)
+        .map(|i| first + (offset + i) % count)
+        .find(|table| !used.contains(table))
+}
+
+// the tables of other service instances have their rules or routes installed already
+pub async fn find_free_table(first: u32, count: u32, offset: u32) -> Option<u32> {
+    let mut used = Vec::new();
+    for args in [&["rule", "show"][..], &["-4", "route", "show", "table", "all"][..]] {
+        if let Ok(output) = crate::util::run_command("ip", args).await {
+            used.extend(parse_used_tables(&output));
+        }
+    }
+    select_free_table(&used, first, count, offset)
+}
+
+// removes the routes added for the device, the kernel ones for the device address stay
+pub async fn flush_device_routes(device: &str) -> anyhow::Result<()> {
+    debug!("Removing routes for {}", device);
+    crate::util::run_command("ip", ["route", "flush", "dev", device, "proto", "boot"]).await?;
+    Ok(())
+}
+
+// the burst allows for a tenth of a second at the full rate, but at least a few full-size packets
+fn rate_limit_burst(rate: u64) -> u64 {
+    (rate / 10).max(16 * 1024)
+}
+
+// token bucket shaping of the outbound traffic and policing of the inbound one, None removes the limits
+pub async fn set_rate_limit(device: &str, rate: Option<u64>, inbound: bool) -> anyhow::Result<()> {
+    let _ = crate::util::run_command("tc", ["qdisc", "del", "dev", device, "root"]).await;
+    let _ = crate::util::run_command("tc", ["qdisc", "del", "dev", device, "ingress"]).await;
+
+    let Some(rate) = rate else {
+        debug!("Removed the rate limit from {}", device);
+        return Ok(());
+    };
+
+    debug!("Limiting the traffic through {} to {} bytes/s", device, rate);
+
+    // tc takes bps as bytes per second
+    let rate_str = format!("{}bps", rate);
+    let burst_str = format!("{}b", rate_limit_burst(rate));
+
+    crate::util::run_command(
+        "tc",
+        [
+            "qdisc", "replace", "dev", device, "root", "tbf", "rate", &rate_str, "burst", &burst_str, "latency", "50ms",
+        ],
+    )
+    .await?;
+
+    if inbound {
+        crate::util::run_command("tc", ["qdisc", "add", "dev", device, "ingress"]).await?;
+        crate::util::run_command(
+            "tc",
+            [
+                "filter", "add", "dev", device, "parent", "ffff:", "matchall", "action", "police", "rate", &rate_str,
+                "burst", &burst_str, "drop",
+            ],
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+fn nft_table_name(device: &str) -> String {
+    format!("snx_rs_{}", device.replace('-', "_"))
+}
+
+fn port_table_name(device: &str) -> String {
+    format!("{}_ports", nft_table_name(device))
+}
+
+// nftables matches the cgroup path relative to the cgroup v2 mount point
+fn cgroup_relative_path(path: &Path) -> PathBuf {
+    path.strip_prefix(CGROUP_ROOT)
+        .unwrap_or(path)
+        .components()
+        .filter(|c| matches!(c, std::path::Component::Normal(_)))
+        .collect()
+}
+
+async fn nft(args: &[&str]) -> anyhow::Result<String> {
+    crate::util::run_command("nft", args).await
+}
+
+// marked traffic is directed to the tunnel routing table, processes in a cgroup are marked by nftables
+pub async fn add_app_routing(app_routing: &AppRouting, device: &str, table: u32) -> anyhow::Result<()> {
+    debug!("Routing {} through {} with table {}", app_routing, device, table);
+
+    let nft_table = nft_table_name(device);
+    let mark = format!("{:#x}", app_routing.mark());
+    let oif = format!("\"{}\"", device);
+
+    let _ = nft(&["delete", "table", "inet", &nft_table]).await;
+    nft(&["add", "table", "inet", &nft_table]).await?;
+
+    if let AppRouting::Cgroup(ref path) = app_routing {
+        let path = cgroup_relative_path(path);
+        let level = path.components().count().to_string();
+        let path = format!("\"{}\"", path.display());
+
+        nft(&[
+            "add", "chain", "inet", &nft_table, "output", "{", "type", "route", "hook", "output", "priority", "mangle",
+            ";", "}",
+        ])
+        .await?;
+        nft(&[
+            "add", "rule", "inet", &nft_table, "output", "socket", "cgroupv2", "level", &level, &path, "meta", "mark",
+            "set", &mark,
+        ])
+        .await?;
+    }
+
+    // the source address is selected before the marked packets are rerouted into the tunnel
+    nft(&[
+        "add",
+        "chain",
+        "inet",
+        &nft_table,
+        "postrouting",
+        "{",
+        "type",
+        "nat",
+        "hook",
+        "postrouting",
+        "priority",
+        "srcnat",
+        ";",
+        "}",
+    ])
+    .await?;
+    nft(&[
+        "add",
+        "rule",
+        "inet",
+        &nft_table,
+        "postrouting",
+        "oifname",
+        &oif,
+        "meta",
+        "mark",
+        &mark,
+        "masquerade",
+    ])
+    .await?;
+
+    let table = table.to_string();
+    crate::util::run_command("ip", ["rule", "add", "fwmark", &mark, "table", &table]).await?;
+
+    Ok(())
+}
+
+pub async fn delete_app_routing(app_routing: &AppRouting, device: &str, table: u32) {
+    let mark = format!("{:#x}", app_routing.mark());
+    let table = table.to_string();
+    let _ = crate::util::run_command("ip", ["rule", "del", "fwmark", &mark, "table", &table]).await;
+    let _ = nft(&["delete", "table", "inet", &nft_table_name(device)]).await;
+}
+
+// traffic to the given destination ports is marked by nftables and directed to the tunnel routing table
+pub async fn add_port_routing(ports: &[u16], device: &str, table: u32) -> anyhow::Result<()> {
+    debug!("Routing ports {:?} through {} with table {}", ports, device, table);
+
+    let nft_table = port_table_name(device);
+    let mark = format!("{:#x}", PORT_ROUTING_MARK);
+    let oif = format!("\"{}\"", device);
+    let ports = format!(
+        "{{ {} }}",
+        ports.iter().map(|p| p.to_string()).collect::<Vec<_>>().join(", ")
+    );
+
+    let _ = nft(&["delete", "table", "inet", &nft_table]).await;
+    nft(&["add", "table", "inet", &nft_table]).await?;
+
+    nft(&[
+        "add", "chain", "inet", &nft_table, "output", "{", "type", "route", "hook", "output", "priority", "mangle",
+        ";", "}",
+    ])
+    .await?;
+    nft(&[
+        "add", "rule", "inet", &nft_table, "output", "meta", "l4proto", "{", "tcp,", "udp", "}", "th", "dport", &ports,
+        "meta", "mark", "set", &mark,
+    ])
+    .await?;
+
+    nft(&[
+        "add",
+        "chain",
+        "inet",
+        &nft_table,
+        "postrouting",
+        "{",
+        "type",
+        "nat",
+        "hook",
+        "postrouting",
+        "priority",
+        "srcnat",
+        ";",
+        "}",
+    ])
+    .await?;
+    nft(&[
+        "add",
+        "rule",
+        "inet",
+        &nft_table,
+        "postrouting",
+        "oifname",
+        &oif,
+        "meta",
+        "mark",
+        &mark,
+        "masquerade",
+    ])
+    .await?;
+
+    let table = table.to_string();
+    crate::util::run_command("ip", ["rule", "add", "fwmark", &mark, "table", &table]).await?;
+
+    Ok(())
+}
+
+pub async fn delete_port_routing(device: &str, table: u32) {
+    let mark = format!("{:#x}", PORT_ROUTING_MARK);
+    let table = table.to_string();
+    let _ = crate::util::run_command("ip", ["rule", "del", "fwmark", &mark, "table", &table]).await;
+    let _ = nft(&["delete", "table", "inet", &port_table_name(device)]).await;
+}
+
+// the resolver settings and the device routes go away together with the link
+async fn find_leftovers(device: &str, address: Option<Ipv4Addr>, table: Option<u32>) -> Vec<String> {
+    let mut leftovers = Vec::new();
+
+    if crate::util::run_command("ip", ["link", "show", "dev", device])
+        .await
+        .is_ok()
+    {
+        leftovers.push(format!("device {}", device));
+    }
+
+    if let (Some(address), Some(table)) = (address, table) {
+        let source = address.to_string();
+        let table_str = table.to_string();
+        let rules = crate::util::run_command("ip", ["rule", "show", "from", &source, "table", &table_str]).await;
+        if rules.is_ok_and(|rules| !rules.trim().is_empty()) {
+            leftovers.push(format!("routing rule from {} to table {}", address, table));
+        }
+    }
+
+    leftovers
+}
+
+async fn remove_leftovers(device: &str, address: Option<Ipv4Addr>, table: Option<u32>, dns_manager: DnsManager) {
+    let _ = revert_dns(device, dns_manager).await;
+    let _ = crate::util::run_command("ip", ["link", "del", "dev", device]).await;
+    if let (Some(address), Some(table)) = (address, table) {
+        let _ = delete_table_rule(address, table).await;
+    }
+}
+
+// checks that the device and the routing rule installed for the tunnel are gone after the cleanup,
+// retries the removal and returns what is still left
+pub async fn verify_cleanup(
+    device: &str,
+    address: Option<Ipv4Addr>,
+    table: Option<u32>,
+    dns_manager: DnsManager,
+) -> Vec<String> {
+    let mut attempt = 1;
+
+    loop {
+        let leftovers = find_leftovers(device, address, table).await;
+        if leftovers.is_empty() || attempt == CLEANUP_VERIFY_ATTEMPTS {
+            return leftovers;
+        }
+
+        warn!("Left after cleanup: {}, retrying removal", leftovers.join(", "));
+
+        // the tunnel may still be cleaning up in the background
+        tokio::time::sleep(CLEANUP_VERIFY_INTERVAL).await;
+        remove_leftovers(device, address, table, dns_manager).await;
+        attempt += 1;
+    }
+}
+
+// destination and device of a line of "ip -o route show", the default route has no parseable destination
+fn parse_route_line(line: &str) -> Option<(Ipv4Net, &str)> {
+    let mut parts = line.split_whitespace().peekable();
+    if matches!(
+        parts.peek(),
+        Some(&("unicast" | "unreachable" | "blackhole" | "prohibit" | "throw"))
+    ) {
+        parts.next();
+    }
+
+    let dest = parts.next().and_then(|p| {
+        p.parse::<Ipv4Net>()
+            .ok()
+            .or_else(|| p.parse::<Ipv4Addr>().ok().map(Ipv4Net::from))
+    })?;
+    let dev = parts.skip_while(|p| *p != "dev").nth(1).unwrap_or("-");
+
+    Some((dest, dev))
+}
+
+// existing routes to the same or a more specific destination win over the tunnel routes
+fn parse_route_conflicts(output: &str, routes: &[Ipv4Net], device: &str) -> Vec<String> {
+    let mut conflicts = Vec::new();
+
+    for (dest, dev) in output.lines().filter_map(parse_route_line) {
+        if dev == device {
+            continue;
+        }
+
+        if let Some(route) = routes.iter().find(|route| route.contains(&dest)) {
+            conflicts.push(format!("{} on {} overlaps the tunnel route {}", dest, dev, route));
+        }
+    }
+
+    conflicts
+}
+
+// with another tunnel even a less specific route is ambiguous, the traffic to the overlapping part
+// leaves through whichever tunnel has the more specific route
+fn parse_tunnel_overlaps(output: &str, routes: &[Ipv4Net], tunnel_devices: &[String]) -> Vec<String> {
+    let mut overlaps = Vec::new();
+
+    for (dest, dev) in output.lines().filter_map(parse_route_line) {
+        if !tunnel_devices.iter().any(|d| d == dev) {
+            continue;
+        }
+
+        if let Some(route) = routes
+            .iter()
+            .find(|route| route.contains(&dest) || dest.contains(*route))
+        {
+            overlaps.push(format!(
+                "{} on tunnel {} overlaps the tunnel route {}",
+                dest, dev, route
+            ));
+        }
+    }
+
+    overlaps
+}
+
+fn parse_link_names(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .map(|name| name.trim_end_matches(':').split('@').next().unwrap_or(name).to_owned())
+        .collect()
+}
+
+// the XFRM and TUN interfaces besides the given one, e.g. of another service instance
+async fn other_tunnel_devices(device: &str) -> Vec<String> {
+    let mut devices = Vec::new();
+    for kind in ["xfrm", "tun"] {
+        if let Ok(output) = crate::util::run_command("ip", ["-o", "link", "show", "type", kind]).await {
+            devices.extend(parse_link_names(&output).into_iter().filter(|name| name != device));
+        }
+    }
+    devices
+}
+
+pub async fn find_tunnel_overlaps(routes: &[Ipv4Net], device: &str) -> Vec<String> {
+    let devices = other_tunnel_devices(device).await;
+    if devices.is_empty() {
+        return Vec::new();
+    }
+    match crate::util::run_command("ip", ["-4", "-o", "route", "show", "table", "main"]).await {
+        Ok(output) => parse_tunnel_overlaps(&output, routes, &devices),
+        Err(_) => Vec::new(),
+    }
+}
+
+pub async fn find_route_conflicts(routes: &[Ipv4Net], device: &str, table: Option<u32>) -> Vec<String> {
+    let table = table.map(|t| t.to_string()).unwrap_or_else(|| "main".to_owned());
+    match crate::util::run_command("ip", ["-4", "-o", "route", "show", "table", &table]).await {
+        Ok(output) => parse_route_conflicts(&output, routes, device),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Directly connected subnet of a local interface
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalSubnet {
+    pub subnet: Ipv4Net,
+    pub device: String,
+}
+
+fn parse_local_subnets(output: &str, skip_device: &str) -> Vec<LocalSubnet> {
+    let mut subnets = Vec::new();
+
+    for line in output.lines().filter(|line| !line.contains("scope host")) {
+        let mut parts = line.split_whitespace().skip(1);
+        let (Some(device), Some("inet"), Some(address)) = (parts.next(), parts.next(), parts.next()) else {
+            continue;
+        };
+        let device = device.split('@').next().unwrap_or(device);
+
+        if let Ok(address) = address.parse::<Ipv4Net>() {
+            let local = LocalSubnet {
+                subnet: address.trunc(),
+                device: device.to_owned(),
+            };
+            if device != skip_device && address.prefix_len() < 32 && !subnets.contains(&local) {
+                subnets.push(local);
+            }
+        }
+    }
+
+    subnets
+}
+
+pub async fn get_local_subnets(skip_device: &str) -> anyhow::Result<Vec<LocalSubnet>> {
+    let output = crate::util::run_command("ip", ["-4", "-o", "addr", "show"]).await?;
+    Ok(parse_local_subnets(&output, skip_device))
+}
+
+async fn exclude_route(command: &str, local: &LocalSubnet, table: Option<u32>) -> anyhow::Result<()> {
+    let dest = local.subnet.to_string();
+    let table = table.map(|t| t.to_string());
+
+    let mut args = vec!["route", command, &dest, "dev", &local.device, "metric", "0"];
+    if let Some(ref table) = table {
+        args.extend(["table", table.as_str()]);
+    }
+    crate::util::run_command("ip", args).await?;
 
     Ok(())
 }
 
-pub async fn add_dns_suffixes<I, T>(suffixes: I, device: &str) -> anyhow::Result<()>
+// returns the routes which have been added, existing kernel routes are left alone
+pub async fn add_exclude_routes(subnets: &[LocalSubnet], table: Option<u32>) -> Vec<LocalSubnet> {
+    let mut added = Vec::new();
+
+    for local in subnets {
+        debug!(
+            "Excluding local subnet {} on {} from the tunnel",
+            local.subnet, local.device
+        );
+        if exclude_route("add", local, table).await.is_ok() {
+            added.push(local.clone());
+        }
+    }
+
+    added
+}
+
+pub async fn delete_exclude_routes(subnets: &[LocalSubnet], table: Option<u32>) {
+    for local in subnets {
+        let _ = exclude_route("del", local, table).await;
+    }
+}
+
+pub async fn add_dns_suffixes<I, T>(suffixes: I, device: &str, manager: DnsManager) -> anyhow::Result<()>
 where
     I: IntoIterator<Item = T>,
     T: AsRef<str>,
 {
-    let mut args = vec!["domain", device];
-
     let suffixes = suffixes
         .into_iter()
         .map(|s| s.as_ref().trim().to_owned())
         .collect::<Vec<_>>();
 
+    let manager = resolver::effective_manager(manager);
+    if manager != DnsManager::Resolved {
+        return resolver::set_domains(device, manager, suffixes).await;
+    }
+
+    let mut args = vec!["domain", device];
+
     args.extend(suffixes.iter().map(|s| s.as_str()));
 
     crate::util::run_command("resolvectl", args).await?;
@@ -165,15 +817,33 @@ where
     Ok(())
 }
 
-pub async fn add_dns_servers<I, T>(servers: I, device: &str) -> anyhow::Result<()>
+pub async fn revert_dns(device: &str, manager: DnsManager) -> anyhow::Result<()> {
+    let manager = resolver::effective_manager(manager);
+    if manager != DnsManager::Resolved {
+        return resolver::revert(device, manager).await;
+    }
+    crate::util::run_command("resolvectl", ["revert", device]).await?;
+    Ok(())
+}
+
+pub async fn flush_dns_cache() {
+    resolver::flush_cache().await;
+}
+
+pub async fn add_dns_servers<I, T>(servers: I, device: &str, manager: DnsManager) -> anyhow::Result<()>
 where
     I: IntoIterator<Item = T>,
     T: AsRef<str>,
 {
-    let mut args = vec!["dns", device];
-
     let servers = servers.into_iter().map(|s| s.as_ref().to_owned()).collect::<Vec<_>>();
 
+    let manager = resolver::effective_manager(manager);
+    if manager != DnsManager::Resolved {
+        return resolver::set_servers(device, manager, servers).await;
+    }
+
+    let mut args = vec!["dns", device];
+
     args.extend(servers.iter().map(|s| s.as_str()));
 
     crate::util::run_command("resolvectl", args).await?;
@@ -181,6 +851,47 @@ where
     Ok(())
 }
 
+// route all DNS queries to the resolvers of the given device, keeping the search domains.
+// Without systemd-resolved the tunnel servers are used for all queries anyway.
+pub async fn set_default_dns_route<I, T>(suffixes: I, device: &str, manager: DnsManager) -> anyhow::Result<()>
+where
+    I: IntoIterator<Item = T>,
+    T: AsRef<str>,
+{
+    let suffixes = suffixes
+        .into_iter()
+        .map(|s| s.as_ref().trim().to_owned())
+        .collect::<Vec<_>>();
+
+    let manager = resolver::effective_manager(manager);
+    if manager != DnsManager::Resolved {
+        return resolver::set_domains(device, manager, suffixes).await;
+    }
+
+    let mut args = vec!["domain", device];
+    args.extend(suffixes.iter().map(|s| s.as_str()));
+    args.push("~.");
+
+    crate::util::run_command("resolvectl", args).await?;
+    crate::util::run_command("resolvectl", ["default-route", device, "true"]).await?;
+
+    Ok(())
+}
+
+// route all DNS queries through the given device to the local forwarder
+pub async fn set_dns_forwarder(address: &str, device: &str, manager: DnsManager) -> anyhow::Result<()> {
+    let manager = resolver::effective_manager(manager);
+    if manager != DnsManager::Resolved {
+        return resolver::set_servers(device, manager, vec![address.to_owned()]).await;
+    }
+
+    crate::util::run_command("resolvectl", ["dns", device, address]).await?;
+    crate::util::run_command("resolvectl", ["domain", device, "~."]).await?;
+    crate::util::run_command("resolvectl", ["default-route", device, "true"]).await?;
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -190,4 +901,142 @@ mod tests {
         let ip = get_default_ip().await.unwrap();
         println!("{}", ip);
     }
+
+    #[test]
+    fn test_read_interface_stats() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+
+        let names = [
+            "rx_bytes",
+            "tx_bytes",
+            "rx_packets",
+            "tx_packets",
+            "rx_errors",
+            "tx_errors",
+            "rx_dropped",
+        ];
+        for (i, name) in names.iter().enumerate() {
+            std::fs::write(dir.join(name), format!("{}\n", i + 1)).unwrap();
+        }
+        assert_eq!(read_interface_stats(dir), None);
+
+        std::fs::write(dir.join("tx_dropped"), "8\n").unwrap();
+        let stats = read_interface_stats(dir).unwrap();
+
+        assert_eq!(stats.rx_bytes, 1);
+        assert_eq!(stats.rx_errors, 5);
+        assert_eq!(stats.tx_dropped, 8);
+    }
+
+    #[test]
+    fn test_cgroup_relative_path() {
+        assert_eq!(
+            cgroup_relative_path(Path::new("/sys/fs/cgroup/user.slice/browser.scope")),
+            PathBuf::from("user.slice/browser.scope")
+        );
+        assert_eq!(
+            cgroup_relative_path(Path::new("/user.slice/browser.scope/")),
+            PathBuf::from("user.slice/browser.scope")
+        );
+    }
+
+    #[test]
+    fn test_parse_local_subnets() {
+        let output = r#"1: lo    inet 127.0.0.1/8 scope host lo\       valid_lft forever preferred_lft forever
+2: wlp3s0    inet 192.168.1.23/24 brd 192.168.1.255 scope global dynamic noprefixroute wlp3s0\       valid_lft 80000sec
+3: docker0    inet 172.17.0.1/16 brd 172.17.255.255 scope global docker0\       valid_lft forever preferred_lft forever
+4: snx-xfrm    inet 10.20.30.40/32 scope global snx-xfrm\       valid_lft forever preferred_lft forever
+5: snx-tun    inet 10.20.0.5/24 scope global snx-tun\       valid_lft forever preferred_lft forever"#;
+
+        let subnets = parse_local_subnets(output, "snx-tun");
+        assert_eq!(
+            subnets,
+            vec![
+                LocalSubnet {
+                    subnet: "192.168.1.0/24".parse().unwrap(),
+                    device: "wlp3s0".to_owned(),
+                },
+                LocalSubnet {
+                    subnet: "172.17.0.0/16".parse().unwrap(),
+                    device: "docker0".to_owned(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_route_conflicts() {
+        let output = r#"default via 192.168.1.1 dev eth0 proto dhcp metric 100
+10.1.0.0/16 via 10.99.0.1 dev wg0 proto static
+10.2.0.5 dev snx-xfrm proto boot scope link
+172.16.0.0/12 dev eth1 proto kernel scope link src 172.16.0.5
+unreachable 10.3.0.0/24 proto static"#;
+
+        let routes = ["10.0.0.0/8".parse().unwrap()];
+        let conflicts = parse_route_conflicts(output, &routes, "snx-xfrm");
+
+        assert_eq!(
+            conflicts,
+            vec![
+                "10.1.0.0/16 on wg0 overlaps the tunnel route 10.0.0.0/8",
+                "10.3.0.0/24 on - overlaps the tunnel route 10.0.0.0/8",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_tunnel_overlaps() {
+        let output = r#"10.0.0.0/8 dev snx-xfrm2 proto boot scope link
+10.1.0.0/16 via 10.99.0.1 dev wg0 proto static
+192.168.50.0/24 dev snx-xfrm2 proto boot scope link"#;
+
+        let routes = ["10.20.0.0/16".parse().unwrap()];
+        let overlaps = parse_tunnel_overlaps(output, &routes, &["snx-xfrm2".to_owned()]);
+
+        assert_eq!(
+            overlaps,
+            vec!["10.0.0.0/8 on tunnel snx-xfrm2 overlaps the tunnel route 10.20.0.0/16"]
+        );
+    }
+
+    #[test]
+    fn test_find_free_table() {
+        let rules = "0:\tfrom all lookup local\n\
+                     32765:\tfrom 10.0.0.2 lookup 7842\n\
+                     32766:\tfrom all lookup main\n";
+        let routes = "10.1.0.0/16 dev snx-xfrm2 table 7843 proto boot scope link\n";
+        let mut used = parse_used_tables(rules);
+        used.extend(parse_used_tables(routes));
+        assert_eq!(used, vec![7842, 7843]);
+
+        assert_eq!(select_free_table(&used, 7800, 100, 10), Some(7810));
+        assert_eq!(select_free_table(&used, 7800, 100, 42), Some(7844));
+        assert_eq!(select_free_table(&[7899], 7800, 100, 99), Some(7800));
+        assert_eq!(select_free_table(&[7800, 7801], 7800, 2, 0), None);
+    }
+
+    #[test]
+    fn test_parse_link_names() {
+        let output = "12: snx-xfrm@NONE: <NOARP,UP,LOWER_UP> mtu 1400 qdisc noqueue\n\
+                      14: snx-tun: <POINTOPOINT,UP> mtu 1350 qdisc fq_codel\n";
+        assert_eq!(parse_link_names(output), vec!["snx-xfrm", "snx-tun"]);
+    }
+
+    #[test]
+    fn test_parse_udp_drops() {
+        let content = "   sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode ref pointer drops\n\
+            1: 00000000:9C40 00000000:0000 07 00000000:00000000 00:00000000 00000000     0        0 12345 2 0000000000000000 17\n\
+            2: 3500007F:0035 00000000:0000 07 00000000:00000000 00:00000000 00000000   101        0 2345 2 0000000000000000 0\n";
+        assert_eq!(parse_udp_drops(content, 40000), Some(17));
+        assert_eq!(parse_udp_drops(content, 53), Some(0));
+        assert_eq!(parse_udp_drops(content, 4500), None);
+    }
+
+    #[test]
+    fn test_is_tun_flags() {
+        assert!(is_tun_flags("0x1001\n"));
+        assert!(!is_tun_flags("0x1002\n"));
+        assert!(!is_tun_flags("garbage"));
+    }
 }
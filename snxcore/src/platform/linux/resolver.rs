@@ -0,0 +1,304 @@
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    fs,
+    net::IpAddr,
+    os::unix::{ffi::OsStrExt, fs::symlink},
+    path::{Path, PathBuf},
+};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use tracing::{debug, warn};
+
+use crate::model::params::DnsManager;
+
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+const RESOLV_CONF_TEMP: &str = "/etc/resolv.conf.snx-rs";
+const RESOLVCONF_PATHS: &[&str] = &["/usr/sbin/resolvconf", "/sbin/resolvconf", "/usr/bin/resolvconf"];
+const NM_CONF_DIR: &str = "/run/NetworkManager/conf.d";
+const BACKUP_DIR: &str = "/run";
+const RESOLVED_RUN_DIR: &str = "/run/systemd/resolve";
+const NSCD_SOCKETS: &[&str] = &["/run/nscd/socket", "/var/run/nscd/socket"];
+const RESOLV_CONF_HEADER: &str = "# generated by snx-rs, the original file is restored on disconnect\n";
+
+// without per-link DNS the servers and domains are applied together, the calls set them one at a time
+#[derive(Default, Clone)]
+struct DnsEntry {
+    servers: Vec<String>,
+    domains: Vec<String>,
+}
+
+static DNS_ENTRIES: Lazy<Mutex<HashMap<String, DnsEntry>>> = Lazy::new(Default::default);
+
+fn detect_manager(resolv_conf_target: Option<&Path>, resolv_conf: &str, has_resolvconf: bool) -> DnsManager {
+    let is_resolved_stub = resolv_conf
+        .lines()
+        .any(|line| line.split_whitespace().eq(["nameserver", "127.0.0.53"]));
+
+    if resolv_conf_target.is_some_and(|target| target.to_string_lossy().contains("systemd/resolve")) || is_resolved_stub
+    {
+        DnsManager::Resolved
+    } else if resolv_conf.contains("Generated by NetworkManager") {
+        DnsManager::NetworkManager
+    } else if has_resolvconf {
+        DnsManager::Resolvconf
+    } else {
+        DnsManager::File
+    }
+}
+
+// the auto mode picks the mechanism which manages /etc/resolv.conf
+pub fn effective_manager(manager: DnsManager) -> DnsManager {
+    if manager != DnsManager::Auto {
+        return manager;
+    }
+
+    let target = fs::read_link(RESOLV_CONF).ok();
+    let resolv_conf = fs::read_to_string(RESOLV_CONF).unwrap_or_default();
+    let has_resolvconf = RESOLVCONF_PATHS.iter().any(|path| Path::new(path).exists());
+
+    let manager = detect_manager(target.as_deref(), &resolv_conf, has_resolvconf);
+    debug!("Detected DNS manager: {}", manager.as_str());
+    manager
+}
+
+// the tunnel servers go first, the original domains are searched after the tunnel ones
+fn resolv_conf_content(original: &str, entry: &DnsEntry) -> String {
+    let mut content = String::new();
+    let mut domains = entry.domains.clone();
+
+    for server in &entry.servers {
+        content.push_str(&format!("nameserver {}\n", server));
+    }
+
+    for line in original.lines() {
+        let mut parts = line.split_whitespace();
+        match parts.next() {
+            Some("search" | "domain") => domains.extend(parts.map(ToOwned::to_owned)),
+            _ => {
+                content.push_str(line);
+                content.push('\n');
+            }
+        }
+    }
+
+    if !domains.is_empty() {
+        content.push_str(&format!("search {}\n", domains.join(" ")));
+    }
+
+    content
+}
+
+fn nm_conf_content(entry: &DnsEntry) -> String {
+    format!(
+        "[global-dns]\nsearches={}\n\n[global-dns-domain-*]\nservers={}\n",
+        entry.domains.join(","),
+        entry.servers.join(",")
+    )
+}
+
+fn backup_path(device: &str) -> PathBuf {
+    Path::new(BACKUP_DIR).join(format!("snx-rs-resolv.conf.{}", device))
+}
+
+fn link_backup_path(device: &str) -> PathBuf {
+    Path::new(BACKUP_DIR).join(format!("snx-rs-resolv.conf.{}.link", device))
+}
+
+fn nm_conf_path(device: &str) -> PathBuf {
+    Path::new(NM_CONF_DIR).join(format!("snx-rs-{}.conf", device))
+}
+
+// the file is replaced rather than written through, the target of a symlinked resolv.conf stays intact
+fn replace_resolv_conf<F>(create: F) -> anyhow::Result<()>
+where
+    F: FnOnce(&Path) -> std::io::Result<()>,
+{
+    let _ = fs::remove_file(RESOLV_CONF_TEMP);
+    create(Path::new(RESOLV_CONF_TEMP))?;
+    fs::rename(RESOLV_CONF_TEMP, RESOLV_CONF)?;
+    Ok(())
+}
+
+// the values end up in resolver configuration files, anything which is not a plain address or name is dropped
+fn valid_entries<F>(values: Vec<String>, kind: &str, is_valid: F) -> Vec<String>
+where
+    F: Fn(&str) -> bool,
+{
+    values
+        .into_iter()
+        .filter(|value| {
+            let valid = is_valid(value);
+            if !valid {
+                warn!("Ignoring invalid DNS {}: {:?}", kind, value);
+            }
+            valid
+        })
+        .collect()
+}
+
+async fn apply(device: &str, manager: DnsManager, entry: &DnsEntry) -> anyhow::Result<()> {
+    match manager {
+        DnsManager::Resolvconf => {
+            let input = resolv_conf_content("", entry);
+            crate::util::run_command_with_input("resolvconf", ["-a", device], &input).await?;
+        }
+        DnsManager::NetworkManager => {
+            fs::create_dir_all(NM_CONF_DIR)?;
+            fs::write(nm_conf_path(device), nm_conf_content(entry))?;
+            crate::util::run_command("nmcli", ["general", "reload", "dns-full"]).await?;
+        }
+        _ => {
+            let backup = backup_path(device);
+            if !backup.exists() {
+                fs::copy(RESOLV_CONF, &backup)?;
+                if let Ok(target) = fs::read_link(RESOLV_CONF) {
+                    fs::write(link_backup_path(device), target.as_os_str().as_bytes())?;
+                }
+            }
+            let original = fs::read_to_string(&backup)?;
+            let content = format!("{}{}", RESOLV_CONF_HEADER, resolv_conf_content(&original, entry));
+            replace_resolv_conf(|path| fs::write(path, content))?;
+        }
+    }
+    Ok(())
+}
+
+async fn update<F>(device: &str, manager: DnsManager, f: F) -> anyhow::Result<()>
+where
+    F: FnOnce(&mut DnsEntry),
+{
+    let entry = {
+        let mut entries = DNS_ENTRIES.lock();
+        let entry = entries.entry(device.to_owned()).or_default();
+        f(entry);
+        entry.clone()
+    };
+    debug!("Applying the DNS settings of {} with {}", device, manager.as_str());
+    apply(device, manager, &entry).await
+}
+
+pub async fn set_servers(device: &str, manager: DnsManager, servers: Vec<String>) -> anyhow::Result<()> {
+    let servers = valid_entries(servers, "server", |server| server.parse::<IpAddr>().is_ok());
+    update(device, manager, |entry| entry.servers = servers).await
+}
+
+pub async fn set_domains(device: &str, manager: DnsManager, domains: Vec<String>) -> anyhow::Result<()> {
+    let domains = valid_entries(domains, "domain", crate::util::is_valid_dns_name);
+    update(device, manager, |entry| entry.domains = domains).await
+}
+
+pub async fn revert(device: &str, manager: DnsManager) -> anyhow::Result<()> {
+    DNS_ENTRIES.lock().remove(device);
+
+    match manager {
+        DnsManager::Resolvconf => {
+            crate::util::run_command("resolvconf", ["-d", device]).await?;
+        }
+        DnsManager::NetworkManager => {
+            let path = nm_conf_path(device);
+            if path.exists() {
+                fs::remove_file(path)?;
+                crate::util::run_command("nmcli", ["general", "reload", "dns-full"]).await?;
+            }
+        }
+        _ => {
+            let backup = backup_path(device);
+            let link_backup = link_backup_path(device);
+            if link_backup.exists() {
+                let target = fs::read(&link_backup)?;
+                replace_resolv_conf(|path| symlink(OsStr::from_bytes(&target), path))?;
+                fs::remove_file(link_backup)?;
+            } else if backup.exists() {
+                let original = fs::read(&backup)?;
+                replace_resolv_conf(|path| fs::write(path, original))?;
+            }
+            if backup.exists() {
+                fs::remove_file(backup)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// the caching resolvers keep the answers for the tunnel domains after disconnect
+pub async fn flush_cache() {
+    let mut caches: Vec<(&str, &[&str])> = Vec::new();
+
+    if Path::new(RESOLVED_RUN_DIR).exists() {
+        caches.push(("resolvectl", &["flush-caches"]));
+    }
+
+    if NSCD_SOCKETS.iter().any(|path| Path::new(path).exists()) {
+        caches.push(("nscd", &["-i", "hosts"]));
+    }
+
+    if caches.is_empty() {
+        debug!("No DNS cache found to flush");
+    }
+
+    for (command, args) in caches {
+        match crate::util::run_command(command, args).await {
+            Ok(_) => debug!("Flushed the DNS cache with {}", command),
+            Err(e) => warn!("Unable to flush the DNS cache with {}: {}", command, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_manager() {
+        let stub = "nameserver 127.0.0.53\noptions edns0\n";
+        assert_eq!(detect_manager(None, stub, true), DnsManager::Resolved);
+        assert_eq!(
+            detect_manager(Some(Path::new("../run/systemd/resolve/resolv.conf")), "", false),
+            DnsManager::Resolved
+        );
+        assert_eq!(
+            detect_manager(None, "# Generated by NetworkManager\nnameserver 10.0.0.1\n", true),
+            DnsManager::NetworkManager
+        );
+        assert_eq!(
+            detect_manager(None, "nameserver 10.0.0.1\n", true),
+            DnsManager::Resolvconf
+        );
+        assert_eq!(detect_manager(None, "nameserver 10.0.0.1\n", false), DnsManager::File);
+    }
+
+    #[test]
+    fn test_resolv_conf_content() {
+        let entry = DnsEntry {
+            servers: vec!["10.1.1.1".to_owned()],
+            domains: vec!["corp.example.com".to_owned()],
+        };
+        let content = resolv_conf_content("nameserver 192.168.1.1\nsearch home.lan\n", &entry);
+        assert_eq!(
+            content,
+            "nameserver 10.1.1.1\n\
+             nameserver 192.168.1.1\n\
+             search corp.example.com home.lan\n"
+        );
+    }
+
+    #[test]
+    fn test_valid_entries() {
+        let domains = vec![
+            "corp.example.com".to_owned(),
+            "corp.example.com\nnameserver 10.0.0.1".to_owned(),
+        ];
+        assert_eq!(
+            valid_entries(domains, "domain", crate::util::is_valid_dns_name),
+            vec!["corp.example.com".to_owned()]
+        );
+
+        let servers = vec!["10.1.1.1".to_owned(), "10.1.1.1 # comment".to_owned()];
+        assert_eq!(
+            valid_entries(servers, "server", |server| server.parse::<IpAddr>().is_ok()),
+            vec!["10.1.1.1".to_owned()]
+        );
+    }
+}
@@ -1,43 +1,96 @@
 use std::{net::Ipv4Addr, sync::Arc};
 
+use anyhow::anyhow;
 use ipnet::Ipv4Net;
 use isakmp::model::{EspAuthAlgorithm, EspCryptMaterial, TransformId};
 use rand::random;
-use tracing::{debug, trace};
+use tracing::{debug, trace, warn};
 
 use crate::{
-    model::{params::TunnelParams, IpsecSession},
-    platform::{self, IpsecConfigurator},
-    util,
+    dns::{self, DnsForwarder},
+    model::{
+        params::{ExistingInterface, TunnelParams},
+        IpsecSession,
+    },
+    platform::{self, EspUsage, IpsecConfigurator, LocalSubnet},
+    progress, util,
 };
 
+// without ESN the kernel uses a 32-bit replay bitmap
+const MAX_REPLAY_WINDOW: u32 = 32;
+
 async fn iproute2(args: &[&str]) -> anyhow::Result<String> {
     util::run_command("ip", args).await
 }
 
+// "oseq 0x..." from the anti-replay context and the byte count following "lifetime current:"
+fn parse_esp_usage(output: &str) -> Option<EspUsage> {
+    let sequence = output
+        .split_whitespace()
+        .skip_while(|p| *p != "oseq")
+        .nth(1)
+        .and_then(|v| u64::from_str_radix(v.trim_end_matches(',').trim_start_matches("0x"), 16).ok())?;
+
+    let bytes = output
+        .split_once("lifetime current:")
+        .and_then(|(_, rest)| rest.split_whitespace().next())
+        .and_then(|v| v.strip_suffix("(bytes),"))
+        .and_then(|v| v.parse().ok())?;
+
+    Some(EspUsage { sequence, bytes })
+}
+
+// "xfrm if_id 0x..." from the detailed link information
+fn parse_xfrm_if_id(output: &str) -> Option<u32> {
+    let mut parts = output.split_whitespace().skip_while(|p| *p != "xfrm");
+    if parts.nth(1)? != "if_id" {
+        return None;
+    }
+    let value = parts.next()?;
+    match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
 struct XfrmLink<'a> {
     name: &'a str,
     if_id: u32,
     address: Ipv4Net,
+    vrf: Option<&'a str>,
+    mtu: Option<u16>,
 }
 
 impl<'a> XfrmLink<'a> {
     async fn add(&self) -> anyhow::Result<()> {
         let _ = self.delete().await;
 
-        iproute2(&[
-            "link",
-            "add",
-            self.name,
-            "type",
-            "xfrm",
-            "if_id",
-            &self.if_id.to_string(),
-        ])
-        .await?;
+        let if_id = self.if_id.to_string();
+        let mut args = vec!["link", "add", self.name, "type", "xfrm", "if_id", if_id.as_str()];
 
+        // outer ESP packets are routed via the VRF table when the underlying device is the VRF master
+        if let Some(vrf) = self.vrf {
+            args.extend(["dev", vrf]);
+        }
+
+        iproute2(&args).await?;
+
+        self.setup().await
+    }
+
+    // an adopted link keeps its if_id, the previous address is replaced
+    async fn adopt(&self) -> anyhow::Result<()> {
+        iproute2(&["addr", "flush", "dev", self.name]).await?;
+        self.setup().await
+    }
+
+    async fn setup(&self) -> anyhow::Result<()> {
         platform::unmanage_device(self.name).await;
 
+        if let Some(mtu) = self.mtu {
+            iproute2(&["link", "set", self.name, "mtu", &mtu.to_string()]).await?;
+        }
+
         let opt = format!("net.ipv4.conf.{}.disable_policy=1", self.name);
         util::run_command("sysctl", ["-qw", &opt]).await?;
 
@@ -66,6 +119,8 @@ struct XfrmState<'a> {
     src_port: u16,
     dst_port: u16,
     if_id: u32,
+    replay_window: Option<u32>,
+    pmtud: bool,
     params: &'a EspCryptMaterial,
 }
 
@@ -95,39 +150,53 @@ impl<'a> XfrmState<'a> {
         let spi = format!("0x{:x}", self.params.spi);
         let src = self.src.to_string();
         let dst = self.dst.to_string();
+        let if_id = self.if_id.to_string();
+        let src_port = self.src_port.to_string();
+        let dst_port = self.dst_port.to_string();
 
-        iproute2(&[
+        let mut args = vec![
             "xfrm",
             "state",
             "add",
             "src",
-            &src,
+            src.as_str(),
             "dst",
-            &dst,
+            dst.as_str(),
             "proto",
             "esp",
             "spi",
-            &spi,
+            spi.as_str(),
             "mode",
             "tunnel",
             "flag",
             "af-unspec",
             "auth-trunc",
             self.auth_alg_as_xfrm_name(),
-            &authkey,
-            &trunc_len,
+            authkey.as_str(),
+            trunc_len.as_str(),
             "enc",
             self.enc_alg_as_xfrm_name(),
-            &enckey,
+            enckey.as_str(),
             "if_id",
-            &self.if_id.to_string(),
+            if_id.as_str(),
             "encap",
             "espinudp",
-            &self.src_port.to_string(),
-            &self.dst_port.to_string(),
+            src_port.as_str(),
+            dst_port.as_str(),
             "0.0.0.0",
-        ])
-        .await?;
+        ];
+
+        let replay_window = self.replay_window.map(|w| w.to_string());
+        if let Some(ref replay_window) = replay_window {
+            args.extend(["replay-window", replay_window.as_str()]);
+        }
+
+        // without the DF bit the outer packets may be fragmented on the path
+        if !self.pmtud {
+            args.extend(["flag", "nopmtudisc"]);
+        }
+
+        iproute2(&args).await?;
 
         Ok(())
     }
@@ -144,6 +213,19 @@ impl<'a> XfrmState<'a> {
 
         Ok(())
     }
+
+    async fn usage(&self) -> anyhow::Result<EspUsage> {
+        let src = self.src.to_string();
+        let dst = self.dst.to_string();
+        let spi = format!("0x{:x}", self.params.spi);
+
+        let output = iproute2(&[
+            "-s", "xfrm", "state", "get", "src", &src, "dst", &dst, "proto", "esp", "spi", &spi,
+        ])
+        .await?;
+
+        parse_esp_usage(&output).ok_or_else(|| anyhow!("Cannot parse the XFRM state statistics!"))
+    }
 }
 
 struct XfrmPolicy {
@@ -229,6 +311,12 @@ pub struct XfrmConfigurator {
     src_port: u16,
     dest_ip: Ipv4Addr,
     subnets: Vec<Ipv4Net>,
+    excluded_subnets: Vec<LocalSubnet>,
+    dns_forwarder: Option<DnsForwarder>,
+    mtu: Option<u16>,
+    route_conflicts: Vec<String>,
+    runtime_routes: Vec<Ipv4Net>,
+    overlap_table: Option<u32>,
 }
 
 impl XfrmConfigurator {
@@ -238,6 +326,7 @@ impl XfrmConfigurator {
         src_port: u16,
         dest_ip: Ipv4Addr,
         subnets: Vec<Ipv4Net>,
+        mtu: Option<u16>,
     ) -> anyhow::Result<Self> {
         let if_id = random();
 
@@ -255,6 +344,26 @@ impl XfrmConfigurator {
             if_id,
             src_port,
             subnets,
+            excluded_subnets: Vec::new(),
+            dns_forwarder: None,
+            mtu,
+            route_conflicts: Vec::new(),
+            runtime_routes: Vec::new(),
+            overlap_table: None,
+        })
+    }
+
+    fn replay_window(&self) -> Option<u32> {
+        self.tunnel_params.anti_replay_window.map(|window| {
+            if window > MAX_REPLAY_WINDOW {
+                warn!(
+                    "Anti-replay window {} exceeds the maximum of {}, clamping",
+                    window, MAX_REPLAY_WINDOW
+                );
+                MAX_REPLAY_WINDOW
+            } else {
+                window
+            }
         })
     }
 
@@ -264,11 +373,66 @@ impl XfrmConfigurator {
             if_id: self.if_id,
             address: Ipv4Net::with_netmask(self.ipsec_session.address, self.ipsec_session.netmask)
                 .unwrap_or_else(|_| Ipv4Net::from(self.ipsec_session.address)),
+            vrf: self.tunnel_params.outer_vrf.as_deref(),
+            mtu: self.mtu,
+        }
+    }
+
+    // the configured table or the one isolating the routes which overlap another tunnel
+    fn routing_table(&self) -> Option<u32> {
+        self.tunnel_params.routing_table().or(self.overlap_table)
+    }
+
+    async fn delete_table_rule(&self) -> anyhow::Result<()> {
+        match (self.overlap_table, self.tunnel_params.routing_table()) {
+            (Some(table), _) => platform::delete_lookup_rule(table).await,
+            (None, Some(table)) => platform::delete_table_rule(self.ipsec_session.address, table).await,
+            (None, None) => Ok(()),
+        }
+    }
+
+    // returns true if the existing interface is adopted
+    async fn check_existing_link(&mut self) -> anyhow::Result<bool> {
+        if !platform::device_exists(&self.name) {
+            return Ok(false);
+        }
+
+        match self.tunnel_params.on_existing_interface {
+            ExistingInterface::Fail => Err(anyhow!("Interface {} already exists!", self.name)),
+            ExistingInterface::Reuse => {
+                let output = iproute2(&["-d", "link", "show", "dev", &self.name]).await?;
+                let if_id = parse_xfrm_if_id(&output)
+                    .ok_or_else(|| anyhow!("Interface {} exists but is not an XFRM interface!", self.name))?;
+                warn!("Reusing the existing interface {} with if_id {:#x}", self.name, if_id);
+                self.if_id = if_id;
+                Ok(true)
+            }
+            ExistingInterface::Recreate => {
+                warn!("Recreating the existing interface {}", self.name);
+                Ok(false)
+            }
         }
     }
 
-    async fn setup_xfrm_link(&self) -> anyhow::Result<()> {
-        self.new_xfrm_link().add().await
+    async fn setup_xfrm_link(&self, reuse: bool) -> anyhow::Result<()> {
+        if reuse {
+            self.new_xfrm_link().adopt().await
+        } else {
+            self.new_xfrm_link().add().await
+        }
+    }
+
+    fn new_xfrm_state<'a>(&self, src: Ipv4Addr, dst: Ipv4Addr, params: &'a EspCryptMaterial) -> XfrmState<'a> {
+        XfrmState {
+            src,
+            dst,
+            src_port: self.src_port,
+            dst_port: 4500,
+            if_id: self.if_id,
+            replay_window: self.replay_window(),
+            pmtud: self.tunnel_params.pmtud,
+            params,
+        }
     }
 
     async fn configure_xfrm_state(
@@ -278,14 +442,7 @@ impl XfrmConfigurator {
         dst: Ipv4Addr,
         params: &EspCryptMaterial,
     ) -> anyhow::Result<()> {
-        let state = XfrmState {
-            src,
-            dst,
-            src_port: self.src_port,
-            dst_port: 4500,
-            if_id: self.if_id,
-            params,
-        };
+        let state = self.new_xfrm_state(src, dst, params);
         match command {
             CommandType::Add => state.add().await?,
             CommandType::Delete => state.delete().await?,
@@ -339,14 +496,65 @@ impl XfrmConfigurator {
         Ok(())
     }
 
-    async fn setup_routing(&self) -> anyhow::Result<()> {
+    // routes overlapping another tunnel go to a routing table of their own if requested, it is looked up
+    // before the main table so that the tunnel routes take precedence over the ones of the other tunnel
+    async fn check_tunnel_overlaps(&mut self) -> Vec<String> {
+        self.overlap_table = None;
+
         let mut subnets = self.tunnel_params.add_routes.clone();
+        subnets.extend(&self.runtime_routes);
+        subnets.extend(&self.subnets);
+
+        let overlaps = platform::find_tunnel_overlaps(&subnets, &self.name).await;
+
+        // with the default route the table would capture the traffic to the gateway as well
+        let isolate = self.tunnel_params.isolate_overlaps
+            && self.tunnel_params.routing_table().is_none()
+            && !self.tunnel_params.default_route
+            && self.tunnel_params.honor_encryption_domain;
+        if overlaps.is_empty() || !isolate {
+            return overlaps;
+        }
+
+        // the name hash keeps the table stable across reconnects, the next free one is taken on collision
+        let offset = util::fnv1a(self.name.as_bytes()) % TunnelParams::OVERLAP_ROUTING_TABLES;
+        match platform::find_free_table(
+            TunnelParams::OVERLAP_ROUTING_TABLE,
+            TunnelParams::OVERLAP_ROUTING_TABLES,
+            offset,
+        )
+        .await
+        {
+            Some(table) => {
+                warn!("Routes overlap another tunnel, using routing table {}", table);
+                self.overlap_table = Some(table);
+                Vec::new()
+            }
+            None => {
+                warn!("No free routing table to isolate the overlapping routes");
+                overlaps
+            }
+        }
+    }
+
+    async fn setup_routing(&mut self) -> anyhow::Result<()> {
+        let overlaps = self.check_tunnel_overlaps().await;
+
+        let mut subnets = self.tunnel_params.add_routes.clone();
+        subnets.extend(&self.runtime_routes);
+        let table = self.routing_table();
+
+        let local_subnets = if self.tunnel_params.auto_exclude_local {
+            platform::get_local_subnets(&self.name).await.unwrap_or_default()
+        } else {
+            Vec::new()
+        };
 
         debug!("Ignoring acquired routes to {}", self.dest_ip);
 
         if !self.tunnel_params.no_routing {
-            if self.tunnel_params.default_route {
-                let _ = platform::add_default_route(&self.name, self.ipsec_session.address).await;
+            if self.tunnel_params.default_route || !self.tunnel_params.honor_encryption_domain {
+                let _ = platform::add_default_route(&self.name, self.ipsec_session.address, table).await;
             } else {
                 subnets.extend(&self.subnets);
             }
@@ -354,10 +562,56 @@ impl XfrmConfigurator {
 
         subnets.retain(|s| !s.contains(&self.dest_ip));
 
+        // the local LAN stays reachable outside of the tunnel
+        subnets.retain(|s| !local_subnets.iter().any(|local| local.subnet.contains(s)));
+
+        self.route_conflicts = platform::find_route_conflicts(&subnets, &self.name, table).await;
+        self.route_conflicts.extend(overlaps);
+        for conflict in &self.route_conflicts {
+            warn!("Route conflict: {}", conflict);
+        }
+
+        if self.tunnel_params.strict_routes && !self.route_conflicts.is_empty() {
+            return Err(anyhow!("Conflicting routes: {}", self.route_conflicts.join(", ")));
+        }
+
         if !subnets.is_empty() {
-            let _ = platform::add_routes(&subnets, &self.name, self.ipsec_session.address).await;
+            progress::report(format!("Installing {} routes", subnets.len()));
+            let _ = platform::add_routes(&subnets, &self.name, self.ipsec_session.address, table).await;
         }
 
+        self.excluded_subnets = platform::add_exclude_routes(&local_subnets, table).await;
+
+        if let Some(table) = self.overlap_table {
+            platform::add_lookup_rule(table).await?;
+        } else if let Some(table) = table {
+            platform::add_table_rule(self.ipsec_session.address, table).await?;
+
+            if let Some(ref app_routing) = self.tunnel_params.app_routing {
+                platform::add_app_routing(app_routing, &self.name, table).await?;
+            }
+
+            if !self.tunnel_params.tunnel_ports.is_empty() {
+                platform::add_port_routing(&self.tunnel_params.tunnel_ports, &self.name, table).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn delete_policy_routing(&self) {
+        let Some(table) = self.routing_table() else {
+            return;
+        };
+        if let Some(ref app_routing) = self.tunnel_params.app_routing {
+            platform::delete_app_routing(app_routing, &self.name, table).await;
+        }
+        if !self.tunnel_params.tunnel_ports.is_empty() {
+            platform::delete_port_routing(&self.name, table).await;
+        }
+    }
+
+    async fn setup_keepalive_routing(&self) -> anyhow::Result<()> {
         let port = TunnelParams::IPSEC_KEEPALIVE_PORT.to_string();
         let dst = self.dest_ip.to_string();
 
@@ -372,7 +626,7 @@ impl XfrmConfigurator {
         Ok(())
     }
 
-    async fn setup_dns(&self) -> anyhow::Result<()> {
+    async fn setup_dns(&mut self) -> anyhow::Result<()> {
         if !self.tunnel_params.no_dns {
             debug!("Adding acquired DNS suffixes: {:?}", self.ipsec_session.domains);
             debug!("Adding provided DNS suffixes: {:?}", self.tunnel_params.search_domains);
@@ -380,40 +634,182 @@ impl XfrmConfigurator {
                 .ipsec_session
                 .domains
                 .iter()
-                .map(|s| s.as_str())
-                .chain(self.tunnel_params.search_domains.iter().map(|s| s.as_ref()))
+                .chain(self.tunnel_params.search_domains.iter())
                 .filter(|&s| {
                     !self
                         .tunnel_params
                         .ignore_search_domains
                         .iter()
                         .any(|d| d.to_lowercase() == s.to_lowercase())
-                });
-            let _ = platform::add_dns_suffixes(suffixes, &self.name).await;
-
-            let servers = self.ipsec_session.dns.iter().map(|server| server.to_string());
-            let _ = platform::add_dns_servers(servers, &self.name).await;
+                })
+                .cloned()
+                .collect::<Vec<_>>();
+
+            // forced tunnel DNS takes precedence over the embedded forwarder unless it is resolved over HTTPS
+            if dns::use_forwarder(&self.tunnel_params) {
+                let split_domains = if self.tunnel_params.dns_split_domains.is_empty() {
+                    suffixes
+                } else {
+                    self.tunnel_params.dns_split_domains.clone()
+                };
+                let forwarder =
+                    dns::start_forwarder(&self.tunnel_params, self.ipsec_session.dns.clone(), split_domains).await?;
+                self.dns_forwarder = Some(forwarder);
+                let _ = platform::set_dns_forwarder(dns::FORWARDER_ADDRESS, &self.name, self.tunnel_params.dns_manager)
+                    .await;
+            } else {
+                if self.tunnel_params.force_tunnel_dns {
+                    debug!("Routing all DNS queries through the tunnel");
+                    let _ = platform::set_default_dns_route(suffixes, &self.name, self.tunnel_params.dns_manager).await;
+                } else {
+                    let _ = platform::add_dns_suffixes(suffixes, &self.name, self.tunnel_params.dns_manager).await;
+                }
+
+                progress::report(format!("Configuring {} DNS servers", self.ipsec_session.dns.len()));
+                let servers = self.ipsec_session.dns.iter().map(|server| server.to_string());
+                let _ = platform::add_dns_servers(servers, &self.name, self.tunnel_params.dns_manager).await;
+            }
         }
         Ok(())
     }
+
+    async fn remove_configuration(&mut self, delete_link: bool) {
+        // dropping the forwarder stops it, the systemd-resolved settings go away together with the link
+        self.dns_forwarder = None;
+        let _ = platform::revert_dns(&self.name, self.tunnel_params.dns_manager).await;
+
+        if self.tunnel_params.flush_dns_on_disconnect {
+            platform::flush_dns_cache().await;
+        }
+
+        let _ = self
+            .configure_xfrm_state(
+                CommandType::Delete,
+                self.source_ip,
+                self.dest_ip,
+                &self.ipsec_session.esp_out,
+            )
+            .await;
+
+        let _ = self
+            .configure_xfrm_state(
+                CommandType::Delete,
+                self.dest_ip,
+                self.source_ip,
+                &self.ipsec_session.esp_in,
+            )
+            .await;
+
+        let _ = self
+            .configure_xfrm_policy(CommandType::Delete, PolicyDir::Out, self.source_ip, self.dest_ip)
+            .await;
+
+        let _ = self
+            .configure_xfrm_policy(CommandType::Delete, PolicyDir::In, self.dest_ip, self.source_ip)
+            .await;
+
+        for route in std::mem::take(&mut self.runtime_routes) {
+            let _ = platform::delete_route(route, &self.name, self.routing_table()).await;
+        }
+
+        if delete_link {
+            let _ = self.new_xfrm_link().delete().await;
+        }
+
+        let dst = self.dest_ip.to_string();
+        let port = TunnelParams::IPSEC_KEEPALIVE_PORT.to_string();
+
+        let _ = iproute2(&[
+            "rule", "del", "to", &dst, "ipproto", "udp", "dport", &port, "table", &port,
+        ])
+        .await;
+
+        self.delete_policy_routing().await;
+
+        let _ = self.delete_table_rule().await;
+
+        let excluded_subnets = std::mem::take(&mut self.excluded_subnets);
+        platform::delete_exclude_routes(&excluded_subnets, self.routing_table()).await;
+        self.overlap_table = None;
+    }
 }
 
 #[async_trait::async_trait]
 impl IpsecConfigurator for XfrmConfigurator {
     async fn configure(&mut self) -> anyhow::Result<()> {
-        self.source_ip = platform::get_default_ip().await?.parse()?;
+        self.source_ip = platform::get_source_ip(&self.tunnel_params).await?;
         debug!("Source IP: {}", self.source_ip);
         debug!("Target IP: {}", self.dest_ip);
 
-        self.cleanup().await;
-        self.setup_xfrm_link().await?;
+        let reuse = self.check_existing_link().await?;
+
+        self.remove_configuration(!reuse).await;
+        self.setup_xfrm_link(reuse).await?;
         self.setup_xfrm_state_and_policies().await?;
+        self.setup_routing().await?;
+        self.setup_keepalive_routing().await?;
+        self.setup_dns().await?;
+
+        Ok(())
+    }
+
+    async fn suspend(&mut self) -> anyhow::Result<()> {
+        debug!("Withdrawing routes and DNS settings from {}", self.name);
+
+        self.dns_forwarder = None;
+        let _ = platform::revert_dns(&self.name, self.tunnel_params.dns_manager).await;
+
+        self.delete_policy_routing().await;
+
+        match self.routing_table() {
+            Some(_) => self.delete_table_rule().await?,
+            None => platform::flush_device_routes(&self.name).await?,
+        }
+
+        let excluded_subnets = std::mem::take(&mut self.excluded_subnets);
+        platform::delete_exclude_routes(&excluded_subnets, self.routing_table()).await;
+
+        Ok(())
+    }
+
+    async fn resume(&mut self) -> anyhow::Result<()> {
+        debug!("Restoring routes and DNS settings for {}", self.name);
+
         self.setup_routing().await?;
         self.setup_dns().await?;
 
         Ok(())
     }
 
+    fn route_conflicts(&self) -> Vec<String> {
+        self.route_conflicts.clone()
+    }
+
+    async fn add_route(&mut self, route: Ipv4Net) -> anyhow::Result<()> {
+        if self.runtime_routes.contains(&route) {
+            return Ok(());
+        }
+        let table = self.routing_table();
+        platform::add_route(route, &self.name, self.ipsec_session.address, table).await?;
+        self.runtime_routes.push(route);
+        Ok(())
+    }
+
+    async fn remove_route(&mut self, route: Ipv4Net) -> anyhow::Result<()> {
+        let Some(index) = self.runtime_routes.iter().position(|r| *r == route) else {
+            return Err(anyhow!("Route {} was not added at runtime!", route));
+        };
+        platform::delete_route(route, &self.name, self.routing_table()).await?;
+        self.runtime_routes.remove(index);
+        Ok(())
+    }
+
+    async fn esp_usage(&self) -> anyhow::Result<EspUsage> {
+        self.new_xfrm_state(self.source_ip, self.dest_ip, &self.ipsec_session.esp_out)
+            .usage()
+            .await
+    }
+
     async fn rekey(&mut self, session: &IpsecSession) -> anyhow::Result<()> {
         trace!(
             "Rekeying XFRM state with new session: IN: {:?}, OUT: {:?}",
@@ -459,40 +855,51 @@ impl IpsecConfigurator for XfrmConfigurator {
     }
 
     async fn cleanup(&mut self) {
-        let _ = self
-            .configure_xfrm_state(
-                CommandType::Delete,
-                self.source_ip,
-                self.dest_ip,
-                &self.ipsec_session.esp_out,
-            )
-            .await;
-
-        let _ = self
-            .configure_xfrm_state(
-                CommandType::Delete,
-                self.dest_ip,
-                self.source_ip,
-                &self.ipsec_session.esp_in,
-            )
-            .await;
-
-        let _ = self
-            .configure_xfrm_policy(CommandType::Delete, PolicyDir::Out, self.source_ip, self.dest_ip)
-            .await;
-
-        let _ = self
-            .configure_xfrm_policy(CommandType::Delete, PolicyDir::In, self.dest_ip, self.source_ip)
-            .await;
+        self.remove_configuration(true).await;
+    }
+}
 
-        let _ = self.new_xfrm_link().delete().await;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_esp_usage() {
+        let output = r#"src 192.168.1.23 dst 203.0.113.10
+	proto esp spi 0x1a2b3c4d reqid 0 mode tunnel
+	replay-window 0 seq 0x00000000 flag af-unspec (0x00100000)
+	auth-trunc hmac(sha256) 0x00 128
+	enc cbc(aes) 0x00
+	encap type espinudp sport 40000 dport 4500 addr 0.0.0.0
+	anti-replay context: seq 0x0, oseq 0x1f4, bitmap 0x00000000
+	if_id 0x2a
+	lifetime config:
+	  limit: soft (INF)(bytes), hard (INF)(bytes)
+	  limit: soft (INF)(packets), hard (INF)(packets)
+	lifetime current:
+	  61440(bytes), 500(packets)
+	  add 2024-05-01 10:00:00 use 2024-05-01 10:00:01
+	stats:
+	  replay-window 0 replay 0 failed 0"#;
+
+        assert_eq!(
+            parse_esp_usage(output),
+            Some(EspUsage {
+                sequence: 500,
+                bytes: 61440
+            })
+        );
+        assert_eq!(parse_esp_usage("src 1.2.3.4 dst 5.6.7.8"), None);
+    }
 
-        let dst = self.dest_ip.to_string();
-        let port = TunnelParams::IPSEC_KEEPALIVE_PORT.to_string();
+    #[test]
+    fn test_parse_xfrm_if_id() {
+        let output = r#"12: snx-xfrm@NONE: <NOARP,UP,LOWER_UP> mtu 1350 qdisc noqueue state UNKNOWN mode DEFAULT group default qlen 1000
+    link/none  promiscuity 0 allmulti 0 minmtu 68 maxmtu 65535
+    xfrm if_id 0x2a addrgenmode eui64 numtxqueues 1 numrxqueues 1"#;
+        assert_eq!(parse_xfrm_if_id(output), Some(42));
 
-        let _ = iproute2(&[
-            "rule", "del", "to", &dst, "ipproto", "udp", "dport", &port, "table", &port,
-        ])
-        .await;
+        let output = "3: snx-tun: <POINTOPOINT,UP> mtu 1500\n    tun type tun pi off vnet_hdr off persist on";
+        assert_eq!(parse_xfrm_if_id(output), None);
     }
 }
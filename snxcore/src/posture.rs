@@ -0,0 +1,146 @@
+use std::path::{Path, PathBuf};
+
+use tracing::{debug, warn};
+
+use crate::model::{
+    params::TunnelParams,
+    proto::{ClientLoggingData, ServerInfoResponse},
+};
+
+const OS_RELEASE: &str = "/etc/os-release";
+
+/// Endpoint compliance facts submitted to the gateway during authentication
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PostureInfo {
+    pub os_name: Option<String>,
+    pub os_version: Option<String>,
+    pub machine_name: Option<String>,
+    pub hardware_model: Option<String>,
+    pub is_compliant: Option<String>,
+}
+
+#[async_trait::async_trait]
+pub trait PostureCollector {
+    async fn collect(&self, info: &mut PostureInfo) -> anyhow::Result<()>;
+}
+
+/// Collects basic facts about the host: kernel name and version, host name and OS distribution
+pub struct HostPostureCollector;
+
+#[async_trait::async_trait]
+impl PostureCollector for HostPostureCollector {
+    async fn collect(&self, info: &mut PostureInfo) -> anyhow::Result<()> {
+        let read = |path: &str| std::fs::read_to_string(path).ok().map(|s| s.trim().to_owned());
+
+        info.os_name = read("/proc/sys/kernel/ostype");
+        info.os_version = read("/proc/sys/kernel/osrelease");
+        info.machine_name = read("/proc/sys/kernel/hostname");
+        info.hardware_model = read(OS_RELEASE).and_then(|data| {
+            data.lines()
+                .find_map(|line| line.strip_prefix("PRETTY_NAME="))
+                .map(|s| s.trim_matches('"').to_owned())
+        });
+
+        Ok(())
+    }
+}
+
+/// Runs a user-provided script which prints `key=value` lines overriding the collected facts
+pub struct ScriptPostureCollector {
+    path: PathBuf,
+}
+
+impl ScriptPostureCollector {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_owned(),
+        }
+    }
+}
+
+fn parse_script_output(output: &str, info: &mut PostureInfo) {
+    for (k, v) in output.lines().filter_map(|line| line.split_once('=')) {
+        let v = Some(v.trim().to_owned());
+        match k.trim() {
+            "os-name" => info.os_name = v,
+            "os-version" => info.os_version = v,
+            "machine-name" => info.machine_name = v,
+            "hardware-model" => info.hardware_model = v,
+            "is-compliant" => info.is_compliant = v,
+            other => warn!("Ignoring unknown posture key: {}", other),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl PostureCollector for ScriptPostureCollector {
+    async fn collect(&self, info: &mut PostureInfo) -> anyhow::Result<()> {
+        debug!("Running posture script: {}", self.path.display());
+        let output = crate::util::run_command(&self.path, Vec::<&str>::new()).await?;
+        parse_script_output(&output, info);
+        Ok(())
+    }
+}
+
+// returns None if the gateway does not request endpoint compliance data
+pub async fn collect(params: &TunnelParams, server_info: Option<&ServerInfoResponse>) -> Option<PostureInfo> {
+    if !server_info.is_some_and(|info| info.end_point_security.ics.run_ics) {
+        debug!("Endpoint compliance data is not requested by the gateway");
+        return None;
+    }
+
+    let mut collectors: Vec<Box<dyn PostureCollector + Send + Sync>> = vec![Box::new(HostPostureCollector)];
+    if let Some(ref script) = params.posture_script {
+        collectors.push(Box::new(ScriptPostureCollector::new(script)));
+    }
+
+    let mut info = PostureInfo::default();
+    for collector in collectors {
+        if let Err(e) = collector.collect(&mut info).await {
+            warn!("Posture collection failed: {}", e);
+        }
+    }
+
+    debug!("Collected posture data: {:?}", info);
+
+    Some(info)
+}
+
+pub fn client_logging_data(posture: Option<&PostureInfo>) -> ClientLoggingData {
+    let mut data = ClientLoggingData {
+        os_name: Some("Windows".to_owned()),
+        device_id: Some(crate::util::get_device_id().into()),
+        ..Default::default()
+    };
+
+    // the compliance data describes the actual host
+    if let Some(posture) = posture {
+        data.os_name.clone_from(&posture.os_name);
+        data.os_version.clone_from(&posture.os_version);
+        data.machine_name.clone_from(&posture.machine_name);
+        data.hardware_model.clone_from(&posture.hardware_model);
+        data.is_compliant.clone_from(&posture.is_compliant);
+    }
+
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_script_output() {
+        let mut info = PostureInfo {
+            os_version: Some("6.1.0".to_owned()),
+            machine_name: Some("host".to_owned()),
+            ..Default::default()
+        };
+
+        parse_script_output("os-version = 6.8.0\nis-compliant=true\ngarbage\n", &mut info);
+
+        assert_eq!(info.os_version.as_deref(), Some("6.8.0"));
+        assert_eq!(info.machine_name.as_deref(), Some("host"));
+        assert_eq!(info.is_compliant.as_deref(), Some("true"));
+    }
+}
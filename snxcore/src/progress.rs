@@ -0,0 +1,66 @@
+use std::collections::VecDeque;
+
+use chrono::Local;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use tokio::sync::broadcast;
+use tracing::debug;
+
+use crate::model::ProgressEvent;
+
+// slow subscribers lose the oldest events instead of holding up the connection
+const CHANNEL_CAPACITY: usize = 64;
+const LOG_SIZE: usize = 100;
+
+struct ProgressChannel {
+    sender: broadcast::Sender<ProgressEvent>,
+    log: Mutex<VecDeque<ProgressEvent>>,
+}
+
+static PROGRESS: Lazy<ProgressChannel> = Lazy::new(|| ProgressChannel {
+    sender: broadcast::channel(CHANNEL_CAPACITY).0,
+    log: Mutex::new(VecDeque::with_capacity(LOG_SIZE)),
+});
+
+// the event goes both to the connection log and to the current subscribers
+pub(crate) fn report<S: Into<String>>(message: S) {
+    let event = ProgressEvent {
+        time: Local::now(),
+        message: message.into(),
+    };
+    debug!("Progress: {}", event.message);
+
+    // the lock keeps the log and the channel in the same order for the subscribe call
+    let mut log = PROGRESS.log.lock();
+    if log.len() == LOG_SIZE {
+        log.pop_front();
+    }
+    log.push_back(event.clone());
+    let _ = PROGRESS.sender.send(event);
+}
+
+// a new connection attempt starts with an empty log
+pub(crate) fn clear() {
+    PROGRESS.log.lock().clear();
+}
+
+/// Events of the current connection so far and the receiver for the following ones
+pub fn subscribe() -> (Vec<ProgressEvent>, broadcast::Receiver<ProgressEvent>) {
+    let log = PROGRESS.log.lock();
+    (log.iter().cloned().collect(), PROGRESS.sender.subscribe())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subscribe() {
+        report("first");
+        let (log, mut receiver) = subscribe();
+        report("second");
+
+        assert_eq!(log.last().map(|e| e.message.as_str()), Some("first"));
+        assert_eq!(receiver.try_recv().unwrap().message, "second");
+    }
+}
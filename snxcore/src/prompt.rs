@@ -1,17 +1,159 @@
 use std::{
-    io::{stderr, stdin, IsTerminal},
+    collections::{BTreeMap, VecDeque},
+    io::{stderr, stdin, BufRead, IsTerminal, Write},
+    os::unix::fs::FileTypeExt,
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
     time::Duration,
 };
 
 use anyhow::anyhow;
+use parking_lot::Mutex;
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    net::unix::pipe,
+};
+
+use crate::model::{MfaChallenge, MfaType};
 
 pub const OTP_TIMEOUT: Duration = Duration::from_secs(120);
 
 pub trait SecurePrompt {
     fn get_secure_input(&self, prompt: &str) -> anyhow::Result<String>;
+
+    fn get_plain_input(&self, prompt: &str) -> anyhow::Result<String> {
+        self.get_secure_input(prompt)
+    }
+
     fn show_notification(&self, summary: &str, message: &str) -> anyhow::Result<()>;
 }
 
+/// Read the next non-empty line written into the named pipe by an external OTP device.
+/// Returns None if the path is not a named pipe.
+pub async fn read_otp_pipe(path: &Path, timeout: Duration) -> anyhow::Result<Option<String>> {
+    if !std::fs::metadata(path).is_ok_and(|meta| meta.file_type().is_fifo()) {
+        return Ok(None);
+    }
+
+    // the read-write mode keeps the pipe open while no writer is attached
+    let receiver = pipe::OpenOptions::new().read_write(true).open_receiver(path)?;
+    let mut lines = BufReader::new(receiver).lines();
+
+    let read = async {
+        while let Some(line) = lines.next_line().await? {
+            let code = line.trim();
+            if !code.is_empty() {
+                return Ok(Some(code.to_owned()));
+            }
+        }
+        Err(anyhow!("OTP pipe {} closed!", path.display()))
+    };
+
+    tokio::time::timeout(timeout, read)
+        .await
+        .map_err(|_| anyhow!("Timeout while waiting for the OTP from {}!", path.display()))?
+}
+
+// supplies MFA answers programmatically, e.g. from a secrets vault, instead of asking the user
+#[async_trait::async_trait]
+pub trait MfaProvider {
+    async fn answer(&self, challenge: &MfaChallenge) -> anyhow::Result<String>;
+}
+
+/// Answers the password-style challenges in order from a list, for scripted logins with several prompts
+pub struct ScriptedAnswers {
+    answers: Mutex<VecDeque<String>>,
+}
+
+impl ScriptedAnswers {
+    pub fn new<I, S>(answers: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            answers: Mutex::new(answers.into_iter().map(Into::into).collect()),
+        }
+    }
+
+    // one answer per line, matched positionally to the server prompts
+    pub fn from_reader<R: BufRead>(reader: R) -> anyhow::Result<Self> {
+        Ok(Self::new(reader.lines().collect::<Result<Vec<_>, _>>()?))
+    }
+}
+
+#[async_trait::async_trait]
+impl MfaProvider for ScriptedAnswers {
+    async fn answer(&self, challenge: &MfaChallenge) -> anyhow::Result<String> {
+        check_scripted(challenge)?;
+        self.answers
+            .lock()
+            .pop_front()
+            .ok_or_else(|| anyhow!("No answer provided for the prompt: {}", challenge.prompt.trim()))
+    }
+}
+
+// only the password-style challenges can be answered by the scripted providers
+fn check_scripted(challenge: &MfaChallenge) -> anyhow::Result<()> {
+    match challenge.mfa_type {
+        MfaType::UserInput => Ok(()),
+        MfaType::SamlSso => Err(anyhow!(
+            "Browser-based authentication cannot be answered non-interactively!"
+        )),
+        MfaType::PasswordChange => Err(anyhow!(
+            "The gateway requires a password change, please connect interactively first!"
+        )),
+    }
+}
+
+/// Answers the password-style challenges from a JSON object keyed by the prompt text or the 1-based prompt index
+pub struct PromptAnswers {
+    answers: BTreeMap<String, String>,
+    index: AtomicUsize,
+}
+
+impl PromptAnswers {
+    pub fn new(answers: BTreeMap<String, String>) -> Self {
+        Self {
+            answers,
+            index: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        Ok(Self::new(serde_json::from_slice(&std::fs::read(path)?)?))
+    }
+
+    // prompts are compared without the surrounding whitespace and the trailing colon
+    fn lookup(&self, prompt: &str, index: usize) -> Option<String> {
+        let normalize = |s: &str| s.trim().trim_end_matches(':').trim().to_lowercase();
+        let prompt = normalize(prompt);
+        self.answers
+            .iter()
+            .find(|(k, _)| normalize(k) == prompt)
+            .or_else(|| self.answers.get_key_value(&index.to_string()))
+            .map(|(_, v)| v.clone())
+    }
+}
+
+#[async_trait::async_trait]
+impl MfaProvider for PromptAnswers {
+    async fn answer(&self, challenge: &MfaChallenge) -> anyhow::Result<String> {
+        check_scripted(challenge)?;
+
+        let index = self.index.fetch_add(1, Ordering::SeqCst) + 1;
+
+        match self.lookup(&challenge.prompt, index) {
+            Some(answer) => Ok(answer),
+            None if stdin().is_terminal() && stderr().is_terminal() => TtyPrompt.get_secure_input(&challenge.prompt),
+            None => Err(anyhow!(
+                "No answer provided for the prompt: {}",
+                challenge.prompt.trim()
+            )),
+        }
+    }
+}
+
 pub struct TtyPrompt;
 
 impl SecurePrompt for TtyPrompt {
@@ -23,8 +165,79 @@ impl SecurePrompt for TtyPrompt {
         }
     }
 
+    fn get_plain_input(&self, prompt: &str) -> anyhow::Result<String> {
+        if stdin().is_terminal() && stderr().is_terminal() {
+            eprint!("{}", prompt);
+            stderr().flush()?;
+            let mut input = String::new();
+            stdin().read_line(&mut input)?;
+            Ok(input.trim().to_owned())
+        } else {
+            Err(anyhow!("No attached TTY to get user input!"))
+        }
+    }
+
     fn show_notification(&self, summary: &str, message: &str) -> anyhow::Result<()> {
         println!("{}: {}", summary, message);
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn challenge(mfa_type: MfaType) -> MfaChallenge {
+        MfaChallenge {
+            mfa_type,
+            prompt: "Password:".to_owned(),
+            delivery: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_check_scripted() {
+        let answers = ScriptedAnswers::new(["secret"]);
+
+        assert!(answers.answer(&challenge(MfaType::PasswordChange)).await.is_err());
+        assert!(answers.answer(&challenge(MfaType::SamlSso)).await.is_err());
+
+        // the rejected challenges do not consume the answers
+        assert_eq!(answers.answer(&challenge(MfaType::UserInput)).await.unwrap(), "secret");
+        assert!(answers.answer(&challenge(MfaType::UserInput)).await.is_err());
+
+        let answers = PromptAnswers::new(BTreeMap::from([("Password".to_owned(), "secret".to_owned())]));
+        assert!(answers.answer(&challenge(MfaType::PasswordChange)).await.is_err());
+    }
+
+    #[test]
+    fn test_prompt_answers_lookup() {
+        let answers = PromptAnswers::new(BTreeMap::from([
+            ("Password".to_owned(), "secret".to_owned()),
+            ("2".to_owned(), "123456".to_owned()),
+        ]));
+
+        assert_eq!(answers.lookup("password: ", 1).as_deref(), Some("secret"));
+        assert_eq!(answers.lookup("Enter the code:", 2).as_deref(), Some("123456"));
+        assert_eq!(answers.lookup("Enter the code:", 3), None);
+    }
+
+    #[tokio::test]
+    async fn test_read_otp_pipe() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("otp");
+        let timeout = Duration::from_secs(5);
+
+        assert_eq!(read_otp_pipe(&path, timeout).await.unwrap(), None);
+
+        nix::unistd::mkfifo(&path, nix::sys::stat::Mode::S_IRWXU).unwrap();
+
+        let writer_path = path.clone();
+        let writer = tokio::task::spawn_blocking(move || std::fs::write(writer_path, "\n 123456 \n"));
+
+        let code = read_otp_pipe(&path, timeout).await;
+        let _ = writer.await;
+
+        assert_eq!(code.unwrap().as_deref(), Some("123456"));
+    }
+}
@@ -0,0 +1,107 @@
+use std::{borrow::Cow, io, sync::Arc};
+
+use regex::Regex;
+use tracing::warn;
+
+// always redacted, a name also matches the fields ending with it, e.g. session_id matches auth_session_id
+const SENSITIVE_FIELDS: &[&str] = &[
+    "password",
+    "user_input",
+    "active_key",
+    "session_id",
+    "cookie",
+    "token",
+    "otp",
+    "secret",
+];
+
+const REDACTED: &str = "***";
+
+/// Scrubs the values of the sensitive fields from the log output
+pub struct Redactor {
+    regex: Regex,
+}
+
+impl Redactor {
+    // extra entries are regular expressions for the field names, invalid ones are skipped
+    pub fn new(extra_fields: &[String]) -> Self {
+        let names = SENSITIVE_FIELDS
+            .iter()
+            // the underscore matches a space as well, for "session id: ..." in the log messages
+            .map(|name| regex::escape(name).replace('_', "[_ ]"))
+            .chain(extra_fields.iter().filter_map(|name| match Regex::new(name) {
+                Ok(_) => Some(format!("(?:{})", name)),
+                Err(e) => {
+                    warn!("Ignoring invalid redaction pattern {}: {}", name, e);
+                    None
+                }
+            }))
+            .collect::<Vec<_>>()
+            .join("|");
+
+        // the value follows a colon, an equal sign or an opening parenthesis, possibly wrapped in
+        // type names from the debug output, such as Some(EncryptedString("..."))
+        let pattern = format!(
+            r#"(?i)((?:{})"?\s*(?:[:=]|\()\s*(?:\w+\()*)("[^"]*"|[^\s"'(),;&]+)"#,
+            names
+        );
+
+        Self {
+            regex: Regex::new(&pattern).expect("Invalid redaction pattern!"),
+        }
+    }
+
+    pub fn redact<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        self.regex.replace_all(text, format!("${{1}}{}", REDACTED))
+    }
+
+    pub fn writer<W: io::Write>(self: &Arc<Self>, inner: W) -> RedactingWriter<W> {
+        RedactingWriter {
+            inner,
+            redactor: self.clone(),
+        }
+    }
+}
+
+/// Log writer which redacts each formatted event before passing it on
+pub struct RedactingWriter<W> {
+    inner: W,
+    redactor: Arc<Redactor>,
+}
+
+impl<W: io::Write> io::Write for RedactingWriter<W> {
+    // the log formatter writes each event with a single call
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let text = String::from_utf8_lossy(buf);
+        self.inner.write_all(self.redactor.redact(&text).as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        let redactor = Redactor::new(&["realm".to_owned()]);
+
+        assert_eq!(
+            redactor.redact(":username (36203a)\n:password (36203a333d372a59)"),
+            ":username (36203a)\n:password (***)"
+        );
+        assert_eq!(
+            redactor.redact(r#"AuthRequest { password: Some(EncryptedString("my secret")), realm: "x" }"#),
+            r#"AuthRequest { password: Some(EncryptedString(***)), realm: *** }"#
+        );
+        assert_eq!(
+            redactor.redact("Authentication OK, session id: 1234, auth_session_id=5678"),
+            "Authentication OK, session id: ***, auth_session_id=***"
+        );
+        assert_eq!(redactor.redact("Sending keepalive"), "Sending keepalive");
+    }
+}
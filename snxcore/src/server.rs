@@ -1,98 +1,762 @@
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use anyhow::anyhow;
+use chrono::Local;
 use futures::pin_mut;
-use tokio::sync::mpsc;
-use tracing::{debug, trace, warn};
+use ipnet::Ipv4Net;
+use tokio::{
+    net::UdpSocket,
+    sync::{broadcast::error::RecvError, mpsc},
+    task::JoinHandle,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info_span, trace, warn, Instrument, Span};
 
 use crate::{
+    bench,
+    ccc::{self, CccHttpClient},
+    certs, ddns,
+    http_api::HttpApi,
+    ipc::{self, ResponseFrame, StreamRequest},
     model::{
-        params::TunnelParams, ConnectionStatus, SessionState, TunnelServiceRequest, TunnelServiceResponse, VpnSession,
+        params::{TunnelParams, TunnelType},
+        BenchmarkResult, ConnectionStatus, MfaType, PhaseTiming, ServiceVersion, SessionState, TrafficUsage,
+        TunnelServiceRequest, TunnelServiceResponse, TunnelStats, VpnSession,
     },
-    tunnel::{self, TunnelConnector, TunnelEvent},
+    platform, progress, timing,
+    tunnel::{self, AuthenticationFailed, ChallengeRejected, GatewayBusy, TunnelConnector, TunnelEvent},
 };
 
 pub const LISTEN_PORT: u16 = 7779;
 
+const INSTANCE_PORT_RANGE: u32 = 1000;
+
 const MAX_PACKET_SIZE: usize = 1_000_000;
+const USAGE_BUCKETS: usize = 24 * 60;
+const USAGE_BUCKET_DURATION: Duration = Duration::from_secs(60);
+const USAGE_SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+const MIN_STANDBY_REFRESH: Duration = Duration::from_secs(60);
+const SESSION_END_WARNING: Duration = Duration::from_secs(300);
+const BUSY_RETRY_DELAY: Duration = Duration::from_secs(5);
+const MAX_BUSY_RETRY_DELAY: Duration = Duration::from_secs(60);
+// the retries must end before the controller gives up waiting for the connect reply
+const MAX_BUSY_RETRY_TIME: Duration = Duration::from_secs(90);
+// nobody waits for the automatic reconnect, the interval suggested by the gateway is honored up to this
+const MAX_SUGGESTED_RETRY_DELAY: Duration = Duration::from_secs(600);
+const CANCEL_CLEANUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+// In-memory cache of MFA answers keyed by challenge prompt, never persisted
+#[derive(Default)]
+struct MfaCache {
+    entries: HashMap<String, (String, Instant)>,
+}
+
+impl MfaCache {
+    fn insert(&mut self, prompt: &str, answer: &str, ttl: Duration) {
+        if !ttl.is_zero() {
+            self.entries
+                .insert(prompt.to_owned(), (answer.to_owned(), Instant::now() + ttl));
+        }
+    }
+
+    fn get(&mut self, prompt: &str) -> Option<String> {
+        let now = Instant::now();
+        self.entries.retain(|_, (_, expires)| *expires > now);
+        self.entries.get(prompt).map(|(answer, _)| answer.clone())
+    }
+
+    fn remove(&mut self, prompt: &str) {
+        self.entries.remove(prompt);
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+// Consecutive authentication failures keyed by server and user, a cooldown starts once the limit is reached
+#[derive(Default)]
+struct AuthFailures {
+    entries: HashMap<String, (u32, Option<Instant>)>,
+}
+
+impl AuthFailures {
+    fn key(params: &TunnelParams) -> String {
+        format!("{}/{}", params.server_name, params.user_name)
+    }
+
+    fn check(&mut self, params: &TunnelParams) -> anyhow::Result<()> {
+        let key = Self::key(params);
+        if params.ignore_cooldown {
+            self.entries.remove(&key);
+            return Ok(());
+        }
+        match self.entries.get(&key) {
+            Some((_, Some(until))) if *until > Instant::now() => Err(anyhow!(
+                "Cooldown active after repeated authentication failures, retry in {} seconds",
+                until.duration_since(Instant::now()).as_secs() + 1
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn failed(&mut self, params: &TunnelParams) {
+        if params.auth_failure_limit == 0 {
+            return;
+        }
+        let entry = self.entries.entry(Self::key(params)).or_default();
+        // a failure after an expired cooldown starts a new one immediately
+        entry.0 += 1;
+        if entry.0 >= params.auth_failure_limit {
+            warn!(
+                "{} consecutive authentication failures, refusing to connect for {} seconds",
+                entry.0,
+                params.auth_cooldown.as_secs()
+            );
+            entry.1 = Some(Instant::now() + params.auth_cooldown);
+        }
+    }
+
+    fn succeeded(&mut self, params: &TunnelParams) {
+        self.entries.remove(&Self::key(params));
+    }
+}
+
+/// Options which make the service run programs or read files as root. They are taken from the service
+/// configuration only, the values sent by the clients are ignored.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PrivilegedOptions {
+    posture_script: Option<PathBuf>,
+    ddns_key: Option<PathBuf>,
+}
+
+impl PrivilegedOptions {
+    pub fn from_params(params: &TunnelParams) -> Self {
+        Self {
+            posture_script: params.posture_script.clone(),
+            ddns_key: params.ddns_key.clone(),
+        }
+    }
+
+    fn replace(name: &str, value: &mut Option<PathBuf>, service_value: &Option<PathBuf>) {
+        if value.is_some() && value != service_value {
+            warn!(
+                "Ignoring the {} option of the client, it is only read from the service configuration",
+                name
+            );
+        }
+        value.clone_from(service_value);
+    }
+
+    fn apply(&self, params: &mut TunnelParams) {
+        Self::replace("posture-script", &mut params.posture_script, &self.posture_script);
+        Self::replace("ddns-key", &mut params.ddns_key, &self.ddns_key);
+    }
+}
+
+fn is_auth_failure(error: &anyhow::Error) -> bool {
+    error.is::<AuthenticationFailed>() || error.is::<ChallengeRejected>()
+}
+
+// doubles with every attempt
+fn busy_retry_delay(attempt: u32) -> Duration {
+    BUSY_RETRY_DELAY
+        .saturating_mul(1 << attempt.min(16))
+        .min(MAX_BUSY_RETRY_DELAY)
+}
+
+// the interval suggested by the gateway takes precedence, no retry is made past the time budget
+// counted from the first attempt
+fn busy_retry_wait(attempt: u32, suggested: Option<Duration>, waited: Duration) -> Option<Duration> {
+    let delay = suggested.unwrap_or_else(|| busy_retry_delay(attempt));
+    (waited.saturating_add(delay) <= MAX_BUSY_RETRY_TIME).then_some(delay)
+}
+
+fn reconnect_delay(attempt: u32, suggested: Option<Duration>) -> Duration {
+    suggested
+        .map(|interval| interval.min(MAX_SUGGESTED_RETRY_DELAY))
+        .unwrap_or_else(|| busy_retry_delay(attempt))
+}
+
+// Rolling traffic usage for the last day, one bucket per minute in a fixed ring buffer
+struct UsageMeter {
+    buckets: Vec<TrafficUsage>,
+    current: usize,
+    bucket_start: Instant,
+    last_received: u64,
+    last_sent: u64,
+}
+
+impl Default for UsageMeter {
+    fn default() -> Self {
+        Self {
+            buckets: vec![TrafficUsage::default(); USAGE_BUCKETS],
+            current: 0,
+            bucket_start: Instant::now(),
+            last_received: 0,
+            last_sent: 0,
+        }
+    }
+}
+
+impl UsageMeter {
+    // takes the cumulative interface counters
+    fn update(&mut self, received: u64, sent: u64) {
+        let elapsed = (self.bucket_start.elapsed().as_secs() / USAGE_BUCKET_DURATION.as_secs()) as usize;
+        for _ in 0..elapsed.min(USAGE_BUCKETS) {
+            self.current = (self.current + 1) % USAGE_BUCKETS;
+            self.buckets[self.current] = TrafficUsage::default();
+        }
+        self.bucket_start += USAGE_BUCKET_DURATION * elapsed as u32;
+
+        // the counters start from zero when the tunnel interface is recreated
+        let delta = |value: u64, last: u64| if value >= last { value - last } else { value };
+
+        let bucket = &mut self.buckets[self.current];
+        bucket.received += delta(received, self.last_received);
+        bucket.sent += delta(sent, self.last_sent);
+
+        self.last_received = received;
+        self.last_sent = sent;
+    }
+
+    fn restart(&mut self) {
+        self.last_received = 0;
+        self.last_sent = 0;
+    }
+
+    fn usage(&self, minutes: usize) -> TrafficUsage {
+        (0..minutes.min(USAGE_BUCKETS))
+            .map(|i| self.buckets[(self.current + USAGE_BUCKETS - i) % USAGE_BUCKETS])
+            .fold(TrafficUsage::default(), |acc, bucket| TrafficUsage {
+                received: acc.received + bucket.received,
+                sent: acc.sent + bucket.sent,
+            })
+    }
+}
+
+// the params have no PartialEq, the serialized form covers all of them except the id of the connect flow
+fn is_same_params(left: &TunnelParams, right: &TunnelParams) -> bool {
+    let right = TunnelParams {
+        correlation_id: left.correlation_id.clone(),
+        ..right.clone()
+    };
+    match (serde_json::to_value(left), serde_json::to_value(right)) {
+        (Ok(left), Ok(right)) => left == right,
+        _ => false,
+    }
+}
+
+// named instances listen on a port derived from the instance name
+pub fn instance_port(instance_name: Option<&str>) -> u16 {
+    match instance_name {
+        Some(name) if !name.is_empty() => {
+            LISTEN_PORT + 1 + (crate::util::fnv1a(name.as_bytes()) % INSTANCE_PORT_RANGE) as u16
+        }
+        _ => LISTEN_PORT,
+    }
+}
+
+// the configured port takes precedence over the one derived from the instance name
+pub fn listen_port(params: &TunnelParams) -> u16 {
+    params
+        .listen_port
+        .unwrap_or_else(|| instance_port(params.instance_name.as_deref()))
+}
+
+// IPv6-only hosts may lack the IPv4 loopback address, ::1 is used there
+pub(crate) async fn bind_loopback(port: u16) -> io::Result<UdpSocket> {
+    match UdpSocket::bind((Ipv4Addr::LOCALHOST, port)).await {
+        Err(e) if e.kind() == io::ErrorKind::AddrNotAvailable => UdpSocket::bind((Ipv6Addr::LOCALHOST, port)).await,
+        result => result,
+    }
+}
+
+async fn recv_packet(socket: &UdpSocket) -> anyhow::Result<(Vec<u8>, SocketAddr)> {
+    let mut buf = vec![0u8; MAX_PACKET_SIZE];
+    let (size, addr) = socket.recv_from(&mut buf).await?;
+    Ok((buf[0..size].to_vec(), addr))
+}
+
+// single-shot datagrams are answered to the sender address, framed requests by their id
+enum ReplyTo {
+    Udp(SocketAddr),
+    Stream(u64, mpsc::Sender<ResponseFrame>),
+}
+
+enum HandleResult {
+    Done(TunnelServiceResponse),
+    Cancelled(ReplyTo),
+}
+
+async fn send_response(socket: &UdpSocket, resp: &TunnelServiceResponse, reply_to: &ReplyTo) -> anyhow::Result<()> {
+    trace!("Response: {:?}", resp);
+    match reply_to {
+        ReplyTo::Udp(addr) => {
+            let json = serde_json::to_vec(resp)?;
+            let _ = socket.send_to(&json, addr).await;
+        }
+        ReplyTo::Stream(id, sender) => {
+            let frame = ResponseFrame {
+                id: *id,
+                response: resp.clone(),
+            };
+            let _ = sender.send(frame).await;
+        }
+    }
+    Ok(())
+}
+
+// next request from either transport, malformed datagrams are answered right away
+async fn recv_request(
+    socket: &UdpSocket,
+    stream_receiver: &mut mpsc::Receiver<StreamRequest>,
+) -> anyhow::Result<(TunnelServiceRequest, ReplyTo)> {
+    loop {
+        tokio::select! {
+            result = recv_packet(socket) => {
+                let (data, addr) = result?;
+                trace!("Command received");
+                match serde_json::from_slice::<TunnelServiceRequest>(&data) {
+                    Ok(req) => return Ok((req, ReplyTo::Udp(addr))),
+                    Err(e) => {
+                        warn!("{}", e);
+                        let resp = TunnelServiceResponse::Error(e.to_string());
+                        send_response(socket, &resp, &ReplyTo::Udp(addr)).await?;
+                    }
+                }
+            }
+            Some(request) = stream_receiver.recv() => {
+                trace!("Framed command received, id: {}", request.frame.id);
+                return Ok((request.frame.request, ReplyTo::Stream(request.frame.id, request.sender)));
+            }
+        }
+    }
+}
+
+// the connect flow is logged with the correlation id generated by the client
+fn request_span(req: &TunnelServiceRequest) -> Span {
+    let params = match req {
+        TunnelServiceRequest::Connect(params)
+        | TunnelServiceRequest::Reconnect(params)
+        | TunnelServiceRequest::ChallengeCode(_, params)
+        | TunnelServiceRequest::AuthTest(params)
+        | TunnelServiceRequest::Prewarm(params) => params,
+        _ => return Span::none(),
+    };
+    match params.correlation_id {
+        Some(ref correlation_id) => info_span!("request", correlation_id = %correlation_id),
+        None => Span::none(),
+    }
+}
+
+// the progress events are sent as responses to the watch request until the connection is closed
+fn start_watch(id: u64, sender: mpsc::Sender<ResponseFrame>) {
+    debug!("Starting progress watch, id: {}", id);
+    let (log, mut receiver) = progress::subscribe();
+
+    let frame = move |event| ResponseFrame {
+        id,
+        response: TunnelServiceResponse::Progress(event),
+    };
+
+    tokio::spawn(async move {
+        for event in log {
+            if sender.send(frame(event)).await.is_err() {
+                return;
+            }
+        }
+
+        loop {
+            match receiver.recv().await {
+                Ok(event) => {
+                    if sender.send(frame(event)).await.is_err() {
+                        break;
+                    }
+                }
+                Err(RecvError::Lagged(count)) => debug!("Progress watch {} skipped {} events", id, count),
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+async fn wait_deadline(deadline: Option<tokio::time::Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => futures::future::pending().await,
+    }
+}
 
 pub struct CommandServer {
     port: u16,
+    socket_path: Option<PathBuf>,
     connection_status: ConnectionStatus,
     session: Option<Arc<VpnSession>>,
+    session_deadline: Option<Instant>,
+    tunnel_task: Option<JoinHandle<()>>,
+    tunnel_detached: bool,
     connector: Option<Box<dyn TunnelConnector + Send>>,
+    mfa_cache: MfaCache,
+    auth_failures: AuthFailures,
+    timings: Vec<PhaseTiming>,
+    cert_expiry: Option<certs::CertExpiry>,
+    busy_retries: u32,
+    suggested_retry_interval: Option<Duration>,
+    params: Arc<TunnelParams>,
+    disconnect_deadline: Option<tokio::time::Instant>,
+    reconnect_deadline: Option<tokio::time::Instant>,
+    reconnect_attempts: u32,
+    session_warning_deadline: Option<tokio::time::Instant>,
+    session_end_deadline: Option<tokio::time::Instant>,
+    reconnect_pending: bool,
+    usage: UsageMeter,
+    auth_test: bool,
+    prewarm: bool,
+    standby_deadline: Option<tokio::time::Instant>,
+    ready_handler: Option<Box<dyn FnOnce() + Send>>,
+    http_api: Option<HttpApi>,
+    privileged: PrivilegedOptions,
+    // aborts the authentication and the tunnel setup of the connection attempt in progress
+    setup_token: CancellationToken,
 }
 
 impl CommandServer {
     pub fn new(port: u16) -> Self {
         Self {
             port,
+            socket_path: None,
             connection_status: ConnectionStatus::default(),
             session: None,
+            session_deadline: None,
+            tunnel_task: None,
+            tunnel_detached: false,
             connector: None,
+            mfa_cache: MfaCache::default(),
+            auth_failures: AuthFailures::default(),
+            timings: Vec::new(),
+            cert_expiry: None,
+            busy_retries: 0,
+            suggested_retry_interval: None,
+            params: Arc::new(TunnelParams::default()),
+            disconnect_deadline: None,
+            reconnect_deadline: None,
+            reconnect_attempts: 0,
+            session_warning_deadline: None,
+            session_end_deadline: None,
+            reconnect_pending: false,
+            usage: UsageMeter::default(),
+            auth_test: false,
+            prewarm: false,
+            standby_deadline: None,
+            ready_handler: None,
+            http_api: None,
+            privileged: PrivilegedOptions::default(),
+            setup_token: CancellationToken::new(),
         }
     }
 
+    // framed requests are accepted on the unix socket in addition to the UDP port
+    pub fn set_socket_path<P: Into<PathBuf>>(&mut self, path: P) {
+        self.socket_path = Some(path.into());
+    }
+
+    // the HTTP API requests are handled like the framed requests from the unix socket
+    pub fn set_http_api(&mut self, api: HttpApi) {
+        self.http_api = Some(api);
+    }
+
+    pub fn set_privileged_options(&mut self, options: PrivilegedOptions) {
+        self.privileged = options;
+    }
+
+    // called once the command port and the IPC socket accept requests
+    pub fn set_ready_handler<F>(&mut self, handler: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.ready_handler = Some(Box::new(handler));
+    }
+
     pub async fn run(mut self) -> anyhow::Result<()> {
         debug!("Starting command server on port {}", self.port);
 
-        let socket = Arc::new(tokio::net::UdpSocket::bind(("127.0.0.1", self.port)).await?);
+        let socket = match bind_loopback(self.port).await {
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+                return Err(anyhow!(
+                    "Command port {} is already in use, set a different listen-port or instance-name!",
+                    self.port
+                ));
+            }
+            result => result?,
+        };
         let (event_sender, mut event_receiver) = mpsc::channel::<TunnelEvent>(16);
+        let (stream_sender, mut stream_receiver) = mpsc::channel::<StreamRequest>(16);
+
+        if let Some(path) = self.socket_path.clone() {
+            match ipc::bind(&path) {
+                Ok(listener) => {
+                    let stream_sender = stream_sender.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = ipc::serve(listener, stream_sender).await {
+                            warn!("IPC listener on {} failed: {}", path.display(), e);
+                        }
+                    });
+                }
+                Err(e) => warn!("Unable to listen on {}: {}", path.display(), e),
+            }
+        }
+
+        if let Some(api) = self.http_api.take() {
+            match api.bind().await {
+                Ok(listener) => {
+                    let stream_sender = stream_sender.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = api.serve(listener, stream_sender).await {
+                            warn!("HTTP API listener failed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => warn!("Unable to start the HTTP API: {}", e),
+            }
+        }
+
+        if let Some(handler) = self.ready_handler.take() {
+            handler();
+        }
+        let mut usage_interval = tokio::time::interval(USAGE_SAMPLE_INTERVAL);
 
         loop {
-            let recv = async {
-                let mut buf = vec![0u8; MAX_PACKET_SIZE];
-                let (size, addr) = socket.recv_from(&mut buf).await?;
-                Ok::<_, anyhow::Error>((buf[0..size].to_vec(), addr))
-            };
+            let recv = recv_request(&socket, &mut stream_receiver);
             pin_mut!(recv);
 
             let event_fut = event_receiver.recv();
             pin_mut!(event_fut);
 
+            let grace_fut = wait_deadline(self.disconnect_deadline);
+            pin_mut!(grace_fut);
+
+            let standby_fut = wait_deadline(self.standby_deadline);
+            pin_mut!(standby_fut);
+
+            let session_fut = wait_deadline(self.session_warning_deadline.or(self.session_end_deadline));
+            pin_mut!(session_fut);
+
+            let reconnect_fut = wait_deadline(self.reconnect_deadline);
+            pin_mut!(reconnect_fut);
+
             tokio::select! {
+                _ = usage_interval.tick() => {
+                    self.sample_usage();
+                }
+                _ = grace_fut => {
+                    debug!("Disconnect grace period expired");
+                    let _ = self.disconnect().await;
+                }
+                _ = standby_fut => {
+                    self.refresh_standby(event_sender.clone()).await;
+                }
+                _ = session_fut => {
+                    self.check_session_duration().await;
+                }
+                _ = reconnect_fut => {
+                    self.reconnect_deadline = None;
+                    debug!("Reconnecting to the busy gateway");
+                    if let Err(e) = self.connect(self.params.clone(), event_sender.clone()).await {
+                        warn!("Reconnect failed: {}", e);
+                        self.reset();
+                        self.schedule_reconnect(&e);
+                    }
+                }
                 event = event_fut => {
                     if let Some(event) = event {
-                        if let Some(ref mut connector) = self.connector {
-                            if connector.handle_tunnel_event(event.clone()).await.is_err() {
-                                self.reset();
+                        let disconnected = matches!(event, TunnelEvent::Disconnected);
+                        if disconnected {
+                            self.tunnel_task = None;
+                            // reported by a tunnel stopped for the session reuse, the connector is kept
+                            if std::mem::take(&mut self.tunnel_detached) {
+                                continue;
+                            }
+                        }
+                        // the SA is kept for a reconnect with the same session
+                        let reconnect = disconnected && std::mem::take(&mut self.reconnect_pending);
+                        if !reconnect {
+                            if let Some(ref mut connector) = self.connector {
+                                if connector.handle_tunnel_event(event.clone()).await.is_err() {
+                                    self.reset();
+                                }
                             }
                         }
                         match event {
                             TunnelEvent::Connected => {
-                                self.connection_status = ConnectionStatus::connected();
+                                self.reconnect_attempts = 0;
+                                self.connection_status.connected_since = Some(Local::now());
+                                self.start_session_duration();
+                                if let Err(e) = self.apply_rate_limit().await {
+                                    warn!("Unable to set the rate limit: {}", e);
+                                }
                             }
                             TunnelEvent::Disconnected => {
-                                self.reset();
+                                if reconnect {
+                                    debug!("Reconnecting after keepalive failure");
+                                    if let Err(e) = self.reconnect(self.params.clone(), event_sender.clone()).await {
+                                        warn!("Reconnect failed: {}", e);
+                                        self.reset();
+                                        self.schedule_reconnect(&e);
+                                    }
+                                } else {
+                                    self.reset();
+                                }
+                            }
+                            TunnelEvent::ReconnectRequired => {
+                                self.reconnect_pending = true;
+                            }
+                            TunnelEvent::EncryptionDomain(domain) => {
+                                self.connection_status.encryption_domain = domain;
+                            }
+                            TunnelEvent::RouteConflicts(conflicts) => {
+                                self.connection_status.warnings = self.cert_warnings();
+                                self.connection_status.warnings.extend(conflicts);
                             }
                             _ => {}
                         }
                     }
                 }
                 result = recv => {
-                    let (data, addr) = result?;
-                    let resp = self.handle(&data, event_sender.clone()).await;
-                    trace!("Response: {:?}", resp);
-                    let json = serde_json::to_vec(&resp)?;
-                    let _ = socket.send_to(&json, addr).await;
+                    match result? {
+                        (TunnelServiceRequest::Watch, ReplyTo::Stream(id, sender)) => start_watch(id, sender),
+                        (req, reply_to) => {
+                            self.handle_cancellable(&socket, &mut stream_receiver, req, reply_to, event_sender.clone())
+                                .await?;
+                        }
+                    }
                 }
             }
         }
     }
 
-    async fn handle(&mut self, packet: &[u8], event_sender: mpsc::Sender<TunnelEvent>) -> TunnelServiceResponse {
-        trace!("Command received");
-        let req = match serde_json::from_slice::<TunnelServiceRequest>(packet) {
-            Ok(req) => req,
-            Err(e) => {
-                warn!("{}", e);
-                return TunnelServiceResponse::Error(e.to_string());
+    // connect and challenge code requests may block for a long time, a cancel request received meanwhile
+    // aborts them, other requests are handled after the current one has completed
+    async fn handle_cancellable(
+        &mut self,
+        socket: &UdpSocket,
+        stream_receiver: &mut mpsc::Receiver<StreamRequest>,
+        req: TunnelServiceRequest,
+        reply_to: ReplyTo,
+        event_sender: mpsc::Sender<TunnelEvent>,
+    ) -> anyhow::Result<()> {
+        let mut deferred = Vec::new();
+
+        let setup_token = self.setup_token.clone();
+
+        let result = {
+            let span = request_span(&req);
+            let handle_fut = self.handle(req, event_sender.clone()).instrument(span);
+            pin_mut!(handle_fut);
+
+            loop {
+                tokio::select! {
+                    resp = &mut handle_fut => break HandleResult::Done(resp),
+                    result = recv_request(socket, stream_receiver) => {
+                        match result? {
+                            (TunnelServiceRequest::Cancel, reply_to) => {
+                                // the aborted setup removes the device, routes and DNS configured so far
+                                setup_token.cancel();
+                                if tokio::time::timeout(CANCEL_CLEANUP_TIMEOUT, &mut handle_fut).await.is_err() {
+                                    warn!("Connection setup did not stop in time, dropping it");
+                                }
+                                break HandleResult::Cancelled(reply_to);
+                            }
+                            // watchers are after the progress of the request being handled
+                            (TunnelServiceRequest::Watch, ReplyTo::Stream(id, sender)) => start_watch(id, sender),
+                            request => deferred.push(request),
+                        }
+                    }
+                }
+            }
+        };
+
+        let resp = match result {
+            HandleResult::Done(resp) => resp,
+            HandleResult::Cancelled(cancel_reply_to) => {
+                debug!("Connection cancelled");
+                self.cancel().await;
+                send_response(socket, &TunnelServiceResponse::Ok, &cancel_reply_to).await?;
+                TunnelServiceResponse::Error("Connection cancelled!".to_owned())
             }
         };
+        send_response(socket, &resp, &reply_to).await?;
+
+        for (req, reply_to) in deferred {
+            let resp = self.handle(req, event_sender.clone()).await;
+            send_response(socket, &resp, &reply_to).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn handle(
+        &mut self,
+        mut req: TunnelServiceRequest,
+        event_sender: mpsc::Sender<TunnelEvent>,
+    ) -> TunnelServiceResponse {
+        if let TunnelServiceRequest::Connect(ref mut params)
+        | TunnelServiceRequest::Reconnect(ref mut params)
+        | TunnelServiceRequest::ChallengeCode(_, ref mut params)
+        | TunnelServiceRequest::AuthTest(ref mut params)
+        | TunnelServiceRequest::Prewarm(ref mut params) = req
+        {
+            self.privileged.apply(params);
+        }
 
         match req {
             TunnelServiceRequest::Connect(params) => {
                 trace!("Handling connect command");
+                self.auth_test = false;
+                self.prewarm = false;
+                match self.connect(Arc::new(params), event_sender).await {
+                    Ok(_) => TunnelServiceResponse::Ok,
+                    Err(e) => {
+                        self.reset();
+                        TunnelServiceResponse::Error(e.to_string())
+                    }
+                }
+            }
+            TunnelServiceRequest::Reconnect(params) => {
+                debug!("Handling reconnect command");
+                self.auth_test = false;
+                self.prewarm = false;
+                match self.reconnect(Arc::new(params), event_sender).await {
+                    Ok(_) => TunnelServiceResponse::Ok,
+                    Err(e) => {
+                        self.reset();
+                        TunnelServiceResponse::Error(e.to_string())
+                    }
+                }
+            }
+            TunnelServiceRequest::AuthTest(params) => {
+                debug!("Handling authentication test command");
+                self.auth_test = true;
+                self.prewarm = false;
+                match self.connect(Arc::new(params), event_sender).await {
+                    Ok(_) => TunnelServiceResponse::Ok,
+                    Err(e) => {
+                        self.reset();
+                        TunnelServiceResponse::Error(e.to_string())
+                    }
+                }
+            }
+            TunnelServiceRequest::Prewarm(params) => {
+                debug!("Handling prewarm command");
+                self.auth_test = false;
+                self.prewarm = true;
                 match self.connect(Arc::new(params), event_sender).await {
                     Ok(_) => TunnelServiceResponse::Ok,
                     Err(e) => {
@@ -104,6 +768,17 @@ impl CommandServer {
             TunnelServiceRequest::Disconnect => {
                 debug!("Handling disconnect command");
 
+                self.mfa_cache.clear();
+
+                // a second disconnect within the grace period tears the tunnel down immediately
+                let grace = self.params.disconnect_grace;
+                if self.is_connected() && !grace.is_zero() && self.disconnect_deadline.is_none() {
+                    debug!("Delaying disconnect by {} seconds", grace.as_secs());
+                    self.disconnect_deadline = Some(tokio::time::Instant::now() + grace);
+                    self.connection_status.disconnect_pending = true;
+                    return TunnelServiceResponse::Ok;
+                }
+
                 match self.disconnect().await {
                     Ok(_) => TunnelServiceResponse::Ok,
                     Err(e) => TunnelServiceResponse::Error(e.to_string()),
@@ -111,15 +786,121 @@ impl CommandServer {
             }
             TunnelServiceRequest::GetStatus => {
                 trace!("Handling get status command");
-                TunnelServiceResponse::ConnectionStatus(self.get_status().clone())
+                TunnelServiceResponse::ConnectionStatus(self.get_status())
+            }
+            TunnelServiceRequest::GetStatusReadOnly => {
+                trace!("Handling read-only get status command");
+                TunnelServiceResponse::ConnectionStatus(self.current_status())
+            }
+            TunnelServiceRequest::Suspend => {
+                debug!("Handling suspend command");
+                match self.set_suspended(true).await {
+                    Ok(_) => TunnelServiceResponse::Ok,
+                    Err(e) => TunnelServiceResponse::Error(e.to_string()),
+                }
+            }
+            TunnelServiceRequest::Resume => {
+                debug!("Handling resume command");
+                match self.set_suspended(false).await {
+                    Ok(_) => TunnelServiceResponse::Ok,
+                    Err(e) => TunnelServiceResponse::Error(e.to_string()),
+                }
+            }
+            TunnelServiceRequest::AddRoute(route) => {
+                debug!("Handling add route command: {}", route);
+                match self.change_route(route, true).await {
+                    Ok(_) => TunnelServiceResponse::Ok,
+                    Err(e) => TunnelServiceResponse::Error(e.to_string()),
+                }
             }
-            TunnelServiceRequest::ChallengeCode(code, _) => {
+            TunnelServiceRequest::RemoveRoute(route) => {
+                debug!("Handling remove route command: {}", route);
+                match self.change_route(route, false).await {
+                    Ok(_) => TunnelServiceResponse::Ok,
+                    Err(e) => TunnelServiceResponse::Error(e.to_string()),
+                }
+            }
+            TunnelServiceRequest::Benchmark(target) => {
+                debug!("Handling benchmark command");
+                if !self.is_connected() {
+                    return TunnelServiceResponse::Error("Tunnel is not connected!".to_owned());
+                }
+                match self.benchmark(target).await {
+                    Ok(result) => TunnelServiceResponse::Benchmark(result),
+                    Err(e) => TunnelServiceResponse::Error(e.to_string()),
+                }
+            }
+            TunnelServiceRequest::Watch => TunnelServiceResponse::Error("Watching requires the IPC socket!".to_owned()),
+            TunnelServiceRequest::Version => {
+                trace!("Handling version command");
+                TunnelServiceResponse::Version(ServiceVersion {
+                    version: env!("CARGO_PKG_VERSION").to_owned(),
+                    protocol: ipc::PROTOCOL_VERSION,
+                })
+            }
+            TunnelServiceRequest::SetRateLimit(rate_limit) => {
+                debug!("Handling rate limit command");
+                if !self.is_connected() {
+                    return TunnelServiceResponse::Error("Tunnel is not connected!".to_owned());
+                }
+                // kept for the reconnects of this connection
+                let previous = self.params.clone();
+                self.params = Arc::new(TunnelParams {
+                    rate_limit,
+                    ..(*self.params).clone()
+                });
+                match self.apply_rate_limit().await {
+                    Ok(_) => TunnelServiceResponse::Ok,
+                    Err(e) => {
+                        self.params = previous;
+                        TunnelServiceResponse::Error(e.to_string())
+                    }
+                }
+            }
+            TunnelServiceRequest::RequestOtpDelivery(method) => {
+                debug!("Handling OTP delivery request");
+                match (self.connector.as_mut(), self.session.clone()) {
+                    (Some(connector), Some(session)) => match connector.request_otp_delivery(session, &method).await {
+                        Ok(()) => TunnelServiceResponse::Ok,
+                        Err(e) => TunnelServiceResponse::Error(e.to_string()),
+                    },
+                    _ => TunnelServiceResponse::Error("No pending authentication!".to_owned()),
+                }
+            }
+            TunnelServiceRequest::Cancel => {
+                debug!("Handling cancel command");
+                if self.is_connected() {
+                    TunnelServiceResponse::Error("No connection in progress!".to_owned())
+                } else {
+                    self.cancel().await;
+                    TunnelServiceResponse::Ok
+                }
+            }
+            TunnelServiceRequest::PollPushApproval => {
+                trace!("Handling push approval poll");
+                match self.poll_push_approval(event_sender).await {
+                    Ok(true) => TunnelServiceResponse::Ok,
+                    Ok(false) => TunnelServiceResponse::ConnectionStatus(self.get_status()),
+                    // the pending session is kept for the code which the client asks for instead
+                    Err(e) => {
+                        warn!("{}", e);
+                        TunnelServiceResponse::Error(e.to_string())
+                    }
+                }
+            }
+            TunnelServiceRequest::ChallengeCode(code, params) => {
                 debug!("Handling challenge code command");
-                match self.challenge_code(&code, event_sender).await {
+                match self.challenge_code(&code, Arc::new(params), event_sender).await {
                     Ok(_) => TunnelServiceResponse::Ok,
                     Err(e) => {
                         warn!("{}", e);
-                        self.reset();
+                        if is_auth_failure(&e) {
+                            self.auth_failures.failed(&self.params);
+                        }
+                        // the pending session is kept for another answer, the client disconnects when giving up
+                        if !e.is::<ChallengeRejected>() {
+                            self.reset();
+                        }
                         TunnelServiceResponse::Error(e.to_string())
                     }
                 }
@@ -127,9 +908,29 @@ impl CommandServer {
         }
     }
 
-    fn is_connected(&self) -> bool {
-        self.connection_status.connected_since.is_some()
-    }
+    // the internal gateway address is taken from the client settings of the current session
+    async fn benchmark(&self, target: Option<SocketAddr>) -> anyhow::Result<BenchmarkResult> {
+        let session = self.session.clone().ok_or_else(|| anyhow!("No session"))?;
+        let source = session
+            .ipsec_session
+            .as_ref()
+            .map(|ipsec_session| ipsec_session.address);
+
+        let target = match target {
+            Some(target) => target,
+            None => {
+                let client = CccHttpClient::new(self.params.clone(), Some(session));
+                let settings = client.get_client_settings().await?;
+                SocketAddr::from((settings.gw_internal_ip, TunnelParams::IPSEC_KEEPALIVE_PORT))
+            }
+        };
+
+        bench::run(target, source).await
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connection_status.connected_since.is_some()
+    }
 
     async fn connect_for_session(
         &mut self,
@@ -142,24 +943,112 @@ impl CommandServer {
             return Err(anyhow!("No tunnel connector!"));
         };
 
-        if let SessionState::PendingChallenge(ref challenge) = session.state {
-            debug!("Pending multi-factor, awaiting for it");
-            self.session = Some(session.clone());
-            self.connection_status = ConnectionStatus::mfa(challenge.clone());
+        let mut session = session;
+        let mut reused_prompts = Vec::new();
+
+        while let SessionState::PendingChallenge(ref challenge) = session.state {
+            let cached = if reused_prompts.contains(&challenge.prompt) {
+                None
+            } else {
+                self.mfa_cache.get(&challenge.prompt)
+            };
+
+            match cached {
+                Some(answer) => {
+                    debug!("Reusing cached MFA answer");
+                    let prompt = challenge.prompt.clone();
+                    match connector.challenge_code(session.clone(), &answer).await {
+                        Ok(new_session) => {
+                            reused_prompts.push(prompt);
+                            session = new_session;
+                        }
+                        Err(e) => {
+                            self.mfa_cache.remove(&prompt);
+                            return Err(e);
+                        }
+                    }
+                }
+                None => {
+                    debug!("Pending multi-factor, awaiting for it");
+                    self.session = Some(session.clone());
+                    self.connection_status = ConnectionStatus::mfa(challenge.clone());
+                    // the codes read from the OTP pipe are generated from the local clock
+                    if self.params.otp_pipe.is_some() && challenge.mfa_type == MfaType::UserInput {
+                        if let Some(skew) = ccc::totp_clock_skew() {
+                            let warning = format!(
+                                "Local clock is {} seconds off the gateway time, generated codes may be rejected",
+                                skew
+                            );
+                            warn!("{}", warning);
+                            self.connection_status.warnings.push(warning);
+                        }
+                    }
+                    return Ok(());
+                }
+            }
+        }
+
+        self.auth_failures.succeeded(&self.params);
+
+        if self.auth_test {
+            debug!("Authentication test succeeded, discarding the session");
+            self.cancel().await;
             return Ok(());
         }
 
+        if self.prewarm {
+            self.enter_standby(session);
+            return Ok(());
+        }
+
+        self.start_tunnel(session, event_sender).await
+    }
+
+    async fn start_tunnel(
+        &mut self,
+        session: Arc<VpnSession>,
+        event_sender: mpsc::Sender<TunnelEvent>,
+    ) -> anyhow::Result<()> {
+        let connector = self.connector.as_mut().ok_or_else(|| anyhow!("No tunnel connector!"))?;
+
         let (command_sender, command_receiver) = mpsc::channel(16);
 
-        let tunnel = connector.create_tunnel(session, command_sender).await?;
+        let (recorder, phase_sender) = if self.params.timing_report {
+            let (recorder, phase_sender) = timing::PhaseRecorder::start();
+            (Some(recorder), Some(phase_sender))
+        } else {
+            (None, None)
+        };
+
+        let tunnel = connector
+            .create_tunnel(session.clone(), command_sender, phase_sender)
+            .await?;
 
-        tokio::spawn(async move {
+        if let Some(recorder) = recorder {
+            self.timings.extend(recorder.finish().await);
+        }
+
+        let status = ConnectionStatus {
+            realm: Some(self.params.realm_id().to_owned()),
+            tunnel_type: Some(self.params.tunnel_type),
+            timings: self.timings.clone(),
+            cert_expires: self.cert_expiry.as_ref().map(|expiry| expiry.expires),
+            warnings: self.cert_warnings(),
+            busy_retries: self.busy_retries,
+            suggested_retry_interval: self.suggested_retry_interval.map(|interval| interval.as_secs()),
+            // a reused session keeps counting its maximum duration
+            session_ends: self.session_end_deadline.and(self.connection_status.session_ends),
+            ..ConnectionStatus::connected_with_session(&session)
+        };
+
+        self.tunnel_task = Some(tokio::spawn(async move {
             if let Err(e) = tunnel.run(command_receiver, event_sender).await {
                 warn!("Tunnel error: {}", e);
             }
-        });
+        }));
 
-        self.connection_status = ConnectionStatus::connected();
+        self.session = Some(session);
+        self.connection_status = status;
 
         Ok(())
     }
@@ -169,11 +1058,95 @@ impl CommandServer {
         params: Arc<TunnelParams>,
         event_sender: mpsc::Sender<TunnelEvent>,
     ) -> anyhow::Result<()> {
+        if !self.prewarm && self.has_standby_for(&params) {
+            debug!("Connecting with the standby session");
+            let session = self.session.take().ok_or_else(|| anyhow!("No standby session!"))?;
+            self.standby_deadline = None;
+            return self.start_tunnel(session, event_sender).await;
+        }
+
         if !self.is_connected() {
+            self.auth_failures.check(&params)?;
+
             self.reset();
 
-            let mut connector = tunnel::new_tunnel_connector(params.clone()).await?;
-            let session = connector.authenticate().await?;
+            if params.mfa_cache_ttl.is_zero() {
+                self.mfa_cache.clear();
+            }
+
+            self.params = params.clone();
+
+            progress::clear();
+            progress::report(format!("Connecting to {}", params.server_name));
+
+            self.cert_expiry = certs::check_client_cert(&params)?;
+
+            // the report is diagnostic only, the connection itself decides whether the gateway is reachable
+            self.timings = if params.timing_report {
+                match timing::probe_gateway(&params).await {
+                    Ok(timings) => timings,
+                    Err(e) => {
+                        warn!("Unable to probe the gateway connection: {}", e);
+                        Vec::new()
+                    }
+                }
+            } else {
+                Vec::new()
+            };
+
+            let started = Instant::now();
+            self.busy_retries = 0;
+            self.suggested_retry_interval = None;
+            let (connector, session) = loop {
+                let result =
+                    match tunnel::cancellable(&self.setup_token, tunnel::new_tunnel_connector(params.clone())).await {
+                        Ok(mut connector) => {
+                            connector.set_cancellation_token(self.setup_token.clone());
+                            tunnel::cancellable(&self.setup_token, connector.authenticate())
+                                .await
+                                .map(|session| (connector, session))
+                        }
+                        Err(e) => Err(e),
+                    };
+                match result {
+                    Ok(result) => break result,
+                    Err(e) if e.is::<GatewayBusy>() && self.busy_retries < params.retry_on_busy => {
+                        self.suggested_retry_interval = e.downcast_ref::<GatewayBusy>().and_then(|busy| busy.1);
+                        let Some(delay) =
+                            busy_retry_wait(self.busy_retries, self.suggested_retry_interval, started.elapsed())
+                        else {
+                            warn!("Giving up the retries on the busy gateway, the time budget is exceeded");
+                            return Err(e);
+                        };
+                        self.busy_retries += 1;
+                        warn!("{}", e);
+                        progress::report(format!(
+                            "Gateway is busy, retry {} of {} in {} seconds",
+                            self.busy_retries,
+                            params.retry_on_busy,
+                            delay.as_secs()
+                        ));
+                        tunnel::cancellable(&self.setup_token, async {
+                            tokio::time::sleep(delay).await;
+                            Ok(())
+                        })
+                        .await?;
+                    }
+                    Err(e) => {
+                        if is_auth_failure(&e) {
+                            self.auth_failures.failed(&params);
+                        }
+                        return Err(e);
+                    }
+                }
+            };
+            if params.timing_report {
+                self.timings.push(PhaseTiming::new("auth", started.elapsed()));
+            }
+            // the gateway keeps the authenticated session for the lifetime of the IKE SA
+            self.session_deadline = Some(started + params.ike_lifetime);
+            // the device name depends on the tunnel type selected in the auto mode
+            self.params = Arc::new(params.with_tunnel_type(connector.tunnel_type()));
             self.connector = Some(connector);
             self.connect_for_session(session, event_sender).await
         } else {
@@ -181,11 +1154,96 @@ impl CommandServer {
         }
     }
 
-    async fn challenge_code(&mut self, code: &str, event_sender: mpsc::Sender<TunnelEvent>) -> anyhow::Result<()> {
+    // an established session is reused for the new tunnel until it expires,
+    // the full authentication including MFA is only needed when the gateway does not accept it anymore
+    async fn reconnect(
+        &mut self,
+        params: Arc<TunnelParams>,
+        event_sender: mpsc::Sender<TunnelEvent>,
+    ) -> anyhow::Result<()> {
+        if let Some(session) = self.reusable_session(&params) {
+            debug!("Reconnecting with the existing session");
+            self.stop_tunnel().await;
+            self.params = Arc::new(params.with_tunnel_type(self.params.tunnel_type));
+            match self.reuse_session(session, event_sender.clone()).await {
+                Ok(()) => return Ok(()),
+                Err(e) => warn!("Unable to reuse the session, authenticating again: {}", e),
+            }
+        }
+
+        // the maximum session duration is counted from the first connect
+        let session_duration = (
+            self.session_end_deadline,
+            self.session_warning_deadline,
+            self.connection_status.session_ends,
+        );
+
+        self.stop_tunnel().await;
+        self.disconnect().await?;
+        self.connect(params, event_sender).await?;
+
+        (
+            self.session_end_deadline,
+            self.session_warning_deadline,
+            self.connection_status.session_ends,
+        ) = session_duration;
+
+        Ok(())
+    }
+
+    fn reusable_session(&self, params: &TunnelParams) -> Option<Arc<VpnSession>> {
+        let valid = self.session_deadline.is_some_and(|deadline| deadline > Instant::now());
+        if self.is_connected() && valid && self.is_same_login(params) {
+            self.session.clone()
+        } else {
+            None
+        }
+    }
+
+    async fn reuse_session(
+        &mut self,
+        session: Arc<VpnSession>,
+        event_sender: mpsc::Sender<TunnelEvent>,
+    ) -> anyhow::Result<()> {
+        let connector = self.connector.as_mut().ok_or_else(|| anyhow!("No tunnel connector!"))?;
+        let session = connector.reuse_session(session).await?;
+        self.disconnect_deadline = None;
+        self.timings.clear();
+        self.start_tunnel(session, event_sender).await
+    }
+
+    // waits for the tunnel cleanup, its disconnect event is discarded
+    async fn stop_tunnel(&mut self) {
+        if let Some(ref mut connector) = self.connector {
+            let _ = connector.terminate_tunnel().await;
+        }
+        if let Some(task) = self.tunnel_task.take() {
+            let _ = task.await;
+            self.tunnel_detached = true;
+        }
+    }
+
+    async fn challenge_code(
+        &mut self,
+        code: &str,
+        params: Arc<TunnelParams>,
+        event_sender: mpsc::Sender<TunnelEvent>,
+    ) -> anyhow::Result<()> {
         if let Some(ref mut connector) = self.connector {
             match self.session.as_ref() {
                 Some(session) => {
-                    let new_session = connector.challenge_code(session.clone(), code).await?;
+                    let started = Instant::now();
+                    let new_session =
+                        tunnel::cancellable(&self.setup_token, connector.challenge_code(session.clone(), code)).await?;
+                    if self.params.timing_report {
+                        self.timings.push(PhaseTiming::new("mfa", started.elapsed()));
+                    }
+                    // a new password is not a code to be answered again
+                    if let SessionState::PendingChallenge(ref challenge) = session.state {
+                        if challenge.mfa_type != MfaType::PasswordChange {
+                            self.mfa_cache.insert(&challenge.prompt, code, params.mfa_cache_ttl);
+                        }
+                    }
                     self.connect_for_session(new_session, event_sender).await
                 }
                 None => Err(anyhow!("No session")),
@@ -195,21 +1253,636 @@ impl CommandServer {
         }
     }
 
+    // false while the push challenge is pending, the connection proceeds once it is approved
+    async fn poll_push_approval(&mut self, event_sender: mpsc::Sender<TunnelEvent>) -> anyhow::Result<bool> {
+        let (Some(connector), Some(session)) = (self.connector.as_mut(), self.session.clone()) else {
+            return Err(anyhow!("No pending authentication!"));
+        };
+
+        match connector.poll_push_approval(session).await? {
+            Some(new_session) => {
+                if let Err(e) = self.connect_for_session(new_session, event_sender).await {
+                    self.reset();
+                    return Err(e);
+                }
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn apply_rate_limit(&mut self) -> anyhow::Result<()> {
+        // nothing to remove unless a limit has been set before
+        if self.params.rate_limit.is_none() && self.connection_status.rate_limit.is_none() {
+            return Ok(());
+        }
+        let connector = self.connector.as_mut().ok_or_else(|| anyhow!("No tunnel connector!"))?;
+        connector
+            .set_rate_limit(self.params.rate_limit, self.params.rate_limit_inbound)
+            .await?;
+        self.connection_status.rate_limit = self.params.rate_limit;
+        Ok(())
+    }
+
+    // routes and DNS settings are withdrawn while suspended, the SA is kept alive by the keepalive
+    async fn set_suspended(&mut self, suspended: bool) -> anyhow::Result<()> {
+        if !self.is_connected() {
+            return Err(anyhow!("Tunnel is not connected!"));
+        }
+
+        if self.connection_status.suspended == suspended {
+            return Ok(());
+        }
+
+        let connector = self.connector.as_mut().ok_or_else(|| anyhow!("No tunnel connector!"))?;
+        if suspended {
+            connector.suspend_tunnel().await?;
+        } else {
+            connector.resume_tunnel().await?;
+        }
+
+        self.connection_status.suspended = suspended;
+
+        Ok(())
+    }
+
+    async fn change_route(&mut self, route: Ipv4Net, add: bool) -> anyhow::Result<()> {
+        if !self.is_connected() {
+            return Err(anyhow!("Tunnel is not connected!"));
+        }
+
+        // the host bits are not accepted by the kernel
+        let route = route.trunc();
+        let connector = self.connector.as_mut().ok_or_else(|| anyhow!("No tunnel connector!"))?;
+        let routes = &mut self.connection_status.added_routes;
+
+        if add {
+            connector.add_route(route).await?;
+            if !routes.contains(&route) {
+                routes.push(route);
+            }
+        } else {
+            if !routes.contains(&route) {
+                return Err(anyhow!("Route {} was not added at runtime!", route));
+            }
+            connector.remove_route(route).await?;
+            routes.retain(|r| *r != route);
+        }
+
+        Ok(())
+    }
+
+    // the certificate expiring soon is reported for the whole connection
+    fn cert_warnings(&self) -> Vec<String> {
+        self.cert_expiry
+            .as_ref()
+            .and_then(|expiry| expiry.warning.clone())
+            .into_iter()
+            .collect()
+    }
+
+    // the busy gateway refusing the automatic reconnect is asked again after the interval it suggests,
+    // or after the backoff delay, up to the retry-on-busy times
+    fn schedule_reconnect(&mut self, error: &anyhow::Error) -> bool {
+        let Some(busy) = error.downcast_ref::<GatewayBusy>() else {
+            return false;
+        };
+        if self.reconnect_attempts >= self.params.retry_on_busy {
+            return false;
+        }
+
+        let delay = reconnect_delay(self.reconnect_attempts, busy.1);
+        self.reconnect_attempts += 1;
+        self.reconnect_deadline = Some(tokio::time::Instant::now() + delay);
+        self.suggested_retry_interval = busy.1;
+        self.connection_status.suggested_retry_interval = busy.1.map(|interval| interval.as_secs());
+        self.connection_status.reconnect_at = chrono::Duration::from_std(delay).ok().map(|delay| Local::now() + delay);
+        progress::report(format!("Gateway is busy, reconnecting in {} seconds", delay.as_secs()));
+        true
+    }
+
+    // counted from the first connect, the reconnects don't extend the session.
+    // The warning comes five minutes ahead or halfway for short durations.
+    fn start_session_duration(&mut self) {
+        let duration = self.params.max_session_duration;
+        if duration.is_zero() || self.session_end_deadline.is_some() {
+            return;
+        }
+
+        let now = tokio::time::Instant::now();
+        self.session_end_deadline = Some(now + duration);
+        self.session_warning_deadline = Some(now + duration - SESSION_END_WARNING.min(duration / 2));
+        self.connection_status.session_ends = chrono::Duration::from_std(duration)
+            .ok()
+            .map(|duration| Local::now() + duration);
+    }
+
+    async fn check_session_duration(&mut self) {
+        if self.session_warning_deadline.take().is_some() {
+            if let Some(ends) = self.connection_status.session_ends {
+                let warning = format!(
+                    "Maximum session duration ends at {}, the tunnel will be disconnected then",
+                    ends.format("%H:%M:%S")
+                );
+                warn!("{}", warning);
+                self.connection_status.warnings.push(warning);
+            }
+            return;
+        }
+
+        debug!("Maximum session duration reached, disconnecting");
+        self.mfa_cache.clear();
+        let _ = self.disconnect().await;
+        self.connection_status.disconnect_reason = Some("max duration reached".to_owned());
+    }
+
+    // the session is kept until the next connect and refreshed before it expires
+    fn enter_standby(&mut self, session: Arc<VpnSession>) {
+        let mut refresh = self.params.prewarm_refresh;
+        if let Some(ref ipsec_session) = session.ipsec_session {
+            refresh = refresh.min(ipsec_session.lifetime / 2);
+        }
+        let refresh = refresh.max(MIN_STANDBY_REFRESH);
+
+        debug!("Standby session ready, refreshing in {} seconds", refresh.as_secs());
+
+        self.session = Some(session);
+        self.standby_deadline = Some(tokio::time::Instant::now() + refresh);
+        self.connection_status = ConnectionStatus {
+            realm: Some(self.params.realm_id().to_owned()),
+            ..ConnectionStatus::standby()
+        };
+    }
+
+    // the tunnel is set up with the params of the standby session, any change needs a new login
+    fn has_standby_for(&self, params: &TunnelParams) -> bool {
+        self.connection_status.standby
+            && is_same_params(&self.params, &params.with_tunnel_type(self.params.tunnel_type))
+    }
+
+    fn is_same_login(&self, params: &TunnelParams) -> bool {
+        self.params.server_name == params.server_name
+            && self.params.user_name == params.user_name
+            && self.params.login_type == params.login_type
+            && (params.tunnel_type == TunnelType::Auto || self.params.tunnel_type == params.tunnel_type)
+    }
+
+    // a refresh which needs an MFA answer not found in the cache cannot complete unattended
+    async fn refresh_standby(&mut self, event_sender: mpsc::Sender<TunnelEvent>) {
+        debug!("Refreshing the standby session");
+
+        self.auth_test = false;
+        self.prewarm = true;
+
+        match self.connect(self.params.clone(), event_sender).await {
+            Ok(_) if self.connection_status.standby => {}
+            Ok(_) => {
+                warn!("Standby session refresh requires user input, dropping the standby session");
+                self.cancel().await;
+            }
+            Err(e) => {
+                warn!("Standby session refresh failed: {}", e);
+                self.reset();
+            }
+        }
+    }
+
     async fn disconnect(&mut self) -> anyhow::Result<()> {
-        if let Some(ref mut connector) = self.connector {
-            let _ = connector.terminate_tunnel().await;
+        let had_tunnel = self.is_connected();
+        let address = self
+            .session
+            .as_ref()
+            .and_then(|session| session.ipsec_session.as_ref())
+            .map(|ipsec_session| ipsec_session.address);
+
+        // the record is removed while the DNS server is still reachable through the tunnel
+        if had_tunnel {
+            ddns::deregister(&self.params).await;
         }
+
+        // the tunnel removes its routes and DNS settings when it is dropped at the end of the task
+        self.stop_tunnel().await;
         self.reset();
+
+        if had_tunnel && self.params.verify_cleanup && !self.params.external_device() {
+            let leftovers = platform::verify_cleanup(
+                self.params.device_name(),
+                address,
+                self.params.routing_table(),
+                self.params.dns_manager,
+            )
+            .await;
+            self.set_cleanup_result(&leftovers);
+        }
+
         Ok(())
     }
 
+    // a clean verification clears the warning left by a previous disconnect
+    fn set_cleanup_result(&mut self, leftovers: &[String]) {
+        self.connection_status.cleanup_warning = if leftovers.is_empty() {
+            None
+        } else {
+            let warning = format!("System not fully restored, left over: {}", leftovers.join(", "));
+            warn!("{}", warning);
+            Some(warning)
+        };
+    }
+
+    // drops the pending MFA session and cleans up whatever was set up so far
+    async fn cancel(&mut self) {
+        // the token is used up, the next connection attempt gets a new one
+        self.setup_token.cancel();
+        self.setup_token = CancellationToken::new();
+        if let Some(ref mut connector) = self.connector {
+            let _ = connector.handle_tunnel_event(TunnelEvent::Disconnected).await;
+        }
+        self.reconnect_pending = false;
+        let _ = self.disconnect().await;
+    }
+
     fn reset(&mut self) {
+        if self.params.persist_stats {
+            self.usage.restart();
+        } else {
+            self.usage = UsageMeter::default();
+        }
+        self.disconnect_deadline = None;
+        self.reconnect_deadline = None;
+        self.session_warning_deadline = None;
+        self.session_end_deadline = None;
+        self.standby_deadline = None;
         self.session = None;
+        self.session_deadline = None;
+        self.tunnel_task = None;
         self.connector = None;
-        self.connection_status = ConnectionStatus::disconnected();
+        // the interval suggested by the busy gateway is kept for the status of the failed connection
+        self.connection_status = ConnectionStatus {
+            suggested_retry_interval: self.suggested_retry_interval.map(|interval| interval.as_secs()),
+            ..ConnectionStatus::disconnected()
+        };
+    }
+
+    fn sample_usage(&mut self) {
+        if self.is_connected() {
+            if let Some(ref connector) = self.connector {
+                let stats = connector.stats();
+                self.usage.update(stats.bytes_received, stats.bytes_sent);
+            }
+        }
     }
 
-    fn get_status(&self) -> &ConnectionStatus {
-        &self.connection_status
+    fn get_status(&mut self) -> ConnectionStatus {
+        self.sample_usage();
+        self.current_status()
+    }
+
+    // the usage meter is not advanced, the traffic since the last sample is accounted later
+    fn current_status(&self) -> ConnectionStatus {
+        let mut status = self.connection_status.clone();
+        if self.is_connected() {
+            status.stats = self.connector.as_ref().map(|connector| TunnelStats {
+                last_minute: self.usage.usage(1),
+                last_hour: self.usage.usage(60),
+                last_day: self.usage.usage(USAGE_BUCKETS),
+                interface: connector.interface_stats(),
+                ..connector.stats()
+            });
+        }
+        status
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::{
+        model::MfaChallenge,
+        tunnel::{PhaseSender, TunnelCommand, VpnTunnel},
+    };
+
+    // fails the challenge answers and the push polls with the given error
+    struct FailingConnector {
+        error: fn() -> anyhow::Error,
+    }
+
+    #[async_trait]
+    impl TunnelConnector for FailingConnector {
+        async fn authenticate(&mut self) -> anyhow::Result<Arc<VpnSession>> {
+            Err((self.error)())
+        }
+
+        async fn challenge_code(
+            &mut self,
+            _session: Arc<VpnSession>,
+            _user_input: &str,
+        ) -> anyhow::Result<Arc<VpnSession>> {
+            Err((self.error)())
+        }
+
+        async fn create_tunnel(
+            &mut self,
+            _session: Arc<VpnSession>,
+            _command_sender: mpsc::Sender<TunnelCommand>,
+            _phase_sender: Option<PhaseSender>,
+        ) -> anyhow::Result<Box<dyn VpnTunnel + Send>> {
+            Err(anyhow!("No tunnel"))
+        }
+
+        async fn terminate_tunnel(&mut self) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        async fn handle_tunnel_event(&mut self, _event: TunnelEvent) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn tunnel_type(&self) -> TunnelType {
+            TunnelType::Ssl
+        }
+
+        async fn poll_push_approval(&mut self, _session: Arc<VpnSession>) -> anyhow::Result<Option<Arc<VpnSession>>> {
+            Err((self.error)())
+        }
+    }
+
+    fn pending_server(error: fn() -> anyhow::Error) -> CommandServer {
+        let challenge = MfaChallenge {
+            prompt: "Enter the code: ".to_owned(),
+            ..Default::default()
+        };
+        let mut server = CommandServer::new(0);
+        server.connector = Some(Box::new(FailingConnector { error }));
+        server.session = Some(Arc::new(VpnSession {
+            ccc_session_id: "session".to_owned(),
+            ipsec_session: None,
+            state: SessionState::PendingChallenge(challenge.clone()),
+        }));
+        server.connection_status = ConnectionStatus::mfa(challenge);
+        server
+    }
+
+    async fn answer_challenge(server: &mut CommandServer) -> TunnelServiceResponse {
+        let (sender, _receiver) = mpsc::channel(16);
+        let request = TunnelServiceRequest::ChallengeCode("123456".to_owned(), TunnelParams::default());
+        server.handle(request, sender).await
+    }
+
+    #[tokio::test]
+    async fn test_rejected_challenge_keeps_session() {
+        let mut server = pending_server(|| ChallengeRejected("Wrong code".to_owned()).into());
+        let response = answer_challenge(&mut server).await;
+        assert!(matches!(response, TunnelServiceResponse::Error(_)));
+        assert!(server.session.is_some());
+        assert!(server.connection_status.mfa.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_failed_push_poll_keeps_session() {
+        let mut server = pending_server(|| anyhow!("Push approval is not supported for this tunnel type!"));
+        let (sender, _receiver) = mpsc::channel(16);
+        let response = server.handle(TunnelServiceRequest::PollPushApproval, sender).await;
+        assert!(matches!(response, TunnelServiceResponse::Error(_)));
+        assert!(server.session.is_some());
+        assert!(server.connection_status.mfa.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_failed_challenge_drops_session() {
+        let mut server = pending_server(|| AuthenticationFailed("Authentication failed!".to_owned()).into());
+        let response = answer_challenge(&mut server).await;
+        assert!(matches!(response, TunnelServiceResponse::Error(_)));
+        assert!(server.session.is_none());
+        assert!(server.connection_status.mfa.is_none());
+    }
+
+    #[test]
+    fn test_session_duration_kept_on_reconnect() {
+        let mut server = CommandServer::new(0);
+        server.params = Arc::new(TunnelParams {
+            max_session_duration: Duration::from_secs(3600),
+            ..Default::default()
+        });
+
+        server.start_session_duration();
+        let deadline = server.session_end_deadline;
+        let ends = server.connection_status.session_ends;
+        assert!(deadline.is_some());
+
+        // the warning has been shown already, a reconnect must not restart the duration
+        server.session_warning_deadline = None;
+        server.start_session_duration();
+        assert_eq!(server.session_end_deadline, deadline);
+        assert_eq!(server.connection_status.session_ends, ends);
+        assert!(server.session_warning_deadline.is_none());
+
+        server.reset();
+        assert!(server.session_end_deadline.is_none());
+    }
+
+    #[test]
+    fn test_busy_retry_wait() {
+        assert_eq!(busy_retry_delay(0), Duration::from_secs(5));
+        assert_eq!(busy_retry_delay(2), Duration::from_secs(20));
+        assert_eq!(busy_retry_delay(10), MAX_BUSY_RETRY_DELAY);
+
+        // 5 + 10 + 20 + 40 seconds fit into the budget, the next minute does not
+        let mut waited = Duration::ZERO;
+        let mut attempt = 0;
+        while let Some(delay) = busy_retry_wait(attempt, None, waited) {
+            waited += delay;
+            attempt += 1;
+        }
+        assert_eq!(attempt, 4);
+        assert_eq!(waited, Duration::from_secs(75));
+
+        let suggested = Some(Duration::from_secs(30));
+        assert_eq!(busy_retry_wait(0, suggested, Duration::ZERO), suggested);
+        assert_eq!(busy_retry_wait(0, suggested, Duration::from_secs(70)), None);
+        assert_eq!(busy_retry_wait(0, Some(Duration::from_secs(600)), Duration::ZERO), None);
+    }
+
+    #[tokio::test]
+    async fn test_schedule_reconnect() {
+        let mut server = CommandServer::new(0);
+        server.params = Arc::new(TunnelParams {
+            retry_on_busy: 2,
+            ..Default::default()
+        });
+
+        assert!(!server.schedule_reconnect(&anyhow!("Connection refused")));
+        assert!(server.reconnect_deadline.is_none());
+
+        let busy = || anyhow::Error::from(GatewayBusy("busy".to_owned(), Some(Duration::from_secs(30))));
+        assert!(server.schedule_reconnect(&busy()));
+        assert!(server.reconnect_deadline.is_some());
+        assert!(server.connection_status.reconnect_at.is_some());
+        assert_eq!(server.connection_status.suggested_retry_interval, Some(30));
+
+        // the suggested interval is kept in the status of the failed connection
+        server.reset();
+        assert!(server.reconnect_deadline.is_none());
+        assert_eq!(server.current_status().suggested_retry_interval, Some(30));
+
+        assert!(server.schedule_reconnect(&busy()));
+        server.reset();
+        assert!(!server.schedule_reconnect(&busy()));
+        assert!(server.reconnect_deadline.is_none());
+
+        assert_eq!(
+            reconnect_delay(0, Some(Duration::from_secs(3600))),
+            MAX_SUGGESTED_RETRY_DELAY
+        );
+        assert_eq!(reconnect_delay(1, None), Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_not_connected() {
+        let mut server = CommandServer::new(0);
+        let (sender, _receiver) = mpsc::channel(16);
+        let response = server
+            .handle(TunnelServiceRequest::SetRateLimit(Some(100_000)), sender)
+            .await;
+        assert!(matches!(response, TunnelServiceResponse::Error(_)));
+        assert!(server.params.rate_limit.is_none());
+        assert!(server.connection_status.rate_limit.is_none());
+    }
+
+    #[test]
+    fn test_privileged_options() {
+        let service_params = TunnelParams {
+            posture_script: Some("/usr/libexec/posture".into()),
+            ddns_key: Some("/etc/snx-rs/ddns.key".into()),
+            ..Default::default()
+        };
+        let options = PrivilegedOptions::from_params(&service_params);
+
+        let mut params = TunnelParams {
+            posture_script: Some("/tmp/script".into()),
+            ddns_key: Some("/root/.ssh/id_rsa".into()),
+            ..Default::default()
+        };
+        options.apply(&mut params);
+        assert_eq!(params.posture_script, service_params.posture_script);
+        assert_eq!(params.ddns_key, service_params.ddns_key);
+
+        let mut params = TunnelParams {
+            ddns_key: Some("/root/.ssh/id_rsa".into()),
+            ..Default::default()
+        };
+        PrivilegedOptions::default().apply(&mut params);
+        assert!(params.posture_script.is_none());
+        assert!(params.ddns_key.is_none());
+    }
+
+    fn standby_server() -> CommandServer {
+        let mut server = CommandServer::new(0);
+        server.params = Arc::new(TunnelParams {
+            server_name: "vpn.example.com".to_owned(),
+            user_name: "user".to_owned(),
+            tunnel_type: TunnelType::Ipsec,
+            ..Default::default()
+        });
+        server.connection_status = ConnectionStatus::standby();
+        server
+    }
+
+    #[test]
+    fn test_has_standby_for() {
+        let server = standby_server();
+        let params = TunnelParams {
+            tunnel_type: TunnelType::Auto,
+            ..(*server.params).clone()
+        };
+        assert!(server.has_standby_for(&params));
+        assert!(server.has_standby_for(&server.params));
+
+        let params = TunnelParams {
+            correlation_id: Some("0badcafe".to_owned()),
+            ..(*server.params).clone()
+        };
+        assert!(server.has_standby_for(&params));
+
+        let params = TunnelParams {
+            tunnel_type: TunnelType::Ssl,
+            ..(*server.params).clone()
+        };
+        assert!(!server.has_standby_for(&params));
+
+        let params = TunnelParams {
+            add_routes: vec!["10.1.0.0/16".parse().unwrap()],
+            ..(*server.params).clone()
+        };
+        assert!(!server.has_standby_for(&params));
+
+        let params = TunnelParams {
+            no_dns: true,
+            ..(*server.params).clone()
+        };
+        assert!(!server.has_standby_for(&params));
+    }
+
+    #[test]
+    fn test_has_standby_for_without_standby() {
+        let mut server = standby_server();
+        server.connection_status = ConnectionStatus::disconnected();
+        assert!(!server.has_standby_for(&server.params.clone()));
+    }
+
+    #[test]
+    fn test_set_cleanup_result() {
+        let mut server = CommandServer::new(0);
+        server.set_cleanup_result(&["route 10.0.0.0/8".to_owned(), "device snx-xfrm".to_owned()]);
+        assert_eq!(
+            server.connection_status.cleanup_warning.as_deref(),
+            Some("System not fully restored, left over: route 10.0.0.0/8, device snx-xfrm")
+        );
+
+        server.set_cleanup_result(&[]);
+        assert!(server.connection_status.cleanup_warning.is_none());
+    }
+
+    fn totals(meter: &UsageMeter, minutes: usize) -> (u64, u64) {
+        let usage = meter.usage(minutes);
+        (usage.received, usage.sent)
+    }
+
+    #[test]
+    fn test_usage_meter() {
+        let mut meter = UsageMeter::default();
+        meter.update(100, 10);
+        meter.update(250, 30);
+        assert_eq!(totals(&meter, 1), (250, 30));
+
+        // two minutes later the traffic goes to a new bucket
+        meter.bucket_start -= USAGE_BUCKET_DURATION * 2;
+        meter.update(300, 50);
+        assert_eq!(totals(&meter, 1), (50, 20));
+        assert_eq!(totals(&meter, 2), (50, 20));
+        assert_eq!(totals(&meter, 60), (300, 50));
+
+        // the counters of a recreated interface start from zero
+        meter.restart();
+        meter.update(40, 5);
+        assert_eq!(totals(&meter, 1), (90, 25));
+        meter.update(20, 5);
+        assert_eq!(totals(&meter, USAGE_BUCKETS), (360, 55));
+    }
+
+    #[test]
+    fn test_usage_meter_wraps_around() {
+        let mut meter = UsageMeter {
+            current: USAGE_BUCKETS - 1,
+            ..Default::default()
+        };
+        meter.update(1000, 100);
+
+        meter.bucket_start -= USAGE_BUCKET_DURATION;
+        meter.update(1500, 200);
+        assert_eq!(meter.current, 0);
+        assert_eq!(totals(&meter, 1), (500, 100));
+        assert_eq!(totals(&meter, USAGE_BUCKETS + 10), (1500, 200));
     }
 }
@@ -1,17 +1,45 @@
 use std::{collections::VecDeque, sync::Arc};
 
+use anyhow::anyhow;
+use serde::Serialize;
+
 use crate::{
     ccc::CccHttpClient,
     model::{
         params::TunnelParams,
-        proto::{LoginDisplayLabelSelect, ServerInfoResponse},
+        proto::{LoginDisplayLabelSelect, LoginOption, ServerInfoResponse},
     },
+    prompt::SecurePrompt,
     sexpr::SExpression,
 };
 
+/// Login type offered by the server, the id is a valid login-type value
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LoginType {
+    pub id: String,
+    pub label: String,
+    pub factors: Vec<String>,
+    /// offered for explicit realm selection
+    pub realm: bool,
+}
+
+impl From<LoginOption> for LoginType {
+    fn from(option: LoginOption) -> Self {
+        Self {
+            id: option.id,
+            label: option.display_name.0,
+            factors: option.factors.into_values().map(|factor| factor.factor_type).collect(),
+            realm: option.show_realm != 0,
+        }
+    }
+}
+
 pub async fn get(params: &TunnelParams) -> anyhow::Result<ServerInfoResponse> {
     let client = CccHttpClient::new(Arc::new(params.clone()), None);
-    let info = client.get_server_info().await?;
+    parse(&client.get_server_info().await?)
+}
+
+pub fn parse(info: &SExpression) -> anyhow::Result<ServerInfoResponse> {
     info.get("CCCserverResponse:ResponseData")
         .cloned()
         .unwrap_or(SExpression::Null)
@@ -19,12 +47,15 @@ pub async fn get(params: &TunnelParams) -> anyhow::Result<ServerInfoResponse> {
 }
 
 pub async fn get_mfa_prompts(params: &TunnelParams) -> anyhow::Result<VecDeque<String>> {
-    let mut mfa_prompts = VecDeque::new();
     if !params.server_prompt {
-        return Ok(mfa_prompts);
+        return Ok(VecDeque::new());
     }
-    let server_info = get(params).await?;
-    let login_type = &params.login_type;
+    Ok(mfa_prompts(get(params).await?, &params.login_type))
+}
+
+// the password prompts of the login factors, in the order of the factors
+pub fn mfa_prompts(server_info: ServerInfoResponse, login_type: &str) -> VecDeque<String> {
+    let mut mfa_prompts = VecDeque::new();
     let login_factors = server_info
         .login_options_data
         .login_options_list
@@ -45,5 +76,156 @@ pub async fn get_mfa_prompts(params: &TunnelParams) -> anyhow::Result<VecDeque<S
         })
         .for_each(|prompt| mfa_prompts.push_back(format!("{}: ", prompt.0.clone())));
 
-    Ok(mfa_prompts)
+    mfa_prompts
+}
+
+pub async fn get_realms(params: &TunnelParams) -> anyhow::Result<Vec<(String, String)>> {
+    Ok(realms(get(params).await?))
+}
+
+// realms which the server offers for explicit selection, as (id, display name) pairs
+pub fn realms(server_info: ServerInfoResponse) -> Vec<(String, String)> {
+    server_info
+        .login_options_data
+        .login_options_list
+        .into_values()
+        .filter(|option| option.show_realm != 0)
+        .map(|option| (option.id, option.display_name.0))
+        .collect()
+}
+
+// structured variant of the login options printed by the info mode
+pub async fn get_login_types(params: &TunnelParams) -> anyhow::Result<Vec<LoginType>> {
+    let server_info = get(params).await?;
+    Ok(server_info
+        .login_options_data
+        .login_options_list
+        .into_values()
+        .map(LoginType::from)
+        .collect())
+}
+
+// SAML identity providers of the realm, as (id, display name) pairs
+pub fn identity_providers(server_info: ServerInfoResponse, realm_id: &str) -> Vec<(String, String)> {
+    server_info
+        .login_options_data
+        .login_options_list
+        .into_values()
+        .find(|option| option.id == realm_id)
+        .and_then(|option| option.identity_providers)
+        .unwrap_or_default()
+        .into_values()
+        .map(|idp| (idp.id, idp.display_name.0))
+        .collect()
+}
+
+fn select<P: SecurePrompt>(prompt: &P, kind: &str, items: &[(String, String)]) -> anyhow::Result<String> {
+    let mut text = format!("Available {}s:\n", kind);
+    for (i, (id, name)) in items.iter().enumerate() {
+        text.push_str(&format!("\t{}: {} ({})\n", i + 1, name, id));
+    }
+    text.push_str(&format!("Select {}: ", kind));
+
+    let input = prompt.get_plain_input(&text)?;
+
+    input
+        .parse::<usize>()
+        .ok()
+        .and_then(|i| items.get(i.wrapping_sub(1)))
+        .map(|(id, _)| id.clone())
+        .ok_or_else(|| anyhow!("Invalid {} selection: {}", kind, input))
+}
+
+pub fn select_realm<P: SecurePrompt>(prompt: &P, realms: &[(String, String)]) -> anyhow::Result<String> {
+    select(prompt, "realm", realms)
+}
+
+// login type for a configuration without one: the configured realm, the only realm offered by the server,
+// or the one selected by the user when the server offers several
+pub async fn resolve_login_type<P: SecurePrompt>(params: &TunnelParams, prompt: &P) -> anyhow::Result<String> {
+    if let Some(ref realm) = params.realm {
+        return Ok(realm.clone());
+    }
+
+    select_login_type(get(params).await?, prompt)
+}
+
+// the only realm offered by the server or the one selected by the user
+pub fn select_login_type<P: SecurePrompt>(server_info: ServerInfoResponse, prompt: &P) -> anyhow::Result<String> {
+    let realms = realms(server_info);
+
+    match realms.as_slice() {
+        [] => Err(anyhow!(
+            "No login type configured and the server offers no realm selection!"
+        )),
+        [(id, _)] => Ok(id.clone()),
+        _ => select_realm(prompt, &realms),
+    }
+}
+
+// the configured provider is matched by id or display name, otherwise the user is asked
+pub fn select_identity_provider<P: SecurePrompt>(
+    prompt: &P,
+    idp: Option<&str>,
+    providers: &[(String, String)],
+) -> anyhow::Result<String> {
+    match idp {
+        Some(idp) => providers
+            .iter()
+            .find(|(id, name)| id == idp || name.eq_ignore_ascii_case(idp))
+            .map(|(id, _)| id.clone())
+            .ok_or_else(|| anyhow!("Unknown identity provider: {}", idp)),
+        None => select(prompt, "identity provider", providers),
+    }
+}
+
+// the selected provider is passed to the gateway as a query parameter of the SAML login URL
+pub fn with_identity_provider(url: &str, idp: &str) -> anyhow::Result<String> {
+    let mut url = reqwest::Url::parse(url)?;
+    url.query_pairs_mut().append_pair("idp", idp);
+    Ok(url.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::model::{proto::LoginFactor, wrappers::QuotedString};
+
+    #[test]
+    fn test_login_type() {
+        let factor = LoginFactor {
+            factor_type: "password".to_owned(),
+            securid_card_type: String::new(),
+            certificate_storage_type: String::new(),
+            custom_display_labels: LoginDisplayLabelSelect::Empty(String::new()),
+        };
+        let option = LoginOption {
+            id: "vpn_Username_Password".to_owned(),
+            secondary_realm_hash: String::new(),
+            display_name: QuotedString("Username Password".to_owned()),
+            show_realm: 1,
+            factors: BTreeMap::from([("0".to_owned(), factor)]),
+            identity_providers: None,
+        };
+
+        assert_eq!(
+            LoginType::from(option),
+            LoginType {
+                id: "vpn_Username_Password".to_owned(),
+                label: "Username Password".to_owned(),
+                factors: vec!["password".to_owned()],
+                realm: true,
+            }
+        );
+    }
+
+    #[test]
+    fn test_with_identity_provider() {
+        assert_eq!(
+            with_identity_provider("https://vpn.example.com/saml-vpn/?session=1", "azure ad").unwrap(),
+            "https://vpn.example.com/saml-vpn/?session=1&idp=azure+ad"
+        );
+    }
 }
@@ -0,0 +1,111 @@
+use std::time::{Duration, Instant};
+
+use tokio::{net::TcpStream, sync::mpsc};
+use tokio_native_tls::native_tls::TlsConnector;
+
+use crate::{
+    model::{params::TunnelParams, ConnectionPhase, PhaseTiming},
+    tunnel::PhaseSender,
+    util,
+};
+
+// the gateway connection is timed separately, the HTTP client doesn't expose its handshake
+pub(crate) async fn probe_gateway(params: &TunnelParams) -> anyhow::Result<Vec<PhaseTiming>> {
+    let started = Instant::now();
+    let (host, port) = util::split_server_name(&params.server_name);
+    let address = util::resolve_server(host, port.unwrap_or(443), params.ip_mode).await?;
+    let mut timings = vec![PhaseTiming::new("dns-resolve", started.elapsed())];
+
+    let started = Instant::now();
+    let tcp = TcpStream::connect(address).await?;
+    timings.push(PhaseTiming::new("tcp-connect", started.elapsed()));
+
+    // only the latency matters here, the certificate is verified by the connection itself
+    let started = Instant::now();
+    let connector: tokio_native_tls::TlsConnector = TlsConnector::builder()
+        .danger_accept_invalid_certs(true)
+        .danger_accept_invalid_hostnames(true)
+        .build()?
+        .into();
+    connector.connect(host, tcp).await?;
+    timings.push(PhaseTiming::new("tls-handshake", started.elapsed()));
+
+    Ok(timings)
+}
+
+fn phase_name(phase: ConnectionPhase) -> &'static str {
+    match phase {
+        ConnectionPhase::Authenticating => "auth",
+        ConnectionPhase::Mfa => "mfa",
+        ConnectionPhase::FetchingSettings => "client-settings",
+        ConnectionPhase::ConfiguringTunnel => "tunnel-setup",
+        ConnectionPhase::Connected => "connected",
+    }
+}
+
+// each phase lasts until the next one is reported or the collection ends
+fn phase_timings(phases: &[(ConnectionPhase, Instant)], finished: Instant) -> Vec<PhaseTiming> {
+    phases
+        .iter()
+        .enumerate()
+        .map(|(i, (phase, started))| {
+            let ended = phases.get(i + 1).map_or(finished, |(_, next)| *next);
+            PhaseTiming::new(phase_name(*phase), ended.saturating_duration_since(*started))
+        })
+        .collect()
+}
+
+/// Records the tunnel setup phases as they are reported
+pub(crate) struct PhaseRecorder {
+    collector: tokio::task::JoinHandle<Vec<(ConnectionPhase, Instant)>>,
+}
+
+impl PhaseRecorder {
+    pub(crate) fn start() -> (Self, PhaseSender) {
+        let (sender, mut receiver) = mpsc::channel(16);
+        let collector = tokio::spawn(async move {
+            let mut phases = Vec::new();
+            while let Some(phase) = receiver.recv().await {
+                phases.push((phase, Instant::now()));
+            }
+            phases
+        });
+        (Self { collector }, sender)
+    }
+
+    // the tunnel drops its phase sender once created, the timeout covers a sender which is kept
+    pub(crate) async fn finish(self) -> Vec<PhaseTiming> {
+        let finished = Instant::now();
+        let mut collector = self.collector;
+        match tokio::time::timeout(Duration::from_secs(1), &mut collector).await {
+            Ok(Ok(phases)) => phase_timings(&phases, finished),
+            _ => {
+                collector.abort();
+                Vec::new()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_phase_timings() {
+        let start = Instant::now();
+        let phases = [
+            (ConnectionPhase::FetchingSettings, start),
+            (ConnectionPhase::ConfiguringTunnel, start + Duration::from_millis(120)),
+        ];
+        let timings = phase_timings(&phases, start + Duration::from_millis(200));
+
+        assert_eq!(
+            timings,
+            vec![
+                PhaseTiming::new("client-settings", Duration::from_millis(120)),
+                PhaseTiming::new("tunnel-setup", Duration::from_millis(80)),
+            ]
+        );
+    }
+}
@@ -1,24 +1,49 @@
-use std::sync::Arc;
+use std::{
+    fmt,
+    future::Future,
+    net::Ipv4Addr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
+use anyhow::anyhow;
 use async_trait::async_trait;
 use bytes::Bytes;
-use tokio::sync::mpsc;
+use ipnet::Ipv4Net;
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
 
 use crate::{
+    ccc::CccHttpClient,
+    certs,
     model::{
-        params::{TunnelParams, TunnelType},
+        params::{Quirk, TunnelParams, TunnelType},
+        proto::ServerInfoResponse,
         *,
     },
+    posture::{self, PostureInfo},
+    progress, server_info,
     tunnel::{ipsec::connector::IpsecTunnelConnector, ssl::connector::CccTunnelConnector},
 };
 
 mod ipsec;
 mod ssl;
 
-#[derive(Debug, Clone, PartialEq)]
+pub(crate) use ipsec::keepalive::make_keepalive_packet;
+
+// the commands with a reply sender are answered once they have been applied
+#[derive(Debug)]
 pub enum TunnelCommand {
     Terminate,
     ReKey(IpsecSession),
+    Suspend(oneshot::Sender<anyhow::Result<()>>),
+    Resume(oneshot::Sender<anyhow::Result<()>>),
+    AddRoute(Ipv4Net, oneshot::Sender<anyhow::Result<()>>),
+    RemoveRoute(Ipv4Net, oneshot::Sender<anyhow::Result<()>>),
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -26,7 +51,115 @@ pub enum TunnelEvent {
     Connected,
     Disconnected,
     RekeyCheck,
+    RekeyRequired,
     RemoteControlData(Bytes),
+    ReconnectRequired,
+    EncryptionDomain(Vec<Ipv4Net>),
+    RouteConflicts(Vec<String>),
+}
+
+// counters shared between the running tunnel and its connector
+#[derive(Debug, Default)]
+pub(crate) struct TunnelCounters {
+    pub recv_errors: AtomicU64,
+    // last keepalive round trip time in milliseconds, 0 if not measured yet
+    pub keepalive_rtt: AtomicU64,
+    // outbound SA sequence number and byte count, 0 if not measured yet
+    pub esp_sequence: AtomicU64,
+    pub esp_bytes: AtomicU64,
+    // packets dropped by the kernel because of a full socket receive buffer
+    pub socket_drops: AtomicU64,
+}
+
+impl TunnelCounters {
+    pub fn stats(&self) -> TunnelStats {
+        let measured = |counter: &AtomicU64| Some(counter.load(Ordering::SeqCst)).filter(|v| *v > 0);
+        TunnelStats {
+            recv_errors: self.recv_errors.load(Ordering::SeqCst),
+            keepalive_rtt: measured(&self.keepalive_rtt),
+            esp_sequence: measured(&self.esp_sequence),
+            esp_bytes: measured(&self.esp_bytes),
+            socket_drops: self.socket_drops.load(Ordering::SeqCst),
+            ..Default::default()
+        }
+    }
+}
+
+// fill in the byte counters from the tunnel interface
+pub(crate) fn with_interface_counters(mut stats: TunnelStats, if_name: &str) -> TunnelStats {
+    if let Some((received, sent)) = crate::platform::get_interface_counters(if_name) {
+        stats.bytes_received = received;
+        stats.bytes_sent = sent;
+    }
+    stats
+}
+
+pub type PhaseSender = mpsc::Sender<ConnectionPhase>;
+
+pub(crate) fn report_phase(sender: Option<&PhaseSender>, phase: ConnectionPhase) {
+    debug!("Connection phase: {}", phase);
+    progress::report(phase.to_string());
+    if let Some(sender) = sender {
+        let _ = sender.try_send(phase);
+    }
+}
+
+/// Challenge answer rejected by the gateway which keeps the authentication session open for another attempt
+#[derive(Debug)]
+pub struct ChallengeRejected(pub String);
+
+impl fmt::Display for ChallengeRejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ChallengeRejected {}
+
+/// Credentials rejected by the gateway, the authentication session is closed
+#[derive(Debug)]
+pub struct AuthenticationFailed(pub String);
+
+impl fmt::Display for AuthenticationFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for AuthenticationFailed {}
+
+/// Connection refused by the gateway because it is at capacity, the same request may succeed later.
+/// The gateway may suggest how long to wait before the next attempt.
+#[derive(Debug)]
+pub struct GatewayBusy(pub String, pub Option<Duration>);
+
+impl fmt::Display for GatewayBusy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for GatewayBusy {}
+
+// sends the command to the tunnel and waits for the outcome
+pub(crate) async fn request<F>(sender: &mpsc::Sender<TunnelCommand>, command: F) -> anyhow::Result<()>
+where
+    F: FnOnce(oneshot::Sender<anyhow::Result<()>>) -> TunnelCommand,
+{
+    let (reply_sender, reply_receiver) = oneshot::channel();
+    sender.send(command(reply_sender)).await?;
+    reply_receiver.await?
+}
+
+// the future is dropped at its current await point when the token is cancelled first
+pub(crate) async fn cancellable<F, T>(token: &CancellationToken, fut: F) -> anyhow::Result<T>
+where
+    F: Future<Output = anyhow::Result<T>>,
+{
+    tokio::select! {
+        result = fut => result,
+        _ = token.cancelled() => Err(anyhow!("Tunnel setup cancelled!")),
+    }
 }
 
 #[async_trait]
@@ -46,14 +179,192 @@ pub trait TunnelConnector {
         &mut self,
         session: Arc<VpnSession>,
         command_sender: mpsc::Sender<TunnelCommand>,
+        phase_sender: Option<PhaseSender>,
     ) -> anyhow::Result<Box<dyn VpnTunnel + Send>>;
     async fn terminate_tunnel(&mut self) -> anyhow::Result<()>;
     async fn handle_tunnel_event(&mut self, event: TunnelEvent) -> anyhow::Result<()>;
+
+    // the tunnel type in use, resolved from the auto mode
+    fn tunnel_type(&self) -> TunnelType;
+
+    // aborts the tunnel setup in create_tunnel, tunnel types without the support ignore it
+    fn set_cancellation_token(&mut self, _token: CancellationToken) {}
+
+    // establishes a new tunnel session within the authenticated one, without the user input
+    async fn reuse_session(&mut self, _session: Arc<VpnSession>) -> anyhow::Result<Arc<VpnSession>> {
+        Err(anyhow!("Session reuse is not supported for this tunnel type!"))
+    }
+
+    async fn request_otp_delivery(&mut self, _session: Arc<VpnSession>, _method: &str) -> anyhow::Result<()> {
+        Err(anyhow!("OTP delivery is not supported for this tunnel type!"))
+    }
+
+    // None while the push challenge is not approved yet
+    async fn poll_push_approval(&mut self, _session: Arc<VpnSession>) -> anyhow::Result<Option<Arc<VpnSession>>> {
+        Err(anyhow!("Push approval is not supported for this tunnel type!"))
+    }
+
+    async fn suspend_tunnel(&mut self) -> anyhow::Result<()> {
+        Err(anyhow!("Suspend is not supported for this tunnel type!"))
+    }
+
+    async fn resume_tunnel(&mut self) -> anyhow::Result<()> {
+        Err(anyhow!("Resume is not supported for this tunnel type!"))
+    }
+
+    async fn add_route(&mut self, _route: Ipv4Net) -> anyhow::Result<()> {
+        Err(anyhow!("Adding routes is not supported for this tunnel type!"))
+    }
+
+    async fn remove_route(&mut self, _route: Ipv4Net) -> anyhow::Result<()> {
+        Err(anyhow!("Removing routes is not supported for this tunnel type!"))
+    }
+
+    // None removes the limit, the inbound flag applies it to the received traffic as well
+    async fn set_rate_limit(&mut self, _rate: Option<u64>, _inbound: bool) -> anyhow::Result<()> {
+        Err(anyhow!("Rate limit is not supported for this tunnel type!"))
+    }
+
+    fn stats(&self) -> TunnelStats {
+        TunnelStats::default()
+    }
+
+    // kernel counters of the tunnel device, read on demand
+    fn interface_stats(&self) -> Option<InterfaceStats> {
+        None
+    }
+}
+
+// routes added while connected must not take over the default route or the traffic to the gateway
+pub(crate) fn check_runtime_route(route: Ipv4Net, gateway: Ipv4Addr) -> anyhow::Result<()> {
+    // the two halves of the address space replace the default route just as well
+    if route.prefix_len() <= 1 {
+        Err(anyhow!("The default route cannot be added at runtime!"))
+    } else if route.contains(&gateway) {
+        Err(anyhow!("Route {} covers the gateway address {}!", route, gateway))
+    } else {
+        Ok(())
+    }
 }
 
 pub async fn new_tunnel_connector(params: Arc<TunnelParams>) -> anyhow::Result<Box<dyn TunnelConnector + Send>> {
+    // a single client hello serves the redirect check, the compliance check and the tunnel type selection
+    let (server_info, redirect) = match CccHttpClient::new(params.clone(), None)
+        .get_server_info_with_host()
+        .await
+    {
+        Ok((info, host)) => (server_info::parse(&info).ok(), Some(host)),
+        // the retries on the busy gateway are made by the caller
+        Err(e) if e.is::<GatewayBusy>() => return Err(e),
+        Err(e) => {
+            debug!("Unable to get the server info: {}", e);
+            (None, None)
+        }
+    };
+
+    let params = match redirect {
+        Some(server_name) if server_name != params.server_name && !params.has_quirk(Quirk::NoRedirectCheck) => {
+            debug!("Using redirected server: {}", server_name);
+            Arc::new(TunnelParams {
+                server_name,
+                ..(*params).clone()
+            })
+        }
+        _ => params,
+    };
+
+    let params = match certs::select_client_cert(&params).await? {
+        Some(cert_path) => {
+            debug!("Selected client certificate: {}", cert_path.display());
+            Arc::new(TunnelParams {
+                cert_path: Some(cert_path),
+                ..(*params).clone()
+            })
+        }
+        None => params,
+    };
+
+    let posture = posture::collect(&params, server_info.as_ref()).await;
+
     match params.tunnel_type {
-        TunnelType::Ssl => Ok(Box::new(CccTunnelConnector::new(params).await?)),
-        TunnelType::Ipsec => Ok(Box::new(IpsecTunnelConnector::new(params).await?)),
+        TunnelType::Ssl => Ok(Box::new(CccTunnelConnector::new(params, posture).await?)),
+        // the kernel decapsulates the ESP traffic into the XFRM interface, it cannot be passed to a TUN device
+        TunnelType::Ipsec if params.tun_device.is_some() => {
+            Err(anyhow!("The tun-device option is not supported with the IPSec tunnel!"))
+        }
+        TunnelType::Ipsec => Ok(Box::new(IpsecTunnelConnector::new(params, posture).await?)),
+        TunnelType::Auto => new_auto_connector(params, server_info.as_ref(), posture).await,
+    }
+}
+
+// IPSec performs better, SSL is used when the gateway does not offer IPSec, the NAT-T probe fails
+// or the traffic goes to a given TUN device
+async fn new_auto_connector(
+    params: Arc<TunnelParams>,
+    server_info: Option<&ServerInfoResponse>,
+    posture: Option<PostureInfo>,
+) -> anyhow::Result<Box<dyn TunnelConnector + Send>> {
+    let ipsec = match server_info {
+        _ if params.tun_device.is_some() => false,
+        Some(info) => supports_ipsec(&info.connectivity_info.supported_data_tunnel_protocols),
+        None => {
+            warn!("Unable to get the tunnel protocols of the gateway, trying IPSec");
+            true
+        }
+    };
+
+    if ipsec {
+        let ipsec_params = Arc::new(params.with_tunnel_type(TunnelType::Ipsec));
+        match IpsecTunnelConnector::new(ipsec_params, posture.clone()).await {
+            Ok(connector) => {
+                report_tunnel_type(TunnelType::Ipsec);
+                return Ok(Box::new(connector));
+            }
+            Err(e) => warn!("IPSec tunnel is not available, falling back to SSL: {}", e),
+        }
+    } else if params.tun_device.is_some() {
+        debug!("TUN device is given, using the SSL tunnel");
+    } else {
+        debug!("Gateway does not offer the IPSec tunnel");
+    }
+
+    let connector = CccTunnelConnector::new(Arc::new(params.with_tunnel_type(TunnelType::Ssl)), posture).await?;
+    report_tunnel_type(TunnelType::Ssl);
+
+    Ok(Box::new(connector))
+}
+
+fn supports_ipsec(protocols: &[String]) -> bool {
+    protocols.iter().any(|protocol| protocol.eq_ignore_ascii_case("ipsec"))
+}
+
+fn report_tunnel_type(tunnel_type: TunnelType) {
+    info!("Selected the {} tunnel", tunnel_type.as_str());
+    progress::report(format!("Using the {} tunnel", tunnel_type.as_str()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_supports_ipsec() {
+        assert!(supports_ipsec(&[
+            "IPSec".to_owned(),
+            "SSL".to_owned(),
+            "L2TP".to_owned()
+        ]));
+        assert!(!supports_ipsec(&["SSL".to_owned()]));
+        assert!(!supports_ipsec(&[]));
+    }
+
+    #[test]
+    fn test_check_runtime_route() {
+        let gateway = Ipv4Addr::new(203, 0, 113, 10);
+        assert!(check_runtime_route("10.20.0.0/16".parse().unwrap(), gateway).is_ok());
+        assert!(check_runtime_route("0.0.0.0/0".parse().unwrap(), gateway).is_err());
+        assert!(check_runtime_route("0.0.0.0/1".parse().unwrap(), gateway).is_err());
+        assert!(check_runtime_route("128.0.0.0/1".parse().unwrap(), gateway).is_err());
+        assert!(check_runtime_route("203.0.113.0/24".parse().unwrap(), gateway).is_err());
     }
 }
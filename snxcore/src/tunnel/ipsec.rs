@@ -1,53 +1,115 @@
 use std::{
-    net::{IpAddr, ToSocketAddrs},
+    net::{IpAddr, Ipv4Addr},
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use anyhow::anyhow;
+use ipnet::Ipv4Net;
 use tokio::{net::UdpSocket, sync::mpsc, time::MissedTickBehavior};
-use tracing::debug;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
 
 use crate::{
     ccc::CccHttpClient,
-    model::{params::TunnelParams, VpnSession},
-    platform::{self, IpsecConfigurator, UdpEncap, UdpSocketExt},
+    ddns,
+    model::{
+        params::{KeepaliveFailure, TunnelParams},
+        ConnectionPhase, VpnSession,
+    },
+    platform::{self, EspUsage, IpsecConfigurator, UdpEncap, UdpSocketExt},
     tunnel::{
-        ipsec::{keepalive::KeepaliveRunner, natt::start_natt_listener},
-        TunnelCommand, TunnelEvent, VpnTunnel,
+        self,
+        ipsec::{decap::start_decap_listener, keepalive::KeepaliveRunner},
+        PhaseSender, TunnelCommand, TunnelCounters, TunnelEvent, VpnTunnel,
     },
     util,
 };
 
 pub mod connector;
+pub mod decap;
 pub mod keepalive;
 pub mod natt;
 
+const ESP_USAGE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+// a rekey which has not completed by then is requested again
+const ESP_REKEY_RETRY_INTERVAL: Duration = Duration::from_secs(120);
+
+// without ESN the sequence number is 32-bit, the kernel drops the packets once it wraps
+const ESP_MAX_SEQUENCE: u64 = u32::MAX as u64;
+
+// rekey at 90% of the sequence number space or of the configured byte limit
+fn esp_limit_close(usage: &EspUsage, max_bytes: u64) -> bool {
+    usage.sequence >= ESP_MAX_SEQUENCE / 10 * 9 || (max_bytes > 0 && usage.bytes >= max_bytes / 10 * 9)
+}
+
+// the configured keepalive target must be routed through the tunnel, otherwise the keepalive always fails
+fn keepalive_target(
+    params: &TunnelParams,
+    encryption_domain: &[Ipv4Net],
+    default: Ipv4Addr,
+) -> anyhow::Result<Ipv4Addr> {
+    let Some(target) = params.keepalive_target else {
+        return Ok(default);
+    };
+
+    let reachable = params.default_route
+        || encryption_domain
+            .iter()
+            .chain(params.add_routes.iter())
+            .any(|subnet| subnet.contains(&target));
+
+    if reachable {
+        Ok(target)
+    } else {
+        Err(anyhow!(
+            "Keepalive target {} is not reachable through the tunnel!",
+            target
+        ))
+    }
+}
+
 pub(crate) struct IpsecTunnel {
     configurator: Box<dyn IpsecConfigurator + Send + Sync>,
     keepalive_runner: KeepaliveRunner,
     natt_socket: Arc<UdpSocket>,
     ready: Arc<AtomicBool>,
+    counters: Arc<TunnelCounters>,
+    encryption_domain: Vec<Ipv4Net>,
+    route_conflicts: Vec<String>,
+    address: Ipv4Addr,
+    params: Arc<TunnelParams>,
 }
 
 impl IpsecTunnel {
-    pub(crate) async fn create(params: Arc<TunnelParams>, session: Arc<VpnSession>) -> anyhow::Result<Self> {
+    // cancel safe: nothing is changed on the system until the configurator runs, which is cleaned up when
+    // the setup is cancelled in the middle of it
+    pub(crate) async fn create(
+        params: Arc<TunnelParams>,
+        session: Arc<VpnSession>,
+        phase_sender: Option<PhaseSender>,
+        counters: Arc<TunnelCounters>,
+        cancel_token: CancellationToken,
+    ) -> anyhow::Result<Self> {
         let ipsec_session = session
             .ipsec_session
             .as_ref()
             .ok_or_else(|| anyhow!("No IPSEC session!"))?;
 
+        tunnel::report_phase(phase_sender.as_ref(), ConnectionPhase::FetchingSettings);
+
         let client = CccHttpClient::new(params.clone(), Some(session.clone()));
-        let client_settings = client.get_client_settings().await?;
+        let client_settings = tunnel::cancellable(&cancel_token, client.get_client_settings()).await?;
 
-        let gateway_address = format!("{}:{}", params.server_name, params.ike_port)
-            .to_socket_addrs()?
-            .next()
-            .ok_or_else(|| anyhow!("No gateway address!"))?
-            .ip();
+        let gateway_address = tunnel::cancellable(
+            &cancel_token,
+            util::resolve_server(&params.server_name, params.ike_port, params.ip_mode),
+        )
+        .await?
+        .ip();
 
         let ipv4address = match gateway_address {
             IpAddr::V4(v4) => v4,
@@ -59,22 +121,78 @@ impl IpsecTunnel {
             ipv4address, client_settings.gw_internal_ip
         );
 
+        // the encryption domain pushed by the gateway defines what is routed through the tunnel
+        let encryption_domain =
+            util::ranges_to_subnets(&client_settings.updated_policies.range.settings).collect::<Vec<_>>();
+        debug!("Encryption domain: {:?}", encryption_domain);
+
+        let keepalive_target = keepalive_target(&params, &encryption_domain, ipv4address)?;
+        debug!("Keepalive target: {}", keepalive_target);
+
         let ready = Arc::new(AtomicBool::new(false));
-        let keepalive_runner = KeepaliveRunner::new(ipsec_session.address, ipv4address, ready.clone());
+        let keepalive_runner = KeepaliveRunner::new(
+            ipsec_session.address,
+            keepalive_target,
+            ready.clone(),
+            params.keepalive,
+            params.reconnects_on(KeepaliveFailure::Resume),
+            counters.clone(),
+        );
 
         let natt_socket = UdpSocket::bind("0.0.0.0:0").await?;
         natt_socket.set_encap(UdpEncap::EspInUdp)?;
+        if let Some(ref vrf) = params.outer_vrf {
+            natt_socket.bind_to_device(vrf)?;
+        }
+        if let Some(size) = params.socket_rcvbuf {
+            let granted = natt_socket.set_recv_buffer_size(size)?;
+            debug!("Requested socket receive buffer of {} bytes, granted {}", size, granted);
+        }
+        if let Some(size) = params.socket_sndbuf {
+            let granted = natt_socket.set_send_buffer_size(size)?;
+            debug!("Requested socket send buffer of {} bytes, granted {}", size, granted);
+        }
 
-        let mut configurator = platform::new_ipsec_configurator(
-            params,
-            ipsec_session.clone(),
-            natt_socket.local_addr()?.port(),
-            ipv4address,
-            util::ranges_to_subnets(&client_settings.updated_policies.range.settings).collect(),
+        tunnel::report_phase(phase_sender.as_ref(), ConnectionPhase::ConfiguringTunnel);
+
+        let mtu = match (params.tunnel_mtu, client_settings.mtu) {
+            (Some(mtu), _) => {
+                debug!("Tunnel MTU: {}, from the configuration", mtu);
+                Some(mtu)
+            }
+            (None, Some(mtu)) => {
+                debug!("Tunnel MTU: {}, pushed by the gateway", mtu);
+                Some(mtu)
+            }
+            (None, None) => {
+                debug!("Tunnel MTU: kernel default");
+                None
+            }
+        };
+
+        let mut configurator = tunnel::cancellable(
+            &cancel_token,
+            platform::new_ipsec_configurator(
+                params.clone(),
+                ipsec_session.clone(),
+                natt_socket.local_addr()?.port(),
+                ipv4address,
+                encryption_domain.clone(),
+                mtu,
+            ),
         )
         .await?;
 
-        configurator.configure().await?;
+        // a setup which is cancelled or refused, e.g. due to route conflicts, is cleaned up
+        let configured = tunnel::cancellable(&cancel_token, configurator.configure()).await;
+        if let Err(ref e) = configured {
+            debug!("Tunnel setup failed: {}, cleaning up", e);
+            configurator.cleanup().await;
+        }
+        configured?;
+
+        let route_conflicts = configurator.route_conflicts();
+
         ready.store(true, Ordering::SeqCst);
 
         Ok(Self {
@@ -82,6 +200,11 @@ impl IpsecTunnel {
             keepalive_runner,
             natt_socket: Arc::new(natt_socket),
             ready,
+            counters,
+            encryption_domain,
+            route_conflicts,
+            address: ipsec_session.address,
+            params,
         })
     }
 }
@@ -95,9 +218,20 @@ impl VpnTunnel for IpsecTunnel {
     ) -> anyhow::Result<()> {
         debug!("Running IPSec tunnel");
 
-        let natt_stopper = start_natt_listener(self.natt_socket.clone(), event_sender.clone()).await?;
+        let natt_stopper =
+            start_decap_listener(self.natt_socket.clone(), event_sender.clone(), self.counters.clone()).await?;
 
         let _ = event_sender.send(TunnelEvent::Connected).await;
+        let _ = event_sender
+            .send(TunnelEvent::EncryptionDomain(self.encryption_domain.clone()))
+            .await;
+        let _ = event_sender
+            .send(TunnelEvent::RouteConflicts(self.route_conflicts.clone()))
+            .await;
+
+        let params = self.params.clone();
+        let address = self.address;
+        tokio::spawn(async move { ddns::register(&params, address).await });
 
         let sender = event_sender.clone();
 
@@ -110,8 +244,36 @@ impl VpnTunnel for IpsecTunnel {
             Ok::<_, anyhow::Error>(())
         });
 
+        let mut usage_interval = tokio::time::interval(ESP_USAGE_CHECK_INTERVAL);
+        usage_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        let mut rekey_requested: Option<Instant> = None;
+
         let fut = async {
-            while let Some(cmd) = command_receiver.recv().await {
+            loop {
+                let cmd = tokio::select! {
+                    cmd = command_receiver.recv() => match cmd {
+                        Some(cmd) => cmd,
+                        None => break,
+                    },
+                    _ = usage_interval.tick() => {
+                        if let Ok(usage) = self.configurator.esp_usage().await {
+                            self.counters.esp_sequence.store(usage.sequence, Ordering::SeqCst);
+                            self.counters.esp_bytes.store(usage.bytes, Ordering::SeqCst);
+                            let port = self.natt_socket.local_addr().map(|addr| addr.port()).unwrap_or_default();
+                            if let Some(drops) = platform::get_udp_drops(port) {
+                                self.counters.socket_drops.store(drops, Ordering::SeqCst);
+                            }
+                            let pending = rekey_requested.is_some_and(|at| at.elapsed() < ESP_REKEY_RETRY_INTERVAL);
+                            if !pending && esp_limit_close(&usage, self.params.esp_max_bytes) {
+                                debug!("ESP usage is close to the limit: {:?}, requesting rekey", usage);
+                                rekey_requested = Some(Instant::now());
+                                let _ = event_sender.send(TunnelEvent::RekeyRequired).await;
+                            }
+                        }
+                        continue;
+                    }
+                };
+
                 match cmd {
                     TunnelCommand::Terminate => break,
                     TunnelCommand::ReKey(session) => {
@@ -122,6 +284,35 @@ impl VpnTunnel for IpsecTunnel {
                         self.ready.store(false, Ordering::SeqCst);
                         let _ = self.configurator.rekey(&session).await;
                         self.ready.store(true, Ordering::SeqCst);
+                        rekey_requested = None;
+                    }
+                    TunnelCommand::Suspend(reply) => {
+                        let result = self.configurator.suspend().await;
+                        if let Err(ref e) = result {
+                            warn!("Cannot suspend the tunnel: {}", e);
+                        }
+                        let _ = reply.send(result);
+                    }
+                    TunnelCommand::Resume(reply) => {
+                        let result = self.configurator.resume().await;
+                        if let Err(ref e) = result {
+                            warn!("Cannot resume the tunnel: {}", e);
+                        }
+                        let _ = reply.send(result);
+                    }
+                    TunnelCommand::AddRoute(route, reply) => {
+                        let result = self.configurator.add_route(route).await;
+                        if let Err(ref e) = result {
+                            warn!("Cannot add route {}: {}", route, e);
+                        }
+                        let _ = reply.send(result);
+                    }
+                    TunnelCommand::RemoveRoute(route, reply) => {
+                        let result = self.configurator.remove_route(route).await;
+                        if let Err(ref e) = result {
+                            warn!("Cannot remove route {}: {}", route, e);
+                        }
+                        let _ = reply.send(result);
                     }
                 }
             }
@@ -134,6 +325,12 @@ impl VpnTunnel for IpsecTunnel {
 
             err = self.keepalive_runner.run() => {
                 debug!("Terminating IPSec tunnel due to keepalive failure");
+                if let Some(failure) = self.keepalive_runner.failure() {
+                    if self.params.reconnects_on(failure) {
+                        debug!("Requesting reconnect after keepalive failure: {}", failure.as_str());
+                        let _ = event_sender.send(TunnelEvent::ReconnectRequired).await;
+                    }
+                }
                 err
             }
         };
@@ -153,3 +350,39 @@ impl Drop for IpsecTunnel {
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_esp_limit_close() {
+        let usage = |sequence, bytes| EspUsage { sequence, bytes };
+
+        assert!(!esp_limit_close(&usage(1000, 1_000_000), 0));
+        assert!(esp_limit_close(&usage(0xf000_0000, 0), 0));
+        assert!(!esp_limit_close(&usage(1000, 899), 1000));
+        assert!(esp_limit_close(&usage(1000, 900), 1000));
+    }
+
+    #[test]
+    fn test_keepalive_target() {
+        let gateway = Ipv4Addr::new(192, 0, 2, 1);
+        let domain = vec!["10.0.0.0/8".parse().unwrap()];
+        let mut params = TunnelParams::default();
+
+        assert_eq!(keepalive_target(&params, &domain, gateway).unwrap(), gateway);
+
+        params.keepalive_target = Some(Ipv4Addr::new(10, 1, 1, 1));
+        assert_eq!(
+            keepalive_target(&params, &domain, gateway).unwrap(),
+            Ipv4Addr::new(10, 1, 1, 1)
+        );
+
+        params.keepalive_target = Some(Ipv4Addr::new(172, 16, 1, 1));
+        assert!(keepalive_target(&params, &domain, gateway).is_err());
+
+        params.add_routes = vec!["172.16.0.0/12".parse().unwrap()];
+        assert!(keepalive_target(&params, &domain, gateway).is_ok());
+    }
+}
@@ -6,18 +6,24 @@ use std::{
 
 use crate::{
     model::{
-        params::{CertType, TunnelParams},
-        proto::{AuthenticationRealm, ClientLoggingData},
-        IpsecSession, MfaChallenge, MfaType, SessionState, VpnSession,
+        params::{CertType, Quirk, TunnelParams, TunnelType},
+        proto::AuthenticationRealm,
+        InterfaceStats, IpsecSession, MfaChallenge, MfaType, SessionState, TunnelStats, VpnSession,
     },
-    platform,
+    platform::{self, UdpSocketExt},
+    posture::{self, PostureInfo},
     sexpr::SExpression,
-    tunnel::{ipsec::natt::NattProber, ipsec::IpsecTunnel, TunnelCommand, TunnelConnector, TunnelEvent, VpnTunnel},
+    tunnel::{
+        self, ipsec::natt::NattProber, ipsec::IpsecTunnel, AuthenticationFailed, PhaseSender, TunnelCommand,
+        TunnelConnector, TunnelCounters, TunnelEvent, VpnTunnel,
+    },
+    util,
 };
 use anyhow::anyhow;
 use async_trait::async_trait;
 use byteorder::{BigEndian, ReadBytesExt};
 use bytes::{Buf, Bytes};
+use ipnet::Ipv4Net;
 use isakmp::{
     ikev1::{codec::Ikev1Codec, service::Ikev1Service, session::Ikev1SyncedSession},
     model::{ConfigAttributeType, EspAttributeType, Identity, PayloadType},
@@ -26,12 +32,17 @@ use isakmp::{
     transport::{IsakmpTransport, UdpTransport},
 };
 use tokio::{net::UdpSocket, sync::mpsc::Sender};
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, trace, warn};
 
 const MIN_ESP_LIFETIME: Duration = Duration::from_secs(60);
 
+// IPSec DOI "Group Description" SA attribute, RFC 2407
+const ESP_ATTR_GROUP_DESCRIPTION: u16 = 3;
+
 pub struct IpsecTunnelConnector {
     params: Arc<TunnelParams>,
+    posture: Option<PostureInfo>,
     service: Ikev1Service<UdpTransport<Ikev1Codec<Ikev1SyncedSession>>>,
     gateway_address: Ipv4Addr,
     last_message_id: u32,
@@ -41,15 +52,17 @@ pub struct IpsecTunnelConnector {
     ipsec_session: IpsecSession,
     last_rekey: Option<SystemTime>,
     command_sender: Option<Sender<TunnelCommand>>,
+    counters: Arc<TunnelCounters>,
+    cancel_token: CancellationToken,
 }
 
 impl IpsecTunnelConnector {
-    pub async fn new(params: Arc<TunnelParams>) -> anyhow::Result<Self> {
+    pub async fn new(params: Arc<TunnelParams>, posture: Option<PostureInfo>) -> anyhow::Result<Self> {
         let identity = match params.cert_type {
             CertType::Pkcs12 => match (&params.cert_path, &params.cert_password) {
                 (Some(path), Some(password)) => Identity::Pkcs12 {
                     path: path.clone(),
-                    password: password.clone(),
+                    password: password.as_str().to_owned(),
                 },
                 _ => return Err(anyhow!("No PKCS12 path and password provided!")),
             },
@@ -60,7 +73,7 @@ impl IpsecTunnelConnector {
             CertType::Pkcs11 => match params.cert_password {
                 Some(ref pin) => Identity::Pkcs11 {
                     driver_path: params.cert_path.clone().unwrap_or_else(|| "opensc-pkcs11.so".into()),
-                    pin: pin.clone(),
+                    pin: pin.as_str().to_owned(),
                     key_id: params
                         .cert_id
                         .as_ref()
@@ -72,17 +85,32 @@ impl IpsecTunnelConnector {
             _ => Identity::None,
         };
 
-        let socket = UdpSocket::bind("0.0.0.0:0").await?;
-        socket
-            .connect(format!("{}:{}", params.server_name, params.ike_port))
-            .await?;
+        if let Some(ref vrf) = params.outer_vrf {
+            let table = platform::get_vrf_table(vrf).await?;
+            debug!("Using VRF {} with routing table {} for the outer traffic", vrf, table);
+        }
+
+        let gateway = util::resolve_server(&params.server_name, params.ike_port, params.ip_mode).await?;
 
-        let gateway_address = match socket.peer_addr()?.ip() {
+        // the ESP transport and the IKE session are IPv4-only
+        let gateway_address = match gateway.ip() {
             IpAddr::V4(v4) => v4,
-            _ => return Err(anyhow!("No IPv4 address for {}", params.server_name)),
+            IpAddr::V6(v6) => {
+                return Err(anyhow!(
+                    "IPSec tunnel requires an IPv4 gateway address, {} resolves to {}, use the SSL tunnel instead",
+                    params.server_name,
+                    v6
+                ))
+            }
         };
 
-        let prober = NattProber::new(gateway_address);
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        if let Some(ref vrf) = params.outer_vrf {
+            socket.bind_to_device(vrf)?;
+        }
+        socket.connect(gateway).await?;
+
+        let prober = NattProber::new(gateway_address, params.outer_vrf.clone());
         prober.probe().await?;
 
         let ikev1_session = Ikev1SyncedSession::new(identity)?;
@@ -91,6 +119,7 @@ impl IpsecTunnelConnector {
 
         Ok(Self {
             params,
+            posture,
             service,
             gateway_address,
             last_message_id: 0,
@@ -100,6 +129,8 @@ impl IpsecTunnelConnector {
             ipsec_session: Default::default(),
             last_rekey: None,
             command_sender: None,
+            counters: Arc::new(TunnelCounters::default()),
+            cancel_token: CancellationToken::new(),
         })
     }
 
@@ -187,6 +218,7 @@ impl IpsecTunnelConnector {
             state: SessionState::PendingChallenge(MfaChallenge {
                 mfa_type: MfaType::from_id(&id),
                 prompt,
+                delivery: Vec::new(),
             }),
         }))
     }
@@ -255,7 +287,7 @@ impl IpsecTunnelConnector {
             }
             Some(status) => {
                 warn!("IPSec authentication failed, status: {}", status);
-                Err(anyhow!("IPSec authentication failed, status: {}", status))
+                Err(AuthenticationFailed(format!("IPSec authentication failed, status: {}", status)).into())
             }
             None => {
                 let attr = self.get_challenge_attribute_type(&id_reply);
@@ -312,10 +344,21 @@ impl IpsecTunnelConnector {
 
         debug!("ESP lifetime: {} seconds", lifetime);
 
+        let pfs_group = attributes.iter().find_map(|a| {
+            if a.attribute_type == ESP_ATTR_GROUP_DESCRIPTION {
+                a.as_short()
+            } else {
+                None
+            }
+        });
+
+        debug!("ESP PFS group: {:?}", pfs_group);
+
         let session = self.service.session();
         self.ipsec_session.lifetime = Duration::from_secs(lifetime as u64);
         self.ipsec_session.esp_in = session.esp_in();
         self.ipsec_session.esp_out = session.esp_out();
+        self.ipsec_session.pfs_group = pfs_group;
 
         Ok(())
     }
@@ -329,24 +372,25 @@ impl IpsecTunnelConnector {
             );
 
             if payload_types.iter().any(|p| *p == PayloadType::SecurityAssociation) {
-                self.rekey_tunnel().await?;
+                self.rekey_tunnel(false).await?;
             }
         }
         Ok(())
     }
 
-    async fn rekey_tunnel(&mut self) -> anyhow::Result<()> {
+    // forced rekey is requested by the tunnel when the SA sequence number or byte limit is close
+    async fn rekey_tunnel(&mut self, force: bool) -> anyhow::Result<()> {
         let lifetime = if self.ipsec_session.lifetime < MIN_ESP_LIFETIME {
             self.ipsec_session.lifetime
         } else {
             self.ipsec_session.lifetime - MIN_ESP_LIFETIME
         };
 
-        if platform::is_online()
-            && self
-                .last_rekey
-                .is_some_and(|last_rekey| SystemTime::now().duration_since(last_rekey).unwrap_or(lifetime) >= lifetime)
-        {
+        let expired = self
+            .last_rekey
+            .is_some_and(|last_rekey| SystemTime::now().duration_since(last_rekey).unwrap_or(lifetime) >= lifetime);
+
+        if platform::is_online() && (force || expired) {
             debug!("Start rekeying IPSec tunnel");
             self.do_esp_proposal().await?;
 
@@ -375,22 +419,21 @@ impl IpsecTunnelConnector {
 #[async_trait]
 impl TunnelConnector for IpsecTunnelConnector {
     async fn authenticate(&mut self) -> anyhow::Result<Arc<VpnSession>> {
-        let my_address = platform::get_default_ip().await?.parse::<Ipv4Addr>()?;
+        let my_address = platform::get_source_ip(&self.params).await?;
         self.service.do_sa_proposal(self.params.ike_lifetime).await?;
         self.service.do_key_exchange(my_address, self.gateway_address).await?;
 
+        debug!("Authenticating with realm: {}", self.params.realm_id());
+
         let realm = AuthenticationRealm {
             client_type: self.params.tunnel_type.as_client_type().to_owned(),
             old_session_id: String::new(),
             protocol_version: 100,
             client_mode: self.params.tunnel_type.as_client_mode().to_owned(),
-            selected_realm_id: self.params.login_type.clone(),
+            selected_realm_id: self.params.realm_id().to_owned(),
             secondary_realm_hash: None,
-            client_logging_data: Some(ClientLoggingData {
-                os_name: Some("Windows".to_owned()),
-                device_id: Some(crate::util::get_device_id().into()),
-                ..Default::default()
-            }),
+            client_logging_data: (!self.params.has_quirk(Quirk::NoClientLoggingData))
+                .then(|| posture::client_logging_data(self.posture.as_ref())),
         };
 
         let realm_expr = SExpression::from(&realm);
@@ -429,9 +472,35 @@ impl TunnelConnector for IpsecTunnelConnector {
         &mut self,
         session: Arc<VpnSession>,
         command_sender: Sender<TunnelCommand>,
+        phase_sender: Option<PhaseSender>,
     ) -> anyhow::Result<Box<dyn VpnTunnel + Send>> {
         self.command_sender = Some(command_sender);
-        Ok(Box::new(IpsecTunnel::create(self.params.clone(), session).await?))
+        Ok(Box::new(
+            IpsecTunnel::create(
+                self.params.clone(),
+                session,
+                phase_sender,
+                self.counters.clone(),
+                self.cancel_token.clone(),
+            )
+            .await?,
+        ))
+    }
+
+    // a new ESP SA is negotiated within the IKE SA like when rekeying, the gateway rejects it once the IKE SA is gone
+    async fn reuse_session(&mut self, session: Arc<VpnSession>) -> anyhow::Result<Arc<VpnSession>> {
+        self.do_esp_proposal().await?;
+
+        self.last_rekey = Some(SystemTime::now());
+
+        Ok(Arc::new(VpnSession {
+            ipsec_session: Some(self.ipsec_session.clone()),
+            ..(*session).clone()
+        }))
+    }
+
+    fn set_cancellation_token(&mut self, token: CancellationToken) {
+        self.cancel_token = token;
     }
 
     async fn terminate_tunnel(&mut self) -> anyhow::Result<()> {
@@ -441,6 +510,45 @@ impl TunnelConnector for IpsecTunnelConnector {
         Ok(())
     }
 
+    async fn suspend_tunnel(&mut self) -> anyhow::Result<()> {
+        match self.command_sender {
+            Some(ref sender) => tunnel::request(sender, TunnelCommand::Suspend).await,
+            None => Err(anyhow!("No tunnel to suspend!")),
+        }
+    }
+
+    async fn resume_tunnel(&mut self) -> anyhow::Result<()> {
+        match self.command_sender {
+            Some(ref sender) => tunnel::request(sender, TunnelCommand::Resume).await,
+            None => Err(anyhow!("No tunnel to resume!")),
+        }
+    }
+
+    async fn add_route(&mut self, route: Ipv4Net) -> anyhow::Result<()> {
+        tunnel::check_runtime_route(route, self.gateway_address)?;
+        match self.command_sender {
+            Some(ref sender) => tunnel::request(sender, |reply| TunnelCommand::AddRoute(route, reply)).await,
+            None => Err(anyhow!("No tunnel to add the route to!")),
+        }
+    }
+
+    async fn remove_route(&mut self, route: Ipv4Net) -> anyhow::Result<()> {
+        match self.command_sender {
+            Some(ref sender) => tunnel::request(sender, |reply| TunnelCommand::RemoveRoute(route, reply)).await,
+            None => Err(anyhow!("No tunnel to remove the route from!")),
+        }
+    }
+
+    // the ESP traffic is handled by the kernel, the limit is applied to the tunnel interface
+    async fn set_rate_limit(&mut self, rate: Option<u64>, inbound: bool) -> anyhow::Result<()> {
+        let if_name = self
+            .params
+            .if_name
+            .as_deref()
+            .unwrap_or(TunnelParams::DEFAULT_IPSEC_IF_NAME);
+        platform::set_rate_limit(if_name, rate, inbound).await
+    }
+
     async fn handle_tunnel_event(&mut self, event: TunnelEvent) -> anyhow::Result<()> {
         match event {
             TunnelEvent::Connected => {
@@ -451,14 +559,41 @@ impl TunnelConnector for IpsecTunnelConnector {
                 let _ = self.delete_sa().await;
             }
             TunnelEvent::RekeyCheck => {
-                self.rekey_tunnel().await?;
+                self.rekey_tunnel(false).await?;
+            }
+            TunnelEvent::RekeyRequired => {
+                debug!("SA usage limit is close, rekeying ahead of the lifetime");
+                self.rekey_tunnel(true).await?;
             }
             TunnelEvent::RemoteControlData(data) => {
                 self.parse_isakmp(data).await?;
             }
+            TunnelEvent::ReconnectRequired | TunnelEvent::EncryptionDomain(_) | TunnelEvent::RouteConflicts(_) => {}
         }
         Ok(())
     }
+
+    fn stats(&self) -> TunnelStats {
+        let if_name = self
+            .params
+            .if_name
+            .as_deref()
+            .unwrap_or(TunnelParams::DEFAULT_IPSEC_IF_NAME);
+        tunnel::with_interface_counters(self.counters.stats(), if_name)
+    }
+
+    fn interface_stats(&self) -> Option<InterfaceStats> {
+        let if_name = self
+            .params
+            .if_name
+            .as_deref()
+            .unwrap_or(TunnelParams::DEFAULT_IPSEC_IF_NAME);
+        platform::get_interface_stats(if_name)
+    }
+
+    fn tunnel_type(&self) -> TunnelType {
+        TunnelType::Ipsec
+    }
 }
 
 impl Drop for IpsecTunnelConnector {
@@ -0,0 +1,75 @@
+use std::{
+    io,
+    sync::{atomic::Ordering, Arc},
+};
+
+use bytes::Bytes;
+use tokio::{
+    net::UdpSocket,
+    sync::{mpsc, oneshot},
+};
+use tracing::{debug, warn};
+
+use crate::tunnel::{TunnelCounters, TunnelEvent};
+
+fn is_recoverable(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::ConnectionRefused
+    ) || error.raw_os_error() == Some(libc::ENOBUFS)
+}
+
+// start a dummy UDP listener with UDP_ENCAP option.
+// this is necessary in order to perform automatic decapsulation of incoming ESP packets
+pub async fn start_decap_listener(
+    socket: Arc<UdpSocket>,
+    sender: mpsc::Sender<TunnelEvent>,
+    counters: Arc<TunnelCounters>,
+) -> anyhow::Result<oneshot::Sender<()>> {
+    let (tx, mut rx) = oneshot::channel();
+
+    debug!("Listening for NAT-T packets on port {}", socket.local_addr()?);
+
+    tokio::spawn(async move {
+        let mut buf = [0u8; 1024];
+
+        loop {
+            tokio::select! {
+                result = socket.recv_from(&mut buf) => {
+                    match result {
+                        Ok((size, _)) => {
+                            let data = Bytes::copy_from_slice(&buf[0..size]);
+                            let _ = sender.send(TunnelEvent::RemoteControlData(data)).await;
+                        }
+                        Err(e) => {
+                            counters.recv_errors.fetch_add(1, Ordering::SeqCst);
+                            if is_recoverable(&e) {
+                                debug!("Recoverable NAT-T receive error: {}", e);
+                            } else {
+                                warn!("NAT-T receive failed: {}", e);
+                            }
+                        }
+                    }
+                }
+                _ = &mut rx => {
+                    break;
+                }
+            }
+        }
+        debug!("NAT-T listener stopped");
+    });
+
+    Ok(tx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_recoverable() {
+        assert!(is_recoverable(&io::Error::from(io::ErrorKind::Interrupted)));
+        assert!(is_recoverable(&io::Error::from_raw_os_error(libc::ENOBUFS)));
+        assert!(!is_recoverable(&io::Error::from_raw_os_error(libc::EBADF)));
+    }
+}
@@ -4,15 +4,17 @@ use std::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::anyhow;
+use parking_lot::Mutex;
 use tracing::{debug, trace, warn};
 
 use crate::{
-    model::params::TunnelParams,
+    model::params::{KeepaliveFailure, TunnelParams},
     platform::{self, UdpSocketExt},
+    tunnel::TunnelCounters,
 };
 
 const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(20);
@@ -21,7 +23,7 @@ const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(5);
 const KEEPALIVE_MAX_RETRIES: u32 = 5;
 
 // picked from wireshark logs
-fn make_keepalive_packet() -> [u8; 84] {
+pub(crate) fn make_keepalive_packet() -> [u8; 84] {
     let mut data = [0u8; 84];
 
     // 0x00000011 looks like a packet type, KEEPALIVE in this case
@@ -39,18 +41,55 @@ fn make_keepalive_packet() -> [u8; 84] {
     data
 }
 
+fn failure_kind(error: &anyhow::Error) -> KeepaliveFailure {
+    match error.downcast_ref::<std::io::Error>().and_then(|e| e.raw_os_error()) {
+        Some(libc::ECONNREFUSED | libc::EHOSTUNREACH | libc::ENETUNREACH) => KeepaliveFailure::Unreachable,
+        _ => KeepaliveFailure::Timeout,
+    }
+}
+
 pub struct KeepaliveRunner {
     src: Ipv4Addr,
     dst: Ipv4Addr,
     ready: Arc<AtomicBool>,
+    enabled: bool,
+    resume_probe: bool,
+    failure: Mutex<Option<KeepaliveFailure>>,
+    counters: Arc<TunnelCounters>,
 }
 
 impl KeepaliveRunner {
-    pub fn new(src: Ipv4Addr, dst: Ipv4Addr, ready: Arc<AtomicBool>) -> Self {
-        Self { src, dst, ready }
+    pub fn new(
+        src: Ipv4Addr,
+        dst: Ipv4Addr,
+        ready: Arc<AtomicBool>,
+        enabled: bool,
+        resume_probe: bool,
+        counters: Arc<TunnelCounters>,
+    ) -> Self {
+        Self {
+            src,
+            dst,
+            ready,
+            enabled,
+            resume_probe,
+            failure: Mutex::new(None),
+            counters,
+        }
+    }
+
+    // the reason of the keepalive failure after run has returned
+    pub fn failure(&self) -> Option<KeepaliveFailure> {
+        *self.failure.lock()
     }
 
     pub async fn run(&self) -> anyhow::Result<()> {
+        if !self.enabled {
+            // never resolves, the tunnel is terminated by the stop command only
+            debug!("Keepalive is disabled");
+            return futures::future::pending().await;
+        }
+
         let src = self.src.to_string();
 
         let udp = tokio::net::UdpSocket::bind((src, TunnelParams::IPSEC_KEEPALIVE_PORT)).await?;
@@ -67,21 +106,28 @@ impl KeepaliveRunner {
                 trace!("Sending keepalive to {}", self.dst);
 
                 let data = make_keepalive_packet();
+                let started = Instant::now();
                 let result = udp.send_receive(&data, KEEPALIVE_TIMEOUT).await;
 
-                if let Ok(reply) = result {
-                    trace!("Received keepalive response from {}, size: {}", self.dst, reply.len());
-                    num_failures = 0;
-                } else {
-                    num_failures += 1;
-                    if num_failures >= KEEPALIVE_MAX_RETRIES {
-                        warn!("Maximum number of keepalive retries reached, exiting");
-                        break;
+                match result {
+                    Ok(reply) => {
+                        trace!("Received keepalive response from {}, size: {}", self.dst, reply.len());
+                        let rtt = started.elapsed().as_millis().max(1) as u64;
+                        self.counters.keepalive_rtt.store(rtt, Ordering::SeqCst);
+                        num_failures = 0;
+                    }
+                    Err(e) => {
+                        num_failures += 1;
+                        if num_failures >= KEEPALIVE_MAX_RETRIES {
+                            warn!("Maximum number of keepalive retries reached, exiting");
+                            *self.failure.lock() = Some(failure_kind(&e));
+                            break;
+                        }
+                        warn!(
+                            "Keepalive failed, retrying in {} secs",
+                            KEEPALIVE_RETRY_INTERVAL.as_secs()
+                        );
                     }
-                    warn!(
-                        "Keepalive failed, retrying in {} secs",
-                        KEEPALIVE_RETRY_INTERVAL.as_secs()
-                    );
                 }
             } else {
                 num_failures = 0;
@@ -94,7 +140,18 @@ impl KeepaliveRunner {
                 KEEPALIVE_RETRY_INTERVAL
             };
 
-            tokio::time::sleep(interval).await;
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {}
+                _ = platform::wait_for_resume(), if self.resume_probe => {
+                    debug!("System resume detected, probing {}", self.dst);
+                    if udp.send_receive(&make_keepalive_packet(), KEEPALIVE_TIMEOUT).await.is_err() {
+                        warn!("No keepalive response after resume, exiting");
+                        *self.failure.lock() = Some(KeepaliveFailure::Resume);
+                        break;
+                    }
+                    num_failures = 0;
+                }
+            }
         }
 
         debug!("Keepalive failed!");
@@ -1,25 +1,26 @@
-use std::{net::Ipv4Addr, sync::Arc, time::Duration};
+use std::{net::Ipv4Addr, time::Duration};
 
 use anyhow::anyhow;
-use bytes::Bytes;
-use tokio::{
-    net::UdpSocket,
-    sync::{mpsc, oneshot},
-};
+use tokio::net::UdpSocket;
 use tracing::debug;
 
-use crate::{platform::UdpSocketExt, tunnel::TunnelEvent};
+use crate::platform::UdpSocketExt;
 
 const MAX_NATT_PROBES: usize = 3;
 
 pub struct NattProber {
     address: Ipv4Addr,
     port: u16,
+    vrf: Option<String>,
 }
 
 impl NattProber {
-    pub fn new(address: Ipv4Addr) -> Self {
-        Self { address, port: 4500 }
+    pub fn new(address: Ipv4Addr, vrf: Option<String>) -> Self {
+        Self {
+            address,
+            port: 4500,
+            vrf,
+        }
     }
 
     pub async fn probe(&self) -> anyhow::Result<()> {
@@ -34,6 +35,9 @@ impl NattProber {
     async fn send_probe(&self) -> anyhow::Result<()> {
         debug!("Sending NAT-T probe to {}", self.address);
         let udp = UdpSocket::bind("0.0.0.0:0").await?;
+        if let Some(ref vrf) = self.vrf {
+            udp.bind_to_device(vrf)?;
+        }
         udp.connect(format!("{}:{}", self.address, self.port)).await?;
 
         let data = vec![0u8; 32];
@@ -57,35 +61,3 @@ impl NattProber {
         }
     }
 }
-
-// start a dummy UDP listener with UDP_ENCAP option.
-// this is necessary in order to perform automatic decapsulation of incoming ESP packets
-pub async fn start_natt_listener(
-    socket: Arc<UdpSocket>,
-    sender: mpsc::Sender<TunnelEvent>,
-) -> anyhow::Result<oneshot::Sender<()>> {
-    let (tx, mut rx) = oneshot::channel();
-
-    debug!("Listening for NAT-T packets on port {}", socket.local_addr()?);
-
-    tokio::spawn(async move {
-        let mut buf = [0u8; 1024];
-
-        loop {
-            tokio::select! {
-                result = socket.recv_from(&mut buf) => {
-                    if let Ok((size, _)) = result {
-                        let data = Bytes::copy_from_slice(&buf[0..size]);
-                        let _ = sender.send(TunnelEvent::RemoteControlData(data)).await;
-                    }
-                }
-                _ = &mut rx => {
-                    break;
-                }
-            }
-        }
-        debug!("NAT-T listener stopped");
-    });
-
-    Ok(tx)
-}
@@ -1,6 +1,7 @@
 use std::{
+    net::Ipv4Addr,
     sync::{
-        atomic::{AtomicU64, Ordering},
+        atomic::{AtomicBool, AtomicU64, Ordering},
         Arc,
     },
     time::Duration,
@@ -19,15 +20,25 @@ use tun::TunPacket;
 use codec::{SslPacketCodec, SslPacketType};
 
 use crate::{
-    model::{params::TunnelParams, proto::*, *},
+    ddns,
+    model::{
+        params::{ExistingInterface, KeepaliveFailure, TunnelParams},
+        proto::*,
+        *,
+    },
     sexpr::SExpression,
-    tunnel::{ssl::keepalive::KeepaliveRunner, TunnelCommand, TunnelEvent, VpnTunnel},
+    tunnel::{
+        self,
+        ssl::{keepalive::KeepaliveRunner, ratelimit::RateLimiter},
+        PhaseSender, TunnelCommand, TunnelEvent, VpnTunnel,
+    },
 };
 
 pub mod codec;
 pub mod connector;
 pub mod device;
 pub mod keepalive;
+pub mod ratelimit;
 
 const REAUTH_LEEWAY: Duration = Duration::from_secs(60);
 const SEND_TIMEOUT: Duration = Duration::from_secs(120);
@@ -71,11 +82,23 @@ pub(crate) struct SslTunnel {
     sender: PacketSender,
     receiver: Option<PacketReceiver>,
     keepalive_counter: Arc<AtomicU64>,
+    outbound_limiter: Arc<RateLimiter>,
+    inbound_limiter: Arc<RateLimiter>,
 }
 
 impl SslTunnel {
-    pub(crate) async fn create(params: Arc<TunnelParams>, session: Arc<VpnSession>) -> anyhow::Result<Self> {
-        let tcp = tokio::net::TcpStream::connect((params.server_name.as_str(), 443)).await?;
+    pub(crate) async fn create(
+        params: Arc<TunnelParams>,
+        session: Arc<VpnSession>,
+        phase_sender: Option<PhaseSender>,
+        outbound_limiter: Arc<RateLimiter>,
+        inbound_limiter: Arc<RateLimiter>,
+    ) -> anyhow::Result<Self> {
+        tunnel::report_phase(phase_sender.as_ref(), ConnectionPhase::ConfiguringTunnel);
+
+        let (host, port) = crate::util::split_server_name(&params.server_name);
+        let address = crate::util::resolve_server(host, port.unwrap_or(443), params.ip_mode).await?;
+        let tcp = tokio::net::TcpStream::connect(address).await?;
 
         let mut builder = TlsConnector::builder();
 
@@ -95,7 +118,7 @@ impl SslTunnel {
         }
 
         let tls: tokio_native_tls::TlsConnector = builder.build()?.into();
-        let stream = tls.connect(params.server_name.as_str(), tcp).await?;
+        let stream = tls.connect(host, tcp).await?;
 
         let (sender, receiver) = make_channel(stream);
 
@@ -110,6 +133,8 @@ impl SslTunnel {
             sender,
             receiver: Some(receiver),
             keepalive_counter: Arc::new(AtomicU64::default()),
+            outbound_limiter,
+            inbound_limiter,
         })
     }
 
@@ -180,14 +205,37 @@ impl VpnTunnel for SslTunnel {
         let reply = self.client_hello().await?;
         trace!("Hello reply: {:?}", reply);
 
-        let tun_name = self
-            .params
-            .if_name
-            .as_deref()
-            .unwrap_or(TunnelParams::DEFAULT_SSL_IF_NAME);
+        let tun_name = self.params.device_name();
+
+        if self.params.tun_device.is_some() {
+            if crate::platform::tun_device_exists(tun_name)? {
+                debug!("Attaching to the existing tun device: {}", tun_name);
+            }
+        } else if crate::platform::device_exists(tun_name) {
+            match self.params.on_existing_interface {
+                ExistingInterface::Fail => return Err(anyhow!("Interface {} already exists!", tun_name)),
+                ExistingInterface::Reuse => {
+                    crate::platform::tun_device_exists(tun_name)?;
+                    warn!("Reusing the existing tun device {}", tun_name);
+                }
+                ExistingInterface::Recreate => {
+                    warn!("Recreating the existing interface {}", tun_name);
+                    crate::platform::delete_device(tun_name).await?;
+                }
+            }
+        }
+
+        let tun = device::TunDevice::new(tun_name, &reply, self.params.tunnel_mtu)?;
+
+        let local_subnets = if self.params.auto_exclude_local {
+            crate::platform::get_local_subnets(tun.name()).await.unwrap_or_default()
+        } else {
+            Vec::new()
+        };
 
-        let tun = device::TunDevice::new(tun_name, &reply)?;
-        tun.setup_dns_and_routing(&self.params).await?;
+        // keep the forwarder running for the tunnel lifetime
+        let _dns_forwarder = tun.setup_dns_and_routing(&self.params, &local_subnets).await?;
+        let excluded_subnets = crate::platform::add_exclude_routes(&local_subnets, None).await;
 
         let dev_name = tun.name().to_owned();
 
@@ -199,6 +247,9 @@ impl VpnTunnel for SslTunnel {
 
         let dev_name2 = dev_name.clone();
         let keepalive_counter = self.keepalive_counter.clone();
+        let revoked = Arc::new(AtomicBool::new(false));
+        let revoked2 = revoked.clone();
+        let inbound_limiter = self.inbound_limiter.clone();
 
         tokio::spawn(async move {
             while let Some(item) = snx_receiver.next().await {
@@ -209,11 +260,18 @@ impl VpnTunnel for SslTunnel {
                             SExpression::Object(Some(name), _) if name == "keepalive" => {
                                 keepalive_counter.fetch_sub(1, Ordering::SeqCst);
                             }
+                            // the keepalive fails once the gateway has terminated the session
+                            SExpression::Object(Some(ref name), _) if name == "disconnect" => {
+                                warn!("Session terminated by the gateway: {}", expr);
+                                revoked2.store(true, Ordering::SeqCst);
+                                break;
+                            }
                             _ => {}
                         }
                     }
                     SslPacketType::Data(data) => {
                         trace!("snx => {}: {}", data.len(), dev_name2);
+                        inbound_limiter.acquire(data.len()).await;
                         let tun_packet = TunPacket::new(data);
                         tun_sender.send(tun_packet).await?;
                     }
@@ -224,11 +282,27 @@ impl VpnTunnel for SslTunnel {
 
         let _ = event_sender.send(TunnelEvent::Connected).await;
 
+        let encryption_domain = crate::util::ranges_to_subnets(&reply.range).collect();
+        let _ = event_sender
+            .send(TunnelEvent::EncryptionDomain(encryption_domain))
+            .await;
+
+        // the update goes through the tunnel which is served by the loop below
+        if let Ok(address) = reply.office_mode.ipaddr.parse::<Ipv4Addr>() {
+            let params = self.params.clone();
+            tokio::spawn(async move { ddns::register(&params, address).await });
+        }
+
         let command_fut = command_receiver.recv();
         pin_mut!(command_fut);
 
-        let keepalive_runner =
-            KeepaliveRunner::new(self.keepalive, self.sender.clone(), self.keepalive_counter.clone());
+        let keepalive_runner = KeepaliveRunner::new(
+            self.keepalive,
+            self.sender.clone(),
+            self.keepalive_counter.clone(),
+            self.params.keepalive,
+            self.params.reconnects_on(KeepaliveFailure::Resume),
+        );
         let ka_run = keepalive_runner.run();
         pin_mut!(ka_run);
 
@@ -244,6 +318,17 @@ impl VpnTunnel for SslTunnel {
                 }
                 _ = &mut ka_run => {
                     warn!("Keepalive failed, exiting");
+                    let failure = if revoked.load(Ordering::SeqCst) {
+                        Some(KeepaliveFailure::AuthRevoked)
+                    } else {
+                        keepalive_runner.failure()
+                    };
+                    if let Some(failure) = failure {
+                        if self.params.reconnects_on(failure) {
+                            debug!("Requesting reconnect after keepalive failure: {}", failure.as_str());
+                            let _ = event_sender.send(TunnelEvent::ReconnectRequired).await;
+                        }
+                    }
                     break Err(anyhow!("Keepalive failed"));
                 }
 
@@ -251,7 +336,10 @@ impl VpnTunnel for SslTunnel {
                     if let Some(Ok(item)) = result {
                         let data = item.into_bytes().to_vec();
                         trace!("{} => snx: {}", dev_name, data.len());
-                        self.send(data).await?;
+                        self.outbound_limiter.acquire(data.len()).await;
+                        if let Err(e) = self.send(data).await {
+                            break Err(e);
+                        }
                     } else {
                         break Err(anyhow!("Receive failed"));
                     }
@@ -259,6 +347,13 @@ impl VpnTunnel for SslTunnel {
             }
         };
 
+        crate::platform::delete_exclude_routes(&excluded_subnets, None).await;
+        let _ = crate::platform::revert_dns(self.params.device_name(), self.params.dns_manager).await;
+
+        if self.params.flush_dns_on_disconnect {
+            crate::platform::flush_dns_cache().await;
+        }
+
         let _ = event_sender.send(TunnelEvent::Disconnected).await;
 
         result
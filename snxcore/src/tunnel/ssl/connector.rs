@@ -1,32 +1,80 @@
 use std::sync::Arc;
 
-use anyhow::anyhow;
 use async_trait::async_trait;
 use tokio::sync::mpsc::Sender;
-use tracing::{debug, warn};
+use tracing::{debug, trace, warn};
+use zeroize::Zeroizing;
 
 use crate::{
     ccc::CccHttpClient,
-    model::{params::TunnelParams, proto::AuthResponse, MfaChallenge, MfaType, SessionState, VpnSession},
-    tunnel::{ssl::SslTunnel, TunnelCommand, TunnelConnector, TunnelEvent, VpnTunnel},
+    model::{
+        params::{TunnelParams, TunnelType},
+        proto::AuthResponse,
+        InterfaceStats, MfaChallenge, MfaType, SessionState, TunnelStats, VpnSession,
+    },
+    platform,
+    posture::PostureInfo,
+    tunnel::{
+        self,
+        ssl::{ratelimit::RateLimiter, SslTunnel},
+        AuthenticationFailed, ChallengeRejected, GatewayBusy, PhaseSender, TunnelCommand, TunnelConnector, TunnelEvent,
+        VpnTunnel,
+    },
 };
 
+const NEW_PASSWORD_PROMPT: &str = "New password: ";
+
+// a busy error code means that the gateway is at capacity, the credentials are not rejected
+fn rejection_error(params: &TunnelParams, code: Option<u32>, message: String) -> anyhow::Error {
+    if code.is_some_and(|code| params.busy_error_codes.contains(&code)) {
+        GatewayBusy(message, None).into()
+    } else {
+        ChallengeRejected(message).into()
+    }
+}
+
 pub struct CccTunnelConnector {
     params: Arc<TunnelParams>,
+    posture: Option<PostureInfo>,
     command_sender: Option<Sender<TunnelCommand>>,
+    outbound_limiter: Arc<RateLimiter>,
+    inbound_limiter: Arc<RateLimiter>,
 }
 
 impl CccTunnelConnector {
-    pub async fn new(params: Arc<TunnelParams>) -> anyhow::Result<Self> {
+    pub async fn new(params: Arc<TunnelParams>, posture: Option<PostureInfo>) -> anyhow::Result<Self> {
+        let outbound_limiter = Arc::new(RateLimiter::new(params.rate_limit));
+        let inbound_limiter = Arc::new(RateLimiter::new(
+            params.rate_limit.filter(|_| params.rate_limit_inbound),
+        ));
         Ok(Self {
             params,
+            posture,
             command_sender: None,
+            outbound_limiter,
+            inbound_limiter,
         })
     }
 
     async fn process_auth_response(&self, data: AuthResponse) -> anyhow::Result<Arc<VpnSession>> {
         let session_id = data.session_id.unwrap_or_default();
 
+        if data.must_change_password == Some(true) {
+            debug!("The gateway requires a password change");
+            return Ok(Arc::new(VpnSession {
+                ccc_session_id: session_id,
+                state: SessionState::PendingChallenge(MfaChallenge {
+                    mfa_type: MfaType::PasswordChange,
+                    prompt: data
+                        .prompt
+                        .map(|p| p.0)
+                        .unwrap_or_else(|| NEW_PASSWORD_PROMPT.to_owned()),
+                    delivery: Vec::new(),
+                }),
+                ipsec_session: None,
+            }));
+        }
+
         match data.authn_status.as_str() {
             "continue" => {
                 return Ok(Arc::new(VpnSession {
@@ -34,6 +82,13 @@ impl CccTunnelConnector {
                     state: SessionState::PendingChallenge(MfaChallenge {
                         mfa_type: MfaType::UserInput,
                         prompt: data.prompt.map(|p| p.0).unwrap_or_default(),
+                        delivery: data
+                            .otp_delivery
+                            .iter()
+                            .flat_map(|s| s.split(','))
+                            .map(|s| s.trim().to_owned())
+                            .filter(|s| !s.is_empty())
+                            .collect(),
                     }),
                     ipsec_session: None,
                 }))
@@ -41,7 +96,7 @@ impl CccTunnelConnector {
             "done" => {}
             other => {
                 warn!("Authn status: {}", other);
-                return Err(anyhow!("Authentication failed!"));
+                return Err(AuthenticationFailed("Authentication failed!".to_owned()).into());
             }
         }
 
@@ -53,7 +108,7 @@ impl CccTunnelConnector {
                     _ => "Authentication failed!".to_owned(),
                 };
                 warn!("{}", msg);
-                return Err(anyhow!(msg));
+                return Err(rejection_error(&self.params, data.error_code, msg));
             }
         };
 
@@ -66,15 +121,48 @@ impl CccTunnelConnector {
         });
         Ok(session)
     }
+
+    // the authentication is repeated with the new password once the gateway has accepted it
+    async fn change_password(
+        &mut self,
+        session: Arc<VpnSession>,
+        new_password: Zeroizing<String>,
+    ) -> anyhow::Result<Arc<VpnSession>> {
+        let client = CccHttpClient::new(self.params.clone(), Some(session));
+
+        let data = client.change_password(&new_password).await?;
+
+        if data.authn_status != "done" || data.must_change_password == Some(true) {
+            let reason = data
+                .error_message
+                .map(|m| m.0)
+                .unwrap_or_else(|| "it does not meet the password policy".to_owned());
+            warn!("Password change failed: {}", reason);
+            return Err(ChallengeRejected(format!("The new password is rejected by the gateway: {}", reason)).into());
+        }
+
+        debug!("Password changed, authenticating with the new password");
+
+        self.params = Arc::new(TunnelParams {
+            password: new_password,
+            ..(*self.params).clone()
+        });
+
+        self.authenticate().await
+    }
 }
 
 #[async_trait]
 impl TunnelConnector for CccTunnelConnector {
     async fn authenticate(&mut self) -> anyhow::Result<Arc<VpnSession>> {
-        debug!("Authenticating to endpoint: {}", self.params.server_name);
+        debug!(
+            "Authenticating to endpoint: {}, realm: {}",
+            self.params.server_name,
+            self.params.realm_id()
+        );
         let client = CccHttpClient::new(self.params.clone(), None);
 
-        let data = client.authenticate().await?;
+        let data = client.authenticate(self.posture.as_ref()).await?;
 
         self.process_auth_response(data).await
     }
@@ -84,6 +172,14 @@ impl TunnelConnector for CccTunnelConnector {
             "Authenticating with challenge code to endpoint: {}",
             self.params.server_name
         );
+        if let SessionState::PendingChallenge(ref challenge) = session.state {
+            if challenge.mfa_type == MfaType::PasswordChange {
+                return self
+                    .change_password(session, Zeroizing::new(user_input.to_owned()))
+                    .await;
+            }
+        }
+
         let client = CccHttpClient::new(self.params.clone(), Some(session));
 
         let data = client.challenge_code(user_input).await?;
@@ -91,13 +187,46 @@ impl TunnelConnector for CccTunnelConnector {
         self.process_auth_response(data).await
     }
 
+    async fn request_otp_delivery(&mut self, session: Arc<VpnSession>, method: &str) -> anyhow::Result<()> {
+        debug!("Requesting OTP delivery via {}", method);
+        let client = CccHttpClient::new(self.params.clone(), Some(session));
+        client.request_otp_delivery(method).await
+    }
+
+    async fn poll_push_approval(&mut self, session: Arc<VpnSession>) -> anyhow::Result<Option<Arc<VpnSession>>> {
+        trace!("Polling the push approval");
+        let client = CccHttpClient::new(self.params.clone(), Some(session.clone()));
+        let data = client.poll_push_status().await?;
+        let new_session = self.process_auth_response(data).await?;
+
+        // the same or no prompt means that the challenge is still pending, another one follows the approval
+        match (&session.state, &new_session.state) {
+            (SessionState::PendingChallenge(current), SessionState::PendingChallenge(next))
+                if next.prompt.is_empty() || next.prompt == current.prompt =>
+            {
+                Ok(None)
+            }
+            _ => Ok(Some(new_session)),
+        }
+    }
+
     async fn create_tunnel(
         &mut self,
         session: Arc<VpnSession>,
         command_sender: Sender<TunnelCommand>,
+        phase_sender: Option<PhaseSender>,
     ) -> anyhow::Result<Box<dyn VpnTunnel + Send>> {
         self.command_sender = Some(command_sender);
-        Ok(Box::new(SslTunnel::create(self.params.clone(), session).await?))
+        Ok(Box::new(
+            SslTunnel::create(
+                self.params.clone(),
+                session,
+                phase_sender,
+                self.outbound_limiter.clone(),
+                self.inbound_limiter.clone(),
+            )
+            .await?,
+        ))
     }
 
     async fn terminate_tunnel(&mut self) -> anyhow::Result<()> {
@@ -115,11 +244,88 @@ impl TunnelConnector for CccTunnelConnector {
             TunnelEvent::Disconnected => {
                 debug!("Tunnel disconnected");
             }
-            TunnelEvent::RekeyCheck => {}
+            TunnelEvent::RekeyCheck
+            | TunnelEvent::RekeyRequired
+            | TunnelEvent::ReconnectRequired
+            | TunnelEvent::EncryptionDomain(_)
+            | TunnelEvent::RouteConflicts(_) => {}
             TunnelEvent::RemoteControlData(_) => {
                 warn!("Tunnel data received: shouldn't happen for SSL tunnel!");
             }
         }
         Ok(())
     }
+
+    async fn set_rate_limit(&mut self, rate: Option<u64>, inbound: bool) -> anyhow::Result<()> {
+        self.outbound_limiter.set_rate(rate);
+        self.inbound_limiter.set_rate(rate.filter(|_| inbound));
+        Ok(())
+    }
+
+    fn stats(&self) -> TunnelStats {
+        tunnel::with_interface_counters(TunnelStats::default(), self.params.device_name())
+    }
+
+    fn interface_stats(&self) -> Option<InterfaceStats> {
+        platform::get_interface_stats(self.params.device_name())
+    }
+
+    fn tunnel_type(&self) -> TunnelType {
+        TunnelType::Ssl
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rejection_error() {
+        let params = TunnelParams {
+            busy_error_codes: vec![302],
+            ..Default::default()
+        };
+        assert!(rejection_error(&params, Some(302), "busy".to_owned()).is::<GatewayBusy>());
+        assert!(rejection_error(&params, Some(101), "rejected".to_owned()).is::<ChallengeRejected>());
+        assert!(rejection_error(&params, None, "rejected".to_owned()).is::<ChallengeRejected>());
+    }
+
+    #[tokio::test]
+    async fn test_must_change_password() {
+        let connector = CccTunnelConnector::new(Arc::new(TunnelParams::default()), None)
+            .await
+            .unwrap();
+
+        let data = AuthResponse {
+            authn_status: "done".to_owned(),
+            session_id: Some("1234".to_owned()),
+            must_change_password: Some(true),
+            ..Default::default()
+        };
+        let session = connector.process_auth_response(data).await.unwrap();
+        assert_eq!(session.ccc_session_id, "1234");
+        assert_eq!(
+            session.state,
+            SessionState::PendingChallenge(MfaChallenge {
+                mfa_type: MfaType::PasswordChange,
+                prompt: NEW_PASSWORD_PROMPT.to_owned(),
+                delivery: Vec::new(),
+            })
+        );
+
+        // a regular challenge is not mistaken for the password change
+        let data = AuthResponse {
+            authn_status: "continue".to_owned(),
+            must_change_password: Some(false),
+            ..Default::default()
+        };
+        let session = connector.process_auth_response(data).await.unwrap();
+        assert!(matches!(
+            session.state,
+            SessionState::PendingChallenge(MfaChallenge {
+                mfa_type: MfaType::UserInput,
+                ..
+            })
+        ));
+    }
 }
@@ -4,8 +4,10 @@ use tracing::debug;
 use tun::{Device, IntoAddress};
 
 use crate::{
+    dns::{self, DnsForwarder},
     model::{params::TunnelParams, proto::HelloReplyData},
-    platform, util,
+    platform::{self, LocalSubnet},
+    progress, util,
 };
 
 pub struct TunDevice {
@@ -16,7 +18,7 @@ pub struct TunDevice {
 }
 
 impl TunDevice {
-    pub fn new(name: &str, reply: &HelloReplyData) -> anyhow::Result<Self> {
+    pub fn new(name: &str, reply: &HelloReplyData, mtu: Option<u16>) -> anyhow::Result<Self> {
         let mut config = platform::new_tun_config();
         let ipaddr = reply.office_mode.ipaddr.parse::<Ipv4Addr>()?;
 
@@ -27,6 +29,11 @@ impl TunDevice {
             config.netmask(netmask.subnet.as_str());
         }
 
+        if let Some(mtu) = mtu {
+            debug!("Tunnel MTU: {}, from the configuration", mtu);
+            config.mtu(mtu.into());
+        }
+
         let dev = tun::create_as_async(&config)?;
 
         let dev_name = dev.get_ref().name()?;
@@ -49,8 +56,13 @@ impl TunDevice {
         self.inner
     }
 
-    pub async fn setup_dns_and_routing(&self, params: &TunnelParams) -> anyhow::Result<()> {
-        let dest_ips = format!("{}:443", params.server_name)
+    pub async fn setup_dns_and_routing(
+        &self,
+        params: &TunnelParams,
+        local_subnets: &[LocalSubnet],
+    ) -> anyhow::Result<Option<DnsForwarder>> {
+        let (host, port) = util::split_server_name(&params.server_name);
+        let dest_ips = (host, port.unwrap_or(443))
             .to_socket_addrs()?
             .flat_map(|s| s.into_address().ok())
             .collect::<Vec<_>>();
@@ -60,8 +72,8 @@ impl TunDevice {
         let mut subnets = params.add_routes.clone();
 
         if !params.no_routing {
-            if params.default_route {
-                let _ = platform::add_default_route(&self.dev_name, self.ipaddr).await;
+            if params.default_route || !params.honor_encryption_domain {
+                let _ = platform::add_default_route(&self.dev_name, self.ipaddr, None).await;
             } else {
                 subnets.extend(util::ranges_to_subnets(&self.reply.range));
             }
@@ -69,31 +81,67 @@ impl TunDevice {
 
         subnets.retain(|s| dest_ips.iter().all(|i| !s.contains(i)));
 
+        // the local LAN stays reachable outside of the tunnel
+        subnets.retain(|s| !local_subnets.iter().any(|local| local.subnet.contains(s)));
+
         if !subnets.is_empty() {
-            let _ = platform::add_routes(&subnets, &self.dev_name, self.ipaddr).await;
+            progress::report(format!("Installing {} routes", subnets.len()));
+            let _ = platform::add_routes(&subnets, &self.dev_name, self.ipaddr, None).await;
         }
 
         if !params.no_dns {
-            if let Some(ref suffixes) = self.reply.office_mode.dns_suffix {
-                debug!("Adding acquired DNS suffixes: {:?}", suffixes.0);
-                debug!("Adding provided DNS suffixes: {:?}", params.search_domains);
-                let suffixes = suffixes.0.iter().chain(params.search_domains.iter()).filter(|&s| {
+            let suffixes = self
+                .reply
+                .office_mode
+                .dns_suffix
+                .as_ref()
+                .map(|s| s.0.clone())
+                .unwrap_or_default();
+            debug!("Adding acquired DNS suffixes: {:?}", suffixes);
+            debug!("Adding provided DNS suffixes: {:?}", params.search_domains);
+            let suffixes = suffixes
+                .iter()
+                .chain(params.search_domains.iter())
+                .filter(|&s| {
                     !s.is_empty()
                         && !params
                             .ignore_search_domains
                             .iter()
                             .any(|d| d.to_lowercase() == s.to_lowercase())
-                });
-                let _ = platform::add_dns_suffixes(suffixes, &self.dev_name).await;
+                })
+                .cloned()
+                .collect::<Vec<_>>();
+
+            let servers = self.reply.office_mode.dns_servers.clone().unwrap_or_default();
+
+            // forced tunnel DNS takes precedence over the embedded forwarder unless it is resolved over HTTPS
+            if dns::use_forwarder(params) {
+                let split_domains = if params.dns_split_domains.is_empty() {
+                    suffixes
+                } else {
+                    params.dns_split_domains.clone()
+                };
+                let servers = servers.iter().flat_map(|s| s.parse().ok()).collect();
+                let forwarder = dns::start_forwarder(params, servers, split_domains).await?;
+                let _ = platform::set_dns_forwarder(dns::FORWARDER_ADDRESS, &self.dev_name, params.dns_manager).await;
+                return Ok(Some(forwarder));
+            }
+
+            if params.force_tunnel_dns {
+                debug!("Routing all DNS queries through the tunnel");
+                let _ = platform::set_default_dns_route(suffixes, &self.dev_name, params.dns_manager).await;
+            } else if self.reply.office_mode.dns_suffix.is_some() {
+                let _ = platform::add_dns_suffixes(suffixes, &self.dev_name, params.dns_manager).await;
             }
 
-            if let Some(ref servers) = self.reply.office_mode.dns_servers {
+            if !servers.is_empty() {
                 debug!("Adding DNS servers: {servers:?}");
-                let _ = platform::add_dns_servers(servers, &self.dev_name).await;
+                progress::report(format!("Configuring {} DNS servers", servers.len()));
+                let _ = platform::add_dns_servers(servers, &self.dev_name, params.dns_manager).await;
             }
         }
 
-        Ok(())
+        Ok(None)
     }
 }
 
@@ -7,38 +7,76 @@ use std::{
 };
 
 use futures::{channel::oneshot, SinkExt};
-use tracing::{trace, warn};
+use parking_lot::Mutex;
+use tracing::{debug, trace, warn};
 
 use crate::{
-    model::proto::KeepaliveRequestData,
+    model::{params::KeepaliveFailure, proto::KeepaliveRequestData},
     platform::{self},
     tunnel::ssl::PacketSender,
 };
 
 const KEEPALIVE_MAX_RETRIES: u64 = 3;
 const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+const RESUME_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+async fn send_keepalive(sender: &mut PacketSender, keepalive_counter: &AtomicU64) -> bool {
+    let req = KeepaliveRequestData { id: "0".to_string() };
+    trace!("Keepalive request: {:?}", req);
+
+    keepalive_counter.fetch_add(1, Ordering::SeqCst);
+
+    matches!(
+        tokio::time::timeout(SEND_TIMEOUT, sender.send(req.into())).await,
+        Ok(Ok(_))
+    )
+}
 
 pub struct KeepaliveRunner {
     interval: Duration,
     sender: PacketSender,
     keepalive_counter: Arc<AtomicU64>,
+    enabled: bool,
+    resume_probe: bool,
+    failure: Arc<Mutex<Option<KeepaliveFailure>>>,
 }
 
 impl KeepaliveRunner {
-    pub fn new(interval: Duration, sender: PacketSender, counter: Arc<AtomicU64>) -> Self {
+    pub fn new(
+        interval: Duration,
+        sender: PacketSender,
+        counter: Arc<AtomicU64>,
+        enabled: bool,
+        resume_probe: bool,
+    ) -> Self {
         Self {
             interval,
             sender,
             keepalive_counter: counter,
+            enabled,
+            resume_probe,
+            failure: Arc::new(Mutex::new(None)),
         }
     }
 
+    // the reason of the keepalive failure after run has returned
+    pub fn failure(&self) -> Option<KeepaliveFailure> {
+        *self.failure.lock()
+    }
+
     pub async fn run(&self) {
+        if !self.enabled {
+            debug!("Keepalive is disabled");
+            return futures::future::pending().await;
+        }
+
         let (stop_sender, stop_receiver) = oneshot::channel();
 
         let interval = self.interval;
         let keepalive_counter = self.keepalive_counter.clone();
         let mut sender = self.sender.clone();
+        let resume_probe = self.resume_probe;
+        let failure = self.failure.clone();
 
         tokio::spawn(async move {
             loop {
@@ -46,23 +84,31 @@ impl KeepaliveRunner {
                     if keepalive_counter.load(Ordering::SeqCst) >= KEEPALIVE_MAX_RETRIES {
                         let msg = "No response for keepalive packets, tunnel appears stuck";
                         warn!(msg);
+                        *failure.lock() = Some(KeepaliveFailure::Timeout);
                         break;
                     }
 
-                    let req = KeepaliveRequestData { id: "0".to_string() };
-                    trace!("Keepalive request: {:?}", req);
-
-                    keepalive_counter.fetch_add(1, Ordering::SeqCst);
+                    if !send_keepalive(&mut sender, &keepalive_counter).await {
+                        warn!("Cannot send keepalive packet, exiting");
+                        *failure.lock() = Some(KeepaliveFailure::Unreachable);
+                        break;
+                    }
+                }
 
-                    match tokio::time::timeout(SEND_TIMEOUT, sender.send(req.into())).await {
-                        Ok(Ok(_)) => {}
-                        _ => {
-                            warn!("Cannot send keepalive packet, exiting");
+                tokio::select! {
+                    _ = tokio::time::sleep(interval) => {}
+                    _ = platform::wait_for_resume(), if resume_probe => {
+                        debug!("System resume detected, probing the tunnel");
+                        keepalive_counter.store(0, Ordering::SeqCst);
+                        let sent = send_keepalive(&mut sender, &keepalive_counter).await;
+                        tokio::time::sleep(RESUME_PROBE_TIMEOUT).await;
+                        if !sent || keepalive_counter.load(Ordering::SeqCst) > 0 {
+                            warn!("No keepalive response after resume, exiting");
+                            *failure.lock() = Some(KeepaliveFailure::Resume);
                             break;
                         }
                     }
                 }
-                tokio::time::sleep(interval).await;
             }
             let _ = stop_sender.send(());
         });
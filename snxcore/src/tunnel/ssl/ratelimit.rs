@@ -0,0 +1,100 @@
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+// the burst allows for a tenth of a second at the full rate, but at least a few full-size packets
+fn burst(rate: u64) -> f64 {
+    (rate / 10).max(16 * 1024) as f64
+}
+
+struct Bucket {
+    rate: Option<u64>,
+    tokens: f64,
+    updated: Instant,
+}
+
+// token bucket for the packets of the SSL data path, the rate may be changed while the tunnel is running
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: Option<u64>) -> Self {
+        Self {
+            bucket: Mutex::new(Bucket {
+                rate,
+                tokens: rate.map(burst).unwrap_or_default(),
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let mut bucket = self.bucket.lock();
+        bucket.rate = rate;
+        bucket.tokens = rate.map(burst).unwrap_or_default();
+        bucket.updated = Instant::now();
+    }
+
+    // the packet is accounted right away, the returned delay spaces out the packets exceeding the rate
+    fn reserve(&self, bytes: usize, now: Instant) -> Duration {
+        let mut bucket = self.bucket.lock();
+        let Some(rate) = bucket.rate.filter(|&rate| rate > 0) else {
+            return Duration::ZERO;
+        };
+
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate as f64).min(burst(rate)) - bytes as f64;
+        bucket.updated = now;
+
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / rate as f64)
+        }
+    }
+
+    pub async fn acquire(&self, bytes: usize) {
+        let delay = self.reserve(bytes, Instant::now());
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unlimited() {
+        let limiter = RateLimiter::new(None);
+        assert_eq!(limiter.reserve(1_000_000, Instant::now()), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let limiter = RateLimiter::new(Some(100_000));
+        let now = Instant::now();
+
+        // the burst of 16 KiB passes at once, the rest is delayed at the rate
+        assert_eq!(limiter.reserve(16 * 1024, now), Duration::ZERO);
+        let delay = limiter.reserve(10_000, now);
+        assert!(delay > Duration::from_millis(99) && delay < Duration::from_millis(101));
+
+        // the tokens refill over time
+        let later = now + Duration::from_millis(200);
+        assert_eq!(limiter.reserve(10_000, later), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_set_rate() {
+        let limiter = RateLimiter::new(Some(100_000));
+        let now = Instant::now();
+        assert_eq!(limiter.reserve(16 * 1024, now), Duration::ZERO);
+        assert!(!limiter.reserve(10_000, now).is_zero());
+
+        limiter.set_rate(None);
+        assert_eq!(limiter.reserve(1_000_000, now), Duration::ZERO);
+    }
+}
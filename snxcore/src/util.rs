@@ -1,12 +1,23 @@
-use std::{ffi::OsStr, fmt, future::Future, path::Path, process::Output};
+use std::{
+    ffi::OsStr,
+    fmt,
+    future::Future,
+    net::SocketAddr,
+    path::Path,
+    process::{Output, Stdio},
+};
 
 use anyhow::anyhow;
 use ipnet::{Ipv4Net, Ipv4Subnets};
-use tokio::process::Command;
+use tokio::{io::AsyncWriteExt, process::Command};
 use tracing::trace;
 use uuid::Uuid;
 
-use crate::{model::proto::NetworkRange, sexpr::SExpression};
+use crate::{
+    model::{params::IpMode, proto::NetworkRange},
+    server_info::LoginType,
+    sexpr::SExpression,
+};
 
 // reverse engineered from vendor snx utility
 const XOR_TABLE: &[u8] = b"-ODIFIED&W0ROPERTY3HEET7ITH/+4HE3HEET)$3?,$!0?!5?02/0%24)%3.5,,\x10&7?70?/\"*%#43";
@@ -69,6 +80,31 @@ where
     process_output(command.output().await?)
 }
 
+// same as run_command, the input is written to the standard input of the process
+pub async fn run_command_with_input<C, I, T>(command: C, args: I, input: &str) -> anyhow::Result<String>
+where
+    C: AsRef<Path> + fmt::Debug,
+    I: IntoIterator<Item = T> + fmt::Debug,
+    T: AsRef<OsStr>,
+{
+    trace!("Exec: {:?} {:?}", command, args);
+
+    let mut command = Command::new(command.as_ref().as_os_str());
+    command
+        .envs(vec![("LANG", "C"), ("LC_ALL", "C")])
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    let mut child = command.spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input.as_bytes()).await?;
+    }
+
+    process_output(child.wait_with_output().await?)
+}
+
 pub fn block_on<F, O>(f: F) -> O
 where
     F: Future<Output = O>,
@@ -81,6 +117,12 @@ where
     rt.block_on(f)
 }
 
+// FNV-1a, stable across builds and platforms
+pub fn fnv1a(data: &[u8]) -> u32 {
+    data.iter()
+        .fold(0x811c9dc5u32, |hash, b| (hash ^ *b as u32).wrapping_mul(0x01000193))
+}
+
 pub fn ranges_to_subnets(ranges: &[NetworkRange]) -> impl Iterator<Item = Ipv4Net> + '_ {
     ranges.iter().flat_map(|r| Ipv4Subnets::new(r.from, r.to, 0))
 }
@@ -111,6 +153,38 @@ pub fn print_login_options(server_info: &SExpression) {
     }
 }
 
+// machine-readable list of the login types, for generating the configuration
+pub fn print_login_types_json(login_types: &[LoginType]) -> anyhow::Result<()> {
+    println!("{}", serde_json::to_string_pretty(login_types)?);
+    Ok(())
+}
+
+// the server name taken from a gateway redirect may carry the port of its HTTPS endpoint
+pub fn split_server_name(server_name: &str) -> (&str, Option<u16>) {
+    match server_name.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') || host.ends_with(']') => match port.parse() {
+            Ok(port) => (host.trim_start_matches('[').trim_end_matches(']'), Some(port)),
+            Err(_) => (server_name, None),
+        },
+        _ => (server_name, None),
+    }
+}
+
+// in auto mode IPv4 addresses are preferred, IPv6 is used for IPv6-only servers
+pub async fn resolve_server(server_name: &str, port: u16, ip_mode: IpMode) -> anyhow::Result<SocketAddr> {
+    let (host, _) = split_server_name(server_name);
+    let addrs = tokio::net::lookup_host((host, port)).await?.collect::<Vec<_>>();
+
+    let addr = match ip_mode {
+        IpMode::Auto => addrs.iter().find(|addr| addr.is_ipv4()).or_else(|| addrs.first()),
+        IpMode::V4 => addrs.iter().find(|addr| addr.is_ipv4()),
+        IpMode::V6 => addrs.iter().find(|addr| addr.is_ipv6()),
+    };
+
+    addr.copied()
+        .ok_or_else(|| anyhow!("No address for {} with IP mode {}", server_name, ip_mode.as_str()))
+}
+
 pub fn get_device_id() -> String {
     let machine_uuid = crate::platform::get_machine_uuid().unwrap_or_else(|_| Uuid::new_v4());
     Uuid::new_v5(&Uuid::NAMESPACE_OID, machine_uuid.as_bytes())
@@ -119,10 +193,34 @@ pub fn get_device_id() -> String {
         .to_owned()
 }
 
+// names which come from the gateway or the configuration end up in resolver files and update scripts
+pub fn is_valid_dns_name(name: &str) -> bool {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    !name.is_empty()
+        && name.len() <= 253
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_split_server_name() {
+        assert_eq!(split_server_name("vpn.acme.com"), ("vpn.acme.com", None));
+        assert_eq!(split_server_name("vpn.acme.com:8443"), ("vpn.acme.com", Some(8443)));
+        assert_eq!(split_server_name("192.168.1.1:8443"), ("192.168.1.1", Some(8443)));
+        assert_eq!(split_server_name("[2001:db8::1]:8443"), ("2001:db8::1", Some(8443)));
+        assert_eq!(split_server_name("2001:db8::1"), ("2001:db8::1", None));
+    }
+
     #[test]
     fn test_encode_decode() {
         let username = "testuser";
@@ -132,4 +230,18 @@ mod tests {
         let decoded = snx_decrypt(secret.as_bytes()).unwrap();
         assert_eq!(decoded, b"testuser");
     }
+
+    #[test]
+    fn test_is_valid_dns_name() {
+        assert!(is_valid_dns_name("corp.example.com"));
+        assert!(is_valid_dns_name("corp.example.com."));
+        assert!(is_valid_dns_name("_ldap.dc-1.corp"));
+        assert!(!is_valid_dns_name(""));
+        assert!(!is_valid_dns_name("."));
+        assert!(!is_valid_dns_name("corp..example.com"));
+        assert!(!is_valid_dns_name("-corp.example.com"));
+        assert!(!is_valid_dns_name("corp.example.com\nnameserver 10.0.0.1"));
+        assert!(!is_valid_dns_name("corp example.com"));
+        assert!(!is_valid_dns_name(&"a".repeat(64)));
+    }
 }
@@ -0,0 +1,255 @@
+use std::{
+    collections::VecDeque,
+    io::Write,
+    time::{Duration, Instant},
+};
+
+use chrono::Local;
+use crossterm::{
+    cursor,
+    event::{self, Event, KeyCode, KeyEvent, KeyModifiers},
+    execute, queue,
+    style::Print,
+    terminal::{self, ClearType},
+};
+
+use snxcore::{
+    browser::BrowserController, controller::ServiceController, model::ConnectionStatus, prompt::SecurePrompt,
+};
+
+const MAX_EVENTS: usize = 8;
+
+fn state_name(status: &ConnectionStatus) -> &'static str {
+    match (status.connected_since, &status.mfa) {
+        (Some(_), _) if status.disconnect_pending => "Disconnect pending",
+        (Some(_), None) if status.suspended => "Suspended",
+        (_, Some(_)) => "MFA pending",
+        (Some(_), None) => "Connected",
+        (None, None) if status.standby => "Standby",
+        (None, None) => "Disconnected",
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+fn format_uptime(seconds: i64) -> String {
+    let seconds = seconds.max(0);
+    format!("{:02}:{:02}:{:02}", seconds / 3600, (seconds / 60) % 60, seconds % 60)
+}
+
+#[derive(Default)]
+struct DashboardState {
+    status: Option<ConnectionStatus>,
+    error: Option<String>,
+    events: VecDeque<String>,
+}
+
+impl DashboardState {
+    fn push_event<S: AsRef<str>>(&mut self, event: S) {
+        if self.events.len() >= MAX_EVENTS {
+            self.events.pop_front();
+        }
+        self.events
+            .push_back(format!("{} {}", Local::now().format("%H:%M:%S"), event.as_ref()));
+    }
+
+    // recent events are derived from the differences between consecutive status responses
+    fn update(&mut self, result: anyhow::Result<ConnectionStatus>) {
+        match result {
+            Ok(status) => {
+                let new_state = state_name(&status);
+                if self.status.as_ref().map(state_name) != Some(new_state) {
+                    self.push_event(new_state);
+                }
+
+                let old_errors = self
+                    .status
+                    .as_ref()
+                    .and_then(|s| s.stats.as_ref())
+                    .map(|s| s.recv_errors)
+                    .unwrap_or_default();
+                if let Some(ref stats) = status.stats {
+                    if stats.recv_errors > old_errors {
+                        self.push_event(format!("{} new receive errors", stats.recv_errors - old_errors));
+                    }
+                }
+
+                self.status = Some(status);
+                self.error = None;
+            }
+            Err(e) => {
+                let msg = e.to_string();
+                if self.error.as_ref() != Some(&msg) {
+                    self.push_event(format!("Error: {}", msg));
+                }
+                self.status = None;
+                self.error = Some(msg);
+            }
+        }
+    }
+
+    fn lines(&self, server_name: &str) -> Vec<String> {
+        let mut lines = vec![format!("snx-rs: {}, press q to quit", server_name), String::new()];
+
+        match self.status {
+            Some(ref status) => {
+                lines.push(format!("State:           {}", state_name(status)));
+
+                if let Some(since) = status.connected_since {
+                    let uptime = (Local::now() - since).num_seconds();
+                    lines.push(format!("Uptime:          {}", format_uptime(uptime)));
+                }
+
+                if let Some(ref realm) = status.realm {
+                    lines.push(format!("Realm:           {}", realm));
+                }
+
+                if let Some(ref stats) = status.stats {
+                    lines.push(format!("Received:        {}", format_bytes(stats.bytes_received)));
+                    lines.push(format!("Sent:            {}", format_bytes(stats.bytes_sent)));
+                    lines.push(format!(
+                        "Keepalive RTT:   {}",
+                        stats
+                            .keepalive_rtt
+                            .map(|rtt| format!("{} ms", rtt))
+                            .unwrap_or_else(|| "n/a".to_owned())
+                    ));
+                    lines.push(format!("Receive errors:  {}", stats.recv_errors));
+                    lines.push(format!("Socket drops:    {}", stats.socket_drops));
+                    if let Some(interface) = stats.interface {
+                        lines.push(format!(
+                            "Interface:       rx {} packets, {} errors, {} dropped",
+                            interface.rx_packets, interface.rx_errors, interface.rx_dropped
+                        ));
+                        lines.push(format!(
+                            "                 tx {} packets, {} errors, {} dropped",
+                            interface.tx_packets, interface.tx_errors, interface.tx_dropped
+                        ));
+                    }
+                    if let Some(sequence) = stats.esp_sequence {
+                        lines.push(format!(
+                            "ESP sequence:    {} ({:.1}% used)",
+                            sequence,
+                            sequence as f64 * 100.0 / u32::MAX as f64
+                        ));
+                    }
+                    for (name, usage) in [
+                        ("Last minute:", stats.last_minute),
+                        ("Last hour:", stats.last_hour),
+                        ("Last day:", stats.last_day),
+                    ] {
+                        lines.push(format!(
+                            "{:<17}{} in, {} out",
+                            name,
+                            format_bytes(usage.received),
+                            format_bytes(usage.sent)
+                        ));
+                    }
+                }
+
+                if let Some(ref crypto) = status.crypto {
+                    lines.push(format!("Encryption:      {}, {}", crypto.encryption, crypto.integrity));
+                }
+
+                if !status.encryption_domain.is_empty() {
+                    lines.push(format!("Routed subnets:  {}", status.encryption_domain.len()));
+                }
+            }
+            None => {
+                lines.push("State:           Unknown".to_owned());
+                if let Some(ref error) = self.error {
+                    lines.push(format!("Error:           {}", error));
+                }
+            }
+        }
+
+        lines.push(String::new());
+        lines.push("Recent events:".to_owned());
+        lines.extend(self.events.iter().rev().map(|event| format!("  {}", event)));
+
+        lines
+    }
+}
+
+fn render(lines: &[String]) -> anyhow::Result<()> {
+    let mut stdout = std::io::stdout();
+
+    queue!(stdout, terminal::Clear(ClearType::All), cursor::MoveTo(0, 0))?;
+    for line in lines {
+        queue!(stdout, Print(line), cursor::MoveToNextLine(1))?;
+    }
+    stdout.flush()?;
+
+    Ok(())
+}
+
+// returns true if q, Esc or Ctrl-C was pressed before the timeout expired
+fn wait_quit_key(timeout: Duration) -> anyhow::Result<bool> {
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() || !event::poll(remaining)? {
+            return Ok(false);
+        }
+
+        if let Event::Key(KeyEvent { code, modifiers, .. }) = event::read()? {
+            match code {
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(true),
+                KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => return Ok(true),
+                _ => {}
+            }
+        }
+    }
+}
+
+async fn run_loop<B, P>(controller: &ServiceController<B, P>, interval: Duration) -> anyhow::Result<()>
+where
+    B: BrowserController + Send + Sync,
+    P: SecurePrompt + Send + Sync,
+{
+    let mut state = DashboardState::default();
+
+    loop {
+        state.update(controller.query_status().await);
+        render(&state.lines(&controller.params.server_name))?;
+
+        if tokio::task::spawn_blocking(move || wait_quit_key(interval)).await?? {
+            break Ok(());
+        }
+    }
+}
+
+pub async fn run<B, P>(controller: &ServiceController<B, P>, interval: Duration) -> anyhow::Result<()>
+where
+    B: BrowserController + Send + Sync,
+    P: SecurePrompt + Send + Sync,
+{
+    let mut stdout = std::io::stdout();
+
+    terminal::enable_raw_mode()?;
+    execute!(stdout, terminal::EnterAlternateScreen, cursor::Hide)?;
+
+    let result = run_loop(controller, interval).await;
+
+    // the terminal must be restored even if the loop has failed
+    let _ = execute!(stdout, cursor::Show, terminal::LeaveAlternateScreen);
+    let _ = terminal::disable_raw_mode();
+
+    result
+}
@@ -1,15 +1,21 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 
+use anyhow::anyhow;
 use clap::Parser;
 use tracing::level_filters::LevelFilter;
 
 use snxcore::{
     browser::BrowserController,
     controller::{ServiceCommand, ServiceController},
-    model::params::TunnelParams,
-    prompt::TtyPrompt,
+    directory,
+    model::{params::TunnelParams, ConnectionPhase, ConnectionStatus},
+    prompt::{PromptAnswers, ScriptedAnswers, TtyPrompt},
+    redact::Redactor,
+    server_info,
 };
 
+mod dashboard;
+
 struct SystemBrowser;
 
 impl BrowserController for SystemBrowser {
@@ -30,32 +36,339 @@ pub struct CmdlineParams {
         help = "Configuration file to use [default: $HOME/.config/snx-rs/snx-rs.conf]"
     )]
     config_file: Option<PathBuf>,
+    #[clap(
+        long = "instance-name",
+        short = 'I',
+        global = true,
+        help = "Service instance to control, its configuration is read from $HOME/.config/snx-rs/<name>/snx-rs.conf"
+    )]
+    instance_name: Option<String>,
+    #[clap(
+        long = "profile",
+        short = 'p',
+        global = true,
+        help = "Gateway profile from the directory server configured with the directory-url option"
+    )]
+    profile: Option<String>,
+    #[clap(
+        long = "answers",
+        short = 'a',
+        global = true,
+        help = "Read the answers for the authentication prompts from a file, one per line in the prompt order, '-' for stdin"
+    )]
+    answers: Option<PathBuf>,
+    #[clap(
+        long = "server-prompt-answers",
+        global = true,
+        conflicts_with = "answers",
+        help = "Read the authentication prompt answers from a JSON object keyed by prompt text or 1-based index"
+    )]
+    server_prompt_answers: Option<PathBuf>,
+    #[clap(
+        long = "quiet",
+        short = 'q',
+        global = true,
+        conflicts_with = "verbose",
+        help = "Only log errors, overrides the log level from the configuration file"
+    )]
+    quiet: bool,
+    #[clap(
+        long = "verbose",
+        short = 'v',
+        global = true,
+        action = clap::ArgAction::Count,
+        help = "Enable debug logging, repeat for trace level"
+    )]
+    verbose: u8,
     #[clap(subcommand)]
     command: SnxCommand,
 }
 
+impl CmdlineParams {
+    fn log_level(&self, default: &str) -> LevelFilter {
+        match (self.quiet, self.verbose) {
+            (true, _) => LevelFilter::ERROR,
+            (false, 0) => default.parse::<LevelFilter>().unwrap_or(LevelFilter::OFF),
+            (false, 1) => LevelFilter::DEBUG,
+            (false, _) => LevelFilter::TRACE,
+        }
+    }
+}
+
 #[derive(Parser)]
 enum SnxCommand {
     #[clap(name = "connect", about = "Connect a tunnel")]
-    Connect,
+    Connect {
+        #[clap(
+            long = "wait",
+            short = 'w',
+            help = "Do not return until the tunnel is connected or has failed"
+        )]
+        wait: bool,
+        #[clap(
+            long = "force",
+            help = "Connect even if a cooldown after authentication failures is active"
+        )]
+        force: bool,
+        #[clap(
+            long = "timings",
+            help = "Wait for the connection and print the duration of each connection phase as JSON"
+        )]
+        timings: bool,
+    },
+    #[clap(
+        name = "ensure",
+        about = "Connect a tunnel unless it is already connected, for scripts and cron jobs"
+    )]
+    Ensure {
+        #[clap(
+            long = "wait",
+            short = 'w',
+            help = "Do not return until the tunnel is connected or has failed"
+        )]
+        wait: bool,
+    },
     #[clap(name = "disconnect", about = "Disconnect a tunnel")]
     Disconnect,
     #[clap(name = "reconnect", about = "Reconnect a tunnel")]
-    Reconnect,
+    Reconnect {
+        #[clap(
+            long = "wait",
+            short = 'w',
+            help = "Do not return until the tunnel is connected or has failed"
+        )]
+        wait: bool,
+    },
     #[clap(name = "status", about = "Show connection status")]
-    Status,
+    Status {
+        #[clap(
+            long = "watch",
+            short = 'w',
+            help = "Refresh the status periodically until interrupted"
+        )]
+        watch: bool,
+        #[clap(
+            long = "interval",
+            short = 'n',
+            default_value = "2",
+            help = "Refresh interval in seconds for the watch mode"
+        )]
+        interval: u64,
+    },
+    #[clap(
+        name = "events",
+        about = "Show the connection progress events of the service until interrupted"
+    )]
+    Events,
+    #[clap(name = "dashboard", about = "Show an interactive connection dashboard")]
+    Dashboard {
+        #[clap(
+            long = "interval",
+            short = 'n',
+            default_value = "1",
+            help = "Refresh interval in seconds"
+        )]
+        interval: u64,
+    },
+    #[clap(
+        name = "benchmark",
+        about = "Measure the throughput and packet loss of the established tunnel"
+    )]
+    Benchmark {
+        #[clap(
+            long = "target",
+            help = "UDP echo service as address:port, the internal gateway address is used by default"
+        )]
+        target: Option<SocketAddr>,
+    },
+    #[clap(
+        name = "auth-test",
+        about = "Run the authentication and MFA challenges without establishing a tunnel"
+    )]
+    AuthTest,
+    #[clap(
+        name = "suspend",
+        about = "Withdraw the tunnel routes and DNS settings while keeping the tunnel up"
+    )]
+    Suspend,
+    #[clap(name = "rate-limit", about = "Change the tunnel rate limit while connected")]
+    RateLimit {
+        #[clap(help = "Bytes per second, 0 removes the limit")]
+        rate: u64,
+    },
+    #[clap(
+        name = "add-route",
+        about = "Route an additional subnet through the connected tunnel"
+    )]
+    AddRoute {
+        #[clap(help = "Subnet in the CIDR notation, e.g. 10.20.0.0/16")]
+        subnet: String,
+    },
+    #[clap(name = "remove-route", about = "Remove a subnet added with add-route")]
+    RemoveRoute {
+        #[clap(help = "Subnet in the CIDR notation")]
+        subnet: String,
+    },
+    #[clap(name = "resume", about = "Restore the routes and DNS settings of a suspended tunnel")]
+    Resume,
+    #[clap(
+        name = "prewarm",
+        about = "Authenticate ahead of time and keep the session ready for a fast connect"
+    )]
+    Prewarm,
+    #[clap(name = "cancel", about = "Cancel a connection in progress")]
+    Cancel,
     #[clap(name = "info", about = "Show server information")]
-    Info,
+    Info {
+        #[clap(long = "json", help = "Print the login types as JSON")]
+        json: bool,
+    },
+    #[clap(
+        name = "fetch-cert",
+        about = "Show the gateway certificate chain with the SHA-256 fingerprints, no tunnel is established"
+    )]
+    FetchCert {
+        #[clap(
+            long = "ca-file",
+            help = "Write the last certificate of the chain into this PEM file"
+        )]
+        ca_file: Option<PathBuf>,
+    },
+    #[clap(name = "profiles", about = "List the gateway profiles from the directory server")]
+    Profiles,
+    #[clap(name = "export", about = "Export configuration into a passphrase-encrypted bundle")]
+    Export {
+        #[clap(help = "Bundle file to write")]
+        file: PathBuf,
+    },
+    #[clap(name = "import", about = "Import configuration from a passphrase-encrypted bundle")]
+    Import {
+        #[clap(help = "Bundle file to read")]
+        file: PathBuf,
+    },
+}
+
+fn print_status(status: ConnectionStatus) {
+    if let Some(since) = status.connected_since {
+        if status.disconnect_pending {
+            println!("Disconnect pending, run disconnect again to tear down the tunnel immediately");
+        }
+        println!(
+            "{} since: {}",
+            if status.mfa.is_some() {
+                "MFA pending"
+            } else if status.suspended {
+                "Suspended"
+            } else {
+                "Connected"
+            },
+            since
+        );
+        if let Some(stats) = status.stats {
+            if stats.recv_errors > 0 {
+                println!("Receive errors: {}", stats.recv_errors);
+            }
+            if stats.socket_drops > 0 {
+                println!("Socket drops: {}", stats.socket_drops);
+            }
+            if let Some(interface) = stats.interface {
+                if interface.rx_errors > 0 || interface.tx_errors > 0 {
+                    println!(
+                        "Interface errors: rx {}, tx {}",
+                        interface.rx_errors, interface.tx_errors
+                    );
+                }
+                if interface.rx_dropped > 0 || interface.tx_dropped > 0 {
+                    println!(
+                        "Interface drops: rx {}, tx {}",
+                        interface.rx_dropped, interface.tx_dropped
+                    );
+                }
+            }
+        }
+        if let Some(realm) = status.realm {
+            println!("Realm: {}", realm);
+        }
+        if let Some(tunnel_type) = status.tunnel_type {
+            println!("Tunnel type: {}", tunnel_type.as_str());
+        }
+        if let Some(rate_limit) = status.rate_limit {
+            println!("Rate limit: {} bytes/s", rate_limit);
+        }
+        if let Some(ends) = status.session_ends {
+            println!("Session ends: {}", ends);
+        }
+        if let Some(expires) = status.cert_expires {
+            println!("Client certificate expires: {}", expires);
+        }
+        if status.busy_retries > 0 {
+            println!("Gateway busy retries: {}", status.busy_retries);
+        }
+        if let Some(interval) = status.suggested_retry_interval {
+            println!("Gateway suggested retry interval: {} seconds", interval);
+        }
+        for warning in status.warnings {
+            println!("Warning: {}", warning);
+        }
+        if !status.encryption_domain.is_empty() {
+            let domain = status
+                .encryption_domain
+                .iter()
+                .map(|net| net.to_string())
+                .collect::<Vec<_>>();
+            println!("Encryption domain: {}", domain.join(", "));
+        }
+        if !status.added_routes.is_empty() {
+            let routes = status
+                .added_routes
+                .iter()
+                .map(|net| net.to_string())
+                .collect::<Vec<_>>();
+            println!("Added routes: {}", routes.join(", "));
+        }
+        if let Some(crypto) = status.crypto {
+            println!(
+                "Encryption: {}, integrity: {}, PFS: {}, NAT-T: {}",
+                crypto.encryption,
+                crypto.integrity,
+                crypto
+                    .pfs_group
+                    .map(|g| format!("group {}", g))
+                    .unwrap_or_else(|| "none".to_owned()),
+                if crypto.natt { "yes" } else { "no" }
+            );
+        }
+    } else if status.standby {
+        println!("Standby, the session is authenticated and ready to connect");
+    } else {
+        match status.disconnect_reason {
+            Some(reason) => println!("Disconnected: {}", reason),
+            None => println!("Disconnected"),
+        }
+        if let Some(reconnect_at) = status.reconnect_at {
+            println!("Reconnecting to the busy gateway at: {}", reconnect_at);
+        }
+        if let Some(interval) = status.suggested_retry_interval {
+            println!("Gateway suggested retry interval: {} seconds", interval);
+        }
+        if let Some(warning) = status.cleanup_warning {
+            println!("Warning: {}", warning);
+        }
+    }
 }
 
-impl From<SnxCommand> for ServiceCommand {
-    fn from(value: SnxCommand) -> Self {
-        match value {
-            SnxCommand::Connect => ServiceCommand::Connect,
-            SnxCommand::Disconnect => ServiceCommand::Disconnect,
-            SnxCommand::Reconnect => ServiceCommand::Reconnect,
-            SnxCommand::Status => ServiceCommand::Status,
-            SnxCommand::Info => ServiceCommand::Info,
+// clear the terminal and redraw the status until Ctrl-C is pressed
+async fn watch_status(service_controller: &mut ServiceController<SystemBrowser, TtyPrompt>, interval: Duration) {
+    loop {
+        print!("\x1b[2J\x1b[H");
+        match service_controller.command(ServiceCommand::Status).await {
+            Ok(status) => print_status(status),
+            Err(e) => println!("Error: {}", e),
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            _ = tokio::signal::ctrl_c() => break,
         }
     }
 }
@@ -67,41 +380,185 @@ async fn main() -> anyhow::Result<()> {
     let config_file = params
         .config_file
         .clone()
-        .unwrap_or_else(TunnelParams::default_config_path);
+        .unwrap_or_else(|| TunnelParams::instance_config_path(params.instance_name.as_deref()));
+
+    let mut tunnel_params = TunnelParams::load(&config_file).unwrap_or_else(|_| TunnelParams {
+        config_file,
+        ..Default::default()
+    });
+
+    if params.instance_name.is_some() {
+        tunnel_params.instance_name.clone_from(&params.instance_name);
+    }
+
+    if let Some(ref profile) = params.profile {
+        directory::apply_profile(&mut tunnel_params, profile).await?;
+    }
+
+    if let SnxCommand::Connect { force, timings, .. } = params.command {
+        tunnel_params.ignore_cooldown = force;
+        tunnel_params.timing_report = timings;
+    }
 
-    let tunnel_params = Arc::new(TunnelParams::load(config_file).unwrap_or_default());
+    let tunnel_params = Arc::new(tunnel_params);
 
     let mut service_controller = ServiceController::new(TtyPrompt, SystemBrowser, tunnel_params)?;
 
+    if let Some(ref answers) = params.answers {
+        let answers = if answers.as_os_str() == "-" {
+            ScriptedAnswers::from_reader(std::io::stdin().lock())?
+        } else {
+            ScriptedAnswers::from_reader(std::io::BufReader::new(std::fs::File::open(answers)?))?
+        };
+        service_controller.set_mfa_provider(answers);
+    }
+
+    if let Some(ref answers) = params.server_prompt_answers {
+        service_controller.set_mfa_provider(PromptAnswers::load(answers)?);
+    }
+
+    // the timing report is the only output then
+    if !params.quiet && !matches!(params.command, SnxCommand::Connect { timings: true, .. }) {
+        service_controller.set_phase_handler(|phase| {
+            // the final status is printed below
+            if phase != ConnectionPhase::Connected {
+                println!("{}...", phase);
+            }
+        });
+    }
+
+    // secure prompts are written directly to the terminal and are not affected by the log level
+    let redactor = Arc::new(Redactor::new(&service_controller.params.log_redact));
     let subscriber = tracing_subscriber::fmt()
-        .with_max_level(
-            service_controller
-                .params
-                .log_level
-                .parse::<LevelFilter>()
-                .unwrap_or(LevelFilter::OFF),
-        )
+        .with_max_level(params.log_level(&service_controller.params.log_level))
+        .with_writer(move || redactor.writer(std::io::stdout()))
         .finish();
     tracing::subscriber::set_global_default(subscriber)?;
 
-    let command = params.command.into();
-
-    match service_controller.command(command).await {
-        Ok(status) if command != ServiceCommand::Info => {
-            if let Some(since) = status.connected_since {
-                println!(
-                    "{} since: {}",
-                    if status.mfa.is_some() {
-                        "MFA pending"
-                    } else {
-                        "Connected"
-                    },
-                    since
-                );
-            } else {
-                println!("Disconnected");
+    let wait = matches!(
+        params.command,
+        SnxCommand::Connect { wait: true, .. }
+            | SnxCommand::Connect { timings: true, .. }
+            | SnxCommand::Reconnect { wait: true }
+            | SnxCommand::Ensure { wait: true }
+    );
+    service_controller.set_wait(wait);
+
+    if let SnxCommand::Connect { timings: true, .. } = params.command {
+        let result = service_controller.connect().await?;
+        let report = serde_json::json!({
+            "timestamp": chrono::Local::now().to_rfc3339(),
+            "server_name": result.server_name,
+            "tunnel_type": service_controller.params.tunnel_type.as_str(),
+            "mfa_rounds": result.mfa_rounds,
+            "total_millis": result.elapsed.as_millis() as u64,
+            "phases": result.status.timings,
+        });
+        println!("{}", report);
+        return Ok(());
+    }
+
+    let command = match params.command {
+        SnxCommand::Connect { .. } => ServiceCommand::Connect,
+        SnxCommand::Ensure { .. } => ServiceCommand::Ensure,
+        SnxCommand::Disconnect => ServiceCommand::Disconnect,
+        SnxCommand::Reconnect { .. } => ServiceCommand::Reconnect,
+        SnxCommand::Status { watch: false, .. } => ServiceCommand::Status,
+        SnxCommand::Status { watch: true, interval } => {
+            watch_status(&mut service_controller, Duration::from_secs(interval.max(1))).await;
+            return Ok(());
+        }
+        SnxCommand::Events => {
+            let watch = service_controller.watch(|event| {
+                println!("{} {}", event.time.format("%H:%M:%S"), event.message);
+            });
+            tokio::select! {
+                result = watch => result?,
+                _ = tokio::signal::ctrl_c() => {}
             }
+            return Ok(());
+        }
+        SnxCommand::Dashboard { interval } => {
+            return dashboard::run(&service_controller, Duration::from_secs(interval.max(1))).await;
+        }
+        SnxCommand::Benchmark { target } => {
+            let result = service_controller.benchmark(target).await?;
+            println!("Target: {}", result.target);
+            println!(
+                "Packets sent: {}, received: {}",
+                result.packets_sent, result.packets_received
+            );
+            println!("Loss: {:.1}%", result.loss_percent());
+            println!("Throughput: {:.2} Mbps", result.throughput_mbps());
+            return Ok(());
+        }
+        SnxCommand::Cancel => ServiceCommand::Cancel,
+        SnxCommand::Suspend => ServiceCommand::Suspend,
+        SnxCommand::Resume => ServiceCommand::Resume,
+        SnxCommand::RateLimit { rate } => ServiceCommand::SetRateLimit(rate),
+        SnxCommand::AddRoute { ref subnet } => {
+            ServiceCommand::AddRoute(subnet.parse().map_err(|_| anyhow!("Invalid subnet: {}", subnet))?)
+        }
+        SnxCommand::RemoveRoute { ref subnet } => {
+            ServiceCommand::RemoveRoute(subnet.parse().map_err(|_| anyhow!("Invalid subnet: {}", subnet))?)
         }
+        SnxCommand::Prewarm => ServiceCommand::Prewarm,
+        SnxCommand::AuthTest => ServiceCommand::AuthTest,
+        SnxCommand::Info { json: false } => ServiceCommand::Info,
+        SnxCommand::Info { json: true } => {
+            let login_types = server_info::get_login_types(&service_controller.params).await?;
+            return snxcore::util::print_login_types_json(&login_types);
+        }
+        SnxCommand::FetchCert { ca_file } => {
+            let chain = service_controller.fetch_cert(ca_file.as_deref()).await?;
+            for (i, cert) in chain.certs.iter().enumerate() {
+                println!("Certificate {}:", i);
+                println!("\tSubject: {}", cert.subject);
+                println!("\tIssuer: {}", cert.issuer);
+                println!("\tExpires: {}", cert.not_after);
+                println!("\tSHA-256: {}", cert.sha256);
+            }
+            println!("Certificate chain cached in {}", chain.cache_path.display());
+            if let (Some(path), Some(_)) = (ca_file, chain.certs.last()) {
+                println!("CA certificate written to {}", path.display());
+            }
+            return Ok(());
+        }
+        SnxCommand::Profiles => {
+            for profile in directory::profiles(&service_controller.params).await? {
+                match profile.description {
+                    Some(description) => println!("{}: {}", profile.name, description),
+                    None => println!("{}", profile.name),
+                }
+            }
+            return Ok(());
+        }
+        SnxCommand::Export { file } => {
+            service_controller.export_config(&file)?;
+            println!("Configuration exported to {}", file.display());
+            return Ok(());
+        }
+        SnxCommand::Import { file } => {
+            service_controller.import_config(&file)?;
+            println!(
+                "Configuration imported into {}",
+                service_controller.params.config_file.display()
+            );
+            return Ok(());
+        }
+    };
+
+    let result = service_controller.command(command).await;
+
+    if let (Err(_), Some(correlation_id)) = (&result, service_controller.correlation_id()) {
+        println!("Correlation id: {}", correlation_id);
+    }
+
+    match result {
+        Ok(_) if command == ServiceCommand::AuthTest => println!("Authentication succeeded"),
+        Ok(status) if command != ServiceCommand::Info => print_status(status),
+        // scripts relying on --wait, the authentication test or ensure need a failure exit code
+        Err(e) if wait || matches!(command, ServiceCommand::AuthTest | ServiceCommand::Ensure) => return Err(e),
         Err(e) => println!("Error: {}", e),
         _ => {}
     }